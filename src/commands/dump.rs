@@ -69,20 +69,34 @@ pub async fn dump(cli: &mut Connection, general: &Options,
         } else {
             anyhow::bail!("`--format=dir` is required when using `--all`");
         }
-        dump_all(cli, general, options.path.as_ref()).await
+        dump_all(cli, general, options.path.as_ref(),
+                 options.buffer_size, &options.exclude_database,
+                 options.schema_only).await
     } else {
         if options.format.is_some() {
             anyhow::bail!("`--format` is reserved for dump using `--all`");
         }
-        dump_db(cli, general, options.path.as_ref()).await
+        if !options.exclude_database.is_empty() {
+            anyhow::bail!("`--exclude-database` can only be used \
+                with `--all`");
+        }
+        dump_db(cli, general, options.path.as_ref(),
+                options.buffer_size, options.schema_only).await
     }
 }
 
-async fn dump_db(cli: &mut Connection, _options: &Options, filename: &Path)
+async fn dump_db(cli: &mut Connection, _options: &Options, filename: &Path,
+    buffer_size: Option<usize>, schema_only: bool)
     -> Result<(), anyhow::Error>
 {
     let mut seq = cli.start_sequence().await?;
-    let (mut output, guard) = Guard::open(filename).await?;
+    let (output, guard) = Guard::open(filename).await?;
+    let mut output = match buffer_size {
+        Some(size) => {
+            Box::new(io::BufWriter::with_capacity(size, output)) as Output
+        }
+        None => output,
+    };
     output.write_all(
         b"\xFF\xD8\x00\x00\xD8EDGEDB\x00DUMP\x00\
           \x00\x00\x00\x00\x00\x00\x00\x01"
@@ -123,6 +137,9 @@ async fn dump_db(cli: &mut Connection, _options: &Options, filename: &Path)
                 seq.expect_ready().await?;
                 break;
             }
+            ServerMessage::DumpBlock(_) if schema_only => {
+                // schema is in the header, server has no way to skip data
+            }
             ServerMessage::DumpBlock(packet) => {
                 // this is ensured because length in the protocol is u32 too
                 assert!(packet.data.len() <= u32::max_value() as usize);
@@ -142,11 +159,12 @@ async fn dump_db(cli: &mut Connection, _options: &Options, filename: &Path)
             }
         }
     }
+    output.flush().await?;
     guard.commit().await?;
     Ok(())
 }
 
-async fn get_databases(cli: &mut Connection)
+pub async fn get_databases(cli: &mut Connection)
     -> Result<Vec<String>, anyhow::Error>
 {
     let mut query = cli.query(
@@ -160,7 +178,7 @@ async fn get_databases(cli: &mut Connection)
     Ok(databases)
 }
 
-async fn get_text(cli: &mut Connection, query: &str)
+pub async fn get_text(cli: &mut Connection, query: &str)
     -> Result<String, anyhow::Error>
 {
     let mut response = cli.query(query, &Value::empty_tuple()).await?;
@@ -176,10 +194,51 @@ async fn get_text(cli: &mut Connection, query: &str)
     Ok(text)
 }
 
-pub async fn dump_all(cli: &mut Connection, options: &Options, dir: &Path)
+pub async fn dump_all(cli: &mut Connection, options: &Options, dir: &Path,
+    buffer_size: Option<usize>, exclude: &[String], schema_only: bool)
     -> Result<(), anyhow::Error>
 {
     let databases = get_databases(cli).await?;
+    check_excluded(&databases, exclude)?;
+    dump_init(cli, dir).await?;
+
+    let mut conn_params = options.conn_params.clone();
+    for database in &databases {
+        if database == "edgedb0" { continue; }
+        if exclude.contains(database) {
+            log::info!("Skipping excluded database {:?}", database);
+            continue;
+        }
+        let mut db_conn = conn_params.database(database).connect().await?;
+        let filename = dir.join(urlencoding::encode(database) + ".dump");
+        dump_db(&mut db_conn, options, &filename, buffer_size,
+                schema_only).await?;
+    }
+
+    Ok(())
+}
+
+/// Checks that databases excluded with `--exclude-database` exist, so
+/// a typo doesn't go unnoticed
+pub fn check_excluded(databases: &[String], exclude: &[String])
+    -> anyhow::Result<()>
+{
+    for name in exclude {
+        if !databases.contains(name) {
+            anyhow::bail!("excluded database {:?} does not exist", name);
+        }
+        if name == "edgedb" {
+            log::warn!("Excluding default database {:?}: it will be \
+                empty after restore", name);
+        }
+    }
+    Ok(())
+}
+
+/// Writes `init.edgeql` with system config and roles (but no databases)
+pub async fn dump_init(cli: &mut Connection, dir: &Path)
+    -> Result<(), anyhow::Error>
+{
     let config = get_text(cli, "DESCRIBE SYSTEM CONFIG").await?;
     let roles = get_text(cli, "DESCRIBE ROLES").await?;
 
@@ -197,14 +256,5 @@ pub async fn dump_all(cli: &mut Connection, options: &Options, dir: &Path)
         init.write_all(b"\n").await?;
     }
     guard.commit().await?;
-
-    let mut conn_params = options.conn_params.clone();
-    for database in &databases {
-        if database == "edgedb0" { continue; }
-        let mut db_conn = conn_params.database(database).connect().await?;
-        let filename = dir.join(urlencoding::encode(database) + ".dump");
-        dump_db(&mut db_conn, options, &filename).await?;
-    }
-
     Ok(())
 }
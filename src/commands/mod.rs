@@ -18,6 +18,7 @@ mod list_scalar_types;
 mod psql;
 mod restore;
 mod roles;
+mod transfer;
 mod type_names;
 pub mod backslash;
 pub mod cli;
@@ -25,7 +26,7 @@ pub mod options;
 pub mod parser;
 
 pub use self::configure::configure;
-pub use self::dump::{dump, dump_all};
+pub use self::dump::{dump, dump_all, dump_init, check_excluded};
 pub use self::describe::describe;
 pub use self::list_aliases::list_aliases;
 pub use self::list_casts::list_casts;
@@ -37,6 +38,8 @@ pub use self::list_ports::list_ports;
 pub use self::list_roles::list_roles;
 pub use self::list_scalar_types::list_scalar_types;
 pub use self::options::Options;
-pub use self::restore::{restore, restore_all};
+pub use self::restore::{restore, restore_all, apply_statements};
+pub use self::restore::{DUMP_MAGIC, MAX_SUPPORTED_DUMP_VER};
 pub use self::psql::psql;
+pub use self::transfer::transfer_all;
 pub use self::exit::ExitCode;
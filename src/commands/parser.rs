@@ -226,6 +226,20 @@ pub struct Dump {
     /// For `--all` only `--format=dir` is required.
     #[clap(long, possible_values=&["dir"][..])]
     pub format: Option<DumpFormat>,
+
+    /// Buffer output in chunks of this many bytes. By default every
+    /// block received from the server is written to the file immediately
+    #[clap(long)]
+    pub buffer_size: Option<usize>,
+
+    /// Dump schema only, without data. Restoring such a dump recreates
+    /// all types, but they have no objects
+    #[clap(long)]
+    pub schema_only: bool,
+
+    /// Don't dump this database (only with `--all`). Can be repeated
+    #[clap(long="exclude-database", number_of_values=1)]
+    pub exclude_database: Vec<String>,
 }
 
 #[derive(Clap, Clone, Debug)]
@@ -244,8 +258,28 @@ pub struct Restore {
     #[clap(long)]
     pub allow_non_empty: bool,
 
-    /// Verbose output
-    #[clap(long, short="v")]
+    /// Restore schema only, skipping data of the dump
+    #[clap(long)]
+    pub schema_only: bool,
+
+    /// Don't restore this database even if it's in the dump (only with
+    /// `--all`). Can be repeated
+    #[clap(long="exclude-database", number_of_values=1)]
+    pub exclude_database: Vec<String>,
+
+    /// Read the dump file in chunks of this many bytes. By default the
+    /// file is read unbuffered, one block at a time
+    #[clap(long)]
+    pub buffer_size: Option<usize>,
+
+    /// Number of jobs the server may use to restore data blocks
+    /// concurrently
+    #[clap(long, default_value="1")]
+    pub jobs: u16,
+
+    /// Verbose output: print timings and every statement executed while
+    /// restoring
+    #[clap(long, short="v", alias="verbose-restore")]
     pub verbose: bool,
 }
 
@@ -5,6 +5,7 @@ use std::ffi::OsString;
 use anyhow::Context;
 use edgedb_client::client::Connection;
 use crate::commands::Options;
+use crate::process;
 use edgedb_client::server_params::PostgresAddress;
 
 
@@ -53,7 +54,8 @@ pub async fn psql<'x>(cli: &mut Connection, _options: &Options)
             #[cfg(unix)]
             let _trap = signal::trap::Trap::trap(&[signal::Signal::SIGINT]);
             cmd.status().context(
-                format!("Error running {:?} (path: {:?})", cmd,
+                format!("Error running {} (path: {:?})",
+                    process::describe(&cmd),
                     path.unwrap_or_else(OsString::new)))?;
         }
         None => {
@@ -1,3 +1,4 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::ffi::OsString;
@@ -20,6 +21,7 @@ use edgedb_protocol::value::Value;
 use edgedb_protocol ::server_message::{ErrorResponse};
 use edgeql_parser::preparser::{is_empty};
 
+use crate::commands::dump::check_excluded;
 use crate::commands::Options;
 use crate::commands::parser::{Restore as RestoreCmd};
 use edgedb_client::client::{Connection, Reader, Writer};
@@ -27,8 +29,10 @@ use crate::statement::{ReadStatement, EndOfFile};
 
 type Input = Box<dyn Read + Unpin + Send>;
 
-const MAX_SUPPORTED_DUMP_VER: i64 = 1;
-const SCHEMA_ERROR: u32 = 0x_04_04_00_00;
+pub const MAX_SUPPORTED_DUMP_VER: i64 = 1;
+/// Signature at the start of the dump file (followed by version)
+pub const DUMP_MAGIC: &[u8] = b"\xFF\xD8\x00\x00\xD8EDGEDB\x00DUMP\x00";
+pub const SCHEMA_ERROR: u32 = 0x_04_04_00_00;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PacketType {
@@ -102,6 +106,10 @@ pub async fn restore<'x>(cli: &mut Connection, options: &Options,
     if params.all {
         restore_all(cli, options, params).await
     } else {
+        if !params.exclude_database.is_empty() {
+            anyhow::bail!("`--exclude-database` can only be used \
+                with `--all`");
+        }
         restore_db(cli, options, params).await
     }
 }
@@ -112,9 +120,12 @@ async fn restore_db<'x>(cli: &mut Connection, options: &Options,
 {
     use PacketType::*;
     let RestoreCmd {
-        allow_non_empty, path: ref filename,
-        all: _, verbose: _,
+        allow_non_empty, path: ref filename, buffer_size, jobs,
+        schema_only, all: _, verbose: _, exclude_database: _,
     } = *params;
+    if jobs == 0 {
+        anyhow::bail!("`--jobs` must be at least 1");
+    }
     if !allow_non_empty {
         if is_empty_db(cli).await.context("Error checking DB emptyness")? {
             if options.command_line {
@@ -138,11 +149,14 @@ async fn restore_db<'x>(cli: &mut Connection, options: &Options,
         .with_context(file_ctx)?
         as Input
     };
+    if let Some(size) = buffer_size {
+        input = Box::new(io::BufReader::with_capacity(size, input));
+    }
     let mut buf = [0u8; 17+8];
     input.read_exact(&mut buf).await
         .context("Cannot read header")
         .with_context(file_ctx)?;
-    if &buf[..17] != b"\xFF\xD8\x00\x00\xD8EDGEDB\x00DUMP\x00" {
+    if &buf[..17] != DUMP_MAGIC {
         Err(anyhow::anyhow!("File is not an edgedb dump"))
         .with_context(file_ctx)?
     }
@@ -159,7 +173,7 @@ async fn restore_db<'x>(cli: &mut Connection, options: &Options,
     seq.send_messages(&[
         ClientMessage::Restore(Restore {
             headers: HashMap::new(),
-            jobs: 1,
+            jobs,
             data: header,
         })
     ]).await?;
@@ -183,7 +197,7 @@ async fn restore_db<'x>(cli: &mut Connection, options: &Options,
         }
     }
     let result = send_blocks(&mut seq.writer, &mut input,
-                             filename.as_ref())
+                             filename.as_ref(), schema_only)
         .race(wait_response(&mut seq.reader, start_headers))
         .await;
     if let Err(..) = result {
@@ -195,7 +209,7 @@ async fn restore_db<'x>(cli: &mut Connection, options: &Options,
 }
 
 async fn send_blocks(writer: &mut Writer<'_>, input: &mut Input,
-    filename: &Path)
+    filename: &Path, schema_only: bool)
     -> Result<(), anyhow::Error>
 {
     use PacketType::*;
@@ -206,6 +220,10 @@ async fn send_blocks(writer: &mut Writer<'_>, input: &mut Input,
             .with_context(|| format!("Failed to read dump {}",
                                      filename.display()))?
     {
+        if schema_only {
+            // Eof right after the header restores empty types
+            break;
+        }
         writer.send_messages(&[
             ClientMessage::RestoreBlock(RestoreBlock { data })
         ]).await?;
@@ -224,7 +242,7 @@ async fn send_blocks(writer: &mut Writer<'_>, input: &mut Input,
     }
 }
 
-async fn wait_response(reader: &mut Reader<'_>, start: Instant)
+pub async fn wait_response(reader: &mut Reader<'_>, start: Instant)
     -> Result<(), anyhow::Error>
 {
     loop {
@@ -257,9 +275,16 @@ fn path_to_database_name(path: &Path) -> anyhow::Result<String> {
 
 async fn apply_init(cli: &mut Connection, path: &Path) -> anyhow::Result<()> {
     let mut input = fs::File::open(path).await?;
+    apply_statements(cli, &mut input).await
+}
+
+pub async fn apply_statements<T>(cli: &mut Connection, input: &mut T)
+    -> anyhow::Result<()>
+    where T: Read + Unpin,
+{
     let mut inbuf = BytesMut::with_capacity(8192);
     loop {
-        let stmt = match ReadStatement::new(&mut inbuf, &mut input).await {
+        let stmt = match ReadStatement::new(&mut inbuf, input).await {
             Ok(chunk) => chunk,
             Err(e) if e.is::<EndOfFile>() => break,
             Err(e) => return Err(e),
@@ -267,33 +292,71 @@ async fn apply_init(cli: &mut Connection, path: &Path) -> anyhow::Result<()> {
         let stmt = str::from_utf8(&stmt[..])
             .context("can't decode statement")?;
         if !is_empty(stmt) {
+            log::info!(target: "edgedb::restore", "Executing {}",
+                       loggable(stmt));
             cli.execute(&stmt).await
-                .with_context(|| format!("failed statement {:?}", stmt))?;
+                .with_context(|| format!("failed statement {:?}",
+                                         loggable(stmt)))?;
         }
     }
     Ok(())
 }
 
+/// Statement as shown in logs and errors. Role statements may contain
+/// passwords or their hashes, so only the kind of such statements (e.g.
+/// `ALTER ROLE`) is shown
+fn loggable(stmt: &str) -> Cow<str> {
+    let words = stmt.split_whitespace()
+        .take(3)
+        .map(|word| word.to_uppercase())
+        .collect::<Vec<_>>();
+    let kind = match &words[..] {
+        [verb, superuser, role, ..] if superuser == "SUPERUSER"
+            && role == "ROLE"
+            => format!("{} SUPERUSER ROLE", verb),
+        [verb, role, ..] if role == "ROLE" => format!("{} ROLE", verb),
+        _ if stmt.to_lowercase().contains("password") => {
+            words.first().cloned().unwrap_or_default()
+        }
+        _ => return stmt.trim().into(),
+    };
+    format!("{} ... (redacted)", kind).into()
+}
+
 pub async fn restore_all<'x>(cli: &mut Connection, options: &Options,
     params: &RestoreCmd)
     -> anyhow::Result<()>
 {
     let dir = &params.path;
+    let dump_ext = OsString::from("dump");
+    let mut dumps = Vec::new();
+    let mut dir_list = fs::read_dir(&dir).await?;
+    while let Some(entry) = dir_list.next().await.transpose()? {
+        let path = entry.path();
+        if path.extension() != Some(&dump_ext) {
+            continue;
+        }
+        let database = path_to_database_name(&path)?;
+        dumps.push((path, database));
+    }
+    let databases = dumps.iter().map(|(_, db)| db.clone()).collect::<Vec<_>>();
+    check_excluded(&databases, &params.exclude_database)?;
+
     let filename = dir.join("init.edgeql");
+    log::info!(target: "edgedb::restore",
+        "Applying init file {}", filename.display());
     apply_init(cli, filename.as_ref()).await
         .with_context(|| format!("error applying init file {:?}", filename))?;
 
     let mut conn_params = options.conn_params.clone();
     let mut params = params.clone();
 
-    let dump_ext = OsString::from("dump");
-    let mut dir_list = fs::read_dir(&dir).await?;
-    while let Some(entry) = dir_list.next().await.transpose()? {
-        let path = entry.path();
-        if path.extension() != Some(&dump_ext) {
+    for (path, database) in dumps {
+        if params.exclude_database.contains(&database) {
+            log::info!(target: "edgedb::restore",
+                "Skipping excluded database {:?}", database);
             continue;
         }
-        let database = path_to_database_name(&path)?;
         let create_db = format!("CREATE DATABASE {}", quote_name(&database));
         let db_error = match cli.execute(create_db).await {
             Ok(_) => None,
@@ -310,6 +373,8 @@ pub async fn restore_all<'x>(cli: &mut Connection, options: &Options,
                 }
             }
         };
+        log::info!(target: "edgedb::restore",
+            "Restoring database {:?} from {}", database, path.display());
         conn_params.database(&database);
         let mut db_conn = match conn_params.connect().await  {
             Ok(conn) => conn,
@@ -333,3 +398,21 @@ pub async fn restore_all<'x>(cli: &mut Connection, options: &Options,
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::loggable;
+
+    #[test]
+    fn test_loggable() {
+        assert_eq!(loggable("  CREATE TYPE Foo;\n"), "CREATE TYPE Foo;");
+        assert_eq!(loggable("ALTER ROLE edgedb {\n\
+                SET password_hash := 'SCRAM-SHA-256$4096:xxx';\n};"),
+                   "ALTER ROLE ... (redacted)");
+        assert_eq!(loggable("create superuser role admin { \
+                set password := 'secret' };"),
+                   "CREATE SUPERUSER ROLE ... (redacted)");
+        assert_eq!(loggable("CONFIGURE SYSTEM SET password := 'x';"),
+                   "CONFIGURE ... (redacted)");
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::default::Default;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use async_std::future::{timeout, pending};
+use async_std::prelude::FutureExt;
+
+use edgeql_parser::helpers::quote_name;
+use edgedb_client::Builder;
+use edgedb_client::client::Connection;
+use edgedb_protocol::client_message::{ClientMessage, Dump};
+use edgedb_protocol::client_message::{Restore, RestoreBlock};
+use edgedb_protocol::server_message::{ServerMessage, ErrorResponse};
+
+use crate::commands::dump::{check_excluded, get_databases, get_text};
+use crate::commands::restore::{apply_statements, wait_response};
+use crate::commands::restore::SCHEMA_ERROR;
+
+
+/// Restores dump of a single database from `source` into `target`
+/// without storing the dump anywhere
+async fn transfer_db(source: &mut Connection, target: &mut Connection,
+    jobs: u16)
+    -> anyhow::Result<()>
+{
+    let mut src = source.start_sequence().await?;
+    src.send_messages(&[
+        ClientMessage::Dump(Dump {
+            headers: Default::default(),
+        }),
+        ClientMessage::Sync,
+    ]).await?;
+    let header = match src.message().await? {
+        ServerMessage::DumpHeader(packet) => packet.data,
+        msg => {
+            return Err(anyhow::anyhow!(
+                "WARNING: unsolicited message {:?}", msg));
+        }
+    };
+
+    let start_headers = Instant::now();
+    let mut seq = target.start_sequence().await?;
+    seq.send_messages(&[
+        ClientMessage::Restore(Restore {
+            headers: HashMap::new(),
+            jobs,
+            data: header,
+        })
+    ]).await?;
+    loop {
+        let msg = seq.message().await?;
+        match msg {
+            ServerMessage::RestoreReady(_) => {
+                log::info!(target: "edgedb::restore",
+                    "Schema applied in {:?}", start_headers.elapsed());
+                break;
+            }
+            ServerMessage::ErrorResponse(err) => {
+                seq.err_sync().await.ok();
+                return Err(anyhow::anyhow!(err)
+                    .context("Error initiating restore protocol"));
+            }
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "WARNING: unsolicited message {:?}", msg));
+            }
+        }
+    }
+
+    let writer = &mut seq.writer;
+    let reader = &mut seq.reader;
+    let send_blocks = async {
+        let start_blocks = Instant::now();
+        loop {
+            match src.message().await? {
+                ServerMessage::DumpBlock(packet) => {
+                    writer.send_messages(&[
+                        ClientMessage::RestoreBlock(RestoreBlock {
+                            data: packet.data,
+                        })
+                    ]).await?;
+                }
+                ServerMessage::CommandComplete(..) => {
+                    src.expect_ready().await?;
+                    break;
+                }
+                msg => {
+                    return Err(anyhow::anyhow!(
+                        "WARNING: unsolicited message {:?}", msg));
+                }
+            }
+        }
+        writer.send_messages(&[ClientMessage::RestoreEof]).await?;
+        log::info!(target: "edgedb::restore",
+            "Blocks transferred in {:?}", start_blocks.elapsed());
+
+        // This future should be canceled by wait_response() receiving
+        // CommandComplete
+        let start_waiting = Instant::now();
+        loop {
+            timeout(Duration::from_secs(60), pending::<()>()).await.ok();
+            log::info!(target: "edgedb::restore",
+                "Waiting for complete {:?}", start_waiting.elapsed());
+        }
+    };
+    let result = send_blocks
+        .race(wait_response(reader, start_headers))
+        .await;
+    if let Err(..) = result {
+        seq.err_sync().await.ok();
+    } else {
+        seq.end_clean();
+    }
+    result
+}
+
+/// Same as `dump --all` on `source` followed by `restore --all` on
+/// `target`, but blocks are sent to the target as soon as they arrive
+pub async fn transfer_all(source: &mut Connection, source_params: &Builder,
+    target: &mut Connection, target_params: &Builder, jobs: u16,
+    exclude: &[String])
+    -> anyhow::Result<()>
+{
+    let databases = get_databases(source).await?;
+    check_excluded(&databases, exclude)?;
+    let config = get_text(source, "DESCRIBE SYSTEM CONFIG").await?;
+    let roles = get_text(source, "DESCRIBE ROLES").await?;
+    apply_statements(target, &mut config.as_bytes()).await
+        .context("error applying system config")?;
+    apply_statements(target, &mut roles.as_bytes()).await
+        .context("error applying roles")?;
+
+    let mut source_params = source_params.clone();
+    let mut target_params = target_params.clone();
+    for database in &databases {
+        if database == "edgedb0" { continue; }
+        if exclude.contains(database) {
+            log::info!(target: "edgedb::restore",
+                "Skipping excluded database {:?}", database);
+            continue;
+        }
+        let create_db = format!("CREATE DATABASE {}", quote_name(database));
+        if let Err(e) = target.execute(create_db).await {
+            let exists = e.downcast_ref::<ErrorResponse>()
+                .map(|e| e.code == SCHEMA_ERROR)
+                .unwrap_or(false);
+            if !exists {
+                return Err(e)
+                    .with_context(|| format!(
+                        "cannot create database {:?}", database));
+            }
+        }
+        let mut src_conn = source_params.database(database).connect().await
+            .with_context(|| format!(
+                "cannot connect to source database {:?}", database))?;
+        let mut dst_conn = target_params.database(database).connect().await
+            .with_context(|| format!(
+                "cannot connect to target database {:?}", database))?;
+        transfer_db(&mut src_conn, &mut dst_conn, jobs).await
+            .with_context(|| format!("transferring database {:?}",
+                                     database))?;
+    }
+    Ok(())
+}
@@ -1,13 +1,82 @@
-use crate::options::{Options, Command};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use humantime::format_rfc3339_millis;
+use once_cell::sync::Lazy;
+
+use crate::options::{Options, Command, LogFormat};
+use crate::process::TRACE;
 use crate::commands::parser::Common;
 use crate::server::options::Command as Server;
 
 
+type Hook = Box<dyn Fn(&log::Record) + Send>;
+
+/// Called for every record that is logged, see `set_hook`
+static HOOK: Lazy<Mutex<Option<Hook>>> = Lazy::new(|| Mutex::new(None));
+
+struct Logger {
+    inner: env_logger::Logger,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+    fn log(&self, record: &log::Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        if let Some(hook) = &*HOOK.lock().unwrap() {
+            hook(record);
+        }
+        self.inner.log(record);
+    }
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+/// Installs the logger built by `builder`, like `builder.init()` does,
+/// but allowing records to be also sent elsewhere with `set_hook`
+pub fn install(builder: &mut env_logger::Builder) {
+    let inner = builder.build();
+    log::set_max_level(inner.filter());
+    log::set_boxed_logger(Box::new(Logger { inner }))
+        .expect("logger is installed once");
+}
+
+/// Sets a function receiving logged records (those enabled by filters)
+/// in addition to stderr. Used by `server daemon` to send progress to
+/// the client. Returns the previous hook
+pub fn set_hook(hook: Option<Hook>) -> Option<Hook> {
+    std::mem::replace(&mut *HOOK.lock().unwrap(), hook)
+}
+
 pub fn init(builder: &mut env_logger::Builder, opt: &Options) {
+    if opt.log_format == LogFormat::Json {
+        builder.format(|buf, record| {
+            writeln!(buf, "{}", serde_json::json!({
+                "timestamp": format_rfc3339_millis(SystemTime::now())
+                    .to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            }))
+        });
+    }
     if opt.debug_print_frames {
         builder.filter_module("edgedb::incoming::frame",
                               log::LevelFilter::Debug);
     }
+    if opt.trace {
+        builder.filter_module(TRACE, log::LevelFilter::Debug);
+    }
+    if opt.quiet {
+        builder.filter_level(log::LevelFilter::Warn);
+        return;
+    }
     match &opt.subcommand {
         Some(Command::Common(Common::Restore(r))) if r.verbose => {
             builder.filter_module("edgedb::restore", log::LevelFilter::Info);
@@ -19,7 +88,18 @@ pub fn init(builder: &mut env_logger::Builder, opt: &Options) {
                 log::LevelFilter::Debug);
         }
         Some(Command::Server(s)) => match &s.subcommand {
-            Server::Upgrade(u) if u.verbose => {
+            Server::Upgrade(u) => {
+                if u.verbose {
+                    builder.filter_module(
+                        "edgedb::server::upgrade", log::LevelFilter::Info);
+                }
+                if u.verbose_restore {
+                    builder.filter_module(
+                        "edgedb::restore", log::LevelFilter::Info);
+                }
+            }
+            // these records are sent to clients as progress events
+            Server::Daemon(_) => {
                 builder.filter_module(
                     "edgedb::server::upgrade", log::LevelFilter::Info);
             }
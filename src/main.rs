@@ -65,7 +65,7 @@ fn _main() -> anyhow::Result<()> {
         env_logger::Env::default().default_filter_or("warn")
     );
     log_levels::init(&mut builder, &opt);
-    builder.init();
+    log_levels::install(&mut builder);
 
     if opt.subcommand.is_some() {
         commands::cli::main(opt)
@@ -1,4 +1,5 @@
 use std::env;
+use std::str::FromStr;
 use std::time::Duration;
 
 use anyhow::Context;
@@ -88,6 +89,22 @@ struct TmpOptions {
     #[clap(short="c")]
     pub query: Option<String>,
 
+    /// Format of log messages: `text` (default) or `json` (one JSON
+    /// object per line)
+    #[clap(long, default_value="text",
+           possible_values=&["text", "json"][..])]
+    pub log_format: LogFormat,
+
+    /// Do not print informational log messages, only warnings and errors
+    #[clap(long)]
+    pub quiet: bool,
+
+    /// Log every external command run and every connection made by
+    /// `server` commands (useful for bug reports). Values of environment
+    /// variables that look like secrets are not logged
+    #[clap(long)]
+    pub trace: bool,
+
     #[clap(subcommand)]
     pub subcommand: Option<Command>,
 }
@@ -130,6 +147,12 @@ pub struct RoleName {
     pub role: String,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
 #[derive(Debug, Clone)]
 pub struct Options {
     pub conn_params: Builder,
@@ -139,6 +162,21 @@ pub struct Options {
     pub debug_print_descriptors: bool,
     pub debug_print_codecs: bool,
     pub output_mode: OutputMode,
+    pub log_format: LogFormat,
+    pub quiet: bool,
+    pub trace: bool,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<LogFormat> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => anyhow::bail!("Unsupported log format, \
+                options: `text`, `json`"),
+        }
+    }
 }
 
 impl Options {
@@ -243,6 +281,9 @@ impl Options {
             debug_print_frames: tmp.debug_print_frames,
             debug_print_descriptors: tmp.debug_print_descriptors,
             debug_print_codecs: tmp.debug_print_codecs,
+            log_format: tmp.log_format,
+            quiet: tmp.quiet,
+            trace: tmp.trace,
             output_mode: if tmp.tab_separated {
                 OutputMode::TabSeparated
             } else if tmp.json {
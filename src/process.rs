@@ -1,49 +1,270 @@
-use std::io;
-use std::process::{Command, Child, exit};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{self, Read, BufRead, BufReader};
+use std::process::{Command, Child, Stdio, exit};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
 
 use anyhow::Context;
+use async_std::prelude::FutureExt;
+use async_std::task;
+use once_cell::sync::Lazy;
+
+/// Number of the last lines of output kept by `ProcessGuard`
+const OUTPUT_LINES: usize = 20;
+/// How often `ProcessGuard` checks whether the process has exited
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Process ids of the children of live `ProcessGuard`s
+static GUARDED: Lazy<Mutex<Vec<u32>>> = Lazy::new(|| Mutex::new(Vec::new()));
+/// Log target enabled by `--trace`
+pub const TRACE: &str = "edgedb::trace";
 
 pub struct ProcessGuard {
     child: Child,
+    /// Last lines of the output, if it's captured
+    output: Option<Arc<Mutex<VecDeque<String>>>>,
 }
 
 
+/// Returns true if value of the environment variable shouldn't be logged
+pub fn is_secret(name: &str) -> bool {
+    let name = name.to_uppercase();
+    ["PASSWORD", "SECRET", "TOKEN", "KEY", "CREDENTIAL"].iter()
+        .any(|word| name.contains(word))
+}
+
+/// Formats the command with its environment like a shell command line,
+/// with values of secret variables redacted. `Command`'s `Debug` output
+/// includes the environment, so it must not be logged or put into errors
+pub fn describe(cmd: &Command) -> String {
+    let mut buf = String::new();
+    for (key, value) in cmd.get_envs() {
+        let key = key.to_string_lossy();
+        match value {
+            Some(_) if is_secret(&key) => {
+                buf.push_str(&format!("{}=<redacted> ", key));
+            }
+            Some(value) => {
+                buf.push_str(&format!("{}={:?} ", key, value));
+            }
+            None => buf.push_str(&format!("-u {} ", key)),
+        }
+    }
+    buf.push_str(&format!("{:?}", cmd.get_program()));
+    for arg in cmd.get_args() {
+        buf.push_str(&format!(" {:?}", arg));
+    }
+    buf
+}
+
+pub fn trace(cmd: &Command) {
+    log::debug!(target: TRACE, "Running {}", describe(cmd));
+}
+
 pub fn run(cmd: &mut Command) -> anyhow::Result<()> {
+    trace(cmd);
     match cmd.status() {
         Ok(s) if s.success() => Ok(()),
-        Ok(s) => anyhow::bail!("process {:?} failed: {}", cmd, s),
-        Err(e) => Err(e).with_context(|| format!("error running {:?}", cmd)),
+        Ok(s) => anyhow::bail!("process {} failed: {}", describe(cmd), s),
+        Err(e) => Err(e)
+            .with_context(|| format!("error running {}", describe(cmd))),
     }
 }
 
 pub fn exit_from(cmd: &mut Command) -> anyhow::Result<()> {
+    trace(cmd);
     match cmd.status() {
         Ok(s) if s.code().is_some() => exit(s.code().unwrap()),
-        Ok(s) => anyhow::bail!("process {:?} failed: {}", cmd, s),
-        Err(e) => Err(e).with_context(|| format!("error running {:?}", cmd)),
+        Ok(s) => anyhow::bail!("process {} failed: {}", describe(cmd), s),
+        Err(e) => Err(e)
+            .with_context(|| format!("error running {}", describe(cmd))),
     }
 }
 
 pub fn get_text(cmd: &mut Command) -> anyhow::Result<String> {
+    trace(cmd);
     let data = match cmd.output() {
         Ok(out) if out.status.success() => out.stdout,
-        Ok(out) => anyhow::bail!("process {:?} failed: {}", cmd, out.status),
-        Err(e) => Err(e).with_context(|| format!("error running {:?}", cmd))?,
+        Ok(out) => anyhow::bail!("process {} failed: {}",
+                                describe(cmd), out.status),
+        Err(e) => Err(e)
+            .with_context(|| format!("error running {}", describe(cmd)))?,
     };
     String::from_utf8(data)
-        .context(format!("can decode output of {:?}", cmd))
+        .with_context(|| format!("can decode output of {}", describe(cmd)))
+}
+
+fn capture(stream: impl Read + Send + 'static,
+    output: &Arc<Mutex<VecDeque<String>>>)
+{
+    let output = output.clone();
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            log::debug!("process output: {}", line);
+            let mut buf = output.lock().expect("output buffer not poisoned");
+            if buf.len() >= OUTPUT_LINES {
+                buf.pop_front();
+            }
+            buf.push_back(line);
+        }
+    });
 }
 
 impl ProcessGuard {
+    /// Spawns the process, its output goes to the output of this process
     pub fn run(cmd: &mut Command) -> anyhow::Result<ProcessGuard> {
-        Ok(ProcessGuard {
-            child: cmd.spawn()?,
-        })
+        trace(cmd);
+        let child = cmd.spawn()?;
+        GUARDED.lock().expect("process list not poisoned").push(child.id());
+        Ok(ProcessGuard { child, output: None })
+    }
+    /// Spawns the process capturing its stdout and stderr, for processes
+    /// run behind the scenes, whose output is only useful on failure
+    pub fn run_captured(cmd: &mut Command) -> anyhow::Result<ProcessGuard> {
+        trace(cmd);
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        let mut child = cmd.spawn()?;
+        GUARDED.lock().expect("process list not poisoned").push(child.id());
+        let output = Arc::new(Mutex::new(VecDeque::new()));
+        if let Some(stdout) = child.stdout.take() {
+            capture(stdout, &output);
+        }
+        if let Some(stderr) = child.stderr.take() {
+            capture(stderr, &output);
+        }
+        Ok(ProcessGuard { child, output: Some(output) })
+    }
+    /// Resolves to an error once the process exits, for racing against
+    /// futures that talk to the process
+    pub async fn exited<T>(&mut self) -> anyhow::Result<T> {
+        loop {
+            match self.child.try_wait() {
+                Ok(Some(_)) => anyhow::bail!("process exited prematurely"),
+                Ok(None) => task::sleep(EXIT_POLL_INTERVAL).await,
+                Err(e) => {
+                    return Err(e).context("cannot check process status");
+                }
+            }
+        }
+    }
+    /// Runs the future talking to the process, failing as soon as the
+    /// process exits rather than when the future gives up waiting for it.
+    /// Errors are annotated like in `with_output`
+    pub fn block_on<T>(&mut self,
+        future: impl Future<Output=anyhow::Result<T>>)
+        -> anyhow::Result<T>
+    {
+        let result = task::block_on(future.race(self.exited()));
+        self.with_output(result)
+    }
+    /// Adds the status and the last lines of the captured output of the
+    /// process to the error (if any)
+    pub fn with_output<T>(&mut self, result: anyhow::Result<T>)
+        -> anyhow::Result<T>
+    {
+        let err = match result {
+            Ok(value) => return Ok(value),
+            Err(err) => err,
+        };
+        let status = match self.child.try_wait() {
+            Ok(Some(status)) => format!("process exited with {}", status),
+            Ok(None) => "process is still running".into(),
+            Err(e) => format!("cannot check process status: {}", e),
+        };
+        let output = match &self.output {
+            Some(output) => output,
+            None => return Err(err.context(status)),
+        };
+        let lines = output.lock().expect("output buffer not poisoned")
+            .iter().cloned().collect::<Vec<_>>();
+        if lines.is_empty() {
+            Err(err.context(format!("{}, no output", status)))
+        } else {
+            Err(err.context(format!("{}, last output lines:\n  {}",
+                status, lines.join("\n  "))))
+        }
+    }
+}
+
+/// Whether a process with the pid exists. Assumed to be true where it can't
+/// be checked
+pub fn is_alive(pid: u32) -> bool {
+    #[cfg(unix)] {
+        if unsafe { libc::kill(pid as i32, 0) } == 0 {
+            return true;
+        }
+        // the process exists but belongs to another user
+        return io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+    }
+    #[cfg(not(unix))] {
+        let _ = pid;
+        return true;
+    }
+}
+
+/// Start time of the process, as reported by `ps`, with a second precision
+pub fn started_at(pid: u32) -> Option<SystemTime> {
+    let output = Command::new("ps")
+        .arg("-o").arg("etime=")
+        .arg("-p").arg(pid.to_string())
+        .stderr(Stdio::null())
+        .output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let elapsed = parse_etime(std::str::from_utf8(&output.stdout).ok()?)?;
+    SystemTime::now().checked_sub(elapsed)
+}
+
+/// Parses elapsed time in the `[[dd-]hh:]mm:ss` format of `ps -o etime`
+fn parse_etime(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (days, time) = match value.find('-') {
+        Some(idx) => (value[..idx].parse::<u64>().ok()?, &value[idx+1..]),
+        None => (0, value),
+    };
+    let mut secs = 0;
+    let mut parts = 0;
+    for part in time.split(':') {
+        secs = secs * 60 + part.parse::<u64>().ok()?;
+        parts += 1;
+    }
+    if !(2..=3).contains(&parts) {
+        return None;
+    }
+    Some(Duration::from_secs(days * 86400 + secs))
+}
+
+/// Stops children of all `ProcessGuard`s
+///
+/// This is for the cases where destructors won't run, i.e. before calling
+/// `exit()`.
+pub fn terminate_all() {
+    let pids = GUARDED.lock().expect("process list not poisoned").clone();
+    for pid in pids {
+        #[cfg(unix)] {
+            if unsafe { libc::kill(pid as i32, libc::SIGTERM) } != 0 {
+                log::error!("error stopping process {}: {}",
+                    pid, io::Error::last_os_error());
+            }
+        }
+        if cfg!(not(unix)) {
+            log::warn!("process {} is left running", pid);
+        }
     }
 }
 
 impl Drop for ProcessGuard {
     fn drop(&mut self) {
+        let pid = self.child.id();
+        GUARDED.lock().expect("process list not poisoned")
+            .retain(|&p| p != pid);
         #[cfg(unix)] {
             let pid = self.child.id() as i32;
             if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
@@ -63,3 +284,20 @@ impl Drop for ProcessGuard {
         }).ok();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::parse_etime;
+
+    #[test]
+    fn test_parse_etime() {
+        assert_eq!(parse_etime("   00:07\n"), Some(Duration::from_secs(7)));
+        assert_eq!(parse_etime("01:02:03"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_etime("2-01:02:03"),
+                   Some(Duration::from_secs(2*86400 + 3723)));
+        assert_eq!(parse_etime("7"), None);
+        assert_eq!(parse_etime(""), None);
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use fn_error_context::context;
+
+use crate::platform::config_dir;
+use crate::server::methods::InstallMethod;
+use crate::server::options::{self, StopAll, StartAll};
+use crate::server::upgrade::{all_instances, write_atomic, Instance};
+
+
+fn stopped_file() -> anyhow::Result<PathBuf> {
+    Ok(config_dir()?.join("stopped_instances.json"))
+}
+
+#[context("failed reading {}", path.display())]
+fn read_stopped(path: &Path) -> anyhow::Result<BTreeSet<String>> {
+    match fs::read(path) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[context("failed writing {}", path.display())]
+fn write_stopped(path: &Path, names: &BTreeSet<String>)
+    -> anyhow::Result<()>
+{
+    if names.is_empty() {
+        match fs::remove_file(path) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    fs::create_dir_all(path.parent().unwrap())?;
+    write_atomic(path, &serde_json::to_vec_pretty(names)?)?;
+    Ok(())
+}
+
+fn instances_by_method(method: &Option<InstallMethod>)
+    -> anyhow::Result<Vec<Instance>>
+{
+    Ok(all_instances()?.into_iter()
+        .filter(|inst| method.as_ref().map(|m| m == &inst.meta.method)
+                       .unwrap_or(true))
+        .collect())
+}
+
+pub fn stop_all(options: &StopAll) -> anyhow::Result<()> {
+    let instances = instances_by_method(&options.method)?;
+    if instances.is_empty() {
+        eprintln!("No instances found");
+        return Ok(());
+    }
+    let path = stopped_file()?;
+    let mut was_running = read_stopped(&path)?;
+    let mut stopped = 0;
+    let mut not_running = 0;
+    let mut failed = 0;
+    for inst in &instances {
+        let result = inst.get_control().and_then(|mut ctl| {
+            let running = ctl.get_status()?.is_running();
+            if running {
+                ctl.stop(&options::Stop {
+                    name: inst.name.clone(),
+                    wait: false,
+                    wait_timeout: None,
+                })?;
+            }
+            Ok(running)
+        });
+        match result {
+            Ok(true) => {
+                log::info!("Instance {:?} stopped", inst.name);
+                was_running.insert(inst.name.clone());
+                stopped += 1;
+            }
+            Ok(false) => {
+                log::info!("Instance {:?} is not running", inst.name);
+                not_running += 1;
+            }
+            Err(e) => {
+                log::warn!("Failed to stop instance {:?}: {:#}",
+                    inst.name, e);
+                failed += 1;
+            }
+        }
+    }
+    write_stopped(&path, &was_running)?;
+    eprintln!("Stopped {} instances, {} were not running, {} failed",
+        stopped, not_running, failed);
+    if failed > 0 {
+        anyhow::bail!("failed to stop {} instances", failed);
+    }
+    Ok(())
+}
+
+pub fn start_all(options: &StartAll) -> anyhow::Result<()> {
+    let mut instances = instances_by_method(&options.method)?;
+    let path = stopped_file()?;
+    let mut previously_running = read_stopped(&path)?;
+    if options.only_previously_running {
+        instances.retain(|inst| previously_running.contains(&inst.name));
+    }
+    if instances.is_empty() {
+        eprintln!("No instances found");
+        return Ok(());
+    }
+    let mut started = 0;
+    let mut failed = 0;
+    for inst in &instances {
+        let result = inst.get_control().and_then(|mut ctl| {
+            ctl.start(&options::Start {
+                name: inst.name.clone(),
+                foreground: false,
+                wait: false,
+                wait_timeout: None,
+            })
+        });
+        match result {
+            Ok(()) => {
+                log::info!("Instance {:?} started", inst.name);
+                previously_running.remove(&inst.name);
+                started += 1;
+            }
+            Err(e) => {
+                log::warn!("Failed to start instance {:?}: {:#}",
+                    inst.name, e);
+                failed += 1;
+            }
+        }
+    }
+    write_stopped(&path, &previously_running)
+        .context("cannot update list of stopped instances")?;
+    eprintln!("Started {} instances, {} failed", started, failed);
+    if failed > 0 {
+        anyhow::bail!("failed to start {} instances", failed);
+    }
+    Ok(())
+}
@@ -8,6 +8,7 @@ use async_std::task;
 use anyhow::Context;
 use serde::Serialize;
 
+use crate::process;
 use crate::server::detect::{Lazy, ARCH};
 use crate::server::detect::{VersionQuery, InstalledPackage, VersionResult};
 use crate::server::docker::DockerCandidate;
@@ -41,6 +42,8 @@ fn repo_file(nightly: bool) -> &'static str {
     }
 }
 
+const KEY_PATH: &str = "/etc/pki/rpm-gpg/RPM-GPG-KEY-edgedb";
+
 fn repo_data(nightly: bool) -> String {
     format!("\
             [edgedb-server-install{name_suffix}]\n\
@@ -116,9 +119,15 @@ impl Centos {
                 data: repo_data.into(),
             });
         }
+        let mut install = Command::new("yum")
+            .arg("-y");
+        if let Some(options) = settings.extra.get("yum_options") {
+            for opt in options.split_whitespace() {
+                install = install.arg(opt);
+            }
+        }
         operations.push(Operation::PrivilegedCmd(
-            Command::new("yum")
-            .arg("-y")
+            install
             .arg("install")
             .arg(format!("{}-{}",
                 settings.package_name, settings.major_version))
@@ -185,6 +194,9 @@ impl<'os> Method for PackageMethod<'os, Centos> {
             self.os.install_operations(settings)?,
             &self.os.linux)
     }
+    fn install_is_exclusive(&self) -> bool {
+        true
+    }
     fn all_versions(&self, nightly: bool) -> anyhow::Result<&[PackageInfo]> {
         Ok(self.os.get_repo(nightly)?
             .map(|x| &x.packages[..]).unwrap_or(&[]))
@@ -196,6 +208,28 @@ impl<'os> Method for PackageMethod<'os, Centos> {
             .ok_or_else(|| anyhow::anyhow!("No repository found"))?;
         package::find_version(packages, query)
     }
+    fn refresh_keys(&self, key: &str) -> anyhow::Result<()> {
+        linux::perform_install(vec![
+            Operation::WritePrivilegedFile {
+                path: KEY_PATH.into(),
+                data: key.into(),
+            },
+            Operation::PrivilegedCmd(
+                Command::new("rpm")
+                .arg("--import")
+                .arg(KEY_PATH)
+            ),
+        ], &self.os.linux)
+    }
+    fn verify_installation(&self, settings: &install::Settings)
+        -> anyhow::Result<bool>
+    {
+        linux::verify_package(StdCommand::new("rpm")
+            .arg("-V")
+            .arg(format!("{}-{}",
+                         settings.package_name, settings.major_version)))?;
+        Ok(true)
+    }
     fn installed_versions(&self) -> anyhow::Result<&[InstalledPackage]> {
         Ok(&self.installed.get_or_try_init(|| {
             let mut cmd = StdCommand::new("yum");
@@ -211,11 +245,11 @@ impl<'os> Method for PackageMethod<'os, Centos> {
                 {
                     return Ok(Vec::new());
                 }
-                anyhow::bail!("cannot get installed packages: {:?} {}",
-                    cmd, out.status);
+                anyhow::bail!("cannot get installed packages: {} {}",
+                    process::describe(&cmd), out.status);
             } else if !out.status.success() {
-                anyhow::bail!("cannot get installed packages: {:?} {}",
-                    cmd, out.status);
+                anyhow::bail!("cannot get installed packages: {} {}",
+                    process::describe(&cmd), out.status);
             }
             let mut lines = out.stdout.split(|&b| b == b'\n');
             for line in &mut lines {
@@ -264,9 +298,21 @@ impl<'os> Method for PackageMethod<'os, Centos> {
             Ok(result)
         })?)
     }
+    fn uninstall(&self, package: &InstalledPackage) -> anyhow::Result<()> {
+        linux::perform_install(vec![Operation::PrivilegedCmd(
+            Command::new("yum")
+                .arg("-y")
+                .arg("remove")
+                .arg(format!("{}-{}",
+                             package.package_name, package.major_version))
+        )], &self.os.linux)
+    }
     fn detect_all(&self) -> serde_json::Value {
         serde_json::to_value(self).expect("can serialize")
     }
+    fn extra_settings(&self) -> &[&'static str] {
+        &["yum_options"]
+    }
     fn get_server_path(&self, major_version: &Version<String>)
         -> anyhow::Result<PathBuf>
     {
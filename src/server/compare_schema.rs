@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+
+use async_std::task;
+use serde::Serialize;
+
+use crate::commands::ExitCode;
+use crate::server::install::exit_codes;
+use crate::server::options::{CompareSchema, OutputFormat};
+use crate::server::print_serialized;
+use crate::server::upgrade::{self, all_instances, Instance};
+use crate::server::verify;
+
+
+#[derive(Serialize, Debug)]
+struct DatabaseDiff {
+    database: String,
+    /// Instance that has no such database
+    #[serde(skip_serializing_if="Option::is_none")]
+    missing_in: Option<String>,
+    /// Lines of DDL prefixed by `-` (first instance only) or `+` (second
+    /// instance only)
+    #[serde(skip_serializing_if="Vec::is_empty")]
+    diff: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct Report {
+    identical: bool,
+    databases: Vec<DatabaseDiff>,
+}
+
+/// Lengths of the longest common subsequences of `old` and every prefix
+/// of `new`, computed one row at a time
+fn lcs_lengths(old: &[&str], new: &[&str]) -> Vec<usize> {
+    let mut row = vec![0; new.len()+1];
+    for line in old {
+        let mut diagonal = 0;
+        for j in 0..new.len() {
+            let above = row[j+1];
+            row[j+1] = if *line == new[j] {
+                diagonal + 1
+            } else {
+                above.max(row[j])
+            };
+            diagonal = above;
+        }
+    }
+    row
+}
+
+/// Hirschberg's algorithm: `old` is split in half, and `new` is split
+/// where the longest common subsequences of the halves meet. Takes linear
+/// space, as schemas may be large
+fn diff_into(old: &[&str], new: &[&str], result: &mut Vec<String>) {
+    match old {
+        [] => {
+            result.extend(new.iter().map(|line| format!("+{}", line)));
+        }
+        [line] => {
+            match new.iter().position(|x| x == line) {
+                Some(idx) => {
+                    diff_into(&[], &new[..idx], result);
+                    diff_into(&[], &new[idx+1..], result);
+                }
+                None => {
+                    result.push(format!("-{}", line));
+                    diff_into(&[], new, result);
+                }
+            }
+        }
+        _ if new.is_empty() => {
+            result.extend(old.iter().map(|line| format!("-{}", line)));
+        }
+        _ => {
+            let mid = old.len() / 2;
+            let left = lcs_lengths(&old[..mid], new);
+            let old_tail = old[mid..].iter().rev().copied()
+                .collect::<Vec<_>>();
+            let new_rev = new.iter().rev().copied().collect::<Vec<_>>();
+            let right = lcs_lengths(&old_tail, &new_rev);
+            let mut split = 0;
+            for j in 0..=new.len() {
+                if left[j] + right[new.len()-j]
+                    > left[split] + right[new.len()-split]
+                {
+                    split = j;
+                }
+            }
+            diff_into(&old[..mid], &new[..split], result);
+            diff_into(&old[mid..], &new[split..], result);
+        }
+    }
+}
+
+/// Lines of `old` and `new` that are not in their longest common
+/// subsequence, in order
+fn diff_lines(old: &str, new: &str) -> Vec<String> {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+    let mut result = Vec::new();
+    diff_into(&old, &new, &mut result);
+    result
+}
+
+fn compare(first: (&str, &BTreeMap<String, String>),
+           second: (&str, &BTreeMap<String, String>))
+    -> Vec<DatabaseDiff>
+{
+    let mut databases = first.1.keys().chain(second.1.keys())
+        .collect::<Vec<_>>();
+    databases.sort();
+    databases.dedup();
+    let mut result = Vec::new();
+    for database in databases {
+        let (missing_in, diff) = match (first.1.get(database),
+                                        second.1.get(database)) {
+            (Some(old), Some(new)) => (None, diff_lines(old, new)),
+            (Some(_), None) => (Some(second.0), Vec::new()),
+            (None, _) => (Some(first.0), Vec::new()),
+        };
+        if missing_in.is_some() || !diff.is_empty() {
+            result.push(DatabaseDiff {
+                database: database.clone(),
+                missing_in: missing_in.map(|name| name.into()),
+                diff,
+            });
+        }
+    }
+    result
+}
+
+fn find_instance(name: &str) -> anyhow::Result<Instance> {
+    all_instances()?.into_iter()
+        .find(|inst| inst.name == name)
+        .ok_or_else(|| anyhow::anyhow!("Instance {:?} not found", name))
+}
+
+async fn read_schemas(inst: &Instance, options: &CompareSchema)
+    -> anyhow::Result<BTreeMap<String, String>>
+{
+    let socket = inst.get_control().and_then(|ctl| ctl.get_socket(true));
+    let (conn_params, mut cli) = upgrade::connect(
+        inst, socket, options.connect_method).await?;
+    verify::schemas(&mut cli, &conn_params).await
+}
+
+pub fn compare_schema(options: &CompareSchema) -> anyhow::Result<()> {
+    let first = find_instance(&options.first)?;
+    let second = find_instance(&options.second)?;
+    let (first_schema, second_schema) = task::block_on(async {
+        Ok::<_, anyhow::Error>((read_schemas(&first, options).await?,
+                                read_schemas(&second, options).await?))
+    })?;
+    let databases = compare((&first.name, &first_schema),
+                            (&second.name, &second_schema));
+    let identical = databases.is_empty();
+    if options.format != OutputFormat::Human {
+        print_serialized(options.format, &Report { identical, databases })?;
+    } else if identical {
+        println!("Schema of {:?} and {:?} is identical",
+            first.name, second.name);
+    } else {
+        println!("--- {}\n+++ {}", first.name, second.name);
+        for db in &databases {
+            match &db.missing_in {
+                Some(name) => {
+                    println!("database {:?}: missing in {:?}",
+                        db.database, name);
+                }
+                None => {
+                    println!("database {:?}:", db.database);
+                    for line in &db.diff {
+                        println!("  {}", line);
+                    }
+                }
+            }
+        }
+    }
+    if !identical {
+        return Err(ExitCode::new(exit_codes::SCHEMA_DIFFERS).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::{compare, diff_lines};
+
+    #[test]
+    fn test_diff_lines() {
+        assert!(diff_lines("a\nb\nc", "a\nb\nc").is_empty());
+        assert_eq!(diff_lines("a\nb\nc", "a\nx\nc\nd"),
+                   vec!["-b", "+x", "+d"]);
+        assert_eq!(diff_lines("", "a"), vec!["+a"]);
+        assert_eq!(diff_lines("a\nb", ""), vec!["-a", "-b"]);
+        assert_eq!(diff_lines("a\nb\nc\nd\ne", "b\nc\ny\ne\nf"),
+                   vec!["-a", "-d", "+y", "+f"]);
+    }
+
+    #[test]
+    fn test_compare() {
+        let mut first = BTreeMap::new();
+        first.insert("edgedb".to_string(), "CREATE TYPE A;".to_string());
+        first.insert("extra".to_string(), String::new());
+        let mut second = BTreeMap::new();
+        second.insert("edgedb".to_string(), "CREATE TYPE A;".to_string());
+        let diff = compare(("a", &first), ("b", &second));
+        assert_eq!(diff.len(), 1);
+        assert_eq!(diff[0].database, "extra");
+        assert_eq!(diff[0].missing_in.as_deref(), Some("b"));
+    }
+}
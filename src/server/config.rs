@@ -0,0 +1,179 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use anyhow::Context;
+use async_std::task;
+use edgedb_client::Builder;
+use edgedb_client::client::Connection;
+use edgeql_parser::helpers::quote_string;
+
+use crate::process::ProcessGuard;
+use crate::server;
+use crate::server::control::read_metadata;
+use crate::server::init::{data_path, write_metadata};
+use crate::server::options::{ConfigGet, ConfigSet};
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Str,
+    Int,
+    /// Comma-separated list of strings
+    StrList,
+}
+
+/// Settings that may be persisted in the metadata. Port is not here as it's
+/// set by `edgedb server init` and passed on the command-line
+const SETTINGS: &[(&str, Kind)] = &[
+    ("listen_addresses", Kind::StrList),
+    ("shared_buffers", Kind::Str),
+    ("query_work_mem", Kind::Str),
+    ("effective_cache_size", Kind::Str),
+    ("effective_io_concurrency", Kind::Int),
+    ("default_statistics_target", Kind::Int),
+];
+
+fn kind(key: &str) -> anyhow::Result<Kind> {
+    SETTINGS.iter()
+        .find(|(name, _)| *name == key)
+        .map(|(_, kind)| *kind)
+        .ok_or_else(|| anyhow::anyhow!("Unknown setting {:?}, \
+            supported: {}", key, SETTINGS.iter()
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>()
+                .join(", ")))
+}
+
+fn statement(key: &str, value: &str) -> anyhow::Result<String> {
+    let value = match kind(key)? {
+        Kind::Str => quote_string(value),
+        Kind::Int => {
+            value.parse::<i64>()
+                .with_context(|| format!("{} must be an integer", key))?
+                .to_string()
+        }
+        Kind::StrList => {
+            format!("{{{}}}", value.split(',')
+                .map(|item| quote_string(item.trim()))
+                .collect::<Vec<_>>()
+                .join(", "))
+        }
+    };
+    // keys are checked against `SETTINGS`, so they don't need quoting
+    Ok(format!("CONFIGURE SYSTEM SET {} := {};", key, value))
+}
+
+/// Applies settings persisted in the metadata to the server
+pub async fn apply(cli: &mut Connection, config: &BTreeMap<String, String>)
+    -> anyhow::Result<()>
+{
+    for (key, value) in config {
+        log::info!(target: "edgedb::server::config",
+            "Setting {} = {:?}", key, value);
+        cli.execute(&statement(key, value)?).await
+            .with_context(|| format!("cannot set {}", key))?;
+    }
+    Ok(())
+}
+
+pub fn config_get(options: &ConfigGet) -> anyhow::Result<()> {
+    let dir = data_path(false)?.join(&options.name);
+    if !dir.exists() {
+        anyhow::bail!("No instance {:?} found", options.name);
+    }
+    let metadata = read_metadata(&dir)?;
+    match &options.key {
+        Some(key) => match metadata.config.get(key) {
+            Some(value) => println!("{}", value),
+            None => anyhow::bail!("Setting {:?} is not set for instance {:?}",
+                key, options.name),
+        },
+        None => {
+            for (key, value) in &metadata.config {
+                println!("{}={}", key, value);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn config_set(options: &ConfigSet) -> anyhow::Result<()> {
+    let dir = data_path(false)?.join(&options.name);
+    if !dir.exists() {
+        anyhow::bail!("No instance {:?} found", options.name);
+    }
+    if options.settings.is_empty() && options.reset.is_empty() {
+        anyhow::bail!("Specify settings to change (`key=value`) \
+            or `--reset`");
+    }
+    let mut metadata = read_metadata(&dir)?;
+    let mut statements = Vec::new();
+    for key in &options.reset {
+        kind(key)?;
+        if metadata.config.remove(key).is_none() {
+            log::warn!("Instance {:?} has no setting {:?}",
+                options.name, key);
+        }
+        statements.push(format!("CONFIGURE SYSTEM RESET {};", key));
+    }
+    for (key, value) in &options.settings {
+        statements.push(statement(key, value)?);
+    }
+
+    let inst = server::get_instance(&options.name)
+        .with_context(|| format!("cannot find instance {:?}", options.name))?;
+    let mut conn_params = Builder::new();
+    conn_params.user("edgedb");
+    conn_params.database("edgedb");
+    conn_params.unix_addr(inst.get_socket(true)?);
+    // Settings are stored in the data directory by the server, so if the
+    // instance is stopped a temporary server is run just to apply them
+    let mut server = if inst.get_status()?.is_running() {
+        None
+    } else {
+        eprintln!("Instance {:?} is not running, starting a temporary \
+            server to apply the settings", options.name);
+        let mut cmd = inst.run_command()?;
+        conn_params.wait_until_available(Duration::from_secs(30));
+        Some(ProcessGuard::run(&mut cmd)
+            .with_context(|| format!("error running server {}",
+                                     crate::process::describe(&cmd)))?)
+    };
+    let result: anyhow::Result<()> = task::block_on(async {
+        let mut cli = conn_params.connect().await?;
+        for statement in &statements {
+            cli.execute(statement).await?;
+        }
+        Ok(())
+    });
+    match &mut server {
+        Some(server) => server.with_output(result)?,
+        None => result?,
+    }
+    drop(server);
+
+    for (key, value) in &options.settings {
+        metadata.config.insert(key.clone(), value.clone());
+    }
+    write_metadata(&dir.join("metadata.json"), &metadata)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::statement;
+
+    #[test]
+    fn test_statement() {
+        assert_eq!(statement("shared_buffers", "1GB").unwrap(),
+            "CONFIGURE SYSTEM SET shared_buffers := '1GB';");
+        assert_eq!(statement("effective_io_concurrency", "10").unwrap(),
+            "CONFIGURE SYSTEM SET effective_io_concurrency := 10;");
+        assert_eq!(
+            statement("listen_addresses", "127.0.0.1, ::1").unwrap(),
+            "CONFIGURE SYSTEM SET listen_addresses := {'127.0.0.1', '::1'};");
+        assert!(statement("effective_io_concurrency", "many").is_err());
+        assert!(statement("shared_bufers", "1GB").is_err());
+        assert!(statement("listen_port", "5656").is_err());
+    }
+}
@@ -1,20 +1,26 @@
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::{PathBuf, Path};
 use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use fn_error_context::context;
 
-use crate::process::{run, exit_from};
+use crate::process::{self, run, exit_from};
 use crate::server::options::{Start, Stop, Restart, Status};
 use crate::server::init::{data_path, Metadata};
 use crate::server::methods::InstallMethod;
 use crate::server::version::Version;
 use crate::server::{linux, macos};
 use crate::server::status;
+use crate::server::upgrade::UpgradeMeta;
 use crate::platform::{home_dir, get_current_uid};
 
 
 pub trait Instance {
+    /// Starts the server, `options.wait` is handled by `wait::start`, as
+    /// connecting to the server needs the instance metadata
     fn start(&mut self, options: &Start) -> anyhow::Result<()>;
     fn stop(&mut self, options: &Stop) -> anyhow::Result<()>;
     fn restart(&mut self, options: &Restart) -> anyhow::Result<()>;
@@ -22,8 +28,20 @@ pub trait Instance {
     fn get_status(&self) -> anyhow::Result<status::Status>;
     fn get_socket(&self, admin: bool) -> anyhow::Result<PathBuf>;
     fn run_command(&self) -> anyhow::Result<Command>;
+    /// Same as `run_command`, but runs `binary` instead of the installed
+    /// server
+    fn run_binary(&self, binary: &Path) -> anyhow::Result<Command>;
+    /// Same as `run_binary`, but the server listens on `port` and puts
+    /// its sockets into `runstate_dir`, so it doesn't interfere with the
+    /// service of the instance
+    fn run_temporary(&self, binary: &Path, port: u16, runstate_dir: &Path)
+        -> anyhow::Result<Command>;
 }
 
+/// Used by `start --wait` and `stop --wait` without `--wait-timeout`
+pub const WAIT_TIMEOUT: Duration = Duration::from_secs(60);
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 pub struct SystemdInstance {
     name: String,
     #[allow(dead_code)]
@@ -31,6 +49,8 @@ pub struct SystemdInstance {
     version: Version<String>,
     data_dir: PathBuf,
     port: u16,
+    env: BTreeMap<String, String>,
+    upgrade: UpgradeState,
 }
 
 pub struct LaunchdInstance {
@@ -43,6 +63,19 @@ pub struct LaunchdInstance {
     unit_path: PathBuf,
     data_dir: PathBuf,
     port: u16,
+    env: BTreeMap<String, String>,
+    upgrade: UpgradeState,
+}
+
+/// Upgrade of the instance, found by its `UPGRADE_IN_PROGRESS` marker
+#[derive(Debug)]
+pub enum UpgradeState {
+    None,
+    /// Another process upgrades the instance, and may replace its service
+    /// and data directory at any moment
+    Running(UpgradeMeta),
+    /// The process that upgraded the instance is not running anymore
+    Interrupted(UpgradeMeta),
 }
 
 #[context("failed to read metadata {}/metadata.json", dir.display())]
@@ -75,6 +108,7 @@ pub fn get_instance_from_metadata(name: &str, system: bool,
  -> anyhow::Result<Box<dyn Instance>>
 {
     let dir = data_path(false)?.join(name);
+    let upgrade = upgrade_state(&dir);
     match metadata.method {
         InstallMethod::Package if cfg!(target_os="linux") => {
             Ok(Box::new(SystemdInstance {
@@ -83,6 +117,8 @@ pub fn get_instance_from_metadata(name: &str, system: bool,
                 version: metadata.version.to_owned(),
                 port: metadata.port,
                 data_dir: dir,
+                env: metadata.env.clone(),
+                upgrade,
             }))
         }
         InstallMethod::Package if cfg!(target_os="macos") => {
@@ -95,6 +131,8 @@ pub fn get_instance_from_metadata(name: &str, system: bool,
                 unit_path: home_dir()?.join("Library/LaunchAgents")
                     .join(&unit_name),
                 port: metadata.port,
+                env: metadata.env.clone(),
+                upgrade,
             }))
         }
         _ => {
@@ -103,8 +141,109 @@ pub fn get_instance_from_metadata(name: &str, system: bool,
     }
 }
 
+pub fn upgrade_state(dir: &Path) -> UpgradeState {
+    let marker = dir.join("UPGRADE_IN_PROGRESS");
+    if !marker.exists() {
+        return UpgradeState::None;
+    }
+    match status::read_upgrade(&marker) {
+        // upgrade itself controls the instance
+        Ok(meta) if meta.pid == std::process::id() => UpgradeState::None,
+        Ok(meta) if is_upgrader(&meta) => UpgradeState::Running(meta),
+        Ok(meta) => UpgradeState::Interrupted(meta),
+        Err(e) => {
+            log::warn!("{:#}", e);
+            UpgradeState::None
+        }
+    }
+}
+
+/// Whether the process that wrote the marker is still running. The pid
+/// may have been reused after a crash or a reboot, so a process started
+/// after the upgrade is not the upgrader
+fn is_upgrader(meta: &UpgradeMeta) -> bool {
+    if !process::is_alive(meta.pid) {
+        return false;
+    }
+    match process::started_at(meta.pid) {
+        // `ps` reports start time with a second precision
+        Some(started) => started <= meta.started + Duration::from_secs(2),
+        None => true,
+    }
+}
+
+/// Refuses to start or stop the instance while it's being upgraded, as
+/// that would break the upgrade. Status can be checked at any time
+fn check_upgrade(name: &str, state: &UpgradeState, operation: &str)
+    -> anyhow::Result<()>
+{
+    match state {
+        UpgradeState::None => {}
+        UpgradeState::Running(meta) => {
+            anyhow::bail!("Instance {:?} is being upgraded from {} to {} \
+                by process {}, so it can't be {} until the upgrade is \
+                finished. Run `edgedb server status {0}` to check",
+                name, meta.source, meta.target, meta.pid, operation);
+        }
+        UpgradeState::Interrupted(meta) => {
+            log::warn!("Upgrade of {0:?} from {1} to {2} was interrupted \
+                (process {3} is not running). Run \
+                `edgedb server upgrade {0}` to continue, or \
+                `edgedb server restore-backup {0}` to roll back",
+                name, meta.source, meta.target, meta.pid);
+        }
+    }
+    Ok(())
+}
+
+/// Pid of the server that `stop --wait` has to wait for
+fn running_pid(ctl: &dyn Instance, options: &Stop)
+    -> anyhow::Result<Option<u32>>
+{
+    if options.wait {
+        Ok(ctl.get_status()?.pid())
+    } else {
+        Ok(None)
+    }
+}
+
+/// Waits until the server process exits (`stop --wait`), `pid` is `None`
+/// if the server was not running
+fn wait_stopped(name: &str, pid: Option<u32>, timeout: Option<Duration>)
+    -> anyhow::Result<()>
+{
+    let pid = match pid {
+        Some(pid) => pid,
+        None => return Ok(()),
+    };
+    let timeout = timeout.unwrap_or(WAIT_TIMEOUT);
+    let started = Instant::now();
+    while process::is_alive(pid) {
+        if started.elapsed() >= timeout {
+            anyhow::bail!("server of instance {:?} (pid {}) is still \
+                running after {}",
+                name, pid, humantime::format_duration(timeout));
+        }
+        thread::sleep(STOP_POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+fn server_command(binary: &Path, port: u16, data_dir: &Path,
+    runstate_dir: &Path, env: &BTreeMap<String, String>)
+    -> Command
+{
+    let mut cmd = Command::new(binary);
+    cmd.arg("--port").arg(port.to_string());
+    cmd.arg("--data-dir").arg(data_dir);
+    cmd.arg("--runstate-dir").arg(runstate_dir);
+    cmd.envs(env);
+    cmd
+}
+
 impl Instance for SystemdInstance {
     fn start(&mut self, options: &Start) -> anyhow::Result<()> {
+        check_upgrade(&self.name, &self.upgrade, "started")?;
         if options.foreground {
             run(&mut self.run_command()?)?;
         } else {
@@ -115,14 +254,17 @@ impl Instance for SystemdInstance {
         }
         Ok(())
     }
-    fn stop(&mut self, _options: &Stop) -> anyhow::Result<()> {
+    fn stop(&mut self, options: &Stop) -> anyhow::Result<()> {
+        check_upgrade(&self.name, &self.upgrade, "stopped")?;
+        let pid = running_pid(self, options)?;
         run(Command::new("systemctl")
             .arg("--user")
             .arg("stop")
             .arg(format!("edgedb-server@{}", self.name)))?;
-        Ok(())
+        wait_stopped(&self.name, pid, options.wait_timeout)
     }
     fn restart(&mut self, _options: &Restart) -> anyhow::Result<()> {
+        check_upgrade(&self.name, &self.upgrade, "restarted")?;
         run(Command::new("systemctl")
             .arg("--user")
             .arg("restart")
@@ -156,13 +298,19 @@ impl Instance for SystemdInstance {
                 self.port)))
     }
     fn run_command(&self) -> anyhow::Result<Command> {
+        self.run_binary(&linux::get_server_path(&self.version))
+    }
+    fn run_binary(&self, binary: &Path) -> anyhow::Result<Command> {
         let sock = self.get_socket(true)?;
         let socket_dir = sock.parent().unwrap();
-        let mut cmd = Command::new(linux::get_server_path(&self.version));
-        cmd.arg("--port").arg(self.port.to_string());
-        cmd.arg("--data-dir").arg(&self.data_dir);
-        cmd.arg("--runstate-dir").arg(&socket_dir);
-        Ok(cmd)
+        Ok(server_command(binary, self.port, &self.data_dir, socket_dir,
+                          &self.env))
+    }
+    fn run_temporary(&self, binary: &Path, port: u16, runstate_dir: &Path)
+        -> anyhow::Result<Command>
+    {
+        Ok(server_command(binary, port, &self.data_dir, runstate_dir,
+                          &self.env))
     }
 }
 
@@ -174,6 +322,7 @@ impl LaunchdInstance {
 
 impl Instance for LaunchdInstance {
     fn start(&mut self, options: &Start) -> anyhow::Result<()> {
+        check_upgrade(&self.name, &self.upgrade, "started")?;
         if options.foreground {
             run(&mut self.run_command()?)?;
         } else {
@@ -183,13 +332,16 @@ impl Instance for LaunchdInstance {
         }
         Ok(())
     }
-    fn stop(&mut self, _options: &Stop) -> anyhow::Result<()> {
+    fn stop(&mut self, options: &Stop) -> anyhow::Result<()> {
+        check_upgrade(&self.name, &self.upgrade, "stopped")?;
+        let pid = running_pid(self, options)?;
         run(Command::new("launchctl")
             .arg("unload")
             .arg(&self.unit_path))?;
-        Ok(())
+        wait_stopped(&self.name, pid, options.wait_timeout)
     }
     fn restart(&mut self, _options: &Restart) -> anyhow::Result<()> {
+        check_upgrade(&self.name, &self.upgrade, "restarted")?;
         run(Command::new("launchctl")
             .arg("kickstart")
             .arg("-k")
@@ -220,12 +372,102 @@ impl Instance for LaunchdInstance {
                 self.port)))
     }
     fn run_command(&self) -> anyhow::Result<Command> {
+        self.run_binary(&macos::get_server_path(&self.version)?)
+    }
+    fn run_binary(&self, binary: &Path) -> anyhow::Result<Command> {
         let sock = self.get_socket(true)?;
         let socket_dir = sock.parent().unwrap();
-        let mut cmd = Command::new(macos::get_server_path(&self.version)?);
-        cmd.arg("--port").arg(self.port.to_string());
-        cmd.arg("--data-dir").arg(&self.data_dir);
-        cmd.arg("--runstate-dir").arg(&socket_dir);
-        Ok(cmd)
+        Ok(server_command(binary, self.port, &self.data_dir, socket_dir,
+                          &self.env))
+    }
+    fn run_temporary(&self, binary: &Path, port: u16, runstate_dir: &Path)
+        -> anyhow::Result<Command>
+    {
+        Ok(server_command(binary, port, &self.data_dir, runstate_dir,
+                          &self.env))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{upgrade_state, wait_stopped, UpgradeState};
+    use crate::server::upgrade::UpgradeMeta;
+    use crate::server::version::Version;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_wait_stopped() {
+        assert!(wait_stopped("inst", None, Some(Duration::new(0, 0))).is_ok());
+
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let pid = child.id();
+        let err = wait_stopped("inst", Some(pid),
+                               Some(Duration::from_millis(200)))
+            .unwrap_err();
+        assert!(err.to_string().contains("is still running"), "{}", err);
+
+        child.kill().unwrap();
+        // exited child has to be reaped to disappear
+        let reaper = thread::spawn(move || child.wait());
+        assert!(wait_stopped("inst", Some(pid),
+                             Some(Duration::from_secs(5))).is_ok());
+        reaper.join().unwrap().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_upgrade_state() {
+        let tmp = tempfile::tempdir().unwrap();
+        assert!(matches!(upgrade_state(tmp.path()), UpgradeState::None));
+
+        let mut child = Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        for &pid in &[std::process::id(), child.id()] {
+            let meta = UpgradeMeta {
+                source: Version("1-alpha5".into()),
+                target: Version("1-alpha6".into()),
+                started: std::time::SystemTime::now(),
+                pid,
+                cli_version: String::new(),
+                schema_version: 1,
+            };
+            std::fs::write(tmp.path().join("UPGRADE_IN_PROGRESS"),
+                           serde_json::to_vec(&meta).unwrap()).unwrap();
+            let state = upgrade_state(tmp.path());
+            if pid == std::process::id() {
+                assert!(matches!(state, UpgradeState::None));
+            } else {
+                assert!(matches!(state, UpgradeState::Interrupted(_)));
+            }
+        }
+
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let now = std::time::SystemTime::now();
+        // marker written by this process vs. a reused pid
+        for &started in &[now, now - Duration::from_secs(3600)] {
+            let meta = UpgradeMeta {
+                source: Version("1-alpha5".into()),
+                target: Version("1-alpha6".into()),
+                started,
+                pid: child.id(),
+                cli_version: String::new(),
+                schema_version: 1,
+            };
+            std::fs::write(tmp.path().join("UPGRADE_IN_PROGRESS"),
+                           serde_json::to_vec(&meta).unwrap()).unwrap();
+            let state = upgrade_state(tmp.path());
+            if started == now {
+                assert!(matches!(state, UpgradeState::Running(_)));
+            } else {
+                assert!(matches!(state, UpgradeState::Interrupted(_)));
+            }
+        }
+        child.kill().unwrap();
+        child.wait().unwrap();
     }
 }
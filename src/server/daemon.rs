@@ -0,0 +1,311 @@
+//! `edgedb server daemon`: instance operations over a local socket
+//!
+//! Every request is a line of JSON:
+//!
+//! ```json
+//! {"id": 1, "method": "upgrade", "params": {"args": ["inst", "--force"]}}
+//! ```
+//!
+//! Methods are `list`, `status` (params: `{"name": ...}`), `upgrade` and
+//! `revert` (params: `{"args": [...]}`, arguments of `edgedb server
+//! upgrade` and `edgedb server restore-backup` respectively). While
+//! `upgrade` or `revert` runs, the log of the daemon is sent as
+//! `{"id": 1, "event": "progress", "level": "INFO", "message": ...}`.
+//! The request is completed by `{"id": 1, "result": ...}` or
+//! `{"id": 1, "error": "message"}`.
+//!
+//! Upgrades and reverts run one at a time, the ones requested on other
+//! connections wait until the running one is finished.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[cfg(unix)] use std::os::unix::net::{UnixListener, UnixStream};
+
+use anyhow::Context;
+use clap::Clap;
+use fn_error_context::context;
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::log_levels;
+use crate::platform::home_dir;
+use crate::server::options::{Daemon, RestoreBackup, Upgrade};
+use crate::server::restore_backup::restore_backup;
+use crate::server::status::{get_status, status_info_all};
+use crate::server::upgrade::{upgrade, needs_confirmation};
+
+
+/// Progress events waiting to be written to a client
+const PROGRESS_QUEUE: usize = 1024;
+/// Client that doesn't read for this long doesn't get the rest of events
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Upgrades and reverts change packages, ports and the log hook
+static OPERATION: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+#[derive(Deserialize, Debug)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Params,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct Params {
+    name: Option<String>,
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum Response {
+    Result { id: Value, result: Value },
+    Error { id: Value, error: String },
+    Progress { id: Value, event: &'static str, level: &'static str,
+               message: String },
+}
+
+fn default_socket() -> anyhow::Result<PathBuf> {
+    Ok(home_dir()?.join(".edgedb").join("run").join("server-daemon.sock"))
+}
+
+fn args<'x>(command: &'x str, args: &'x [String])
+    -> impl Iterator<Item=&'x str>
+{
+    iter::once(command).chain(args.iter().map(|arg| &arg[..]))
+}
+
+fn run_upgrade(params: &Params) -> anyhow::Result<Value> {
+    let mut options = Upgrade::try_parse_from(args("upgrade", &params.args))?;
+    if options.interactive {
+        anyhow::bail!("`--interactive` can't be used with the daemon");
+    }
+    if options.no_verify_tls || !options.repository_url.is_empty() {
+        // both are process-wide and would apply to every later request
+        anyhow::bail!("`--no-verify-tls` and `--repository-url` can't be \
+            used with the daemon");
+    }
+    if options.instances_from_stdin {
+        anyhow::bail!("`--instances-from-stdin` can't be used with \
+            the daemon, use `--inventory` instead");
+    }
+    if needs_confirmation(&options) {
+        // confirmation would be read from stdin of the daemon
+        anyhow::bail!("`--allow-channel-switch`, `--allow-non-empty` \
+            and `--schema-only` require `--non-interactive` \
+            with the daemon");
+    }
+    if options.deadline.is_some() {
+        // deadline is process-wide, and its watchdog stops servers of
+        // every upgrade run by the daemon
+        anyhow::bail!("`--deadline` can't be used with the daemon");
+    }
+    let summary = tempfile::NamedTempFile::new()?;
+    options.quiet = true;
+    options.summary_file = Some(summary.path().to_path_buf());
+    upgrade(&options)?;
+    let data = fs::read(summary.path())?;
+    if data.is_empty() {
+        // nothing to upgrade, so no summary is written
+        return Ok(Value::Null);
+    }
+    Ok(serde_json::from_slice(&data)?)
+}
+
+fn run_revert(params: &Params) -> anyhow::Result<Value> {
+    let options = RestoreBackup::try_parse_from(
+        args("restore-backup", &params.args))?;
+    restore_backup(&options)?;
+    Ok(serde_json::to_value(get_status(&options.name, false)?.info())?)
+}
+
+#[cfg(unix)]
+fn send(stream: &UnixStream, response: &Response) -> io::Result<()> {
+    let mut line = serde_json::to_vec(response)?;
+    line.push(b'\n');
+    let mut stream = stream;
+    stream.write_all(&line)
+}
+
+/// Runs the operation, sending what is logged meanwhile as progress events
+///
+/// Events are written by a separate thread, so a client that doesn't read
+/// them never blocks logging (and the operation). If the queue is full or
+/// the client is gone, events are dropped.
+#[cfg(unix)]
+fn with_progress(id: &Value, stream: &UnixStream,
+    f: impl FnOnce() -> anyhow::Result<Value>)
+    -> anyhow::Result<Value>
+{
+    let _running = OPERATION.lock().unwrap_or_else(|e| e.into_inner());
+    let stream = stream.try_clone()?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let (tx, rx) = mpsc::sync_channel::<Response>(PROGRESS_QUEUE);
+    let writer = thread::spawn(move || {
+        for event in rx.iter() {
+            if send(&stream, &event).is_err() {
+                // keep draining, so the hook never blocks on a full queue
+                rx.iter().for_each(drop);
+                break;
+            }
+        }
+    });
+    let id = id.clone();
+    let previous = log_levels::set_hook(Some(Box::new(move |record| {
+        tx.try_send(Response::Progress {
+            id: id.clone(),
+            event: "progress",
+            level: record.level().as_str(),
+            message: record.args().to_string(),
+        }).ok();
+    })));
+    let result = f();
+    // dropping our hook closes the channel and stops the writer
+    drop(log_levels::set_hook(previous));
+    writer.join().ok();
+    result
+}
+
+#[cfg(unix)]
+fn call(request: &Request, stream: &UnixStream) -> anyhow::Result<Value> {
+    let params = &request.params;
+    match &request.method[..] {
+        "list" => Ok(serde_json::to_value(status_info_all()?)?),
+        "status" => {
+            let name = params.name.as_ref()
+                .context("`name` parameter is required")?;
+            Ok(serde_json::to_value(get_status(name, false)?.info())?)
+        }
+        "upgrade" => with_progress(&request.id, stream,
+                                   || run_upgrade(params)),
+        "revert" => with_progress(&request.id, stream,
+                                  || run_revert(params)),
+        _ => anyhow::bail!("unknown method {:?}", request.method),
+    }
+}
+
+#[cfg(unix)]
+fn serve(stream: UnixStream) -> anyhow::Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => match call(&request, &stream) {
+                Ok(result) => Response::Result { id: request.id, result },
+                Err(e) => Response::Error {
+                    id: request.id,
+                    error: format!("{:#}", e),
+                },
+            },
+            Err(e) => Response::Error {
+                id: Value::Null,
+                error: format!("invalid request: {}", e),
+            },
+        };
+        send(&stream, &response)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+#[context("cannot listen on {}", path.display())]
+fn bind(path: &Path) -> anyhow::Result<UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    if let Some(dir) = path.parent() {
+        // existing directories (e.g. of a custom `--socket`) are kept as is
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            anyhow::bail!("another daemon is running");
+        }
+        // left by the daemon that was killed
+        fs::remove_file(path)?;
+    }
+    // operations are run with permissions of the user running the daemon,
+    // so the socket must never be accessible by others, not even until
+    // it's chmod'ed (no threads are running yet, so umask is safe to change)
+    let old_umask = unsafe { libc::umask(0o077) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(old_umask) };
+    let listener = listener?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+#[cfg(unix)]
+pub fn daemon(options: &Daemon) -> anyhow::Result<()> {
+    let path = match &options.socket {
+        Some(path) => path.clone(),
+        None => default_socket()?,
+    };
+    let listener = bind(&path)?;
+    eprintln!("Listening on {}", path.display());
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("Cannot accept connection: {}", e);
+                continue;
+            }
+        };
+        thread::spawn(move || {
+            if let Err(e) = serve(stream) {
+                log::warn!("Connection closed: {:#}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn daemon(_options: &Daemon) -> anyhow::Result<()> {
+    anyhow::bail!("`edgedb server daemon` is supported on unix only");
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, BufReader, Write};
+    #[cfg(unix)] use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    use serde_json::{json, Value};
+
+    use super::serve;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_errors() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let daemon = thread::spawn(move || serve(server));
+        client.write_all(b"not json\n\n\
+            {\"id\": 7, \"method\": \"reboot\"}\n\
+            {\"id\": 8, \"method\": \"status\"}\n").unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let responses = BufReader::new(&client).lines()
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect::<Vec<Value>>();
+        daemon.join().unwrap().unwrap();
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0]["id"], Value::Null);
+        assert_eq!(responses[1], json!({
+            "id": 7,
+            "error": "unknown method \"reboot\"",
+        }));
+        assert_eq!(responses[2]["error"], "`name` parameter is required");
+    }
+}
@@ -1,5 +1,7 @@
+use std::ffi::OsStr;
 use std::fs;
 use std::io;
+use std::path::Path;
 use std::str;
 use std::process::Command as StdCommand;
 
@@ -7,9 +9,11 @@ use anyhow::Context;
 use async_std::task;
 use serde::Serialize;
 
+use crate::process;
 use crate::server::detect::{Lazy, ARCH, InstalledPackage};
 use crate::server::docker::DockerCandidate;
 use crate::server::install::{self, Operation, Command};
+use crate::server::install::prefix::{self, PrefixPackage};
 use crate::server::package::{RepositoryInfo, PackageCandidate};
 use crate::server::remote;
 use crate::server::methods::InstallationMethods;
@@ -27,6 +31,13 @@ pub struct Debian {
 }
 
 
+/// Settings accepted via `--set`
+///
+/// * `apt_options` -- extra (whitespace-separated) arguments to `apt-get
+///   install`
+pub const EXTRA_SETTINGS: &[&str] = &["apt_options"];
+
+
 fn sources_list_path(nightly: bool) -> &'static str {
     if nightly {
         "/etc/apt/sources.list.d/edgedb_server_install_nightly.list"
@@ -90,18 +101,20 @@ impl Debian {
             docker: DockerCandidate::detect()?,
         })
     }
+    pub fn key_operations(&self, key: &str) -> Vec<Operation> {
+        vec![Operation::FeedPrivilegedCmd {
+            input: key.into(),
+            cmd: Command::new("apt-key")
+                .arg("add")
+                .arg("-"),
+        }]
+    }
     pub fn install_operations(&self, settings: &install::Settings)
         -> anyhow::Result<Vec<Operation>>
     {
         let key = task::block_on(remote::get_string(install::KEY_FILE_URL))
             .context("downloading key file")?;
-        let mut operations = Vec::new();
-        operations.push(Operation::FeedPrivilegedCmd {
-            input: key.into(),
-            cmd: Command::new("apt-key")
-                .arg("add")
-                .arg("-"),
-        });
+        let mut operations = self.key_operations(&key);
         let sources_list = sources_list(&self.codename, settings.nightly);
         let list_path = sources_list_path(settings.nightly);
         let update_list = match fs::read(list_path) {
@@ -133,10 +146,16 @@ impl Debian {
                 //     .arg(format!("Dir::Etc::sourcelist={}", list_path))
                 // .arg("-o").arg("Dir::Etc::sourceparts=-")
         ));
-        operations.push(Operation::PrivilegedCmd(
-            Command::new("apt-get")
+        let mut install = Command::new("apt-get")
             .arg("install")
-            .arg("-y")
+            .arg("-y");
+        if let Some(options) = settings.extra.get("apt_options") {
+            for opt in options.split_whitespace() {
+                install = install.arg(opt);
+            }
+        }
+        operations.push(Operation::PrivilegedCmd(
+            install
             // TODO(tailhook) version
             .arg(format!("{}-{}",
                          settings.package_name, settings.major_version))
@@ -144,6 +163,88 @@ impl Debian {
         ));
         return Ok(operations);
     }
+    /// Downloads the package using private apt configuration and state
+    /// directories (so root isn't needed), and unpacks it into `prefix`
+    /// without running package scripts
+    pub fn install_to_prefix(&self, settings: &install::Settings,
+        prefix: &Path)
+        -> anyhow::Result<()>
+    {
+        let key = task::block_on(remote::get_string(install::KEY_FILE_URL))
+            .context("downloading key file")?;
+        let tmp = tempfile::tempdir()?;
+        let root = tmp.path();
+        fs::create_dir_all(root.join("lists").join("partial"))?;
+        fs::create_dir_all(root.join("cache").join("archives")
+                           .join("partial"))?;
+        fs::create_dir_all(root.join("trusted.gpg.d"))?;
+        fs::write(root.join("trusted.gpg.d").join("edgedb.asc"), key)?;
+        fs::write(root.join("sources.list"),
+                  sources_list(&self.codename, settings.nightly))?;
+        let apt_get = || {
+            let mut cmd = StdCommand::new("apt-get");
+            cmd.current_dir(root);
+            let options = [
+                ("Dir::Etc::SourceList", root.join("sources.list")),
+                ("Dir::Etc::SourceParts", "-".into()),
+                ("Dir::Etc::TrustedParts", root.join("trusted.gpg.d")),
+                ("Dir::State::Lists", root.join("lists")),
+                ("Dir::Cache", root.join("cache")),
+            ];
+            for (name, value) in &options {
+                cmd.arg("-o").arg(format!("{}={}", name, value.display()));
+            }
+            cmd.arg("-o").arg("Debug::NoLocking=1");
+            cmd
+        };
+        process::run(apt_get().arg("update"))?;
+        let package = format!("{}-{}",
+            settings.package_name, settings.major_version);
+        process::run(apt_get().arg("download").arg(&package))?;
+        let mut deb = None;
+        for item in fs::read_dir(root)? {
+            let path = item?.path();
+            if path.extension() == Some(OsStr::new("deb")) {
+                deb = Some(path);
+            }
+        }
+        let deb = deb.ok_or_else(|| {
+            anyhow::anyhow!("apt-get download of {} produced no file",
+                            package)
+        })?;
+        fs::create_dir_all(prefix)
+            .with_context(|| format!("cannot create {}", prefix.display()))?;
+        process::run(StdCommand::new("dpkg-deb")
+            .arg("--extract").arg(&deb).arg(prefix))?;
+        prefix::add_package(PrefixPackage {
+            prefix: prefix.into(),
+            package: InstalledPackage {
+                package_name: settings.package_name.clone(),
+                major_version: settings.major_version.clone(),
+                version: settings.version.clone(),
+                revision: String::new(),
+            },
+        })?;
+        Ok(())
+    }
+}
+
+/// Operations removing the package installed by `install_operations`
+pub fn uninstall_operations(package: &InstalledPackage)
+    -> anyhow::Result<Vec<Operation>>
+{
+    if let Some(path) = prefix::find(&package.major_version) {
+        anyhow::bail!("EdgeDB {} is unpacked into {} rather than \
+            installed by apt, remove the directory instead",
+            package.major_version, path.display());
+    }
+    Ok(vec![Operation::PrivilegedCmd(
+        Command::new("apt-get")
+            .arg("remove")
+            .arg("-y")
+            .arg(format!("{}-{}",
+                         package.package_name, package.major_version))
+    )])
 }
 
 pub fn get_installed() -> anyhow::Result<Vec<InstalledPackage>> {
@@ -153,8 +254,8 @@ pub fn get_installed() -> anyhow::Result<Vec<InstalledPackage>> {
     let out = cmd.output()
         .context("cannot get installed packages")?;
     if !out.status.success() {
-        anyhow::bail!("cannot get installed packages: {:?} {}",
-            cmd, out.status);
+        anyhow::bail!("cannot get installed packages: {} {}",
+            process::describe(&cmd), out.status);
     }
     let mut result = Vec::new();
     for line in out.stdout.split(|&b| b == b'\n') {
@@ -175,8 +276,8 @@ pub fn get_installed() -> anyhow::Result<Vec<InstalledPackage>> {
         let out = cmd.output()
             .context("cannot get installed packages")?;
         if !out.status.success() {
-            anyhow::bail!("cannot get installed packages: {:?} {}",
-                cmd, out.status);
+            anyhow::bail!("cannot get installed packages: {} {}",
+                process::describe(&cmd), out.status);
         }
         for line in out.stdout.split(|&b| b == b'\n') {
             let line = match str::from_utf8(line).ok() {
@@ -207,5 +308,12 @@ pub fn get_installed() -> anyhow::Result<Vec<InstalledPackage>> {
             }
         }
     }
+    for pkg in prefix::read_packages()? {
+        if !result.iter()
+            .any(|p| p.major_version == pkg.package.major_version)
+        {
+            result.push(pkg.package);
+        }
+    }
     Ok(result)
 }
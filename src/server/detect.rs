@@ -2,11 +2,13 @@ use std::fmt;
 use std::collections::BTreeMap;
 
 use once_cell::sync::OnceCell;
-use serde::Serialize;
+use serde::{Serialize, Deserialize};
 
 use crate::server::version::Version;
 use crate::server::os_trait::CurrentOs;
 use crate::server::methods::{self, InstallMethod};
+use crate::server::options::{Detect, OutputFormat};
+use crate::server::print_serialized;
 
 use anyhow::Context;
 
@@ -27,15 +29,17 @@ pub enum VersionQuery {
     Nightly,
 }
 
-#[derive(Clone, Serialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct VersionResult {
     pub package_name: String,
     pub major_version: Version<String>,
     pub version: Version<String>,
     pub revision: String,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub sha256: Option<String>,
 }
 
-#[derive(Clone, Serialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct InstalledPackage {
     pub package_name: String,
     pub major_version: Version<String>,
@@ -82,12 +86,11 @@ pub fn current_os() -> anyhow::Result<Box<dyn CurrentOs>> {
     }
 }
 
-pub fn main(_arg: &crate::server::options::Detect)
-    -> Result<(), anyhow::Error>
-{
+pub fn main(options: &Detect) -> Result<(), anyhow::Error> {
     #[derive(Serialize)]
     struct Info {
         os_type: &'static str,
+        architecture: &'static str,
         os_info: serde_json::Value,
         detected: methods::InstallationMethods,
         methods: BTreeMap<InstallMethod, serde_json::Value>,
@@ -95,15 +98,38 @@ pub fn main(_arg: &crate::server::options::Detect)
 
     let os = current_os()?;
     let detected = os.get_available_methods()?;
-    let methods = detected.instantiate_all(&*os, true)?;
-    serde_json::to_writer_pretty(std::io::stdout(), &Info {
-        os_type: os.get_type_name(),
-        os_info: os.detect_all(),
-        detected,
-        methods: methods.iter()
-            .map(|(mname, meth)| (mname.clone(), meth.detect_all()))
-            .collect(),
-    })?;
+    match options.format {
+        OutputFormat::Human => {
+            let package = &detected.package;
+            println!("Operating system: {}", os.get_type_name()
+                .rsplit("::").next().unwrap_or("unknown"));
+            println!("Distribution: {} {}",
+                package.distro_name, package.distro_version);
+            println!("Architecture: {}", ARCH);
+            for (name, supported, reason) in &[
+                (InstallMethod::Package, package.supported,
+                 package.reason()),
+                (InstallMethod::Docker, detected.docker.supported,
+                 detected.docker.reason()),
+            ] {
+                println!("Method {}: {} ({})", name.option(),
+                    if *supported { "available" } else { "not available" },
+                    reason);
+            }
+        }
+        format => {
+            let methods = detected.instantiate_all(&*os, true)?;
+            print_serialized(format, &Info {
+                os_type: os.get_type_name(),
+                architecture: ARCH,
+                os_info: os.detect_all(),
+                detected,
+                methods: methods.iter()
+                    .map(|(mname, meth)| (mname.clone(), meth.detect_all()))
+                    .collect(),
+            })?;
+        }
+    }
     Ok(())
 }
 
@@ -1,14 +1,19 @@
+use std::collections::BTreeMap;
 use std::path::PathBuf;
+use std::process::Command;
 
-use serde::Serialize;
+use anyhow::Context;
+use serde::{Serialize, Deserialize};
 
 use crate::server::detect::{VersionQuery, InstalledPackage, VersionResult};
 use crate::server::os_trait::{CurrentOs, Method};
 use crate::server::install;
 use crate::server::init;
+use crate::server::upgrade;
 use crate::server::package::PackageInfo;
 use crate::server::version::Version;
 use crate::server::methods::InstallMethod;
+use crate::process;
 
 
 #[derive(Debug, Serialize)]
@@ -27,6 +32,138 @@ pub struct DockerMethod<'os, O: CurrentOs + ?Sized> {
     cli: PathBuf,
 }
 
+/// Port the server listens on inside of the container
+const CONTAINER_PORT: &str = "5656/tcp";
+
+/// Part of `docker container inspect` output needed to recreate the
+/// container
+#[derive(Debug, Deserialize)]
+#[serde(rename_all="PascalCase")]
+struct Container {
+    config: ContainerConfig,
+    host_config: HostConfig,
+    #[serde(default)]
+    mounts: Vec<Mount>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all="PascalCase")]
+struct ContainerConfig {
+    #[serde(default)]
+    env: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all="PascalCase")]
+struct HostConfig {
+    #[serde(default)]
+    port_bindings: Option<BTreeMap<String, Option<Vec<PortBinding>>>>,
+    #[serde(default)]
+    restart_policy: Option<RestartPolicy>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all="PascalCase")]
+struct PortBinding {
+    #[serde(default)]
+    host_ip: String,
+    host_port: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all="PascalCase")]
+struct RestartPolicy {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all="PascalCase")]
+struct Mount {
+    #[serde(rename="Type")]
+    kind: String,
+    #[serde(default)]
+    name: Option<String>,
+    source: String,
+    destination: String,
+    #[serde(rename="RW")]
+    rw: bool,
+}
+
+/// Name of the container running the instance
+pub fn container_name(instance: &str) -> String {
+    format!("edgedb-{}", instance)
+}
+
+fn image_name(version: &Version<String>) -> String {
+    format!("edgedb/edgedb:{}", version)
+}
+
+/// Splits environment of the container into names and values. Only names
+/// are passed in the arguments of `docker run` (values are in its
+/// environment), so values don't show up in logs
+fn split_env(container: &Container) -> Vec<(String, String)> {
+    container.config.env.iter().flatten()
+        .map(|pair| {
+            let mut pair = pair.splitn(2, '=');
+            let key = pair.next().unwrap_or_default();
+            (key.to_owned(), pair.next().unwrap_or_default().to_owned())
+        })
+        .collect()
+}
+
+/// Arguments of `docker run` that recreate the `container` with another
+/// `image`, keeping its volumes and publishing the server on `port`
+fn run_args(name: &str, container: &Container, image: &str, port: u16)
+    -> Vec<String>
+{
+    let mut args = vec![
+        "run".into(), "--detach".into(), "--name".into(), name.into()];
+    if let Some(policy) = &container.host_config.restart_policy {
+        if !policy.name.is_empty() && policy.name != "no" {
+            args.push("--restart".into());
+            args.push(policy.name.clone());
+        }
+    }
+    for mount in &container.mounts {
+        let source = match (&mount.kind[..], &mount.name) {
+            ("volume", Some(name)) => name,
+            ("bind", _) => &mount.source,
+            // tmpfs and others don't keep data
+            _ => continue,
+        };
+        let mut spec = format!("type={},source={},target={}",
+            mount.kind, source, mount.destination);
+        if !mount.rw {
+            spec.push_str(",readonly");
+        }
+        args.push("--mount".into());
+        args.push(spec);
+    }
+    for (key, _) in split_env(container) {
+        args.push("--env".into());
+        args.push(key);
+    }
+    let bindings = container.host_config.port_bindings.iter().flatten();
+    for (container_port, bindings) in bindings {
+        for binding in bindings.iter().flatten() {
+            let host_port = if container_port == CONTAINER_PORT {
+                port.to_string()
+            } else {
+                binding.host_port.clone()
+            };
+            args.push("--publish".into());
+            if binding.host_ip.is_empty() {
+                args.push(format!("{}:{}", host_port, container_port));
+            } else {
+                args.push(format!("{}:{}:{}",
+                    binding.host_ip, host_port, container_port));
+            }
+        }
+    }
+    args.push(image.into());
+    args
+}
+
 impl DockerCandidate {
     pub fn detect() -> anyhow::Result<DockerCandidate> {
         let cli = which::which("docker").ok();
@@ -70,6 +207,16 @@ impl DockerCandidate {
         }
         buf.push('\n');
     }
+    /// Explains why docker is or isn't available
+    pub fn reason(&self) -> String {
+        if !self.platform_supported {
+            return "Docker is not supported for this platform".into();
+        }
+        match &self.cli {
+            Some(cli) => format!("`docker` is found at {}", cli.display()),
+            None => "`docker` command-line tool is not found in PATH".into(),
+        }
+    }
     pub fn make_method<'os, O>(&self, os: &'os O)
         -> anyhow::Result<DockerMethod<'os, O>>
         where O: CurrentOs + ?Sized,
@@ -84,6 +231,37 @@ impl DockerCandidate {
     }
 }
 
+impl<'os, O: CurrentOs + ?Sized> DockerMethod<'os, O> {
+    fn docker(&self) -> Command {
+        Command::new(&self.cli)
+    }
+    fn inspect(&self, name: &str) -> anyhow::Result<Container> {
+        let data = process::get_text(self.docker()
+            .arg("container").arg("inspect").arg(name))
+            .with_context(|| format!("cannot inspect container {:?}", name))?;
+        let mut containers: Vec<Container> = serde_json::from_str(&data)
+            .with_context(|| format!("cannot decode description \
+                of container {:?}", name))?;
+        containers.pop()
+            .with_context(|| format!("container {:?} is not found", name))
+    }
+    /// Puts the old container back after the new one failed to start
+    fn restore_container(&self, name: &str, old: &str) {
+        let mut remove = self.docker();
+        remove.arg("rm").arg("--force").arg(name);
+        let mut rename = self.docker();
+        rename.arg("rename").arg(old).arg(name);
+        let mut start = self.docker();
+        start.arg("start").arg(name);
+        for cmd in &mut [remove, rename, start] {
+            if let Err(e) = process::run(cmd) {
+                log::warn!(target: "edgedb::server::upgrade",
+                    "Cannot restore container {:?}: {:#}", name, e);
+            }
+        }
+    }
+}
+
 impl<'os, O: CurrentOs + ?Sized> Method for DockerMethod<'os, O> {
     fn name(&self) -> InstallMethod {
         InstallMethod::Docker
@@ -97,10 +275,21 @@ impl<'os, O: CurrentOs + ?Sized> Method for DockerMethod<'os, O> {
         // TODO(tailhook) implement fetching versions from docker
         Ok(&[])
     }
-    fn get_version(&self, _query: &VersionQuery)
+    fn get_version(&self, query: &VersionQuery)
         -> anyhow::Result<VersionResult>
     {
-        anyhow::bail!("Docker support is not implemented yet"); // TODO
+        match query {
+            // image tags are not fetched, so only exact tags are known
+            VersionQuery::Stable(Some(version)) => Ok(VersionResult {
+                package_name: image_name(version),
+                major_version: version.clone(),
+                version: version.clone(),
+                revision: "docker".into(),
+                sha256: None,
+            }),
+            _ => anyhow::bail!("Docker instances can only be upgraded \
+                to a specific version, use `--to-version`"),
+        }
     }
     fn installed_versions(&self) -> anyhow::Result<&[InstalledPackage]> {
         Ok(&[])
@@ -111,6 +300,34 @@ impl<'os, O: CurrentOs + ?Sized> Method for DockerMethod<'os, O> {
     fn is_system_only(&self) -> bool {
         true
     }
+    fn upgrade_in_place(&self, inst: &upgrade::Instance,
+        version: &VersionResult, port: u16)
+        -> anyhow::Result<bool>
+    {
+        let name = container_name(&inst.name);
+        let container = self.inspect(&name)?;
+        let image = image_name(&version.version);
+        log::info!(target: "edgedb::server::upgrade",
+            "Pulling image {}", image);
+        process::run(self.docker().arg("pull").arg(&image))?;
+
+        log::info!(target: "edgedb::server::upgrade",
+            "Recreating container {:?} from image {}", name, image);
+        // old container is kept until the new one is running, volumes are
+        // not removed with it
+        let old = format!("{}.old", name);
+        process::run(self.docker().arg("stop").arg(&name))?;
+        process::run(self.docker().arg("rename").arg(&name).arg(&old))?;
+        let mut cmd = self.docker();
+        cmd.args(run_args(&name, &container, &image, port));
+        cmd.envs(split_env(&container));
+        if let Err(e) = process::run(&mut cmd) {
+            self.restore_container(&name, &old);
+            return Err(e);
+        }
+        process::run(self.docker().arg("rm").arg(&old))?;
+        Ok(true)
+    }
     fn get_server_path(&self, _major_version: &Version<String>)
         -> anyhow::Result<PathBuf>
     {
@@ -122,3 +339,47 @@ impl<'os, O: CurrentOs + ?Sized> Method for DockerMethod<'os, O> {
         anyhow::bail!("Docker support is not implemented yet"); // TODO
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Container, run_args, split_env};
+
+    #[test]
+    fn test_run_args() {
+        let container: Container = serde_json::from_str(r#"{
+            "Config": {"Env": ["EDGEDB_PASSWORD=x=y", "TZ=UTC"]},
+            "HostConfig": {
+                "PortBindings": {
+                    "5656/tcp": [{"HostIp": "127.0.0.1", "HostPort": "10700"}],
+                    "8888/tcp": [{"HostIp": "", "HostPort": "8888"}]
+                },
+                "RestartPolicy": {"Name": "unless-stopped"}
+            },
+            "Mounts": [
+                {"Type": "volume", "Name": "edgedb-data", "Source": "/var/x",
+                 "Destination": "/var/lib/edgedb/data", "RW": true},
+                {"Type": "bind", "Source": "/etc/edgedb",
+                 "Destination": "/etc/edgedb", "RW": false},
+                {"Type": "tmpfs", "Source": "",
+                 "Destination": "/tmp", "RW": true}
+            ]
+        }"#).unwrap();
+        assert_eq!(run_args("edgedb-inst1", &container,
+                            "edgedb/edgedb:1-beta2", 10701), vec![
+            "run", "--detach", "--name", "edgedb-inst1",
+            "--restart", "unless-stopped",
+            "--mount",
+            "type=volume,source=edgedb-data,target=/var/lib/edgedb/data",
+            "--mount", "type=bind,source=/etc/edgedb,target=/etc/edgedb,\
+                        readonly",
+            "--env", "EDGEDB_PASSWORD", "--env", "TZ",
+            "--publish", "127.0.0.1:10701:5656/tcp",
+            "--publish", "8888:8888/tcp",
+            "edgedb/edgedb:1-beta2",
+        ]);
+        assert_eq!(split_env(&container), vec![
+            ("EDGEDB_PASSWORD".into(), "x=y".into()),
+            ("TZ".into(), "UTC".into()),
+        ]);
+    }
+}
@@ -0,0 +1,332 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::commands::ExitCode;
+use crate::process;
+use crate::server::control::read_metadata;
+use crate::server::detect;
+use crate::server::init::{data_path, read_ports};
+use crate::server::install::exit_codes;
+use crate::server::methods::InstallationMethods;
+use crate::server::options::{Doctor, OutputFormat};
+use crate::server::os_trait::CurrentOs;
+use crate::server::status::read_upgrade;
+use crate::server::upgrade::{UpgradeMeta, UPGRADE_DONE, is_dir_entry};
+use crate::server::upgrade::{check_removable, dir_size, format_bytes};
+use crate::server::{confirm, is_valid_name, print_serialized};
+
+
+/// What `--fix` may do about the finding
+#[derive(Debug)]
+enum Action {
+    /// Upgrade marker left by an upgrade that completed
+    RemoveMarker(PathBuf),
+    /// Dump left by upgrade, removed after confirmation
+    RemoveDump(PathBuf),
+}
+
+#[derive(Debug, Serialize)]
+struct Finding {
+    instance: Option<String>,
+    problem: String,
+    /// Suggested command to fix the problem
+    fix: Option<String>,
+    fixed: bool,
+    #[serde(skip)]
+    action: Option<Action>,
+}
+
+impl Finding {
+    fn new(instance: Option<&str>, problem: String, fix: Option<String>)
+        -> Finding
+    {
+        Finding {
+            instance: instance.map(|name| name.to_string()),
+            problem,
+            fix,
+            fixed: false,
+            action: None,
+        }
+    }
+    fn with_action(mut self, action: Action) -> Finding {
+        self.action = Some(action);
+        self
+    }
+}
+
+fn check_marker(name: &str, dir: &Path, findings: &mut Vec<Finding>) {
+    let marker = dir.join("UPGRADE_IN_PROGRESS");
+    if !marker.exists() {
+        return;
+    }
+    let meta = match read_upgrade(&marker) {
+        Ok(meta) => meta,
+        Err(e) => {
+            findings.push(Finding::new(Some(name),
+                format!("upgrade marker is unreadable: {:#}", e),
+                Some(format!("edgedb server restore-backup {}", name))));
+            return;
+        }
+    };
+    if process::is_alive(meta.pid) {
+        // upgrade is running right now
+        return;
+    }
+    let done = fs::read(dir.join(UPGRADE_DONE)).ok()
+        .and_then(|data| serde_json::from_slice::<UpgradeMeta>(&data).ok())
+        .map(|done| done.target == meta.target)
+        .unwrap_or(false);
+    if done {
+        findings.push(Finding::new(Some(name),
+            format!("upgrade to {} is complete, but its marker is left",
+                    meta.target),
+            Some("edgedb server doctor --fix".into())
+        ).with_action(Action::RemoveMarker(marker)));
+    } else {
+        findings.push(Finding::new(Some(name),
+            format!("upgrade {} -> {} was interrupted (process {} \
+                is not running)", meta.source, meta.target, meta.pid),
+            Some(format!("edgedb server upgrade {0}  # to continue, or\n\
+                edgedb server restore-backup {0}  # to roll back", name))));
+    }
+}
+
+fn check_instance(name: &str, dir: &Path,
+    os: &dyn CurrentOs, avail: &InstallationMethods,
+    ports: &mut BTreeMap<u16, Vec<String>>,
+    findings: &mut Vec<Finding>)
+{
+    let meta = match read_metadata(dir) {
+        Ok(meta) => meta,
+        Err(e) => {
+            findings.push(Finding::new(Some(name),
+                format!("metadata is unreadable: {:#}", e),
+                Some(format!("edgedb server repair-metadata {}", name))));
+            return;
+        }
+    };
+    ports.entry(meta.port).or_insert_with(Vec::new).push(name.into());
+    check_marker(name, dir, findings);
+    if !avail.is_supported(&meta.method) {
+        findings.push(Finding::new(Some(name),
+            format!("installation method {} is not available",
+                    meta.method.title()),
+            None));
+        return;
+    }
+    let server_path = os.make_method(&meta.method, avail)
+        .and_then(|method| method.get_server_path(&meta.version));
+    match server_path {
+        Ok(path) if path.exists() => {}
+        Ok(path) => {
+            findings.push(Finding::new(Some(name),
+                format!("server binary {} of version {} is missing",
+                        path.display(), meta.version),
+                Some(format!("edgedb server install --version={} {}",
+                             meta.version, meta.method.option()))));
+        }
+        Err(e) => {
+            findings.push(Finding::new(Some(name),
+                format!("cannot find server binary: {:#}", e),
+                Some(format!("edgedb server install --version={} {}",
+                             meta.version, meta.method.option()))));
+        }
+    }
+}
+
+/// Finds dumps and backups made by upgrade. Dumps of instances that are
+/// being upgraded are still needed and are not reported
+fn check_leftover(base: &Path, file_name: &str, findings: &mut Vec<Finding>)
+{
+    let path = base.join(file_name);
+    if let Some(name) = file_name.strip_suffix(".dump") {
+        if !is_valid_name(name)
+            || base.join(name).join("UPGRADE_IN_PROGRESS").exists()
+        {
+            return;
+        }
+        findings.push(Finding::new(Some(name),
+            format!("dump left by upgrade at {} ({})", path.display(),
+                    format_bytes(dir_size(&path).ok())),
+            Some("edgedb server doctor --fix".into())
+        ).with_action(Action::RemoveDump(path)));
+    } else if let Some(idx) = file_name.find(".backup") {
+        let name = &file_name[..idx];
+        if !is_valid_name(name) {
+            return;
+        }
+        findings.push(Finding::new(Some(name),
+            format!("backup of the data directory at {} ({})",
+                    path.display(), format_bytes(dir_size(&path).ok())),
+            Some(format!("edgedb server restore-backup {}  \
+                # to roll back, otherwise remove it manually", name))));
+    }
+}
+
+fn check_ports(ports: &BTreeMap<u16, Vec<String>>,
+    findings: &mut Vec<Finding>)
+{
+    for (port, names) in ports {
+        if names.len() < 2 {
+            continue;
+        }
+        findings.push(Finding::new(None,
+            format!("instances {} use the same port {}",
+                    names.join(", "), port),
+            Some(format!("edgedb server next-port  # to pick a free port\n\
+                          edgedb server upgrade {} --port=<port>",
+                         names[names.len()-1]))));
+    }
+    match read_ports() {
+        Ok(reserved) => {
+            for (name, reserved_port) in reserved {
+                let used = ports.iter()
+                    .find(|(_, names)| names.contains(&name))
+                    .map(|(port, _)| *port);
+                match used {
+                    Some(port) if port != reserved_port => {
+                        findings.push(Finding::new(Some(&name),
+                            format!("port {} is reserved for the instance, \
+                                but it uses port {}", reserved_port, port),
+                            None));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Err(e) => {
+            findings.push(Finding::new(None, format!("{:#}", e), None));
+        }
+    }
+}
+
+/// Applies the action of the finding, returns whether it's fixed
+fn fix(action: &Action, root: &Path) -> anyhow::Result<bool> {
+    match action {
+        Action::RemoveMarker(path) => {
+            fs::remove_file(path)?;
+            Ok(true)
+        }
+        Action::RemoveDump(path) => {
+            if !atty::is(atty::Stream::Stdin) {
+                log::warn!("Stdin is not a terminal, keeping {}",
+                    path.display());
+                return Ok(false);
+            }
+            if !confirm(&format!("Remove {}?", path.display()))? {
+                return Ok(false);
+            }
+            check_removable(path, root)?;
+            fs::remove_dir_all(path)?;
+            Ok(true)
+        }
+    }
+}
+
+pub fn doctor(options: &Doctor) -> anyhow::Result<()> {
+    let base = data_path(false)?;
+    let os = detect::current_os()?;
+    let avail = os.get_available_methods()?;
+    let mut findings = Vec::new();
+    let mut ports = BTreeMap::new();
+    if base.exists() {
+        let mut items = fs::read_dir(&base)?.collect::<Result<Vec<_>, _>>()?;
+        items.sort_by_key(|item| item.file_name());
+        for item in items {
+            let file_name = match item.file_name().to_str() {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            if !is_dir_entry(&item) {
+                continue;
+            }
+            if is_valid_name(&file_name) {
+                check_instance(&file_name, &item.path(), &*os, &avail,
+                               &mut ports, &mut findings);
+            } else {
+                check_leftover(&base, &file_name, &mut findings);
+            }
+        }
+    }
+    check_ports(&ports, &mut findings);
+    if options.fix {
+        for finding in &mut findings {
+            let action = match &finding.action {
+                Some(action) => action,
+                None => continue,
+            };
+            match fix(action, &base) {
+                Ok(fixed) => finding.fixed = fixed,
+                Err(e) => {
+                    log::error!("Cannot fix {:?}: {:#}", finding.problem, e);
+                }
+            }
+        }
+    }
+
+    let remaining = findings.iter().filter(|f| !f.fixed).count();
+    if options.format != OutputFormat::Human {
+        print_serialized(options.format, &findings)?;
+    } else if findings.is_empty() {
+        println!("No problems found");
+    } else {
+        print_findings(&findings, remaining);
+    }
+    if remaining > 0 {
+        return Err(ExitCode::new(exit_codes::PROBLEMS_FOUND).into());
+    }
+    Ok(())
+}
+
+fn print_findings(findings: &[Finding], remaining: usize) {
+    for finding in findings {
+        match &finding.instance {
+            Some(name) => println!("Instance {:?}: {}", name, finding.problem),
+            None => println!("{}", finding.problem),
+        }
+        if finding.fixed {
+            println!("  Fixed");
+        } else if let Some(fix) = &finding.fix {
+            for line in fix.lines() {
+                println!("  {}", line);
+            }
+        }
+    }
+    println!("{} problem(s) found, {} fixed",
+        findings.len(), findings.len() - remaining);
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::{check_leftover, Action};
+
+    #[test]
+    fn test_leftovers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path();
+        for name in &["done.dump", "busy.dump", "busy", "done.backup.1",
+                      "not-an-instance.dump"]
+        {
+            fs::create_dir(base.join(name)).unwrap();
+        }
+        fs::write(base.join("busy").join("UPGRADE_IN_PROGRESS"), "{}")
+            .unwrap();
+        let mut findings = Vec::new();
+        for name in &["done.dump", "busy.dump", "done.backup.1",
+                      "not-an-instance.dump"]
+        {
+            check_leftover(base, name, &mut findings);
+        }
+        assert_eq!(findings.len(), 2);
+        assert!(matches!(&findings[0].action,
+                         Some(Action::RemoveDump(path))
+                         if path == &base.join("done.dump")));
+        assert_eq!(findings[1].instance.as_deref(), Some("done"));
+        assert!(findings[1].action.is_none());
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use prettytable::{Table, Cell, Row};
+use serde::Serialize;
+
+use crate::commands::ExitCode;
+use crate::server::detect::{self, VersionQuery, ARCH};
+use crate::server::install::exit_codes;
+use crate::server::options::{CheckUpdates, Channel, Drift, OutputFormat};
+use crate::server::os_trait::Method;
+use crate::server::print_serialized;
+use crate::server::upgrade::{all_instances, get_installed, Instance};
+use crate::server::version::Version;
+use crate::table;
+
+
+#[derive(Serialize, Debug)]
+struct DriftInfo {
+    instance: String,
+    channel: &'static str,
+    #[serde(skip_serializing_if="Option::is_none")]
+    installed: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    latest: Option<String>,
+    /// Number of newer versions available in the channel
+    #[serde(skip_serializing_if="Option::is_none")]
+    behind: Option<usize>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    error: Option<String>,
+}
+
+
+fn newer_versions(method: &dyn Method, nightly: bool,
+    installed: &Version<String>)
+    -> anyhow::Result<usize>
+{
+    Ok(method.all_versions(nightly)?.iter()
+        .filter(|pkg| pkg.slot.is_some() && pkg.architecture == ARCH)
+        .filter(|pkg| pkg.basename == "edgedb" ||
+                      pkg.basename == "edgedb-server")
+        .map(|pkg| pkg.full_version())
+        .filter(|ver| ver > installed)
+        .map(|ver| ver.0)
+        .collect::<BTreeSet<_>>()
+        .len())
+}
+
+fn instance_drift(inst: &Instance, method: &dyn Method)
+    -> anyhow::Result<(Option<Version<String>>, Version<String>, usize)>
+{
+    let (installed_query, channel_query) = if inst.meta.nightly {
+        (VersionQuery::Nightly, VersionQuery::Nightly)
+    } else {
+        (VersionQuery::Stable(Some(inst.meta.version.clone())),
+         VersionQuery::Stable(None))
+    };
+    let installed = get_installed(&installed_query, method)?;
+    let latest = method.get_version(&channel_query)?.full_version();
+    let behind = match &installed {
+        Some(installed) => newer_versions(method, inst.meta.nightly,
+                                          installed)?,
+        None => 0,
+    };
+    Ok((installed, latest, behind))
+}
+
+fn collect_drift(channel: Option<Channel>)
+    -> anyhow::Result<Vec<DriftInfo>>
+{
+    let mut instances = all_instances()?;
+    if let Some(channel) = channel {
+        instances.retain(|inst| {
+            inst.meta.nightly == (channel == Channel::Nightly)
+        });
+    }
+    let mut report = Vec::with_capacity(instances.len());
+    if instances.is_empty() {
+        return Ok(report);
+    }
+    let os = detect::current_os()?;
+    let avail = os.get_available_methods()?;
+    let mut methods = BTreeMap::new();
+    for inst in &instances {
+        if !methods.contains_key(&inst.meta.method) {
+            let method = os.make_method(&inst.meta.method, &avail)?;
+            methods.insert(inst.meta.method.clone(), method);
+        }
+        let method = &methods[&inst.meta.method];
+        let mut info = DriftInfo {
+            instance: inst.name.clone(),
+            channel: if inst.meta.nightly { "nightly" } else { "stable" },
+            installed: None,
+            latest: None,
+            behind: None,
+            error: None,
+        };
+        match instance_drift(inst, &**method) {
+            Ok((installed, latest, behind)) => {
+                info.installed = installed.map(|v| v.0);
+                info.latest = Some(latest.0);
+                info.behind = info.installed.as_ref().map(|_| behind);
+            }
+            Err(e) => info.error = Some(format!("{:#}", e)),
+        }
+        report.push(info);
+    }
+    Ok(report)
+}
+
+pub fn drift(options: &Drift) -> anyhow::Result<()> {
+    let report = collect_drift(None)?;
+    if report.is_empty() {
+        eprintln!("No instances found");
+        return Ok(());
+    }
+    match options.format {
+        OutputFormat::Human => print_drift(&report),
+        format => print_serialized(format, &report)?,
+    }
+    Ok(())
+}
+
+fn has_update(info: &DriftInfo) -> bool {
+    info.behind.map(|n| n > 0).unwrap_or(false)
+}
+
+/// Like `drift` but only lists instances having upgrades, and exits with
+/// `UPDATES_AVAILABLE` if there are any, for monitoring
+pub fn check_updates(options: &CheckUpdates) -> anyhow::Result<()> {
+    let report = collect_drift(options.channel)?;
+    let failed = report.iter().filter(|info| info.error.is_some()).count();
+    let updates = report.into_iter()
+        .filter(|info| has_update(info) || info.error.is_some())
+        .collect::<Vec<_>>();
+    match options.format {
+        OutputFormat::Human if updates.is_empty() => {
+            eprintln!("All instances are up to date");
+        }
+        OutputFormat::Human => print_drift(&updates),
+        format => print_serialized(format, &updates)?,
+    }
+    if updates.iter().any(has_update) {
+        return Err(ExitCode::new(exit_codes::UPDATES_AVAILABLE).into());
+    }
+    if failed > 0 {
+        anyhow::bail!("cannot check updates of {} instance(s)", failed);
+    }
+    Ok(())
+}
+
+fn print_drift(report: &[DriftInfo]) {
+    let mut table = Table::new();
+    table.set_format(*table::FORMAT);
+    table.add_row(Row::new(vec![
+        table::header_cell("Instance"),
+        table::header_cell("Channel"),
+        table::header_cell("Installed"),
+        table::header_cell("Latest"),
+        table::header_cell("Status"),
+    ]));
+    for info in report {
+        let status = match (&info.error, info.behind) {
+            (Some(e), _) => Cell::new(e).style_spec("Fr"),
+            (None, None) => Cell::new("not installed").style_spec("Fr"),
+            (None, Some(0)) => Cell::new("up to date").style_spec("Fg"),
+            (None, Some(n)) => {
+                Cell::new(&format!("behind by {}", n)).style_spec("bFr")
+            }
+        };
+        table.add_row(Row::new(vec![
+            Cell::new(&info.instance),
+            Cell::new(info.channel),
+            Cell::new(info.installed.as_ref().map(|x| &x[..]).unwrap_or("-")),
+            Cell::new(info.latest.as_ref().map(|x| &x[..]).unwrap_or("-")),
+            status,
+        ]));
+    }
+    table.printstd();
+}
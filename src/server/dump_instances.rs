@@ -0,0 +1,156 @@
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::SystemTime;
+
+use anyhow::Context;
+use async_std::task;
+use serde::Serialize;
+
+use crate::server::init::Metadata;
+use crate::server::options::{self, DumpAllInstances};
+use crate::server::memory_budget::MemoryBudget;
+use crate::server::upgrade::{all_instances, dir_size, dump_to};
+use crate::server::upgrade::write_json_atomic;
+use crate::server::upgrade::{DumpSettings, Instance, START_TIMEOUT};
+
+/// Written into the output directory, lists dumped instances
+pub const MANIFEST: &str = "manifest.json";
+
+
+#[derive(Serialize)]
+struct Manifest {
+    #[serde(with="humantime_serde")]
+    timestamp: SystemTime,
+    instances: Vec<Entry>,
+}
+
+#[derive(Serialize)]
+struct Entry {
+    name: String,
+    status: &'static str,
+    #[serde(skip_serializing_if="Option::is_none")]
+    error: Option<String>,
+    metadata: Metadata,
+}
+
+pub fn dump_all_instances(options: &DumpAllInstances) -> anyhow::Result<()> {
+    if options.jobs == 0 {
+        anyhow::bail!("`--jobs` must be at least 1");
+    }
+    if options.max_memory == Some(0) {
+        anyhow::bail!("`--max-memory` must be greater than zero");
+    }
+    let budget = options.max_memory
+        .map(|max| Arc::new(MemoryBudget::new(max)));
+    let dir = &options.output_dir;
+    fs::create_dir_all(dir)
+        .with_context(|| format!("cannot create {}", dir.display()))?;
+    let queue = Arc::new(Mutex::new(all_instances()?));
+    let entries = Arc::new(Mutex::new(Vec::new()));
+    let threads = (0..options.jobs).map(|_| {
+        let queue = queue.clone();
+        let entries = entries.clone();
+        let budget = budget.clone();
+        let options = options.clone();
+        thread::spawn(move || loop {
+            let inst = match queue.lock().expect("not poisoned").pop() {
+                Some(inst) => inst,
+                None => break,
+            };
+            let _reserved = budget.as_ref().map(|budget| {
+                budget.reserve(dir_size(&inst.data_dir).unwrap_or(0))
+            });
+            let entry = dump_entry(inst, &options);
+            entries.lock().expect("not poisoned").push(entry);
+        })
+    }).collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().expect("dump thread doesn't panic");
+    }
+    let mut entries = Arc::try_unwrap(entries).ok()
+        .expect("all threads are finished")
+        .into_inner().expect("not poisoned");
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    let failed = entries.iter().filter(|e| e.status == "failed").count();
+    write_json_atomic(&dir.join(MANIFEST), &Manifest {
+        timestamp: SystemTime::now(),
+        instances: entries,
+    })?;
+    if failed > 0 {
+        anyhow::bail!("failed to dump {} instance(s), see {}",
+            failed, dir.join(MANIFEST).display());
+    }
+    Ok(())
+}
+
+fn dump_entry(inst: Instance, options: &DumpAllInstances) -> Entry {
+    let path = options.output_dir.join(&inst.name);
+    let (status, error) = match dump_one(&inst, &path, options) {
+        Ok(true) => {
+            eprintln!("Instance {:?} is dumped into {}",
+                inst.name, path.display());
+            ("dumped", None)
+        }
+        Ok(false) => {
+            eprintln!("Instance {:?} is not running, skipping", inst.name);
+            ("skipped", None)
+        }
+        Err(e) => {
+            eprintln!("Error dumping instance {:?}: {:#}", inst.name, e);
+            ("failed", Some(format!("{:#}", e)))
+        }
+    };
+    Entry {
+        name: inst.name,
+        status,
+        error,
+        metadata: inst.meta,
+    }
+}
+
+/// Returns `false` if the instance is skipped because it's not running
+fn dump_one(inst: &Instance, path: &Path, options: &DumpAllInstances)
+    -> anyhow::Result<bool>
+{
+    if path.exists() {
+        anyhow::bail!("{} already exists", path.display());
+    }
+    let mut ctl = inst.get_control()?;
+    let running = ctl.get_status()?.is_running();
+    if !running {
+        if options.only_if_running {
+            return Ok(false);
+        }
+        log::info!("Starting instance {:?} for the dump", inst.name);
+        ctl.start(&options::Start {
+            name: inst.name.clone(),
+            foreground: false,
+            wait: false,
+            wait_timeout: None,
+        })?;
+    }
+    let result = task::block_on(dump_to(inst, ctl.get_socket(true), path,
+        &DumpSettings {
+            connect_method: None,
+            start_timeout: START_TIMEOUT,
+            fingerprint: false,
+            skip_empty: false,
+            buffer_size: None,
+            exclude_databases: Vec::new(),
+            schema_only: false,
+        }));
+    if !running {
+        ctl.stop(&options::Stop {
+            name: inst.name.clone(),
+            wait: false,
+            wait_timeout: None,
+        })
+            .map_err(|e| log::warn!("Cannot stop instance {:?} \
+                after the dump: {:#}", inst.name, e))
+            .ok();
+    }
+    result?;
+    Ok(true)
+}
@@ -0,0 +1,150 @@
+//! Paths of dumps made by `edgedb server upgrade --dump-layout`
+//!
+//! Templates may contain `{name}`, `{timestamp}` (unix time of the start
+//! of the upgrade) and `{version}` (version the instance is upgraded
+//! from). Relative paths are relative to the directory containing data
+//! directories of instances.
+
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::server::is_valid_name;
+use crate::server::upgrade::DUMP_META;
+
+
+const PLACEHOLDERS: &[&str] = &["name", "timestamp", "version"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DumpLayout(String);
+
+impl FromStr for DumpLayout {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<DumpLayout> {
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            let len = rest[start..].find('}')
+                .ok_or_else(|| anyhow::anyhow!("unclosed `{{` in {:?}", s))?;
+            let name = &rest[start+1..start+len];
+            if !PLACEHOLDERS.contains(&name) {
+                anyhow::bail!("unknown placeholder {{{}}}, supported: {}",
+                    name, PLACEHOLDERS.iter()
+                        .map(|p| format!("{{{}}}", p))
+                        .collect::<Vec<_>>().join(", "));
+            }
+            rest = &rest[start+len+1..];
+        }
+        if rest.contains('}') {
+            anyhow::bail!("unmatched `}}` in {:?}", s);
+        }
+        if !s.contains("{name}") {
+            // dumps of different instances would overwrite each other
+            anyhow::bail!("dump layout must contain {{name}}");
+        }
+        if Path::new(s).components().any(|c| c == Component::ParentDir) {
+            anyhow::bail!("dump layout must not contain `..`");
+        }
+        Ok(DumpLayout(s.into()))
+    }
+}
+
+impl DumpLayout {
+    pub fn has_timestamp(&self) -> bool {
+        self.0.contains("{timestamp}")
+    }
+    /// Returns path of the dump of instance `name` relative to `root`
+    pub fn expand(&self, root: &Path, name: &str, timestamp: SystemTime,
+        version: &str)
+        -> anyhow::Result<PathBuf>
+    {
+        let timestamp = timestamp.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs()).unwrap_or(0);
+        let expanded = self.0
+            .replace("{name}", name)
+            .replace("{timestamp}", &timestamp.to_string())
+            .replace("{version}", version);
+        let path = root.join(&expanded);
+        // absolute templates and symlinks may point into `root` too
+        let real_root = canonicalize_prefix(root);
+        let real_path = canonicalize_prefix(&path);
+        if let Ok(relative) = real_path.strip_prefix(&real_root) {
+            let first = relative.components().next()
+                .and_then(|c| c.as_os_str().to_str());
+            if first.map(is_valid_name).unwrap_or(false) {
+                anyhow::bail!("dump layout {:?} puts the dump of {:?} \
+                    into {}, which is a data directory of an instance",
+                    self.0, name, real_root.join(first.unwrap()).display());
+            }
+        }
+        Ok(path)
+    }
+}
+
+/// Canonical form of the `path` that may not exist yet: symlinks are
+/// resolved in the longest existing prefix of it
+fn canonicalize_prefix(path: &Path) -> PathBuf {
+    let mut existing = path;
+    let mut rest = Vec::new();
+    loop {
+        if let Ok(canonical) = existing.canonicalize() {
+            return rest.iter().rev()
+                .fold(canonical, |path, name| path.join(name));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name);
+                existing = parent;
+            }
+            _ => return path.to_path_buf(),
+        }
+    }
+}
+
+/// With custom layouts dumps aren't recognized by their path, so an old
+/// dump is only removed if it contains nothing but files written by dump
+pub fn check_dump_files(path: &Path) -> anyhow::Result<()> {
+    for item in fs::read_dir(path)? {
+        let item = item?;
+        let name = item.file_name();
+        let known = name.to_str().map(|name| {
+            name.ends_with(".dump") || name == "init.edgeql"
+                || name == DUMP_META
+        }).unwrap_or(false);
+        if !known || !item.file_type()?.is_file() {
+            anyhow::bail!("refusing to remove {}: {:?} is not written by \
+                dump", path.display(), name);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::DumpLayout;
+
+    #[test]
+    fn test_expand() {
+        let root = Path::new("/data");
+        let time = UNIX_EPOCH + Duration::from_secs(1600000000);
+        let layout: DumpLayout = "{name}.dumps/{timestamp}-{version}"
+            .parse().unwrap();
+        assert_eq!(layout.expand(root, "inst", time, "1-alpha6").unwrap(),
+                   Path::new("/data/inst.dumps/1600000000-1-alpha6"));
+        let layout: DumpLayout = "{name}/{timestamp}".parse().unwrap();
+        assert!(layout.expand(root, "inst", time, "1-alpha6").is_err());
+        let layout: DumpLayout = "/data/{name}/dump".parse().unwrap();
+        assert!(layout.expand(root, "inst", time, "1-alpha6").is_err());
+        let layout: DumpLayout = "/backups/{name}".parse().unwrap();
+        assert_eq!(layout.expand(root, "inst", time, "1-alpha6").unwrap(),
+                   Path::new("/backups/inst"));
+        assert!("{nme}.dump".parse::<DumpLayout>().is_err());
+        assert!("{name.dump".parse::<DumpLayout>().is_err());
+        assert!("dump}".parse::<DumpLayout>().is_err());
+        assert!("../{name}".parse::<DumpLayout>().is_err());
+        assert!("latest.dump".parse::<DumpLayout>().is_err());
+    }
+}
@@ -0,0 +1,433 @@
+//! Compressed and encrypted backups (`upgrade --backup-encrypt`)
+//!
+//! Contents of the backup directory are archived with tar, compressed with
+//! gzip and encrypted with ChaCha20-Poly1305 in chunks of
+//! `CHUNK_SIZE` bytes. The cipher key is derived from the passphrase with
+//! Argon2id using a random salt stored in the header of the archive. Nonce
+//! of every chunk is made of a random prefix, number of the chunk and
+//! a flag of the last chunk, so modified, reordered or truncated chunks
+//! fail to decrypt. `backup.json` and `metadata.json` are kept
+//! unencrypted, so backups can be listed and versions checked without
+//! the key.
+
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write, BufReader, BufWriter};
+use std::path::Path;
+
+use anyhow::Context;
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use fn_error_context::context;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use serde::{Serialize, Deserialize};
+
+use crate::platform::tmp_file_name;
+use crate::server::upgrade::{BackupMeta, write_backup_meta};
+use crate::server::upgrade::check_removable_data;
+
+
+/// Name of the encrypted archive in the backup directory
+pub const ARCHIVE: &str = "data.tar.gz.enc";
+/// Environment variable used if no key file is specified
+pub const KEY_ENV: &str = "EDGEDB_BACKUP_KEY";
+const MAGIC: &[u8; 8] = b"EDBBAK\x00\x01";
+const CHUNK_SIZE: usize = 65536;
+const TAG_SIZE: usize = 16;
+const NONCE_PREFIX_SIZE: usize = 7;
+const SALT_SIZE: usize = 16;
+/// Argon2id parameters: memory in KiB and number of passes
+const KDF_MEMORY: u32 = 19456;
+const KDF_PASSES: u32 = 2;
+/// Set in the length of the last chunk
+const LAST_CHUNK: u32 = 0x8000_0000;
+/// Files kept unencrypted next to the archive
+const PLAIN_FILES: &[&str] = &["backup.json", "metadata.json", ARCHIVE];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum BackupFormat {
+    /// Data directory as is
+    Directory,
+    /// Data directory in the encrypted archive (`ARCHIVE`)
+    Encrypted,
+}
+
+impl Default for BackupFormat {
+    fn default() -> BackupFormat {
+        BackupFormat::Directory
+    }
+}
+
+/// Passphrase, the cipher key is derived from it for every archive
+pub struct BackupKey(Vec<u8>);
+
+struct EncryptWriter<W: Write> {
+    inner: W,
+    cipher: LessSafeKey,
+    prefix: [u8; NONCE_PREFIX_SIZE],
+    counter: u32,
+    buf: Vec<u8>,
+}
+
+struct DecryptReader<R: Read> {
+    inner: R,
+    cipher: LessSafeKey,
+    prefix: [u8; NONCE_PREFIX_SIZE],
+    counter: u32,
+    buf: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+/// Reads the key from `path`, or from the `EDGEDB_BACKUP_KEY` variable.
+/// Key may be of any length, trailing whitespace is ignored
+pub fn read_key(path: Option<&Path>) -> anyhow::Result<BackupKey> {
+    let data = match path {
+        Some(path) => fs::read(path)
+            .with_context(|| format!("cannot read key file {}",
+                                     path.display()))?,
+        None => match env::var_os(KEY_ENV) {
+            Some(value) => value.into_string()
+                .map_err(|_| anyhow::anyhow!("{} must be valid UTF-8",
+                                             KEY_ENV))?
+                .into_bytes(),
+            None => anyhow::bail!("Encrypted backups need a key: \
+                use `--backup-key-file` or set {}", KEY_ENV),
+        },
+    };
+    // so the key file can be written by `echo`
+    let end = data.iter().rposition(|c| !c.is_ascii_whitespace())
+        .map(|pos| pos + 1).unwrap_or(0);
+    if end == 0 {
+        anyhow::bail!("backup key is empty");
+    }
+    Ok(BackupKey(data[..end].to_vec()))
+}
+
+fn cipher(key: &BackupKey, salt: &[u8; SALT_SIZE])
+    -> io::Result<LessSafeKey>
+{
+    let config = argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        mem_cost: KDF_MEMORY,
+        time_cost: KDF_PASSES,
+        hash_length: 32,
+        .. argon2::Config::default()
+    };
+    let derived = argon2::hash_raw(&key.0, salt, &config)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other,
+                                    format!("cannot derive key: {}", e)))?;
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &derived)
+        .map_err(|_| io::Error::new(io::ErrorKind::Other,
+                                    "invalid backup key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+fn nonce(prefix: &[u8; NONCE_PREFIX_SIZE], counter: u32, last: bool)
+    -> Nonce
+{
+    let mut nonce = [0u8; 12];
+    nonce[..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_SIZE..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    Nonce::assume_unique_for_key(nonce)
+}
+
+impl<W: Write> EncryptWriter<W> {
+    fn new(mut inner: W, key: &BackupKey) -> io::Result<EncryptWriter<W>> {
+        let salt = rand::random::<[u8; SALT_SIZE]>();
+        let prefix = rand::random::<[u8; NONCE_PREFIX_SIZE]>();
+        let cipher = cipher(key, &salt)?;
+        inner.write_all(MAGIC)?;
+        inner.write_all(&salt)?;
+        inner.write_all(&prefix)?;
+        Ok(EncryptWriter {
+            inner,
+            cipher,
+            prefix,
+            counter: 0,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        })
+    }
+    fn seal(&mut self, end: usize, last: bool) -> io::Result<()> {
+        let nonce = nonce(&self.prefix, self.counter, last);
+        let mut chunk = self.buf[..end].to_vec();
+        self.cipher.seal_in_place_append_tag(nonce, Aad::empty(), &mut chunk)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other,
+                                        "cannot encrypt backup"))?;
+        let mut len = chunk.len() as u32;
+        if last {
+            len |= LAST_CHUNK;
+        }
+        self.inner.write_all(&len.to_be_bytes())?;
+        self.inner.write_all(&chunk)?;
+        self.buf.drain(..end);
+        self.counter = self.counter.checked_add(1)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other,
+                                          "backup is too large"))?;
+        Ok(())
+    }
+    /// Writes the last chunk, must be called for the archive to be valid
+    fn finish(mut self) -> io::Result<W> {
+        self.seal(self.buf.len(), true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        // the last chunk is written by `finish`, even if it's empty
+        while self.buf.len() > CHUNK_SIZE {
+            self.seal(CHUNK_SIZE, false)?;
+        }
+        Ok(data.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl<R: Read> DecryptReader<R> {
+    fn new(mut inner: R, key: &BackupKey) -> io::Result<DecryptReader<R>> {
+        let mut magic = [0u8; 8];
+        inner.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not an encrypted backup"));
+        }
+        let mut salt = [0u8; SALT_SIZE];
+        inner.read_exact(&mut salt)?;
+        let mut prefix = [0u8; NONCE_PREFIX_SIZE];
+        inner.read_exact(&mut prefix)?;
+        Ok(DecryptReader {
+            inner,
+            cipher: cipher(key, &salt)?,
+            prefix,
+            counter: 0,
+            buf: Vec::new(),
+            pos: 0,
+            done: false,
+        })
+    }
+    fn open_next(&mut self) -> io::Result<()> {
+        let mut len = [0u8; 4];
+        self.inner.read_exact(&mut len)
+            .map_err(|_| invalid("backup is truncated"))?;
+        let len = u32::from_be_bytes(len);
+        let last = len & LAST_CHUNK != 0;
+        let len = (len & !LAST_CHUNK) as usize;
+        if len < TAG_SIZE || len > CHUNK_SIZE + TAG_SIZE {
+            return Err(invalid("backup is corrupted"));
+        }
+        let mut chunk = vec![0u8; len];
+        self.inner.read_exact(&mut chunk)
+            .map_err(|_| invalid("backup is truncated"))?;
+        let nonce = nonce(&self.prefix, self.counter, last);
+        let len = self.cipher.open_in_place(nonce, Aad::empty(), &mut chunk)
+            .map_err(|_| invalid("cannot decrypt backup: \
+                the key is wrong or the backup is modified"))?
+            .len();
+        chunk.truncate(len);
+        self.buf = chunk;
+        self.pos = 0;
+        self.counter += 1;
+        self.done = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.open_next()?;
+        }
+        let len = out.len().min(self.buf.len() - self.pos);
+        out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+fn write_archive(dir: &Path, path: &Path, key: &BackupKey)
+    -> anyhow::Result<()>
+{
+    let file = fs::File::create(path)?;
+    let writer = EncryptWriter::new(BufWriter::new(file), key)?;
+    let skip = path.file_name().expect("path has a file name");
+    let mut tar = tar::Builder::new(
+        GzEncoder::new(writer, Compression::default()));
+    tar.follow_symlinks(false);
+    for item in fs::read_dir(dir)? {
+        let item = item?;
+        let name = item.file_name();
+        if name == "backup.json" || name == skip {
+            continue;
+        }
+        if item.file_type()?.is_dir() {
+            tar.append_dir_all(&name, item.path())?;
+        } else {
+            tar.append_path_with_name(item.path(), &name)?;
+        }
+    }
+    let writer = tar.into_inner()?.finish()?;
+    let file = writer.finish()?.into_inner()?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Replaces contents of the backup directory made by upgrade with the
+/// encrypted archive
+#[context("cannot encrypt backup {}", dir.display())]
+pub fn encrypt_backup(dir: &Path, key: &BackupKey) -> anyhow::Result<()> {
+    let meta_path = dir.join("backup.json");
+    let mut meta: BackupMeta = serde_json::from_slice(&fs::read(&meta_path)?)
+        .with_context(|| format!("cannot decode {}", meta_path.display()))?;
+    if meta.format == BackupFormat::Encrypted {
+        return Ok(());
+    }
+    let archive_path = dir.join(ARCHIVE);
+    let tmp_path = dir.join(tmp_file_name(&archive_path));
+    if let Err(e) = write_archive(dir, &tmp_path, key) {
+        fs::remove_file(&tmp_path).ok();
+        return Err(e);
+    }
+    fs::rename(&tmp_path, &archive_path)?;
+
+    meta.format = BackupFormat::Encrypted;
+    write_backup_meta(&meta_path, &meta)?;
+    check_removable_data(dir)?;
+    for item in fs::read_dir(dir)? {
+        let item = item?;
+        if PLAIN_FILES.iter().any(|name| item.file_name() == *name) {
+            continue;
+        }
+        if item.file_type()?.is_dir() {
+            fs::remove_dir_all(item.path())?;
+        } else {
+            fs::remove_file(item.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn unpack_archive(dir: &Path, dest: &Path, key: &BackupKey)
+    -> anyhow::Result<()>
+{
+    let file = fs::File::open(dir.join(ARCHIVE))?;
+    let reader = DecryptReader::new(BufReader::new(file), key)?;
+    let mut archive = tar::Archive::new(GzDecoder::new(reader));
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive.unpack(dest)?;
+    Ok(())
+}
+
+/// Unpacks the encrypted backup into a new directory `dest`. Partially
+/// unpacked directory is removed on failure
+#[context("cannot decrypt backup {}", dir.display())]
+pub fn decrypt_backup(dir: &Path, dest: &Path, key: &BackupKey)
+    -> anyhow::Result<()>
+{
+    // removed if unpacking fails
+    check_removable_data(dest)?;
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)] {
+        use std::os::unix::fs::DirBuilderExt;
+        // data directory itself is not in the archive, and the server
+        // refuses to run with one that is readable by others
+        builder.mode(0o700);
+    }
+    builder.create(dest)?;
+    unpack_archive(dir, dest, key).map_err(|e| {
+        fs::remove_dir_all(dest).ok();
+        e
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::time::SystemTime;
+
+    use crate::server::upgrade::{BackupMeta, write_backup_meta};
+    use super::{BackupKey, BackupFormat, EncryptWriter, DecryptReader};
+    use super::{decrypt_backup, encrypt_backup, CHUNK_SIZE, ARCHIVE};
+
+    fn key(secret: &str) -> BackupKey {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("key");
+        fs::write(&path, secret).unwrap();
+        super::read_key(Some(&path)).unwrap()
+    }
+
+    fn encrypt(data: &[u8], key: &BackupKey) -> Vec<u8> {
+        let mut writer = EncryptWriter::new(Vec::new(), key).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt(data: &[u8], key: &BackupKey) -> std::io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        DecryptReader::new(data, key)?.read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn test_chunks() {
+        let key = key("secret\n");
+        for &len in &[0, 10, CHUNK_SIZE, CHUNK_SIZE * 2 + 10] {
+            let data = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+            let encrypted = encrypt(&data, &key);
+            assert_eq!(decrypt(&encrypted, &key).unwrap(), data);
+        }
+        let data = vec![7u8; CHUNK_SIZE * 2];
+        let encrypted = encrypt(&data, &key);
+        assert!(decrypt(&encrypted, &self::key("other")).is_err());
+        let mut modified = encrypted.clone();
+        modified[100] ^= 1;
+        assert!(decrypt(&modified, &key).is_err());
+        // the last chunk is missing
+        let truncated = &encrypted[..encrypted.len() - 100];
+        assert!(decrypt(truncated, &key).is_err());
+    }
+
+    #[test]
+    fn test_backup_roundtrip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let backup = tmp.path().join("inst.backup");
+        fs::create_dir_all(backup.join("base")).unwrap();
+        fs::write(backup.join("base").join("1"), "data").unwrap();
+        fs::write(backup.join("metadata.json"), "{}").unwrap();
+        write_backup_meta(&backup.join("backup.json"), &BackupMeta {
+            timestamp: SystemTime::now(),
+            snapshot: None,
+            format: BackupFormat::Directory,
+        }).unwrap();
+
+        let key = key("secret");
+        encrypt_backup(&backup, &key).unwrap();
+        assert!(!backup.join("base").exists());
+        assert!(backup.join(ARCHIVE).exists());
+        assert!(backup.join("metadata.json").exists());
+
+        let restored = tmp.path().join("inst");
+        assert!(decrypt_backup(&backup, &restored, &self::key("wrong"))
+                .is_err());
+        assert!(!restored.exists());
+        decrypt_backup(&backup, &restored, &key).unwrap();
+        assert_eq!(fs::read(restored.join("base").join("1")).unwrap(),
+                   b"data");
+        assert!(!restored.join("backup.json").exists());
+    }
+}
@@ -13,7 +13,7 @@ use prettytable::{Table, Row, Cell};
 use serde::{Serialize, Deserialize};
 use fn_error_context::context;
 
-use crate::process::ProcessGuard;
+use crate::process::{self, ProcessGuard};
 use crate::platform::{config_dir, home_dir};
 use crate::server::control;
 use crate::server::reset_password::{generate_password, write_credentials};
@@ -21,15 +21,15 @@ use crate::server::detect::{self, VersionQuery, InstalledPackage};
 use crate::server::methods::{InstallMethod, Methods};
 use crate::server::options::{Init, Start, StartConf};
 use crate::server::os_trait::Method;
+use crate::server::ports::{self, PortRange};
+use crate::server::upgrade::{check_removable_data, write_atomic};
+use crate::server::upgrade::write_json_atomic;
 use crate::server::version::Version;
 use crate::table;
 
 use edgedb_client::credentials::Credentials;
 
 
-const MIN_PORT: u16 = 10700;
-
-
 pub struct Settings {
     pub name: String,
     pub system: bool,
@@ -54,6 +54,15 @@ pub struct Metadata {
     pub port: u16,
     pub nightly: bool,
     pub start_conf: StartConf,
+    #[serde(default, skip_serializing_if="BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// Environment variables set for the server process
+    #[serde(default, skip_serializing_if="BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// Server settings set by `config-set`, reapplied when the instance
+    /// is recreated by upgrade
+    #[serde(default, skip_serializing_if="BTreeMap::is_empty")]
+    pub config: BTreeMap<String, String>,
 }
 
 pub fn data_path(system: bool) -> anyhow::Result<PathBuf> {
@@ -89,43 +98,72 @@ fn _read_ports(path: &Path) -> anyhow::Result<BTreeMap<String, u16>> {
     Ok(serde_json::from_str(&data)?)
 }
 
-fn next_min_port(port_map: &BTreeMap<String, u16>) -> u16 {
-    if port_map.len() == 0 {
-        return MIN_PORT;
-    }
-    let port_set: BTreeSet<u16> = port_map.values().cloned().collect();
-    let mut prev = MIN_PORT - 1;
-    for port in port_set {
-        if port > prev+1 {
-            return prev + 1;
-        }
-        prev = port;
-    }
-    return prev+1;
-}
-
 fn _write_ports(port_map: &BTreeMap<String, u16>, port_file: &Path)
     -> anyhow::Result<()>
 {
     let config_dir = config_dir()?;
     fs::create_dir_all(&config_dir)?;
-    let tmp_file = config_dir.join(".instance_ports.json.tmp");
-    fs::remove_file(&tmp_file).ok();
-    serde_json::to_writer_pretty(fs::File::create(&tmp_file)?, &port_map)?;
-    fs::rename(&tmp_file, &port_file)?;
+    write_atomic(port_file, &serde_json::to_vec_pretty(&port_map)?)?;
+    Ok(())
+}
+
+/// Assigns specified port to the instance in the port mapping
+pub fn assign_port(name: &str, port: u16) -> anyhow::Result<()> {
+    let port_file = port_file()?;
+    let mut port_map = _read_ports(&port_file)?;
+    if let Some((other, _)) = port_map.iter()
+        .find(|(other, &other_port)| other_port == port && *other != name)
+    {
+        anyhow::bail!("Port {} is already used by instance {:?}",
+            port, other);
+    }
+    if port_map.get(name) == Some(&port) {
+        return Ok(());
+    }
+    port_map.insert(name.to_string(), port);
+    _write_ports(&port_map, &port_file).with_context(|| {
+        format!("failed writing port mapping {}", port_file.display())
+    })?;
+    Ok(())
+}
+
+/// Moves the port mapping of instance `old` to instance `new`
+pub fn rename_port(old: &str, new: &str, port: u16) -> anyhow::Result<()> {
+    let port_file = port_file()?;
+    let mut port_map = _read_ports(&port_file)?;
+    port_map.remove(old);
+    port_map.insert(new.to_string(), port);
+    _write_ports(&port_map, &port_file).with_context(|| {
+        format!("failed writing port mapping {}", port_file.display())
+    })?;
     Ok(())
 }
 
-fn allocate_port(name: &str) -> anyhow::Result<u16> {
+/// Picks the port for the instance. The port reserved for the instance is
+/// kept, the `default` instance gets 5656, unless `range` is given and
+/// doesn't contain these ports
+fn allocate_port(name: &str, range: Option<&PortRange>)
+    -> anyhow::Result<u16>
+{
     let port_file = port_file()?;
     let mut port_map = _read_ports(&port_file)?;
-    if let Some(port) = port_map.get(name) {
-        return Ok(*port);
+    if let Some(&port) = port_map.get(name) {
+        match range {
+            Some(range) if !range.contains(port) => {
+                anyhow::bail!("Port {} is reserved for the instance {:?}, \
+                    but it isn't in range {}-{}. Use `--port={}` to keep \
+                    it, or remove the instance from {} to pick a new one",
+                    port, name, range.start, range.end, port,
+                    port_file.display());
+            }
+            _ => return Ok(port),
+        }
     }
-    if name == "default" {
+    if name == "default" && range.map(|r| r.contains(5656)).unwrap_or(true)
+    {
         return Ok(5656);
     }
-    let port = next_min_port(&port_map);
+    let port = ports::allocate(&range.copied().unwrap_or_default())?;
     port_map.insert(name.to_string(), port);
     _write_ports(&port_map, &port_file).with_context(|| {
         format!("failed writing port mapping {}", port_file.display())
@@ -133,6 +171,21 @@ fn allocate_port(name: &str) -> anyhow::Result<u16> {
     Ok(port)
 }
 
+/// Removes contents of the directory, but not the directory itself, as it
+/// may be a mount point or a btrfs subvolume made for the instance
+fn clear_dir(dir: &Path) -> anyhow::Result<()> {
+    check_removable_data(dir)?;
+    for item in fs::read_dir(dir)? {
+        let item = item?;
+        if item.file_type()?.is_dir() {
+            fs::remove_dir_all(item.path())?;
+        } else {
+            fs::remove_file(item.path())?;
+        }
+    }
+    Ok(())
+}
+
 fn try_bootstrap(settings: &Settings, method: &dyn Method)
     -> anyhow::Result<()>
 {
@@ -149,11 +202,12 @@ fn try_bootstrap(settings: &Settings, method: &dyn Method)
         cmd.arg("--default-database-user=edgedb");
     }
 
-    log::debug!("Running bootstrap {:?}", cmd);
+    process::trace(&cmd);
     match cmd.status() {
         Ok(s) if s.success() => {}
-        Ok(s) => anyhow::bail!("Command {:?} {}", cmd, s),
-        Err(e) => Err(e).context(format!("Failed running {:?}", cmd))?,
+        Ok(s) => anyhow::bail!("Command {} {}", process::describe(&cmd), s),
+        Err(e) => Err(e).context(format!("Failed running {}",
+                                         process::describe(&cmd)))?,
     }
 
     if let Some(upgrade_marker) = &settings.upgrade_marker {
@@ -169,6 +223,9 @@ fn try_bootstrap(settings: &Settings, method: &dyn Method)
         port: settings.port,
         nightly: settings.nightly,
         start_conf: settings.start_conf,
+        labels: BTreeMap::new(),
+        env: BTreeMap::new(),
+        config: BTreeMap::new(),
     })?;
     Ok(())
 }
@@ -253,7 +310,12 @@ pub fn init(options: &Init) -> anyhow::Result<()> {
                 edgedb server install");
         }
     };
-    let port = allocate_port(&options.name)?;
+    let port = if let Some(port) = options.port {
+        assign_port(&options.name, port)?;
+        port
+    } else {
+        allocate_port(&options.name, options.port_range.as_ref())?
+    };
     let settings = Settings {
         name: options.name.clone(),
         system: options.system,
@@ -283,7 +345,7 @@ pub fn init(options: &Init) -> anyhow::Result<()> {
         }
         if settings.directory.exists() {
             if options.overwrite {
-                fs::remove_dir_all(&settings.directory)
+                clear_dir(&settings.directory)
                     .with_context(|| format!("cannot remove previous \
                         instance directory {}",
                         settings.directory.display()))?;
@@ -299,6 +361,7 @@ pub fn init(options: &Init) -> anyhow::Result<()> {
             Ok(()) => {}
             Err(e) => {
                 log::error!("Bootstrap error, cleaning up...");
+                check_removable_data(&settings.directory)?;
                 fs::remove_dir_all(&settings.directory)
                     .with_context(|| format!("failed to clean up {}",
                                              settings.directory.display()))?;
@@ -320,6 +383,8 @@ pub fn init(options: &Init) -> anyhow::Result<()> {
                 inst.start(&Start {
                         name: settings.name.clone(),
                         foreground: false,
+                        wait: false,
+                        wait_timeout: None,
                     })?;
                 init_credentials(&settings, &*inst)?;
                 println!("Bootstrap complete. Server is up and runnning now.");
@@ -327,11 +392,11 @@ pub fn init(options: &Init) -> anyhow::Result<()> {
             (StartConf::Manual, _) | (_, true) => {
                 let inst = control::get_instance(&settings.name)?;
                 let mut cmd = inst.run_command()?;
-                log::debug!("Running server: {:?}", cmd);
-                let child = ProcessGuard::run(&mut cmd)
+                let mut child = ProcessGuard::run(&mut cmd)
                     .with_context(||
-                        format!("error running server {:?}", cmd))?;
-                init_credentials(&settings, &*inst)?;
+                        format!("error running server {}",
+                                crate::process::describe(&cmd)))?;
+                child.with_output(init_credentials(&settings, &*inst))?;
                 drop(child);
                 println!("Bootstrap complete. To start a server:\n  \
                           edgedb server start {}",
@@ -389,14 +454,15 @@ fn init_credentials(settings: &Settings, inst: &dyn control::Instance)
 
 #[context("failed to write upgrade marker {}", path.display())]
 fn write_upgrade(path: &Path, data: &str) -> anyhow::Result<()> {
-    fs::write(path, data.as_bytes())?;
+    write_atomic(path, data.as_bytes())?;
     Ok(())
 }
 
 #[context("failed to write metadata file {}", path.display())]
-fn write_metadata(path: &Path, metadata: &Metadata) -> anyhow::Result<()> {
-    fs::write(path, serde_json::to_vec(&metadata)?)?;
-    Ok(())
+pub fn write_metadata(path: &Path, metadata: &Metadata)
+    -> anyhow::Result<()>
+{
+    write_json_atomic(path, metadata)
 }
 
 impl Settings {
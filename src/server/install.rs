@@ -1,60 +1,304 @@
 use std::process::exit;
+use std::sync::Mutex;
+
+use anyhow::Context;
+use async_std::task;
+use once_cell::sync::Lazy;
 
 use crate::server::options::Install;
-use crate::server::detect::{self, VersionQuery};
-use crate::server::methods::InstallMethod;
+use crate::server::detect::{self, InstalledPackage, VersionQuery};
+use crate::server::methods::{InstallMethod, METHOD_ENV, default_method};
+use crate::server::os_trait::Method;
+use crate::server::remote;
+use crate::server::version::Version;
 
 pub mod operation;
 pub mod exit_codes;
+pub mod history;
+pub mod prefix;
 pub mod settings;
 
 
 pub(in crate::server) use operation::{Operation, Command};
 pub(in crate::server) use settings::{Settings, SettingsBuilder};
+pub(in crate::server) use settings::extra_settings;
 
 pub const KEY_FILE_URL: &str = "https://packages.edgedb.com/keys/edgedb.asc";
+/// Fingerprint of the key at `KEY_FILE_URL`, checked by `refresh-keys`
+// TODO: fill in from the published key, couldn't be fetched offline
+pub const KEY_FINGERPRINT: &str = "<packages.edgedb.com key fingerprint>";
+
+static INSTALL_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
 
 
 
 pub fn install(options: &Install) -> Result<(), anyhow::Error> {
+    if !options.repository_url.is_empty() {
+        remote::set_mirrors(options.repository_url.clone());
+    }
+    if options.no_verify_tls {
+        remote::disable_tls_verification();
+    }
     let current_os = detect::current_os()?;
     let avail_methods = current_os.get_available_methods()?;
-    if options.method.is_none() && !options.interactive &&
-        !avail_methods.package.supported
+    // flags override the environment
+    let env_method = if options.method.is_none()
+        && options.prefer_method.is_empty()
+    {
+        default_method()?
+    } else {
+        None
+    };
+    let method = options.method.clone().or_else(|| env_method.clone());
+    if method.is_none() && options.prefer_method.is_empty() &&
+        !options.interactive && !avail_methods.package.supported
     {
         anyhow::bail!(avail_methods.format_error());
     }
     let methods = avail_methods.instantiate_all(&*current_os, false)?;
-    let effective_method = options.method.clone()
-        .unwrap_or(InstallMethod::Package);
-    let version = VersionQuery::new(options.nightly, options.version.as_ref());
+    if let Some(method) = &env_method {
+        if !methods.contains_key(method) {
+            anyhow::bail!("Installation method {} set by {} \
+                is not available on this system", method.title(), METHOD_ENV);
+        }
+    }
+    let effective_method = if let Some(method) = &method {
+        method.clone()
+    } else if !options.prefer_method.is_empty() {
+        let method = options.prefer_method.iter()
+            .find(|meth| methods.contains_key(*meth))
+            .or_else(|| methods.keys().next())
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!(avail_methods.format_error()))?;
+        eprintln!("Using installation method {}", method.title());
+        method
+    } else {
+        InstallMethod::Package
+    };
+    let mut installed = Vec::new();
     for (meth_kind, meth) in &methods {
         for old_ver in meth.installed_versions()? {
-            if version.installed_matches(&old_ver) {
-                if &effective_method == meth_kind {
-                    eprintln!("EdgeDB {} ({}-{}) is already installed. \
-                        Use `edgedb server upgrade` for upgrade.",
-                        old_ver.major_version,
-                        old_ver.version, old_ver.revision);
-                } else {
-                    eprintln!("EdgeDB {} is already installed via {}. \
-                        Please deinstall before installing via {}.",
-                        old_ver.major_version, meth_kind.option(),
-                        effective_method.option());
-                }
-                exit(exit_codes::ALREADY_INSTALLED);
-            }
+            installed.push((meth_kind.clone(), old_ver.clone()));
         }
     }
     let mut settings_builder = SettingsBuilder::new(
         &*current_os, options, methods)?;
+    if !options.prefer_method.is_empty() || env_method.is_some() {
+        settings_builder.method = effective_method.clone();
+    }
     settings_builder.auto_version()?;
     let (settings, method) = settings_builder.build()?;
+    // only the same major version can't be installed twice, other versions
+    // are installed side by side
+    if let Some((meth_kind, old_ver)) = find_conflict(&installed,
+        &settings.major_version, settings.nightly)
+    {
+        if &effective_method == meth_kind {
+            eprintln!("EdgeDB {} ({}-{}) is already installed. \
+                Use `edgedb server upgrade` for upgrade.",
+                old_ver.major_version,
+                old_ver.version, old_ver.revision);
+        } else {
+            eprintln!("EdgeDB {} is already installed via {}. \
+                Please deinstall before installing via {}.",
+                old_ver.major_version, meth_kind.option(),
+                effective_method.option());
+        }
+        exit(exit_codes::ALREADY_INSTALLED);
+    }
+    for (_, old_ver) in &installed {
+        if old_ver.major_version > settings.major_version {
+            eprintln!("Note: newer EdgeDB {} is installed too, \
+                {} is installed alongside it.",
+                old_ver.major_version, settings.major_version);
+        }
+    }
     settings.print();
-    method.install(&settings)?;
+    if options.dry_run {
+        return dry_run(&settings, &*method);
+    }
+    perform(&*method, &settings)?;
+    // package is installed anyway, so only warn if it can't be recorded
+    if let Err(e) = history::record(&*method, &settings) {
+        log::warn!("{:#}", e);
+    }
+    if options.verify && settings.prefix.is_some() {
+        log::warn!("Files unpacked into a prefix can't be verified");
+    } else if options.verify && method.verify_installation(&settings)? {
+        println!("Installed files are verified");
+    }
     println!("\nEdgedb server is installed now. Great!\n\
         Initialize and start a new database instance with:\n  \
           edgedb server init{arg}",
           arg=if options.nightly { " --nightly" } else { "" });
     Ok(())
 }
+
+/// Package of the same major version (and channel) as the one being
+/// installed
+fn find_conflict<'a>(installed: &'a [(InstallMethod, InstalledPackage)],
+    major: &Version<String>, nightly: bool)
+    -> Option<&'a (InstallMethod, InstalledPackage)>
+{
+    installed.iter().find(|(_, pkg)| {
+        &pkg.major_version == major && pkg.is_nightly() == nightly
+    })
+}
+
+/// Runs `method.install`, one at a time for methods that can't install
+/// concurrently (`Method::install_is_exclusive`)
+pub fn perform(method: &dyn Method, settings: &Settings)
+    -> Result<(), anyhow::Error>
+{
+    if method.install_is_exclusive() {
+        let _guard = INSTALL_LOCK.lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        method.install(settings)
+    } else {
+        method.install(settings)
+    }
+}
+
+/// Resolves the exact package and checks that the repository is reachable,
+/// without downloading or installing anything
+fn dry_run(settings: &Settings, method: &dyn Method)
+    -> Result<(), anyhow::Error>
+{
+    let query = VersionQuery::new(settings.nightly,
+                                  Some(&settings.major_version));
+    let pkg = method.get_version(&query)
+        .with_context(|| format!("cannot resolve version {}", query))?;
+    if settings.method == InstallMethod::Package {
+        task::block_on(remote::get_string(KEY_FILE_URL))
+            .context("repository is not reachable")?;
+    }
+    println!("\nDry run, nothing is downloaded or installed.");
+    println!("Package: {}-{}", pkg.package_name, pkg.major_version);
+    println!("Version: {}", pkg.version);
+    println!("Revision: {}", pkg.revision);
+    if settings.method == InstallMethod::Package {
+        println!("Repository: {}", remote::resolve_url(remote::BASE_URL));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::server::detect::VersionResult;
+    use crate::server::init;
+    use crate::server::package::PackageInfo;
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Exclusive {
+        running: AtomicUsize,
+        overlapped: AtomicUsize,
+    }
+
+    impl Method for Exclusive {
+        fn name(&self) -> InstallMethod {
+            InstallMethod::Package
+        }
+        fn install(&self, _settings: &Settings) -> anyhow::Result<()> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            if running > 1 {
+                self.overlapped.fetch_add(1, Ordering::SeqCst);
+            }
+            thread::sleep(Duration::from_millis(50));
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        }
+        fn install_is_exclusive(&self) -> bool {
+            true
+        }
+        fn all_versions(&self, _nightly: bool)
+            -> anyhow::Result<&[PackageInfo]>
+        {
+            Ok(&[])
+        }
+        fn get_version(&self, _query: &VersionQuery)
+            -> anyhow::Result<VersionResult>
+        {
+            anyhow::bail!("not used by the test");
+        }
+        fn installed_versions(&self) -> anyhow::Result<&[InstalledPackage]> {
+            Ok(&[])
+        }
+        fn detect_all(&self) -> serde_json::Value {
+            serde_json::Value::Null
+        }
+        fn get_server_path(&self, _major_version: &Version<String>)
+            -> anyhow::Result<PathBuf>
+        {
+            anyhow::bail!("not used by the test");
+        }
+        fn create_user_service(&self, _settings: &init::Settings)
+            -> anyhow::Result<()>
+        {
+            anyhow::bail!("not used by the test");
+        }
+    }
+
+    fn settings(major: &str) -> Settings {
+        Settings {
+            method: InstallMethod::Package,
+            package_name: "edgedb-server".into(),
+            major_version: Version(major.into()),
+            version: Version(format!("{}.0", major)),
+            revision: "1".into(),
+            nightly: false,
+            extra: Default::default(),
+            prefix: None,
+        }
+    }
+
+    fn installed(major: &str, version: &str)
+        -> (InstallMethod, InstalledPackage)
+    {
+        (InstallMethod::Package, InstalledPackage {
+            package_name: "edgedb-server".into(),
+            major_version: Version(major.into()),
+            version: Version(version.into()),
+            revision: "2020100500".into(),
+        })
+    }
+
+    #[test]
+    fn test_older_version_alongside() {
+        let pkgs = vec![installed("1-alpha6", "1.0a6")];
+        assert!(find_conflict(&pkgs, &Version("1-alpha5".into()), false)
+                .is_none());
+        assert!(find_conflict(&pkgs, &Version("1-alpha6".into()), false)
+                .is_some());
+        // nightly of the same major is a separate package
+        assert!(find_conflict(&pkgs, &Version("1-alpha6".into()), true)
+                .is_none());
+        let pkgs = vec![
+            installed("1-alpha6", "1.0a6"),
+            installed("1-alpha7", "1.0a7.dev5123"),
+        ];
+        assert!(find_conflict(&pkgs, &Version("1-alpha7".into()), false)
+                .is_none());
+        assert!(find_conflict(&pkgs, &Version("1-alpha7".into()), true)
+                .is_some());
+    }
+
+    #[test]
+    fn test_exclusive_installs_serialized() {
+        let method = Arc::new(Exclusive::default());
+        let threads = ["1-alpha6", "1-alpha7"].iter().map(|major| {
+            let method = method.clone();
+            thread::spawn(move || perform(&*method, &settings(major)))
+        }).collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap().unwrap();
+        }
+        assert_eq!(method.overlapped.load(Ordering::SeqCst), 0);
+    }
+}
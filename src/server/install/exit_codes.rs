@@ -1,2 +1,7 @@
 pub const NO_SUDO: i32 = 50;
 pub const ALREADY_INSTALLED: i32 = 51;
+pub const DEADLINE_EXCEEDED: i32 = 52;
+pub const UPDATES_AVAILABLE: i32 = 53;
+pub const PARTIALLY_FAILED: i32 = 54;
+pub const PROBLEMS_FOUND: i32 = 55;
+pub const SCHEMA_DIFFERS: i32 = 56;
@@ -0,0 +1,176 @@
+//! Record of packages installed by `edgedb server install`
+//!
+//! Every successful install is appended to
+//! `~/.edgedb/config/install-history.json`, so it's possible to check
+//! later what exactly was installed and where it was downloaded from.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::Context;
+use fn_error_context::context;
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+use crate::platform::config_dir;
+use crate::server::detect::VersionResult;
+use crate::server::install::Settings;
+use crate::server::methods::InstallMethod;
+use crate::server::options::{InstallHistory, OutputFormat};
+use crate::server::os_trait::Method;
+use crate::server::print_serialized;
+use crate::server::remote;
+use crate::server::upgrade::write_atomic;
+
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InstallRecord {
+    #[serde(with="humantime_serde")]
+    pub timestamp: SystemTime,
+    pub method: InstallMethod,
+    #[serde(flatten)]
+    pub package: VersionResult,
+    /// Repository (or registry) the package was installed from
+    pub source: String,
+    /// SHA-256 of the installed server binary
+    pub sha256: Option<String>,
+}
+
+fn history_path() -> anyhow::Result<PathBuf> {
+    Ok(config_dir()?.join("install-history.json"))
+}
+
+fn read_from(path: &Path) -> anyhow::Result<Vec<InstallRecord>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let data = fs::read(path)?;
+    Ok(serde_json::from_slice(&data)
+        .with_context(|| format!("cannot decode {}", path.display()))?)
+}
+
+fn append_to(path: &Path, record: InstallRecord) -> anyhow::Result<()> {
+    let mut records = read_from(path)?;
+    records.push(record);
+    fs::create_dir_all(path.parent().unwrap())?;
+    write_atomic(path, &serde_json::to_vec_pretty(&records)?)?;
+    Ok(())
+}
+
+pub(in crate::server) fn sha256_file(path: &Path) -> anyhow::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn source(settings: &Settings) -> String {
+    match settings.method {
+        InstallMethod::Package => remote::resolve_url(remote::BASE_URL),
+        InstallMethod::Docker => "docker.io/edgedb/edgedb".into(),
+    }
+}
+
+#[context("cannot read install history")]
+pub fn read_history() -> anyhow::Result<Vec<InstallRecord>> {
+    read_from(&history_path()?)
+}
+
+/// Appends the package just installed with `settings` to the history
+///
+/// The version is the one resolved before the installation: querying the
+/// repository again could return a newer package published meanwhile.
+#[context("cannot record installation of {}-{}",
+          settings.package_name, settings.major_version)]
+pub fn record(method: &dyn Method, settings: &Settings)
+    -> anyhow::Result<()>
+{
+    let package = VersionResult {
+        package_name: settings.package_name.clone(),
+        major_version: settings.major_version.clone(),
+        version: settings.version.clone(),
+        revision: settings.revision.clone(),
+        sha256: None,
+    };
+    let sha256 = match method.get_server_path(&settings.major_version) {
+        Ok(path) if path.exists() => Some(sha256_file(&path)
+            .with_context(|| format!("cannot read {}", path.display()))?),
+        _ => None,
+    };
+    append_to(&history_path()?, InstallRecord {
+        timestamp: SystemTime::now(),
+        method: settings.method.clone(),
+        package,
+        source: source(settings),
+        sha256,
+    })
+}
+
+pub fn install_history(options: &InstallHistory) -> anyhow::Result<()> {
+    let records = read_history()?;
+    if options.format != OutputFormat::Human {
+        return print_serialized(options.format, &records);
+    }
+    if records.is_empty() {
+        println!("No installations recorded");
+        return Ok(());
+    }
+    for rec in &records {
+        println!("{} {}-{} {}-{} ({}) from {}",
+            humantime::format_rfc3339_seconds(rec.timestamp),
+            rec.package.package_name, rec.package.major_version,
+            rec.package.version, rec.package.revision,
+            rec.method.title(), rec.source);
+        if let Some(sha256) = &rec.sha256 {
+            println!("  sha256: {}", sha256);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::SystemTime;
+
+    use crate::server::detect::VersionResult;
+    use crate::server::methods::InstallMethod;
+    use crate::server::version::Version;
+    use super::{append_to, read_from, sha256_file, InstallRecord};
+
+    #[test]
+    fn test_append() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("config").join("install-history.json");
+        assert!(read_from(&path).unwrap().is_empty());
+        for version in &["1.0a5", "1.0a6"] {
+            append_to(&path, InstallRecord {
+                timestamp: SystemTime::now(),
+                method: InstallMethod::Package,
+                package: VersionResult {
+                    package_name: "edgedb-server".into(),
+                    major_version: Version("1-alpha6".into()),
+                    version: Version(version.to_string()),
+                    revision: "2020100500".into(),
+                    sha256: None,
+                },
+                source: "https://packages.edgedb.com".into(),
+                sha256: None,
+            }).unwrap();
+        }
+        let records = read_from(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].package.version.as_ref(), "1.0a6");
+    }
+
+    #[test]
+    fn test_sha256() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("edgedb-server");
+        std::fs::write(&path, "abc").unwrap();
+        assert_eq!(sha256_file(&path).unwrap(), concat!(
+            "ba7816bf8f01cfea414140de5dae2223",
+            "b00361a396177a9cb410ff61f20015ad"));
+    }
+}
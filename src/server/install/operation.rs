@@ -7,6 +7,8 @@ use std::path::{Path, PathBuf};
 
 use anyhow::Context as ContextExt;
 
+use crate::process::{TRACE, describe, is_secret};
+
 
 #[derive(Debug)]
 pub struct Command {
@@ -87,6 +89,23 @@ impl Command {
         self.environ.insert(key.into(), arg.into());
         self
     }
+    fn trace(&self) {
+        let mut buf = String::new();
+        for (key, value) in &self.environ {
+            let key = key.to_string_lossy();
+            if is_secret(&key) {
+                buf.push_str(&format!("{}=<redacted> ", key));
+            } else {
+                buf.push_str(&format!("{}={} ", key, value.to_string_lossy()));
+            }
+        }
+        buf.push_str(&self.cmd.display().to_string());
+        for arg in &self.arguments {
+            buf.push(' ');
+            buf.push_str(&arg.to_string_lossy());
+        }
+        log::debug!(target: TRACE, "Running {}", buf);
+    }
 }
 
 fn cmd_result(status: Result<ExitStatus, io::Error>, cmd: StdCommand)
@@ -94,8 +113,8 @@ fn cmd_result(status: Result<ExitStatus, io::Error>, cmd: StdCommand)
 {
     match status {
         Ok(s) if s.success() => Ok(()),
-        Ok(s) => Err(anyhow::anyhow!("Command {:?} {}", cmd, s)),
-        Err(e) => Err(e).context(format!("Command {:?} error", cmd)),
+        Ok(s) => Err(anyhow::anyhow!("Command {} {}", describe(&cmd), s)),
+        Err(e) => Err(e).context(format!("Command {} error", describe(&cmd))),
     }
 }
 
@@ -158,6 +177,7 @@ impl Operation {
 
         match self {
             FeedPrivilegedCmd {cmd, input} => {
+                cmd.trace();
                 let mut os_cmd = cmd.to_std(&ctx.sudo_cmd);
                 os_cmd.stdin(Stdio::piped());
                 let mut child = os_cmd.spawn()
@@ -168,6 +188,7 @@ impl Operation {
                 cmd_result(child.wait(), os_cmd)
             }
             PrivilegedCmd(cmd) => {
+                cmd.trace();
                 let mut os_cmd = cmd.to_std(&ctx.sudo_cmd);
                 log::info!("Executing {:?}", os_cmd);
                 cmd_result(os_cmd.status(), os_cmd)
@@ -0,0 +1,73 @@
+//! Installations into a user-writable prefix (`install --prefix`)
+//!
+//! Package contents are unpacked into the prefix without running package
+//! manager scripts, so no root privileges are needed. The list of packages
+//! unpacked this way is kept in `~/.edgedb/config/prefix-install.json`, so
+//! that `init`, `upgrade` and the service files use binaries from the
+//! prefix.
+//!
+//! Only Debian and Ubuntu packages support this for now.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use fn_error_context::context;
+use serde::{Serialize, Deserialize};
+
+use crate::platform::config_dir;
+use crate::server::detect::InstalledPackage;
+use crate::server::upgrade::write_atomic;
+use crate::server::version::Version;
+
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PrefixPackage {
+    pub prefix: PathBuf,
+    #[serde(flatten)]
+    pub package: InstalledPackage,
+}
+
+fn manifest_path() -> anyhow::Result<PathBuf> {
+    Ok(config_dir()?.join("prefix-install.json"))
+}
+
+#[context("cannot read list of packages installed into a prefix")]
+pub fn read_packages() -> anyhow::Result<Vec<PrefixPackage>> {
+    let path = manifest_path()?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let data = fs::read(&path)?;
+    Ok(serde_json::from_slice(&data)
+        .with_context(|| format!("cannot decode {}", path.display()))?)
+}
+
+#[context("cannot record package installed into {}",
+          package.prefix.display())]
+pub fn add_package(package: PrefixPackage) -> anyhow::Result<()> {
+    let mut packages = read_packages()?;
+    packages.retain(|p| {
+        p.package.major_version != package.package.major_version
+    });
+    packages.push(package);
+    let path = manifest_path()?;
+    fs::create_dir_all(path.parent().unwrap())?;
+    write_atomic(&path, &serde_json::to_vec_pretty(&packages)?)?;
+    Ok(())
+}
+
+/// Returns prefix where the major version is installed, if any
+pub fn find(major_version: &Version<String>) -> Option<PathBuf> {
+    let packages = read_packages()
+        .map_err(|e| log::warn!("{:#}", e))
+        .unwrap_or_else(|_| Vec::new());
+    packages.into_iter()
+        .find(|p| &p.package.major_version == major_version)
+        .map(|p| p.prefix)
+}
+
+/// Path of the file inside of the prefix (`path` is absolute system path)
+pub fn join(prefix: &Path, path: &Path) -> PathBuf {
+    prefix.join(path.strip_prefix("/").unwrap_or(path))
+}
@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use linked_hash_map::LinkedHashMap;
 use prettytable::{Table, Row, Cell};
 
@@ -16,7 +18,9 @@ pub struct SettingsBuilder<'a> {
     pub package_name: Option<String>,
     pub major_version: Option<Version<String>>,
     pub version: Option<Version<String>>,
+    pub revision: Option<String>,
     pub extra: LinkedHashMap<String, String>,
+    pub prefix: Option<PathBuf>,
     pub os: &'a dyn CurrentOs,
     pub methods: LinkedHashMap<InstallMethod, Box<dyn Method + 'a>>,
 }
@@ -27,8 +31,12 @@ pub struct Settings {
     pub package_name: String,
     pub major_version: Version<String>,
     pub version: Version<String>,
+    pub revision: String,
     pub nightly: bool,
     pub extra: LinkedHashMap<String, String>,
+    /// Unpack the package into this directory instead of installing it
+    /// system-wide
+    pub prefix: Option<PathBuf>,
 }
 
 impl<'os> SettingsBuilder<'os> {
@@ -47,7 +55,9 @@ impl<'os> SettingsBuilder<'os> {
             package_name: None,
             major_version: None,
             version: None,
-            extra: LinkedHashMap::new(),
+            revision: None,
+            extra: options.extra.iter().cloned().collect(),
+            prefix: options.prefix.clone(),
             methods,
         })
     }
@@ -55,19 +65,26 @@ impl<'os> SettingsBuilder<'os> {
         -> anyhow::Result<(Settings, Box<dyn Method + 'os>)>
     {
         if self.package_name.is_none() || self.major_version.is_none() ||
-            self.version.is_none()
+            self.version.is_none() || self.revision.is_none()
         {
             anyhow::bail!("No installable version found");
         }
         let method = self.methods.remove(&self.method)
             .expect("method exists");
+        check_extra(&*method, self.extra.keys())?;
+        if self.prefix.is_some() && !method.supports_prefix() {
+            anyhow::bail!("Installation method {} doesn't support \
+                `--prefix` on this system", method.name().title());
+        }
         let settings = Settings {
             method: self.method,
             package_name: self.package_name.unwrap(),
             major_version: self.major_version.unwrap(),
             version: self.version.unwrap(),
+            revision: self.revision.unwrap(),
             nightly: self.version_query.is_nightly(),
             extra: self.extra,
+            prefix: self.prefix,
         };
         Ok((settings, method))
     }
@@ -80,6 +97,7 @@ impl<'os> SettingsBuilder<'os> {
             .ok();
         if let Some(res) = res {
             self.version = Some(res.version);
+            self.revision = Some(res.revision);
             self.package_name = Some(res.package_name);
             self.major_version = Some(res.major_version);
         }
@@ -87,6 +105,35 @@ impl<'os> SettingsBuilder<'os> {
     }
 }
 
+fn check_extra<'x>(method: &dyn Method,
+    keys: impl IntoIterator<Item=&'x String>)
+    -> anyhow::Result<()>
+{
+    let allowed = method.extra_settings();
+    for key in keys {
+        if !allowed.iter().any(|name| *name == key.as_str()) {
+            if allowed.is_empty() {
+                anyhow::bail!("Installation method {} has no settings, \
+                    but `--set {}` is specified",
+                    method.name().title(), key);
+            } else {
+                anyhow::bail!("Unknown setting {:?} for installation \
+                    method {}, options: {}",
+                    key, method.name().title(), allowed.join(", "));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Validates `--set` parameters against the ones supported by the method
+pub fn extra_settings(method: &dyn Method, values: &[(String, String)])
+    -> anyhow::Result<LinkedHashMap<String, String>>
+{
+    check_extra(method, values.iter().map(|(k, _)| k))?;
+    Ok(values.iter().cloned().collect())
+}
+
 impl Settings {
     pub fn print(&self) {
         let mut table = Table::new();
@@ -109,6 +156,13 @@ impl Settings {
             Cell::new("Exact version"),
             Cell::new(self.version.num()),
         ]));
+        if let Some(prefix) = &self.prefix {
+            table.add_row(Row::new(vec![
+                Cell::new("Prefix"),
+                Cell::new(&prefix.display().to_string()),
+                Cell::new(&format!("--prefix={}", prefix.display())),
+            ]));
+        }
         for (k, v) in &self.extra {
             table.add_row(Row::new(vec![
                 Cell::new(k),
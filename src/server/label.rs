@@ -0,0 +1,28 @@
+use crate::server::control::read_metadata;
+use crate::server::init::{data_path, write_metadata};
+use crate::server::options::Label;
+
+
+pub fn label(options: &Label) -> anyhow::Result<()> {
+    let dir = data_path(false)?.join(&options.name);
+    if !dir.exists() {
+        anyhow::bail!("No instance {:?} found", options.name);
+    }
+    let mut metadata = read_metadata(&dir)?;
+    if options.labels.is_empty() && options.remove.is_empty() {
+        for (key, value) in &metadata.labels {
+            println!("{}={}", key, value);
+        }
+        return Ok(());
+    }
+    for key in &options.remove {
+        if metadata.labels.remove(key).is_none() {
+            log::warn!("Instance {:?} has no label {:?}", options.name, key);
+        }
+    }
+    for (key, value) in &options.labels {
+        metadata.labels.insert(key.clone(), value.clone());
+    }
+    write_metadata(&dir.join("metadata.json"), &metadata)?;
+    Ok(())
+}
@@ -1,4 +1,6 @@
+use std::collections::BTreeMap;
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::process::{Command, exit};
 
@@ -7,11 +9,11 @@ use dirs::home_dir;
 use serde::Serialize;
 
 use crate::platform::{Uid, get_current_uid};
-use crate::process::run;
+use crate::process::{self, run};
 use crate::server::detect::Lazy;
 use crate::server::docker::DockerCandidate;
 use crate::server::init;
-use crate::server::install::{operation, exit_codes, Operation};
+use crate::server::install::{operation, exit_codes, prefix, Operation};
 use crate::server::methods::{InstallationMethods, InstallMethod};
 use crate::server::options::StartConf;
 use crate::server::os_trait::{CurrentOs, Method};
@@ -136,6 +138,31 @@ pub fn detect_distro() -> Result<Box<dyn CurrentOs>, anyhow::Error> {
     }
 }
 
+/// Runs `dpkg --verify` or `rpm -V` command and fails if it reports any
+/// changed files (except configuration files)
+pub fn verify_package(cmd: &mut Command) -> anyhow::Result<()> {
+    let out = cmd.output()
+        .with_context(|| format!("error running {}",
+                                 process::describe(cmd)))?;
+    let text = String::from_utf8_lossy(&out.stdout);
+    let changed = text.lines()
+        .filter(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            !fields.is_empty() && !(fields.len() == 3 && fields[1] == "c")
+        })
+        .collect::<Vec<_>>();
+    if !changed.is_empty() {
+        anyhow::bail!("installed files don't match the package:\n  {}",
+            changed.join("\n  "));
+    }
+    if !out.status.success() {
+        anyhow::bail!("process {} failed: {}: {}",
+            process::describe(cmd), out.status,
+            String::from_utf8_lossy(&out.stderr).trim());
+    }
+    Ok(())
+}
+
 pub fn perform_install(operations: Vec<Operation>, linux: &Linux)
     -> anyhow::Result<()>
 {
@@ -169,8 +196,12 @@ pub fn perform_install(operations: Vec<Operation>, linux: &Linux)
 }
 
 pub fn get_server_path(major_version: &Version<String>) -> PathBuf {
-    Path::new("/usr/bin")
-    .join(format!("edgedb-server-{}", major_version))
+    let path = Path::new("/usr/bin")
+        .join(format!("edgedb-server-{}", major_version));
+    match prefix::find(major_version) {
+        Some(prefix) => prefix::join(&prefix, &path),
+        None => path,
+    }
 }
 
 pub fn systemd_unit(settings: &init::Settings, meth: &dyn Method)
@@ -222,6 +253,15 @@ fn unit_dir(system: bool) -> anyhow::Result<PathBuf> {
     }
 }
 
+/// `systemctl` talking to the system or to the user's service manager
+fn systemctl(system: bool) -> Command {
+    let mut cmd = Command::new("systemctl");
+    if !system {
+        cmd.arg("--user");
+    }
+    cmd
+}
+
 fn unit_name(name: &str) -> String {
     format!("edgedb-server@{}.service", name)
 }
@@ -232,6 +272,67 @@ pub fn systemd_service_path(name: &str, system: bool)
     Ok(unit_dir(system)?.join(&unit_name(name)))
 }
 
+/// Disables and removes the unit of the instance along with its drop-ins
+pub fn remove_systemd_service(name: &str, system: bool)
+    -> anyhow::Result<()>
+{
+    let unit_path = systemd_service_path(name, system)?;
+    if unit_path.exists() {
+        run(systemctl(system)
+            .arg("disable")
+            .arg(unit_name(name)))?;
+        fs::remove_file(&unit_path)
+            .with_context(|| format!("cannot remove {}",
+                                     unit_path.display()))?;
+    }
+    let dropin_dir = unit_dir(system)?
+        .join(format!("{}.d", unit_name(name)));
+    if dropin_dir.exists() {
+        fs::remove_dir_all(&dropin_dir)
+            .with_context(|| format!("cannot remove {}",
+                                     dropin_dir.display()))?;
+    }
+    run(systemctl(system)
+        .arg("daemon-reload"))?;
+    Ok(())
+}
+
+fn systemd_quote(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('%', "%%")
+}
+
+/// Writes environment of the instance into a drop-in of the service unit,
+/// so it isn't lost when the unit file is regenerated
+pub fn write_systemd_env(name: &str, system: bool,
+    env: &BTreeMap<String, String>)
+    -> anyhow::Result<()>
+{
+    let dropin_dir = unit_dir(system)?
+        .join(format!("{}.d", unit_name(name)));
+    let path = dropin_dir.join("environment.conf");
+    if env.is_empty() {
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e)
+                .with_context(|| format!("cannot remove {}",
+                                         path.display())),
+        }
+    } else {
+        let mut data = String::from("[Service]\n");
+        for (key, value) in env {
+            data.push_str(&format!("Environment=\"{}={}\"\n",
+                systemd_quote(key), systemd_quote(value)));
+        }
+        fs::create_dir_all(&dropin_dir)?;
+        fs::write(&path, data)
+            .with_context(|| format!("cannot write {}", path.display()))?;
+    }
+    run(systemctl(system)
+        .arg("daemon-reload"))?;
+    Ok(())
+}
+
 pub fn create_systemd_service(settings: &init::Settings, meth: &dyn Method)
     -> anyhow::Result<()>
 {
@@ -240,12 +341,10 @@ pub fn create_systemd_service(settings: &init::Settings, meth: &dyn Method)
     let unit_name = unit_name(&settings.name);
     let unit_path = unit_dir.join(&unit_name);
     fs::write(&unit_path, systemd_unit(&settings, meth)?)?;
-    run(Command::new("systemctl")
-        .arg("--user")
+    run(systemctl(settings.system)
         .arg("daemon-reload"))?;
     if settings.start_conf == StartConf::Auto {
-        run(Command::new("systemctl")
-            .arg("--user")
+        run(systemctl(settings.system)
             .arg("enable")
             .arg(&unit_name))?;
     }
@@ -0,0 +1,73 @@
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+use crate::server::control::read_metadata;
+use crate::server::encrypted_backup::BackupFormat;
+use crate::server::init::data_path;
+use crate::server::options::{ListBackups, OutputFormat};
+use crate::server::print_serialized;
+use crate::server::restore_backup::find_backups;
+use crate::server::upgrade::{dir_size, format_bytes};
+use crate::server::version::Version;
+
+
+#[derive(Serialize, Debug)]
+struct BackupInfo {
+    path: String,
+    #[serde(with="humantime_serde")]
+    timestamp: SystemTime,
+    /// Version of the server the backup is made by
+    version: Option<Version<String>>,
+    size: Option<u64>,
+    /// Filesystem of the snapshot, if backup is a snapshot
+    snapshot: Option<String>,
+    /// Backup is an encrypted archive, `restore-backup` needs the key
+    encrypted: bool,
+    /// Whether `restore-backup` uses this backup without `--timestamp`
+    default: bool,
+}
+
+pub fn list_backups(options: &ListBackups) -> anyhow::Result<()> {
+    if !data_path(false)?.join(&options.name).exists() {
+        anyhow::bail!("No instance {:?} found", options.name);
+    }
+    let mut backups = find_backups(&options.name)?;
+    backups.sort_by_key(|b| b.meta.timestamp);
+    let latest = backups.len().checked_sub(1);
+    let infos = backups.iter().enumerate().map(|(idx, b)| BackupInfo {
+        path: b.path.display().to_string(),
+        timestamp: b.meta.timestamp,
+        version: read_metadata(&b.path)
+            .map_err(|e| log::warn!("{:#}", e))
+            .ok().map(|meta| meta.version),
+        size: dir_size(&b.path).ok(),
+        snapshot: b.meta.snapshot.as_ref().map(|s| s.filesystem.clone()),
+        encrypted: b.meta.format == BackupFormat::Encrypted,
+        default: Some(idx) == latest,
+    }).collect::<Vec<_>>();
+
+    if options.format != OutputFormat::Human {
+        return print_serialized(options.format, &infos);
+    }
+    if infos.is_empty() {
+        println!("No backups of instance {:?} found", options.name);
+        return Ok(());
+    }
+    for info in &infos {
+        println!("{}{} {} version {} {}{}{}",
+            if info.default { "* " } else { "  " },
+            humantime::format_rfc3339_seconds(info.timestamp),
+            info.path,
+            info.version.as_ref().map(|v| v.to_string())
+                .unwrap_or_else(|| "unknown".into()),
+            format_bytes(info.size),
+            info.snapshot.as_ref()
+                .map(|fs| format!(" ({} snapshot)", fs))
+                .unwrap_or_default(),
+            if info.encrypted { " (encrypted)" } else { "" });
+    }
+    println!("* backup used by `edgedb server restore-backup {}`",
+        options.name);
+    Ok(())
+}
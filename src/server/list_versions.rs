@@ -1,15 +1,17 @@
 use std::collections::{BTreeSet, BTreeMap};
 
 use prettytable::{Table, Cell, Row};
+use serde::Serialize;
 
 use crate::server::detect;
 use crate::server::methods::{InstallMethod, Methods};
-use crate::server::options::ListVersions;
+use crate::server::options::{ListVersions, OutputFormat};
+use crate::server::print_serialized;
 use crate::server::version::Version;
 use crate::table;
 
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct VersionInfo {
     available: BTreeSet<InstallMethod>,
     installed: BTreeMap<InstallMethod, Version<String>>,
@@ -31,14 +33,14 @@ pub fn list_versions(options: &ListVersions) -> Result<(), anyhow::Error> {
         let versions = versions.into_iter()
             .filter(|(_m, v)| !v.installed.is_empty())
             .collect();
-        print_versions(versions);
+        print_versions(versions, options.format)?;
     } else {
         remote(&methods, &mut versions)?;
         installed(&methods, &mut versions)
             .map_err(|e| {
                 log::warn!("Error fetching installed versions: {:#}", e);
             }).ok();
-        print_versions(versions);
+        print_versions(versions, options.format)?;
     }
     Ok(())
 }
@@ -105,7 +107,13 @@ fn remote(methods: &Methods, versions: &mut BTreeMap<Version<String>, VersionInf
     Ok(())
 }
 
-fn print_versions(versions: BTreeMap<Version<String>, VersionInfo>) {
+fn print_versions(versions: BTreeMap<Version<String>, VersionInfo>,
+    format: OutputFormat)
+    -> anyhow::Result<()>
+{
+    if format != OutputFormat::Human {
+        return print_serialized(format, &versions);
+    }
     let mut table = Table::new();
     table.set_format(*table::FORMAT);
     table.add_row(Row::new(vec![
@@ -156,4 +164,5 @@ fn print_versions(versions: BTreeMap<Version<String>, VersionInfo>) {
         ]));
     }
     table.printstd();
+    Ok(())
 }
@@ -8,7 +8,7 @@ use async_std::task;
 use serde::Serialize;
 
 use crate::platform::{Uid, get_current_uid, home_dir};
-use crate::process::run;
+use crate::process::{self, run};
 use crate::server::detect::{ARCH, Lazy, VersionQuery, VersionResult};
 use crate::server::detect::{InstalledPackage};
 use crate::server::docker::DockerCandidate;
@@ -135,12 +135,17 @@ impl<'os> Method for PackageMethod<'os, Macos> {
     fn install(&self, settings: &install::Settings)
         -> Result<(), anyhow::Error>
     {
-        let tmpdir = tempfile::tempdir()?;
         let ver = self.get_version(&VersionQuery::new(
             settings.nightly, Some(&settings.major_version)))?;
         let package_name = format!("edgedb-server-{}_{}_{}.pkg",
             settings.major_version, settings.version, ver.revision);
-        let pkg_path = tmpdir.path().join(&package_name);
+        // not a temporary directory, so an interrupted download is resumed
+        // by the next install
+        let download_dir = home_dir()?.join(".edgedb").join("downloads");
+        fs::create_dir_all(&download_dir)
+            .with_context(|| format!("cannot create {}",
+                                     download_dir.display()))?;
+        let pkg_path = download_dir.join(&package_name);
         let url = if settings.nightly {
             format!("https://packages.edgedb.com/archive/\
                 macos-{arch}.nightly/{name}",
@@ -150,13 +155,25 @@ impl<'os> Method for PackageMethod<'os, Macos> {
                 macos-{arch}/{name}",
                 arch=ARCH, name=package_name)
         };
-        task::block_on(remote::get_file(&pkg_path, &url))
+        let partial = remote::partial_size(&pkg_path)
+            .filter(|_| ver.sha256.is_some());
+        if let Some(size) = partial {
+            // without a checksum `get_file` starts from scratch
+            println!("Resuming download of {} ({} bytes downloaded before)",
+                package_name, size);
+        }
+        if ver.sha256.is_none() {
+            log::info!("Repository has no checksum of {}, \
+                the download is not verified", package_name);
+        }
+        task::block_on(remote::get_file(&pkg_path, &url,
+                                        ver.sha256.as_deref()))
             .context("failed to download package")?;
 
         let operations = vec![
             Operation::PrivilegedCmd(
                 Command::new("installer")
-                .arg("-package").arg(pkg_path)
+                .arg("-package").arg(&pkg_path)
                 .arg("-target").arg("/")
                 .env("_EDGEDB_INSTALL_SKIP_BOOTSTRAP", "1")
             )
@@ -184,10 +201,13 @@ impl<'os> Method for PackageMethod<'os, Macos> {
                 }
             }
         }
-        for op in &operations {
-            op.perform(&ctx)?;
-        }
-        Ok(())
+        let result = operations.iter().try_for_each(|op| op.perform(&ctx));
+        // only interrupted downloads are worth keeping
+        fs::remove_file(&pkg_path).ok();
+        result
+    }
+    fn install_is_exclusive(&self) -> bool {
+        true
     }
     fn all_versions(&self, nightly: bool) -> anyhow::Result<&[PackageInfo]> {
         Ok(self.os.get_repo(nightly)?
@@ -209,8 +229,8 @@ impl<'os> Method for PackageMethod<'os, Macos> {
             if out.status.code() == Some(1) {
                 return Ok(Vec::new());
             } else if !out.status.success() {
-                anyhow::bail!("cannot get installed packages: {:?} {}",
-                    cmd, out.status);
+                anyhow::bail!("cannot get installed packages: {} {}",
+                    process::describe(&cmd), out.status);
             }
             let mut result = Vec::new();
             let lines = out.stdout.split(|&b| b == b'\n')
@@ -226,8 +246,8 @@ impl<'os> Method for PackageMethod<'os, Macos> {
                 let out = cmd.output()
                     .context("cannot get package version")?;
                 if !out.status.success() {
-                    anyhow::bail!("cannot get package version: {:?} {}",
-                        cmd, out.status);
+                    anyhow::bail!("cannot get package version: {} {}",
+                        process::describe(&cmd), out.status);
                 }
                 let lines = out.stdout.split(|&b| b == b'\n')
                     .filter_map(|line| str::from_utf8(line).ok());
@@ -312,6 +332,19 @@ pub fn launchd_plist_path(name: &str, system: bool)
     Ok(plist_dir(system)?.join(plist_name(name)))
 }
 
+/// Removes the plist of the instance (it must be already unloaded)
+pub fn remove_launchd_service(name: &str, system: bool)
+    -> anyhow::Result<()>
+{
+    let plist_path = launchd_plist_path(name, system)?;
+    if plist_path.exists() {
+        fs::remove_file(&plist_path)
+            .with_context(|| format!("cannot remove {}",
+                                     plist_path.display()))?;
+    }
+    Ok(())
+}
+
 fn plist_data(settings: &init::Settings)
     -> anyhow::Result<String>
 {
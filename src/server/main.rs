@@ -1,12 +1,37 @@
-use crate::server::options::{ServerCommand, Command};
+use crate::server::options::{ServerCommand, Command, OutputFormat};
+use crate::server::batch_control;
+use crate::server::compare_schema;
+use crate::server::config;
+use crate::server::daemon;
 use crate::server::install;
 use crate::server::detect;
+use crate::server::doctor;
+use crate::server::drift;
+use crate::server::dump_instances;
+use crate::server::list_backups;
 use crate::server::list_versions;
+use crate::server::ping;
+use crate::server::ports;
+use crate::server::print_serialized;
+use crate::server::prune_versions;
+use crate::server::refresh_keys;
+use crate::server::reinit;
+use crate::server::rename;
 use crate::server::init;
+use crate::server::label;
+use crate::server::metadata;
 use crate::server::control;
 use crate::server::upgrade;
+use crate::server::repair_metadata;
 use crate::server::reset_password;
+use crate::server::restore_backup;
+use crate::server::schema_dump;
+use crate::server::set_env;
+use crate::server::set_version;
 use crate::server::status;
+use crate::server::validate_dump;
+use crate::server::wait;
+use crate::server::which;
 
 
 pub fn main(cmd: &ServerCommand) -> Result<(), anyhow::Error> {
@@ -16,18 +41,54 @@ pub fn main(cmd: &ServerCommand) -> Result<(), anyhow::Error> {
         Install(c) => install::install(c),
         Init(c) => init::init(c),
         ListVersions(c) => list_versions::list_versions(c),
-        Start(c) => control::get_instance(&c.name)?.start(c),
+        Start(c) => wait::start(c),
         Stop(c) => control::get_instance(&c.name)?.stop(c),
         Restart(c) => control::get_instance(&c.name)?.restart(c),
+        StopAll(c) => batch_control::stop_all(c),
+        StartAll(c) => batch_control::start_all(c),
         Status(c) => {
-            if c.all {
+            if c.format != OutputFormat::Human {
+                if c.all {
+                    print_serialized(c.format, &status::status_info_all()?)
+                } else {
+                    let inst = control::get_instance(&c.name)?;
+                    print_serialized(c.format, &inst.get_status()?.info())
+                }
+            } else if c.all {
                 status::print_status_all(c.extended)
             } else {
                 control::get_instance(&c.name)?.status(c)
             }
         }
+        Ping(c) => ping::ping(c),
         Upgrade(c) => upgrade::upgrade(c),
         ResetPassword(c) => reset_password::reset_password(c),
-        _Detect(c) => detect::main(c),
+        RepairMetadata(c) => repair_metadata::repair_metadata(c),
+        Label(c) => label::label(c),
+        Which(c) => which::which(c),
+        ExportMetadata(c) => metadata::export_metadata(c),
+        ImportMetadata(c) => metadata::import_metadata(c),
+        RefreshKeys(c) => refresh_keys::refresh_keys(c),
+        SetEnv(c) => set_env::set_env(c),
+        Drift(c) => drift::drift(c),
+        RestoreBackup(c) => restore_backup::restore_backup(c),
+        SetVersion(c) => set_version::set_version(c),
+        ValidateDump(c) => validate_dump::validate_dump(c),
+        Detect(c) => detect::main(c),
+        SchemaDump(c) => schema_dump::schema_dump(c),
+        DumpAllInstances(c) => dump_instances::dump_all_instances(c),
+        ConfigGet(c) => config::config_get(c),
+        ConfigSet(c) => config::config_set(c),
+        Rename(c) => rename::rename(c),
+        Doctor(c) => doctor::doctor(c),
+        Wait(c) => wait::wait(c),
+        InstallHistory(c) => install::history::install_history(c),
+        Reinit(c) => reinit::reinit(c),
+        ListBackups(c) => list_backups::list_backups(c),
+        Daemon(c) => daemon::daemon(c),
+        CheckUpdates(c) => drift::check_updates(c),
+        NextPort(c) => ports::next_port(c),
+        CompareSchema(c) => compare_schema::compare_schema(c),
+        PruneVersions(c) => prune_versions::prune_versions(c),
     }
 }
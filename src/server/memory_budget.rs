@@ -0,0 +1,80 @@
+//! Memory budget of concurrent operations (`--max-memory`)
+//!
+//! Cost of an operation on an instance is estimated as the size of its data
+//! directory. Operations that don't fit into the budget wait until running
+//! ones finish.
+
+use std::sync::{Condvar, Mutex};
+
+
+pub struct MemoryBudget {
+    max: u64,
+    used: Mutex<u64>,
+    released: Condvar,
+}
+
+/// Part of the budget used by a running operation, returned on drop
+pub struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    amount: u64,
+}
+
+impl MemoryBudget {
+    pub fn new(max: u64) -> MemoryBudget {
+        MemoryBudget {
+            max,
+            used: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+    /// Waits until `cost` fits into the budget. An operation that costs
+    /// more than the whole budget runs when no other one does
+    pub fn reserve(&self, cost: u64) -> Reservation {
+        let mut used = self.used.lock().expect("budget not poisoned");
+        while *used > 0 && used.saturating_add(cost) > self.max {
+            used = self.released.wait(used).expect("budget not poisoned");
+        }
+        *used += cost;
+        Reservation { budget: self, amount: cost }
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let mut used = self.budget.used.lock().expect("budget not poisoned");
+        *used -= self.amount;
+        self.budget.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, mpsc};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::MemoryBudget;
+
+    #[test]
+    fn test_reserve() {
+        let budget = Arc::new(MemoryBudget::new(10));
+        let first = budget.reserve(6);
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::spawn({
+            let budget = budget.clone();
+            move || {
+                let _second = budget.reserve(6);
+                tx.send(()).unwrap();
+            }
+        });
+        // queued until the first one is done
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+        drop(first);
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        thread.join().unwrap();
+        // larger than the budget, but nothing else is running
+        drop(budget.reserve(20));
+        let _small = budget.reserve(4);
+        drop(budget.reserve(6));
+    }
+}
@@ -0,0 +1,69 @@
+use std::fs;
+use std::io::{stdin, Read};
+
+use anyhow::Context;
+
+use crate::server::control::read_metadata;
+use crate::server::detect;
+use crate::server::init::{Metadata, data_path, write_metadata};
+use crate::server::options::{ExportMetadata, ImportMetadata};
+use crate::server::upgrade::InventoryItem;
+
+
+pub fn export_metadata(options: &ExportMetadata) -> anyhow::Result<()> {
+    let dir = data_path(false)?.join(&options.name);
+    if !dir.exists() {
+        anyhow::bail!("No instance {:?} found", options.name);
+    }
+    let metadata = read_metadata(&dir)?;
+    if options.inventory_item {
+        println!("{}", serde_json::to_string(&InventoryItem {
+            name: options.name.clone(),
+            method: Some(metadata.method),
+            target_version: None,
+        })?);
+    } else {
+        println!("{}", serde_json::to_string_pretty(&metadata)?);
+    }
+    Ok(())
+}
+
+pub fn import_metadata(options: &ImportMetadata) -> anyhow::Result<()> {
+    let dir = data_path(false)?.join(&options.name);
+    if !dir.exists() {
+        anyhow::bail!("No data directory {} found. Restore the data \
+            directory of the instance first.", dir.display());
+    }
+    let path = dir.join("metadata.json");
+    if path.exists() && !options.overwrite {
+        anyhow::bail!("Instance {:?} already has metadata. \
+            Use `--overwrite` to replace it.", options.name);
+    }
+    let mut data = Vec::new();
+    if options.path.to_str() == Some("-") {
+        stdin().read_to_end(&mut data)?;
+    } else {
+        data = fs::read(&options.path)
+            .with_context(|| format!("cannot read {}",
+                                     options.path.display()))?;
+    }
+    let metadata: Metadata = serde_json::from_slice(&data)
+        .context("cannot decode metadata")?;
+
+    let os = detect::current_os()?;
+    let avail = os.get_available_methods()?;
+    if !avail.is_supported(&metadata.method) {
+        anyhow::bail!("Installation method {} is not supported on this \
+            host:\n{}", metadata.method.title(), avail.format_error());
+    }
+    let method = os.make_method(&metadata.method, &avail)?;
+    let installed = method.installed_versions()?.iter()
+        .any(|ver| ver.major_version == metadata.version);
+    if !installed {
+        log::warn!("Version {} is not installed. Run:\n  \
+            edgedb server install --version={} {}",
+            metadata.version, metadata.version, metadata.method.option());
+    }
+    write_metadata(&path, &metadata)?;
+    Ok(())
+}
@@ -1,3 +1,4 @@
+use std::env;
 use std::str::FromStr;
 
 use serde::{Serialize, Deserialize};
@@ -10,6 +11,9 @@ use crate::server::docker::DockerCandidate;
 
 pub type Methods<'a> = LinkedHashMap<InstallMethod, Box<dyn Method + 'a>>;
 
+/// Environment variable providing the default of `--method`
+pub const METHOD_ENV: &str = "EDGEDB_SERVER_METHOD";
+
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 #[derive(Serialize, Deserialize)]
@@ -102,11 +106,22 @@ impl FromStr for InstallMethod {
             "package" => Ok(InstallMethod::Package),
             "docker" => Ok(InstallMethod::Docker),
             _ => anyhow::bail!("Unknown installation method {:?}. \
-                Options: package, docker"),
+                Options: package, docker", s),
         }
     }
 }
 
+/// Method set by `EDGEDB_SERVER_METHOD`, if any
+pub fn default_method() -> anyhow::Result<Option<InstallMethod>> {
+    match env::var(METHOD_ENV) {
+        Ok(value) if value.is_empty() => Ok(None),
+        Ok(value) => value.parse().map(Some)
+            .map_err(|e| anyhow::anyhow!("invalid {}: {:#}", METHOD_ENV, e)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(e) => anyhow::bail!("invalid {}: {}", METHOD_ENV, e),
+    }
+}
+
 impl InstallMethod {
     pub fn title(&self) -> &'static str {
         use InstallMethod::*;
@@ -6,6 +6,9 @@ mod remote;
 mod version;
 mod os_trait;
 mod debian_like;
+mod verify;
+mod snapshot;
+mod encrypted_backup;
 
 // OSs
 mod linux;
@@ -21,18 +24,60 @@ mod docker;
 mod package;
 
 // commands
+mod batch_control;
+mod compare_schema;
+mod config;
 mod control;
+mod daemon;
+mod doctor;
+mod drift;
+mod dump_instances;
+mod dump_layout;
 mod init;
 mod install;
+mod label;
+mod list_backups;
 mod list_versions;
+mod memory_budget;
+mod metadata;
+mod ping;
+mod ports;
+mod prune_versions;
+mod refresh_keys;
+mod reinit;
+mod rename;
+mod repair_metadata;
 mod reset_password;
+mod restore_backup;
+mod schema_dump;
+mod set_env;
+mod set_version;
 mod status;
 mod upgrade;
+mod validate_dump;
+mod wait;
+mod which;
+
+use std::io::{stdout, Write};
+
+use serde::Serialize;
+
+use crate::self_install::read_choice;
+use crate::server::options::OutputFormat;
 
 pub use main::main;
 pub use control::get_instance;
 
 
+/// Human-readable description of `is_valid_name` rules for error messages
+const NAME_RULES: &str = "instance name must start with a letter or \
+    an underscore, and contain only letters, digits and underscores";
+
+/// Instance name is used as a name of the data directory, of the service
+/// and in the socket path, so only letters (including non-ASCII ones),
+/// digits and underscores are allowed, and the first character must not be
+/// a digit. Directories with other names in the data dir (e.g. backups
+/// and dumps made by upgrade) are not instances.
 fn is_valid_name(name: &str) -> bool {
     let mut chars = name.chars();
     match chars.next() {
@@ -46,3 +91,67 @@ fn is_valid_name(name: &str) -> bool {
     }
     return true
 }
+
+fn confirm(question: &str) -> anyhow::Result<bool> {
+    loop {
+        print!("{} (y/N) ", question);
+        stdout().flush()?;
+        match read_choice()?.as_ref() {
+            "y" | "yes" => return Ok(true),
+            "n" | "no" | "" => return Ok(false),
+            choice => {
+                eprintln!("Invalid choice {:?}. \
+                    Use single letter `y` or `n`.",
+                    choice);
+            }
+        }
+    }
+}
+
+/// Serializes the same data as JSON or YAML. Human-readable output is
+/// formatted by the command itself
+fn serialize<T: Serialize>(format: OutputFormat, value: &T)
+    -> anyhow::Result<String>
+{
+    match format {
+        OutputFormat::Json => Ok(serde_json::to_string_pretty(value)?),
+        OutputFormat::Yaml => Ok(serde_yaml::to_string(value)?),
+        OutputFormat::Human => {
+            unreachable!("human-readable output is not serialized");
+        }
+    }
+}
+
+fn print_serialized<T: Serialize>(format: OutputFormat, value: &T)
+    -> anyhow::Result<()>
+{
+    println!("{}", serialize(format, value)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::is_valid_name;
+
+    #[test]
+    fn test_valid_names() {
+        assert!(is_valid_name("default"));
+        assert!(is_valid_name("_private"));
+        assert!(is_valid_name("inst_2"));
+        assert!(is_valid_name("x"));
+        assert!(is_valid_name("база"));
+        assert!(is_valid_name("données_1"));
+    }
+
+    #[test]
+    fn test_invalid_names() {
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("1inst"));
+        assert!(!is_valid_name("٣inst"));
+        assert!(!is_valid_name("my-inst"));
+        assert!(!is_valid_name("-inst"));
+        assert!(!is_valid_name("inst.backup"));
+        assert!(!is_valid_name("inst name"));
+        assert!(!is_valid_name("inst/name"));
+    }
+}
@@ -1,12 +1,16 @@
 use std::fmt;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
 use clap::{Clap, AppSettings, ArgSettings};
 use serde::{Serialize, Deserialize};
 
 use crate::server::version::Version;
+use crate::server::dump_layout::DumpLayout;
 use crate::server::methods::InstallMethod;
-use crate::server::is_valid_name;
+use crate::server::ports::PortRange;
+use crate::server::{is_valid_name, NAME_RULES};
 
 
 #[derive(Clap, Debug, Clone)]
@@ -30,14 +34,75 @@ pub enum Command {
     Stop(Stop),
     #[clap(about="Restart an instance")]
     Restart(Restart),
+    #[clap(about="Stop all instances")]
+    StopAll(StopAll),
+    #[clap(about="Start all instances")]
+    StartAll(StartAll),
     #[clap(about="Status of an instance")]
     Status(Status),
+    #[clap(about="Check that an instance accepts connections")]
+    Ping(Ping),
     #[clap(about="Upgrade installations and instances")]
     Upgrade(Upgrade),
     #[clap(about="Reset password for a user in the instance")]
     ResetPassword(ResetPassword),
-    #[clap(name="_detect")]
-    _Detect(Detect),
+    #[clap(about="Rebuild missing or corrupt metadata of an instance")]
+    RepairMetadata(RepairMetadata),
+    #[clap(about="Set or show labels of an instance")]
+    Label(Label),
+    #[clap(about="Show path to the server binary used by an instance")]
+    Which(Which),
+    #[clap(about="Print metadata of an instance as JSON")]
+    ExportMetadata(ExportMetadata),
+    #[clap(about="Write metadata of an instance from JSON")]
+    ImportMetadata(ImportMetadata),
+    #[clap(about="Re-import the key of the package repository")]
+    RefreshKeys(RefreshKeys),
+    #[clap(about="Set or show environment variables of an instance")]
+    SetEnv(SetEnv),
+    #[clap(about="Show how far instances are behind the latest versions")]
+    Drift(Drift),
+    #[clap(about="Replace data of an instance with a backup made by upgrade")]
+    RestoreBackup(RestoreBackup),
+    #[clap(about="Change version recorded in the metadata of an instance")]
+    SetVersion(SetVersion),
+    #[clap(about="Check structure of a dump and show its contents")]
+    ValidateDump(ValidateDump),
+    #[clap(about="Show detected platform and available installation methods",
+           alias="_detect")]
+    Detect(Detect),
+    #[clap(about="Print JSON Schema of metadata files")]
+    SchemaDump(SchemaDump),
+    #[clap(about="Dump every instance into a single directory")]
+    DumpAllInstances(DumpAllInstances),
+    #[clap(about="Show server settings persisted for an instance")]
+    ConfigGet(ConfigGet),
+    #[clap(about="Change server settings of an instance and persist them")]
+    ConfigSet(ConfigSet),
+    #[clap(about="Rename an instance")]
+    Rename(Rename),
+    #[clap(about="Check instances for problems and suggest fixes")]
+    Doctor(Doctor),
+    #[clap(about="Wait until an instance responds to queries")]
+    Wait(Wait),
+    #[clap(about="Show packages installed by `edgedb server install`")]
+    InstallHistory(InstallHistory),
+    #[clap(about="Rebuild data directory of an instance from a dump")]
+    Reinit(Reinit),
+    #[clap(about="Show backups of an instance that can be restored")]
+    ListBackups(ListBackups),
+    #[clap(about="Serve instance operations over a local socket")]
+    Daemon(Daemon),
+    #[clap(about="Check for upgrades of instances, exit with code 53 \
+                  if any are available")]
+    CheckUpdates(CheckUpdates),
+    #[clap(about="Show the port that `init` would allocate")]
+    NextPort(NextPort),
+    #[clap(about="Compare schema of two instances, exit with code 56 \
+                  if it differs")]
+    CompareSchema(CompareSchema),
+    #[clap(about="Uninstall server versions that no instance uses")]
+    PruneVersions(PruneVersions),
 }
 
 #[derive(Clap, Debug, Clone)]
@@ -48,8 +113,43 @@ pub struct Install {
     pub nightly: bool,
     #[clap(long, conflicts_with="nightly")]
     pub version: Option<Version<String>>,
+    /// Installation method, `EDGEDB_SERVER_METHOD` environment variable
+    /// is used if not specified
     #[clap(long, possible_values=&["package", "docker"][..])]
     pub method: Option<InstallMethod>,
+    /// Comma-separated list of installation methods in the order of
+    /// preference. First one supported on this system is used
+    /// (if none is supported, any available method is used)
+    #[clap(long, conflicts_with="method", use_delimiter=true,
+           possible_values=&["package", "docker"][..])]
+    pub prefer_method: Vec<InstallMethod>,
+    /// Set installation method specific parameter (`key=value`),
+    /// may be specified multiple times
+    #[clap(long="set", number_of_values=1,
+           parse(try_from_str=key_value))]
+    pub extra: Vec<(String, String)>,
+    /// Check that installed files match the package after installation
+    #[clap(long)]
+    pub verify: bool,
+    /// Comma-separated list of mirrors of `https://packages.edgedb.com`
+    /// to download packages and indexes from (tried in order)
+    #[clap(long, use_delimiter=true, number_of_values=1)]
+    pub repository_url: Vec<String>,
+    /// Don't verify TLS certificates when downloading from the repository
+    /// (for internal mirrors with self-signed certificates). This is
+    /// insecure and is never the default
+    #[clap(long)]
+    pub no_verify_tls: bool,
+    /// Unpack server binaries into this (user-writable) directory instead
+    /// of installing the package system-wide, so no root access is needed.
+    /// Only supported by the package method on Debian and Ubuntu. The
+    /// prefix is remembered, so `init` and `upgrade` use binaries from it
+    #[clap(long)]
+    pub prefix: Option<PathBuf>,
+    /// Resolve the package and check that the repository is reachable,
+    /// but don't download or install anything
+    #[clap(long)]
+    pub dry_run: bool,
 }
 
 #[derive(Clap, Debug, Clone)]
@@ -57,6 +157,10 @@ pub struct Install {
 pub struct ListVersions {
     #[clap(long)]
     pub installed_only: bool,
+    /// Output format
+    #[clap(long, default_value="human",
+           possible_values=&["human", "json", "yaml"][..])]
+    pub format: OutputFormat,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
@@ -65,6 +169,31 @@ pub enum StartConf {
     Manual,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectMethod {
+    Unix,
+    Tcp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TempAuth {
+    TrustLocal,
+    Password,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channel {
+    Stable,
+    Nightly,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Human,
+    Json,
+    Yaml,
+}
+
 #[derive(Clap, Debug, Clone)]
 pub struct Init {
     /// Database server instance name
@@ -80,8 +209,17 @@ pub struct Init {
     pub version: Option<Version<String>>,
     #[clap(long, possible_values=&["package", "docker"][..])]
     pub method: Option<InstallMethod>,
+    /// Port of the server. It's recorded in the port mapping (replacing
+    /// the port reserved for the instance), and fails if another instance
+    /// has the port reserved
     #[clap(long)]
     pub port: Option<u16>,
+    /// Pick the port from this range (like `10700-10800`), skipping
+    /// ports used by other instances or bound by other processes. Applies
+    /// to the `default` instance too, and fails if the port already
+    /// reserved for the instance is outside of the range
+    #[clap(long, conflicts_with="port")]
+    pub port_range: Option<PortRange>,
     #[clap(long, default_value="auto",
            possible_values=&["auto", "manual"][..])]
     pub start_conf: StartConf,
@@ -127,6 +265,13 @@ pub struct Start {
                     launchctl to manage the process (note you might need to \
                     stop non-foreground instance first)"))]
     pub foreground: bool,
+    /// Wait until the server accepts queries
+    #[clap(long, conflicts_with="foreground")]
+    pub wait: bool,
+    /// How long `--wait` waits for the server (default 60s)
+    #[clap(long, requires="wait",
+           parse(try_from_str=humantime::parse_duration))]
+    pub wait_timeout: Option<Duration>,
 }
 
 #[derive(Clap, Debug, Clone)]
@@ -135,6 +280,13 @@ pub struct Stop {
     /// Database server instance name
     #[clap(default_value="default", validator(instance_name_opt))]
     pub name: String,
+    /// Wait until the server process exits
+    #[clap(long)]
+    pub wait: bool,
+    /// How long `--wait` waits for the server to exit (default 60s)
+    #[clap(long, requires="wait",
+           parse(try_from_str=humantime::parse_duration))]
+    pub wait_timeout: Option<Duration>,
 }
 
 #[derive(Clap, Debug, Clone)]
@@ -145,6 +297,25 @@ pub struct Restart {
     pub name: String,
 }
 
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct StopAll {
+    /// Only stop instances installed using specified method
+    #[clap(long, possible_values=&["package", "docker"][..])]
+    pub method: Option<InstallMethod>,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct StartAll {
+    /// Only start instances installed using specified method
+    #[clap(long, possible_values=&["package", "docker"][..])]
+    pub method: Option<InstallMethod>,
+    /// Only start instances that were running before last `stop-all`
+    #[clap(long)]
+    pub only_previously_running: bool,
+}
+
 #[derive(Clap, Debug, Clone)]
 #[clap(setting=AppSettings::DisableVersion)]
 pub struct Status {
@@ -163,6 +334,33 @@ pub struct Status {
     /// Print status of all instances
     #[clap(long)]
     pub all: bool,
+
+    /// Output format. JSON and YAML contain the same fields as the status
+    /// reported by `server daemon`
+    #[clap(long, default_value="human",
+           possible_values=&["human", "json", "yaml"][..],
+           conflicts_with_all=&["service", "extended"])]
+    pub format: OutputFormat,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct Ping {
+    /// Database server instance name
+    #[clap(default_value="default", validator(instance_name_opt))]
+    pub name: String,
+    /// Fail if instance doesn't respond within this time
+    #[clap(long, default_value="10s",
+           parse(try_from_str=humantime::parse_duration))]
+    pub timeout: Duration,
+    /// How to connect to the instance. By default unix socket is tried
+    /// first, then TCP (using the credentials file)
+    #[clap(long, possible_values=&["unix", "tcp"][..])]
+    pub connect_method: Option<ConnectMethod>,
+    /// Output format
+    #[clap(long, default_value="human",
+           possible_values=&["human", "json", "yaml"][..])]
+    pub format: OutputFormat,
 }
 
 #[derive(Clap, Debug, Clone)]
@@ -180,30 +378,404 @@ edgedb server upgrade <name> [--to-version=<ver>|--to-nightly]
 
 edgedb server upgrade --nightly
   Upgrades all existing nightly instances to the latest EdgeDB nightly.
+
+edgedb server upgrade --all-channels
+  Upgrades non-nightly instances to a latest minor version and nightly
+  instances to the latest nightly in a single run.
 ")]
 pub struct Upgrade {
     /// Upgrade all nightly instances
     #[clap(long)]
     pub nightly: bool,
 
+    /// Upgrade both stable (to a latest minor version) and nightly
+    /// instances
+    #[clap(long, conflicts_with_all=&["nightly", "name"])]
+    pub all_channels: bool,
+
     /// Upgrade specified instance(s) to a specified major version
     #[clap(long)]
     pub to_version: Option<Version<String>>,
 
+    /// Only upgrade instances of this major version to its latest minor
+    /// version, leaving instances of other versions untouched
+    #[clap(long, conflicts_with_all=&["nightly", "name", "to_version"])]
+    pub only_major: Option<Version<String>>,
+
     /// Upgrade specifies instance to a latest nightly version
     #[clap(long)]
     pub to_nightly: bool,
 
+    /// Allow upgrading a stable instance to nightly or a nightly instance
+    /// to stable. Switch is confirmed interactively, unless
+    /// `--non-interactive` is used
+    #[clap(long)]
+    pub allow_channel_switch: bool,
+
     /// Only upgrade specicified database instance
+    #[clap(validator(instance_name_opt))]
     pub name: Option<String>,
 
+    /// Move the instance to a new port while upgrading (only valid when
+    /// upgrading a single instance). If the instance is up to date, it's
+    /// only moved: its service is recreated on the new port
+    #[clap(long)]
+    pub port: Option<u16>,
+
+    /// Refuse to upgrade the instance unless it currently runs this version:
+    /// either its major version (e.g. `1-alpha5`) or the full installed
+    /// version. Guards scripted rollouts against upgrading an instance that
+    /// someone else has already changed
+    #[clap(long, alias="since-version", requires="name")]
+    pub from_version: Option<Version<String>>,
+
+    /// Upgrade instances listed in this JSON file, in the listed order.
+    /// The file is an array of objects with `name` and optional `method`
+    /// and `target_version` (major version to upgrade to), as printed by
+    /// `export-metadata --inventory-item`. Other fields are rejected
+    #[clap(long, conflicts_with_all=&[
+        "name", "nightly", "all_channels", "to_version", "to_nightly",
+    ])]
+    pub inventory: Option<PathBuf>,
+
+    /// Upgrade instances read from stdin, in the given order: an instance
+    /// name per line, or a JSON object per line with the same fields as
+    /// `--inventory` items. Instances that aren't found are reported
+    #[clap(long, conflicts_with_all=&[
+        "name", "nightly", "all_channels", "to_version", "to_nightly",
+        "inventory", "interactive",
+    ])]
+    pub instances_from_stdin: bool,
+
+    /// With `--inventory` or `--instances-from-stdin`, continue with the
+    /// next instance if one is not found or fails to upgrade. The command
+    /// still fails at the end if any of them did
+    #[clap(long)]
+    pub continue_on_error: bool,
+
+    /// Before upgrading an instance to a new major version, check that its
+    /// dump can be restored by the new version: downgrades and upgrades
+    /// skipping more than two major versions are refused
+    #[clap(long)]
+    pub compat_check: bool,
+
+    /// Only upgrade instances having the label (`key=value`). If specified
+    /// multiple times, instances must have all of the labels
+    #[clap(long="tag", number_of_values=1,
+           parse(try_from_str=key_value))]
+    pub tags: Vec<(String, String)>,
+
+    /// Only upgrade instances installed using specified method. Defaults
+    /// to the `EDGEDB_SERVER_METHOD` environment variable, if set
+    #[clap(long, possible_values=&["package", "docker"][..])]
+    pub method: Option<InstallMethod>,
+
     /// Verbose output
     #[clap(short="v", long)]
     pub verbose: bool,
 
-    /// Force upgrade process even if there is no new version
+    /// Print every statement and step of restoring the dump, to find
+    /// where a failed restore stopped
+    #[clap(long)]
+    pub verbose_restore: bool,
+
+    /// Choose which of the matching instances to upgrade (only when
+    /// upgrading multiple instances and stdin is a terminal)
+    #[clap(short="i", long)]
+    pub interactive: bool,
+
+    /// Force upgrade process even if there is no new version, if there
+    /// are clients connected to the instance, or if the instance could not
+    /// be stopped before installing the package
     #[clap(long)]
     pub force: bool,
+
+    /// Do not print the summary when upgrade is finished
+    #[clap(short="q", long)]
+    pub quiet: bool,
+
+    /// Fail instead of warning and continuing when: an instance has
+    /// unreadable metadata (it's skipped otherwise), the installation
+    /// method of an instance is not available (its instances are skipped
+    /// otherwise), or an instance fails to stop before the package is
+    /// upgraded in place (ignored otherwise if it's not running, or with
+    /// `--force`)
+    #[clap(long)]
+    pub abort_on_warning: bool,
+
+    /// Format of the summary printed when upgrade is finished. The summary
+    /// includes sizes and durations of dump and restore of each instance
+    #[clap(long, default_value="human",
+           possible_values=&["human", "json", "yaml"][..])]
+    pub format: OutputFormat,
+
+    /// Also write the summary as JSON into this file (even with `--quiet`),
+    /// for automation that doesn't read the output
+    #[clap(long)]
+    pub summary_file: Option<PathBuf>,
+
+    /// Resolve new versions and print which instances would be upgraded,
+    /// without stopping instances or installing anything
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// Print estimated dump size and downtime of the matching instances
+    /// instead of upgrading them. Running instances are also queried for
+    /// the number of objects in each database. Estimates are more precise
+    /// if the instance was dumped by an upgrade before
+    #[clap(long)]
+    pub estimate: bool,
+
+    /// Abort if the whole upgrade takes longer than this (e.g. `2h`).
+    /// Servers run by the upgrade are stopped, the instance being upgraded
+    /// is rolled back to its backup, instances not upgraded yet are left
+    /// as is, and the command exits with code 52
+    #[clap(long, parse(try_from_str=humantime::parse_duration))]
+    pub deadline: Option<Duration>,
+
+    /// Before stopping the instance, wait up to this long for connected
+    /// clients to disconnect. Current server versions keep accepting new
+    /// connections meanwhile. Clients still connected after that are
+    /// disconnected (without this option they fail the upgrade unless
+    /// `--force` is used)
+    #[clap(long, parse(try_from_str=humantime::parse_duration))]
+    pub drain_timeout: Option<Duration>,
+
+    /// How long to wait for a server (the instance or the temporary server
+    /// used for restore) to start accepting connections. Stopping the
+    /// instance waits up to 60s for the server to exit
+    #[clap(long, default_value="30s",
+           parse(try_from_str=humantime::parse_duration))]
+    pub start_timeout: Duration,
+
+    /// Fail if dumping an instance takes longer than this. The dump is
+    /// left incomplete and the instance is not modified
+    #[clap(long, parse(try_from_str=humantime::parse_duration))]
+    pub dump_timeout: Option<Duration>,
+
+    /// Fail if restoring (or streaming) data into the new version takes
+    /// longer than this. The backup of the data directory is kept
+    #[clap(long, parse(try_from_str=humantime::parse_duration))]
+    pub restore_timeout: Option<Duration>,
+
+    /// Intended to save restorable state of long restores every so often,
+    /// so an interrupted restore continues from the last checkpoint.
+    /// Currently has no effect (a warning is printed): the server applies
+    /// the restored dump of a database only when all of its blocks are
+    /// received, so a partial restore can't be resumed
+    #[clap(long, parse(try_from_str=humantime::parse_duration),
+           value_name="duration")]
+    pub checkpoint: Option<Duration>,
+
+    /// Do not check that data directory is writable before stopping the
+    /// instance (the check creates and removes a temporary file)
+    #[clap(long)]
+    pub assume_writable: bool,
+
+    /// Keep permissions, ownership and modification times of files if the
+    /// data directory has to be copied to make the backup (when the backup
+    /// location is on another filesystem). Use
+    /// `--preserve-permissions=false` to copy files with default ones
+    #[clap(long, default_value="true", parse(try_from_str),
+           value_name="bool")]
+    pub preserve_permissions: bool,
+
+    /// Make the backup as a filesystem snapshot of the data directory
+    /// (btrfs subvolume), so `restore-backup` doesn't need to copy anything.
+    /// Falls back to the usual backup if the data directory is not on a
+    /// filesystem supporting snapshots
+    #[clap(long)]
+    pub snapshot: bool,
+
+    /// Compress and encrypt the backup of the data directory once the
+    /// upgrade is complete. The key is read from `--backup-key-file` or
+    /// the `EDGEDB_BACKUP_KEY` environment variable, and is needed for
+    /// `restore-backup`
+    #[clap(long, conflicts_with="snapshot")]
+    pub backup_encrypt: bool,
+
+    /// File containing the key for `--backup-encrypt`
+    #[clap(long, requires="backup_encrypt")]
+    pub backup_key_file: Option<PathBuf>,
+
+    /// How to connect to the instance for dump and restore. By default unix
+    /// socket is tried first, then TCP (using the credentials file). The
+    /// temporary server used for restore is reached over unix socket only,
+    /// unless `tcp` is specified (then it runs on the port of the instance)
+    #[clap(long, possible_values=&["unix", "tcp"][..])]
+    pub connect_method: Option<ConnectMethod>,
+
+    /// Port for the temporary server that the dump is restored into. By
+    /// default a free port is picked, so it doesn't clash with ports of
+    /// other instances. Not used with `--connect-method=tcp` and
+    /// `--temp-auth=password`, as TCP connections use the port of the
+    /// instance
+    #[clap(long)]
+    pub temp_port: Option<u16>,
+
+    /// How restore authenticates to the temporary server. `trust-local`
+    /// (default) uses the admin unix socket of the server, which is
+    /// trusted without password: any process of the same user can connect
+    /// to it as superuser while restore is running (the socket is in a
+    /// private directory, though). `password` connects over TCP with
+    /// the password of the `edgedb` user from the credentials file, so
+    /// authentication configured in the instance is respected. The
+    /// password is set on the new instance over the admin socket, before
+    /// anything is restored
+    #[clap(long, default_value="trust-local",
+           possible_values=&["trust-local", "password"][..])]
+    pub temp_auth: TempAuth,
+
+    /// Count objects of every type before dump and after restore, and fail
+    /// if the numbers differ. Takes extra time but ensures that no data is
+    /// lost during the upgrade
+    #[clap(long)]
+    pub verify_after_restore: bool,
+
+    /// Do not dump the instance if a complete dump left by a previous
+    /// upgrade attempt exists (and the instance was not started since, as
+    /// told by the modification time of `postmaster.opts` in the data
+    /// directory)
+    #[clap(long)]
+    pub reuse_dump: bool,
+
+    /// Do not dump and restore databases of instances that have no user
+    /// data (no databases except the default one, and no schema or objects
+    /// in it). Roles and configuration are still transferred. If emptiness
+    /// can't be checked, the instance is dumped as usual
+    #[clap(long, conflicts_with="stream")]
+    pub skip_empty_dump: bool,
+
+    /// Allow restoring the dump into databases that are not empty, e.g.
+    /// to merge data into an existing instance. Conflicting objects may
+    /// fail the restore or be overwritten, so this is confirmed
+    /// interactively, unless `--non-interactive` is used
+    #[clap(long, conflicts_with="stream")]
+    pub allow_non_empty: bool,
+
+    /// Transfer only schema to the upgraded instance, without data. This
+    /// is much faster, but all data is lost (it's kept in the backup of
+    /// the data directory only), so it's confirmed interactively unless
+    /// `--non-interactive` is used. Meant for instances that can be
+    /// refilled from elsewhere, like caches
+    #[clap(long, conflicts_with_all=&["stream", "verify_after_restore"])]
+    pub schema_only: bool,
+
+    /// Don't ask for confirmation of `--allow-channel-switch`,
+    /// `--allow-non-empty` and `--schema-only`
+    #[clap(long)]
+    pub non_interactive: bool,
+
+    /// Don't transfer this database to the upgraded instance, e.g. a
+    /// large scratch database that can be recreated. It's lost after the
+    /// upgrade (but kept in the backup). Can be repeated
+    #[clap(long="exclude-database", number_of_values=1)]
+    pub exclude_database: Vec<String>,
+
+    /// Transfer data directly from the old server to the new one instead
+    /// of writing a dump to disk. This requires both servers to be run
+    /// simultaneously (so it only works for upgrades to a new major
+    /// version), and data being transferred is kept in memory instead of
+    /// disk
+    #[clap(long, conflicts_with_all=&["reuse_dump", "nightly"])]
+    pub stream: bool,
+
+    /// Upgrade without downtime: restore the dump into a new instance of
+    /// the target version running next to the old one, replay changes made
+    /// since the dump, and then switch the port to the new instance.
+    /// Requires change capture in the server (writes made after the dump
+    /// would be lost on the switch otherwise). No released version
+    /// supports it, so for now a warning is printed and the usual upgrade
+    /// is done, with instances unavailable while data is restored
+    #[clap(long)]
+    pub zero_downtime: bool,
+
+    /// Buffer size in bytes for writing and reading the dump files.
+    /// Larger buffers mean fewer system calls at the cost of memory. By
+    /// default every block is written and read as is, without buffering.
+    /// Not used with `--stream`
+    #[clap(long)]
+    pub transfer_buffer: Option<usize>,
+
+    /// Number of jobs the new server may use to restore data blocks
+    /// concurrently. More jobs may speed up restoring databases with many
+    /// large types, but put more load on the server
+    #[clap(long, default_value="1")]
+    pub transfer_parallelism: u16,
+
+    /// Stop at most this many instances at once before the package is
+    /// upgraded in place. By default all instances of the package are
+    /// stopped at once
+    #[clap(long)]
+    pub max_parallel_stops: Option<usize>,
+
+    /// Start at most this many instances at once after the package is
+    /// upgraded in place, the next ones are started when these accept
+    /// connections. By default all of them are started at once
+    #[clap(long)]
+    pub max_parallel_starts: Option<usize>,
+
+    /// Memory budget in bytes of instances started at once after the
+    /// package is upgraded in place. Memory needed by the server to start
+    /// and replay its data is estimated as the size of the data directory,
+    /// instances that don't fit are queued until started ones accept
+    /// connections. An instance larger than the budget is started alone
+    #[clap(long)]
+    pub max_memory: Option<u64>,
+
+    /// Refuse to upgrade if the instance is estimated to be down for longer
+    /// than this (e.g. `15min`). Estimate is based on the previous dump of
+    /// the instance if there is one, or on the size of the data directory
+    /// otherwise. Use `--force` to upgrade anyway
+    #[clap(long, parse(try_from_str=humantime::parse_duration))]
+    pub max_downtime: Option<Duration>,
+
+    /// Execute EdgeQL statements from the file after data is restored,
+    /// before the instance is restarted. If any statement fails upgrade
+    /// fails too, and the backup of the data directory is kept
+    #[clap(long)]
+    pub post_restore_script: Option<PathBuf>,
+
+    /// Run this server binary instead of the installed one for the
+    /// temporary server that the dump is restored into, e.g. to test
+    /// an unreleased build. The instance itself keeps running the
+    /// installed server after the upgrade
+    #[clap(long)]
+    pub server_binary: Option<PathBuf>,
+
+    /// Where to write dumps, as a path template with `{name}`,
+    /// `{timestamp}` (unix time of the start of the upgrade) and
+    /// `{version}` (version upgraded from), e.g.
+    /// `/backups/{name}/{timestamp}`. Relative paths are relative to the
+    /// directory of data directories. Default is `{name}.dump`
+    #[clap(long)]
+    pub dump_layout: Option<DumpLayout>,
+
+    /// Maximum age of the dump to reuse with `--reuse-dump`
+    #[clap(long, default_value="6h",
+           parse(try_from_str=humantime::parse_duration))]
+    pub reuse_dump_max_age: Duration,
+
+    /// Set installation method specific parameter (`key=value`) for
+    /// installing new package, may be specified multiple times
+    #[clap(long="set", number_of_values=1,
+           parse(try_from_str=key_value))]
+    pub extra: Vec<(String, String)>,
+
+    /// Check that installed files match the package after installation
+    #[clap(long)]
+    pub verify: bool,
+
+    /// Comma-separated list of mirrors of `https://packages.edgedb.com`
+    /// to download packages and indexes from (tried in order)
+    #[clap(long, use_delimiter=true, number_of_values=1)]
+    pub repository_url: Vec<String>,
+
+    /// Don't verify TLS certificates when downloading from the repository
+    /// (for internal mirrors with self-signed certificates). This is
+    /// insecure and is never the default
+    #[clap(long)]
+    pub no_verify_tls: bool,
 }
 
 #[derive(Clap, Debug, Clone)]
@@ -234,9 +806,359 @@ pub struct ResetPassword {
 }
 
 #[derive(Clap, Debug, Clone)]
-#[clap(setting=AppSettings::Hidden)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct RepairMetadata {
+    /// Database server instance name
+    #[clap(validator(instance_name_opt))]
+    pub name: String,
+    /// Write detected metadata without asking for confirmation
+    #[clap(long)]
+    pub from_detected: bool,
+    /// Rebuild metadata even if existing one is readable
+    #[clap(long)]
+    pub force: bool,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct Label {
+    /// Database server instance name
+    #[clap(validator(instance_name_opt))]
+    pub name: String,
+    /// Labels to set (`key=value`). Without labels and `--remove` current
+    /// labels are printed
+    #[clap(parse(try_from_str=key_value))]
+    pub labels: Vec<(String, String)>,
+    /// Remove the label with specified key
+    #[clap(long, number_of_values=1)]
+    pub remove: Vec<String>,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct Which {
+    /// Database server instance name
+    #[clap(default_value="default", validator(instance_name_opt))]
+    pub name: String,
+    /// Show server binaries of all installed versions instead
+    #[clap(long)]
+    pub all: bool,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct ExportMetadata {
+    /// Database server instance name
+    #[clap(validator(instance_name_opt))]
+    pub name: String,
+    /// Print the instance as an item of `upgrade --inventory` (on a single
+    /// line, as `upgrade --instances-from-stdin` reads it) instead
+    #[clap(long)]
+    pub inventory_item: bool,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct ImportMetadata {
+    /// Database server instance name (data directory of the instance must
+    /// already exist)
+    #[clap(validator(instance_name_opt))]
+    pub name: String,
+    /// File to read metadata from (as printed by `export-metadata`). Use
+    /// dash `-` to read from stdin
+    #[clap(default_value="-")]
+    pub path: PathBuf,
+    /// Replace existing metadata of the instance
+    #[clap(long)]
+    pub overwrite: bool,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct RestoreBackup {
+    /// Database server instance name
+    #[clap(validator(instance_name_opt))]
+    pub name: String,
+    /// Use the latest backup made before this time (e.g.
+    /// `2020-11-01 12:00:00`). By default the latest backup is used
+    #[clap(long, parse(try_from_str=humantime::parse_rfc3339_weak))]
+    pub timestamp: Option<SystemTime>,
+    /// File containing the key of the encrypted backup. By default the
+    /// `EDGEDB_BACKUP_KEY` environment variable is used
+    #[clap(long)]
+    pub backup_key_file: Option<PathBuf>,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct Drift {
+    /// Output format
+    #[clap(long, default_value="human",
+           possible_values=&["human", "json", "yaml"][..])]
+    pub format: OutputFormat,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct SetEnv {
+    /// Database server instance name
+    #[clap(validator(instance_name_opt))]
+    pub name: String,
+    /// Environment variables to set for the server process (`KEY=VALUE`).
+    /// Without variables and `--unset` names of current variables are
+    /// printed
+    #[clap(parse(try_from_str=key_value))]
+    pub vars: Vec<(String, String)>,
+    /// Remove the variable with specified name
+    #[clap(long, number_of_values=1)]
+    pub unset: Vec<String>,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct Doctor {
+    /// Fix problems that are safe to fix automatically: remove markers of
+    /// upgrades that are complete, and (after confirmation) dumps left by
+    /// upgrades. Other problems are only reported. The command exits with
+    /// code 55 if problems remain
+    #[clap(long)]
+    pub fix: bool,
+    /// Output format
+    #[clap(long, default_value="human",
+           possible_values=&["human", "json", "yaml"][..])]
+    pub format: OutputFormat,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct PruneVersions {
+    /// Only print the versions that would be uninstalled
+    #[clap(long)]
+    pub dry_run: bool,
+    /// Keep this many latest installed versions of each installation
+    /// method, even if no instance uses them
+    #[clap(long, default_value="0")]
+    pub keep_latest: usize,
+    /// Don't ask for confirmation
+    #[clap(long)]
+    pub non_interactive: bool,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct CompareSchema {
+    /// First instance (lines only in its schema are prefixed by `-`)
+    #[clap(validator(instance_name_opt))]
+    pub first: String,
+    /// Second instance (lines only in its schema are prefixed by `+`)
+    #[clap(validator(instance_name_opt))]
+    pub second: String,
+    /// How to connect to the instances. By default unix socket is tried
+    /// first, then TCP (using the credentials file)
+    #[clap(long, possible_values=&["unix", "tcp"][..])]
+    pub connect_method: Option<ConnectMethod>,
+    /// Output format
+    #[clap(long, default_value="human",
+           possible_values=&["human", "json", "yaml"][..])]
+    pub format: OutputFormat,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct NextPort {
+    /// Range to pick the port from, like `10700-10800`. By default ports
+    /// from 10700 up are used
+    #[clap(long)]
+    pub port_range: Option<PortRange>,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct CheckUpdates {
+    /// Only check instances of this channel. By default both are checked
+    #[clap(long, possible_values=&["stable", "nightly"][..])]
+    pub channel: Option<Channel>,
+    /// Output format
+    #[clap(long, default_value="human",
+           possible_values=&["human", "json", "yaml"][..])]
+    pub format: OutputFormat,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct Daemon {
+    /// Unix socket to listen on. By default
+    /// `~/.edgedb/run/server-daemon.sock`
+    #[clap(long)]
+    pub socket: Option<PathBuf>,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct ListBackups {
+    /// Database server instance name
+    #[clap(validator(instance_name_opt))]
+    pub name: String,
+    /// Output format
+    #[clap(long, default_value="human",
+           possible_values=&["human", "json", "yaml"][..])]
+    pub format: OutputFormat,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct Reinit {
+    /// Database server instance name
+    #[clap(validator(instance_name_opt))]
+    pub name: String,
+    /// Dump to restore. By default the dump made by the last upgrade
+    /// (`<name>.dump` next to the data directory) is used
+    #[clap(long)]
+    pub dump_path: Option<PathBuf>,
+    /// Rebuild the instance even if it responds to queries, or if it's
+    /// stopped (so it can't be checked)
+    #[clap(long)]
+    pub force: bool,
+    /// Restore the dump using this server binary instead of the
+    /// installed one (see `upgrade --server-binary`)
+    #[clap(long)]
+    pub server_binary: Option<PathBuf>,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct InstallHistory {
+    /// Output format
+    #[clap(long, default_value="human",
+           possible_values=&["human", "json", "yaml"][..])]
+    pub format: OutputFormat,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct Wait {
+    /// Database server instance name
+    #[clap(validator(instance_name_opt))]
+    pub name: String,
+    /// Exit with non-zero code if instance isn't ready within this time
+    #[clap(long, default_value="60s",
+           parse(try_from_str=humantime::parse_duration))]
+    pub timeout: Duration,
+    /// How to connect to the instance. By default unix socket is tried
+    /// first, then TCP (using the credentials file)
+    #[clap(long, possible_values=&["unix", "tcp"][..])]
+    pub connect_method: Option<ConnectMethod>,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct Rename {
+    /// Current name of the instance
+    #[clap(validator(instance_name_opt))]
+    pub old_name: String,
+    /// New name of the instance. Instance is restarted if it's running
+    pub new_name: String,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct ConfigGet {
+    /// Database server instance name
+    #[clap(validator(instance_name_opt))]
+    pub name: String,
+    /// Setting to show. Without it all persisted settings are printed
+    pub key: Option<String>,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct ConfigSet {
+    /// Database server instance name
+    #[clap(validator(instance_name_opt))]
+    pub name: String,
+    /// Settings to change (`key=value`). Lists (`listen_addresses`) are
+    /// comma-separated
+    #[clap(parse(try_from_str=key_value))]
+    pub settings: Vec<(String, String)>,
+    /// Reset the setting to the server default
+    #[clap(long, number_of_values=1)]
+    pub reset: Vec<String>,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct ValidateDump {
+    /// Dump directory (made by `edgedb dump --all --format=dir` or by
+    /// `edgedb server upgrade`) or a single database dump file
+    pub path: PathBuf,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct SetVersion {
+    /// Database server instance name
+    #[clap(validator(instance_name_opt))]
+    pub name: String,
+    /// Major version of the installed server package the instance's data
+    /// belongs to (as displayed by `edgedb server list-versions
+    /// --installed-only`). Only metadata is changed, data is not upgraded
+    pub version: Version<String>,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct RefreshKeys {
+    /// Fingerprint of the new key, as published on the website, if the
+    /// key was replaced after this version was released. By default the
+    /// key is only imported if it matches the fingerprint built into the
+    /// tool
+    #[clap(long)]
+    pub fingerprint: Option<String>,
+
+    /// Comma-separated list of mirrors of `https://packages.edgedb.com`
+    /// to download the key from (tried in order)
+    #[clap(long, use_delimiter=true, number_of_values=1)]
+    pub repository_url: Vec<String>,
+}
+
+#[derive(Clap, Debug, Clone)]
 #[clap(setting=AppSettings::DisableVersion)]
 pub struct Detect {
+    /// Output format. JSON and YAML include all the details of detection
+    #[clap(long, default_value="human",
+           possible_values=&["human", "json", "yaml"][..])]
+    pub format: OutputFormat,
+}
+
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct DumpAllInstances {
+    /// Directory to write the dumps into. Each instance is dumped into
+    /// a subdirectory named after it, and `manifest.json` lists versions
+    /// and metadata of the instances
+    #[clap(long)]
+    pub output_dir: PathBuf,
+    /// Number of instances to dump concurrently
+    #[clap(long, default_value="1")]
+    pub jobs: u16,
+    /// Memory budget in bytes of concurrent dumps. Cost of a dump is
+    /// estimated as the size of the data directory of the instance, and
+    /// dumps that don't fit wait for the running ones (a dump larger than
+    /// the budget runs alone)
+    #[clap(long)]
+    pub max_memory: Option<u64>,
+    /// Skip instances that are not running. By default they are started
+    /// for the dump and stopped afterwards
+    #[clap(long)]
+    pub only_if_running: bool,
+}
+
+/// Prints JSON Schema of `metadata.json`, `backup.json` and the upgrade
+/// marker (`UPGRADE_IN_PROGRESS`) for use by external tools
+#[derive(Clap, Debug, Clone)]
+#[clap(setting=AppSettings::Hidden)]
+#[clap(setting=AppSettings::DisableVersion)]
+pub struct SchemaDump {
 }
 
 impl FromStr for StartConf {
@@ -251,6 +1173,55 @@ impl FromStr for StartConf {
     }
 }
 
+impl FromStr for ConnectMethod {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<ConnectMethod> {
+        match s {
+            "unix" => Ok(ConnectMethod::Unix),
+            "tcp" => Ok(ConnectMethod::Tcp),
+            _ => anyhow::bail!("Unsupported connect method, \
+                options: `unix`, `tcp`"),
+        }
+    }
+}
+
+impl FromStr for TempAuth {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<TempAuth> {
+        match s {
+            "trust-local" => Ok(TempAuth::TrustLocal),
+            "password" => Ok(TempAuth::Password),
+            _ => anyhow::bail!("Unsupported auth mode, \
+                options: `trust-local`, `password`"),
+        }
+    }
+}
+
+impl FromStr for Channel {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Channel> {
+        match s {
+            "stable" => Ok(Channel::Stable),
+            "nightly" => Ok(Channel::Nightly),
+            _ => anyhow::bail!("Unsupported channel, \
+                options: `stable`, `nightly`"),
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<OutputFormat> {
+        match s {
+            "human" => Ok(OutputFormat::Human),
+            "json" => Ok(OutputFormat::Json),
+            "yaml" => Ok(OutputFormat::Yaml),
+            _ => anyhow::bail!("Unsupported output format, \
+                options: `human`, `json`, `yaml`"),
+        }
+    }
+}
+
 impl StartConf {
     fn as_str(&self) -> &str {
         match self {
@@ -266,11 +1237,20 @@ impl fmt::Display for StartConf {
     }
 }
 
+fn key_value(s: &str) -> anyhow::Result<(String, String)> {
+    let mut pair = s.splitn(2, '=');
+    match (pair.next(), pair.next()) {
+        (Some(key), Some(value)) if !key.is_empty() => {
+            Ok((key.into(), value.into()))
+        }
+        _ => anyhow::bail!("expected `key=value`"),
+    }
+}
+
 fn instance_name_opt(name: &str) -> Result<(), String> {
     if is_valid_name(&name) {
         return Ok(())
     }
-    return Err("instance name must be a valid identifier, \
-                (regex: ^[a-zA-Z_][a-zA-Z_0-9]*$)".into())
+    return Err(NAME_RULES.into())
 }
 
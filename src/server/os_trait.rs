@@ -1,13 +1,15 @@
 use std::fmt;
 use std::any::type_name;
 use std::path::PathBuf;
+use std::slice;
 
 use crate::server::install;
 use crate::server::detect::{VersionQuery, InstalledPackage, VersionResult};
 use crate::server::methods::{InstallationMethods, InstallMethod};
-use crate::server::package::PackageInfo;
+use crate::server::package::{self, PackageInfo};
 use crate::server::version::Version;
 use crate::server::init;
+use crate::server::upgrade;
 
 
 pub trait CurrentOs: fmt::Debug + Send + Sync + 'static {
@@ -27,11 +29,92 @@ pub trait Method: fmt::Debug + Send + Sync {
     fn all_versions(&self, nightly: bool) -> anyhow::Result<&[PackageInfo]>;
     fn get_version(&self, query: &VersionQuery)
         -> anyhow::Result<VersionResult>;
+    /// Resolves multiple queries at once, results are in the same order
+    ///
+    /// Methods that can fetch multiple versions in a single request should
+    /// override this, by default `get_version` is called for each query.
+    fn get_versions(&self, queries: &[VersionQuery])
+        -> Vec<anyhow::Result<VersionResult>>
+    {
+        queries.iter().map(|query| self.get_version(query)).collect()
+    }
+    /// Latest stable version of the major version `current`. Unlike
+    /// `get_version`, fails instead of returning a version of another
+    /// major, so automated minor upgrades never cross major versions
+    fn latest_compatible_version(&self, current: &Version<String>)
+        -> anyhow::Result<VersionResult>
+    {
+        self.latest_compatible_versions(slice::from_ref(current))
+            .pop().expect("one result per version")
+    }
+    /// `latest_compatible_version` of each of the `current` versions,
+    /// resolved by a single `get_versions` call
+    fn latest_compatible_versions(&self, current: &[Version<String>])
+        -> Vec<anyhow::Result<VersionResult>>
+    {
+        let queries = current.iter()
+            .map(|version| VersionQuery::Stable(Some(version.clone())))
+            .collect::<Vec<_>>();
+        self.get_versions(&queries).into_iter().zip(current)
+            .map(|(found, current)| {
+                found.and_then(|found| {
+                    package::check_compatible(current, found)
+                })
+            })
+            .collect()
+    }
     fn installed_versions(&self) -> anyhow::Result<&[InstalledPackage]>;
+    /// Removes the installed package. Callers check that no instance uses
+    /// its version
+    fn uninstall(&self, _package: &InstalledPackage) -> anyhow::Result<()> {
+        anyhow::bail!("Installation method {} doesn't support \
+            uninstalling packages", self.name().title());
+    }
+    /// Checks that files on disk match the manifest of installed package.
+    /// Returns `false` if the method can't verify them
+    fn verify_installation(&self, _settings: &install::Settings)
+        -> anyhow::Result<bool>
+    {
+        log::warn!("Installation method {} doesn't support \
+            verification of installed files", self.name().title());
+        Ok(false)
+    }
+    /// Imports new repository key (`KEY_FILE_URL` contents) into the
+    /// keyring used by the package manager
+    fn refresh_keys(&self, _key: &str) -> anyhow::Result<()> {
+        anyhow::bail!("Installation method {} doesn't use repository keys",
+            self.name().title());
+    }
     fn detect_all(&self) -> serde_json::Value;
     fn is_system_only(&self) -> bool {
         false
     }
+    /// Names of parameters accepted in `install::Settings::extra`
+    fn extra_settings(&self) -> &[&'static str] {
+        &[]
+    }
+    /// Whether `install::Settings::prefix` is supported (see
+    /// `install::prefix`)
+    fn supports_prefix(&self) -> bool {
+        false
+    }
+    /// Whether `install` uses a system package manager that can't run
+    /// concurrently, so installs must be serialized (see
+    /// `install::perform`)
+    fn install_is_exclusive(&self) -> bool {
+        false
+    }
+    /// Applies the new version to the instance without dumping and
+    /// restoring its data (e.g. by replacing the image of a container and
+    /// keeping its volumes), publishing the server on `port`. Returns
+    /// `false` if the method can't do that and the instance must be
+    /// reinitialized with the dump restored, which is the default
+    fn upgrade_in_place(&self, _inst: &upgrade::Instance,
+        _version: &VersionResult, _port: u16)
+        -> anyhow::Result<bool>
+    {
+        Ok(false)
+    }
     fn get_server_path(&self, major_version: &Version<String>)
         -> anyhow::Result<PathBuf>;
     fn create_user_service(&self, settings: &init::Settings)
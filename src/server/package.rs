@@ -1,7 +1,7 @@
 use serde::{Serialize, Deserialize};
 
 use crate::server::version::Version;
-use crate::server::detect::{Lazy, InstalledPackage, VersionQuery};
+use crate::server::detect::{Lazy, InstalledPackage, VersionQuery, ARCH};
 use crate::server::detect::{VersionResult};
 use crate::server::os_trait::CurrentOs;
 
@@ -35,6 +35,9 @@ pub struct PackageInfo {
     pub version: Version<String>,
     pub revision: String,
     pub architecture: String,
+    /// Checksum of the package file, if the repository publishes it
+    #[serde(default)]
+    pub sha256: Option<String>,
 }
 
 impl PackageCandidate {
@@ -63,6 +66,18 @@ impl PackageCandidate {
         }
         buf.push('\n');
     }
+    /// Explains why packages are or aren't available
+    pub fn reason(&self) -> String {
+        if self.supported {
+            format!("packages for {} {} ({}) are available",
+                self.distro_name, self.distro_version, ARCH)
+        } else if !self.distro_supported {
+            "native packages are not built for this platform".into()
+        } else {
+            format!("no packages are built for {} {} ({})",
+                self.distro_name, self.distro_version, ARCH)
+        }
+    }
     pub fn make_method<'os, O>(&self, os: &'os O)
         -> anyhow::Result<PackageMethod<'os, O>>
         where O: CurrentOs + ?Sized,
@@ -121,14 +136,82 @@ pub fn find_version(haystack: &RepositoryInfo, ver: &VersionQuery)
             major_version: major,
             version: target.version.clone(),
             revision: target.revision.clone(),
+            sha256: target.sha256.clone(),
         })
     } else {
         anyhow::bail!("Version {} not found", ver)
     }
 }
 
+/// Checks that the `found` version has `current` major version
+pub fn check_compatible(current: &Version<String>, found: VersionResult)
+    -> anyhow::Result<VersionResult>
+{
+    if &found.major_version != current {
+        anyhow::bail!("Latest version {} found for {} belongs to \
+            another major version {}",
+            found.version, current, found.major_version);
+    }
+    Ok(found)
+}
+
 impl PackageInfo {
     pub fn full_version(&self) -> Version<String> {
         Version(format!("{}-{}", self.version, self.revision))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::server::detect::{VersionQuery, VersionResult};
+    use crate::server::version::Version;
+
+    use super::{find_version, check_compatible, RepositoryInfo, PackageInfo};
+
+    fn package(slot: &str, version: &str, revision: &str) -> PackageInfo {
+        PackageInfo {
+            basename: "edgedb-server".into(),
+            slot: Some(Version(slot.into())),
+            version: Version(version.into()),
+            revision: revision.into(),
+            architecture: "x86_64".into(),
+            sha256: None,
+        }
+    }
+
+    #[test]
+    fn test_compatible_version() {
+        let repo = RepositoryInfo {
+            packages: vec![
+                package("1-alpha5", "1.0a5", "2020091700"),
+                package("1-alpha5", "1.0a5", "2020100200"),
+                package("1-alpha6", "1.0a6", "2020100500"),
+                package("1-alpha6", "1.0a6", "2020101200"),
+            ],
+        };
+        let current = Version("1-alpha5".into());
+        let found = find_version(&repo,
+            &VersionQuery::Stable(Some(current.clone()))).unwrap();
+        let found = check_compatible(&current, found).unwrap();
+        assert_eq!(found.major_version, current);
+        assert_eq!(found.revision, "2020100200");
+
+        let latest = find_version(&repo, &VersionQuery::Stable(None))
+            .unwrap();
+        assert_eq!(latest.major_version, Version("1-alpha6".into()));
+        assert!(check_compatible(&current, latest).is_err());
+    }
+
+    #[test]
+    fn test_incompatible_major() {
+        let result = VersionResult {
+            package_name: "edgedb-server".into(),
+            major_version: Version("1-beta1".into()),
+            version: Version("1.0b1".into()),
+            revision: "2020111000".into(),
+            sha256: None,
+        };
+        assert!(check_compatible(&Version("1-alpha7".into()), result)
+                .is_err());
+    }
+}
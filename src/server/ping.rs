@@ -0,0 +1,96 @@
+use std::time::{Duration, Instant};
+
+use async_std::future::timeout;
+use async_std::stream::StreamExt;
+use async_std::task;
+use edgedb_protocol::value::Value;
+use serde::Serialize;
+
+use crate::commands::ExitCode;
+use crate::server::options::{Ping, OutputFormat, ConnectMethod};
+use crate::server::print_serialized;
+use crate::server::upgrade::{self, all_instances, Instance};
+
+
+#[derive(Serialize, Debug)]
+struct PingResult {
+    instance: String,
+    healthy: bool,
+    #[serde(skip_serializing_if="Option::is_none")]
+    latency_ms: Option<f64>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    server_version: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    error: Option<String>,
+}
+
+/// Returns query latency and version reported by the running server
+pub async fn query_version(inst: &Instance,
+    connect_method: Option<ConnectMethod>)
+    -> anyhow::Result<(Duration, String)>
+{
+    let socket = inst.get_control().and_then(|ctl| ctl.get_socket(true));
+    let (_, mut cli) = upgrade::connect(
+        inst, socket, connect_method).await?;
+    let start = Instant::now();
+    let mut items = cli.query::<String>(
+        "SELECT sys::get_version_as_str()",
+        &Value::empty_tuple(),
+    ).await?;
+    let mut version = String::new();
+    while let Some(item) = items.next().await.transpose()? {
+        version = item;
+    }
+    Ok((start.elapsed(), version))
+}
+
+async fn ping_instance(inst: &Instance, options: &Ping)
+    -> anyhow::Result<(Duration, String)>
+{
+    let query = query_version(inst, options.connect_method);
+    match timeout(options.timeout, query).await {
+        Ok(result) => result,
+        Err(_) => anyhow::bail!("no response within {}",
+            humantime::format_duration(options.timeout)),
+    }
+}
+
+pub fn ping(options: &Ping) -> anyhow::Result<()> {
+    let inst = all_instances()?.into_iter()
+        .find(|inst| inst.name == options.name)
+        .ok_or_else(|| anyhow::anyhow!("Instance {:?} not found",
+                                       options.name))?;
+    let result = task::block_on(ping_instance(&inst, options));
+    match options.format {
+        OutputFormat::Human => {
+            let (latency, version) = result?;
+            println!("Instance {:?} is healthy: EdgeDB {}, \
+                query took {:.1}ms",
+                inst.name, version, latency.as_secs_f64() * 1000.0);
+        }
+        format => {
+            let healthy = result.is_ok();
+            let report = match result {
+                Ok((latency, version)) => PingResult {
+                    instance: inst.name.clone(),
+                    healthy: true,
+                    latency_ms: Some(latency.as_secs_f64() * 1000.0),
+                    server_version: Some(version),
+                    error: None,
+                },
+                Err(e) => PingResult {
+                    instance: inst.name.clone(),
+                    healthy: false,
+                    latency_ms: None,
+                    server_version: None,
+                    error: Some(format!("{:#}", e)),
+                },
+            };
+            print_serialized(format, &report)?;
+            if !healthy {
+                return Err(ExitCode::new(1).into());
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,136 @@
+//! Allocation of ports for new instances
+//!
+//! A port is considered used if it's reserved in the port mapping
+//! (`instance_ports.json`), recorded in metadata of an instance, or bound
+//! by any process at the moment.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::TcpListener;
+use std::str::FromStr;
+
+use anyhow::Context;
+
+use crate::server::control::read_metadata;
+use crate::server::init::{data_path, read_ports};
+use crate::server::is_valid_name;
+use crate::server::options::NextPort;
+
+
+pub const MIN_PORT: u16 = 10700;
+
+/// Inclusive range of ports, written as `10700-10800`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl Default for PortRange {
+    fn default() -> PortRange {
+        PortRange { start: MIN_PORT, end: u16::MAX }
+    }
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<PortRange> {
+        let (start, end) = match s.find('-') {
+            Some(idx) => (&s[..idx], &s[idx+1..]),
+            None => anyhow::bail!("port range must be `<first>-<last>`"),
+        };
+        let start = start.trim().parse()
+            .with_context(|| format!("invalid port {:?}", start))?;
+        let end = end.trim().parse()
+            .with_context(|| format!("invalid port {:?}", end))?;
+        if start > end {
+            anyhow::bail!("port range {}-{} is empty", start, end);
+        }
+        Ok(PortRange { start, end })
+    }
+}
+
+/// Ports used by instances, with names of the instances using them
+pub fn used_ports() -> anyhow::Result<BTreeMap<u16, String>> {
+    let mut ports = BTreeMap::new();
+    let base = data_path(false)?;
+    if base.exists() {
+        for item in fs::read_dir(&base)? {
+            let item = item?;
+            let name = match item.file_name().to_str() {
+                Some(name) if is_valid_name(name) => name.to_string(),
+                _ => continue,
+            };
+            // broken instances are reported by `doctor`, here the port
+            // mapping is enough for them
+            if let Ok(meta) = read_metadata(&item.path()) {
+                ports.insert(meta.port, name);
+            }
+        }
+    }
+    for (name, port) in read_ports()? {
+        ports.entry(port).or_insert(name);
+    }
+    Ok(ports)
+}
+
+pub fn is_bound(port: u16) -> bool {
+    TcpListener::bind(("127.0.0.1", port)).is_err()
+}
+
+/// Picks the first port in `range` that is neither used by an instance
+/// nor bound by another process
+pub fn find_free(range: &PortRange, used: &BTreeMap<u16, String>,
+    is_bound: impl Fn(u16) -> bool)
+    -> anyhow::Result<u16>
+{
+    (range.start..=range.end)
+        .find(|port| !used.contains_key(port) && !is_bound(*port))
+        .with_context(|| format!("no free ports in range {}-{}",
+                                 range.start, range.end))
+}
+
+pub fn allocate(range: &PortRange) -> anyhow::Result<u16> {
+    find_free(range, &used_ports()?, is_bound)
+}
+
+pub fn next_port(options: &NextPort) -> anyhow::Result<()> {
+    println!("{}", allocate(&options.port_range.unwrap_or_default())?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::{find_free, PortRange};
+
+    #[test]
+    fn test_range() {
+        assert_eq!("10700-10800".parse::<PortRange>().unwrap(),
+                   PortRange { start: 10700, end: 10800 });
+        assert!("10800-10700".parse::<PortRange>().is_err());
+        assert!("10700".parse::<PortRange>().is_err());
+        assert!("10700-99999".parse::<PortRange>().is_err());
+        let range = PortRange { start: 10700, end: 10800 };
+        assert!(range.contains(10700) && range.contains(10800));
+        assert!(!range.contains(5656));
+    }
+
+    #[test]
+    fn test_find_free() {
+        let range = PortRange { start: 10700, end: 10703 };
+        let mut used = BTreeMap::new();
+        used.insert(10700, "first".to_string());
+        used.insert(10702, "second".to_string());
+        assert_eq!(find_free(&range, &used, |_| false).unwrap(), 10701);
+        assert_eq!(find_free(&range, &used, |p| p == 10701).unwrap(), 10703);
+        assert!(find_free(&range, &used, |p| p > 10700).is_err());
+    }
+}
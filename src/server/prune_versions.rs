@@ -0,0 +1,187 @@
+//! `edgedb server prune-versions`: uninstall versions no instance uses
+//!
+//! A version is used by an instance if the major version and the channel
+//! (stable or nightly) in its metadata match the package, and the instance
+//! is installed using the same method. Versions of backups of data
+//! directories (needed by `restore-backup`) and both versions of upgrades
+//! in progress are used too.
+
+use std::collections::BTreeSet;
+use std::fs;
+
+use anyhow::Context;
+
+use crate::server::confirm;
+use crate::server::control;
+use crate::server::detect::{self, InstalledPackage};
+use crate::server::init::data_path;
+use crate::server::methods::InstallMethod;
+use crate::server::options::PruneVersions;
+use crate::server::upgrade::{read_instances, is_dir_entry, Instance};
+use crate::server::upgrade::UpgradeMeta;
+use crate::server::version::Version;
+
+type Used = (InstallMethod, Version<String>, bool);
+
+
+/// Packages not used by any instance of `used` (major version and nightly
+/// flag), except for the `keep_latest` latest packages
+fn unused_versions<'a>(installed: &'a [InstalledPackage],
+    used: &BTreeSet<(Version<String>, bool)>, keep_latest: usize)
+    -> Vec<&'a InstalledPackage>
+{
+    let mut packages = installed.iter().collect::<Vec<_>>();
+    packages.sort_by(|a, b| {
+        (&b.major_version, b.full_version())
+            .cmp(&(&a.major_version, a.full_version()))
+    });
+    packages.into_iter()
+        .skip(keep_latest)
+        .filter(|pkg| {
+            !used.contains(&(pkg.major_version.clone(), pkg.is_nightly()))
+        })
+        .collect()
+}
+
+/// Versions used by instances, their backups and upgrades in progress
+fn used_versions(instances: &[Instance]) -> anyhow::Result<Vec<Used>> {
+    let mut used = Vec::new();
+    for inst in instances {
+        let method = &inst.meta.method;
+        used.push((method.clone(), inst.meta.version.clone(),
+                   inst.meta.nightly));
+        let marker = inst.data_dir.join("UPGRADE_IN_PROGRESS");
+        if !marker.exists() {
+            continue;
+        }
+        let upgrade = fs::read(&marker).ok()
+            .and_then(|data| {
+                serde_json::from_slice::<UpgradeMeta>(&data).ok()
+            })
+            .with_context(|| format!("cannot read {} (remove it if no \
+                upgrade is running)", marker.display()))?;
+        // marker doesn't record the channel, so both are kept
+        for version in &[upgrade.source, upgrade.target] {
+            for &nightly in &[false, true] {
+                used.push((method.clone(), version.clone(), nightly));
+            }
+        }
+    }
+    let base = data_path(false)?;
+    if !base.exists() {
+        return Ok(used);
+    }
+    for item in fs::read_dir(&base)? {
+        let item = item?;
+        // `<name>.backup` and backups put aside, like `<name>.backup.<ts>`
+        let is_backup = item.file_name().to_str()
+            .map(|name| name.contains(".backup"))
+            .unwrap_or(false);
+        if !is_backup || !is_dir_entry(&item) {
+            continue;
+        }
+        let meta = control::read_metadata(&item.path())
+            .with_context(|| format!("cannot read metadata of backup {} \
+                (remove the backup if it isn't needed)",
+                item.path().display()))?;
+        used.push((meta.method, meta.version, meta.nightly));
+    }
+    Ok(used)
+}
+
+pub fn prune_versions(options: &PruneVersions) -> anyhow::Result<()> {
+    // with unreadable metadata, a version used by the instance could be
+    // removed
+    let instances = read_instances(true)?;
+    let all_used = used_versions(&instances)?;
+    let os = detect::current_os()?;
+    let methods = os.get_available_methods()?.instantiate_all(&*os, true)?;
+    let mut unused = Vec::new();
+    for (meth_name, method) in &methods {
+        let used = all_used.iter()
+            .filter(|(method, _, _)| method == meth_name)
+            .map(|(_, version, nightly)| (version.clone(), *nightly))
+            .collect::<BTreeSet<_>>();
+        let installed = method.installed_versions()?;
+        for pkg in unused_versions(installed, &used, options.keep_latest) {
+            unused.push((meth_name, method, pkg));
+        }
+    }
+    if unused.is_empty() {
+        println!("All installed versions are used by instances");
+        return Ok(());
+    }
+    println!("Versions not used by any instance:");
+    for (meth_name, _, pkg) in &unused {
+        println!("  {} ({}-{}, {})", pkg.major_version,
+            pkg.version, pkg.revision, meth_name.short_name());
+    }
+    if options.dry_run {
+        println!("Dry run, nothing is uninstalled.");
+        return Ok(());
+    }
+    if !options.non_interactive {
+        let question = format!("Uninstall {} version(s)?", unused.len());
+        if !confirm(&question)? {
+            anyhow::bail!("Canceled by user");
+        }
+    }
+    let mut failed = Vec::new();
+    for (meth_name, method, pkg) in &unused {
+        match method.uninstall(pkg) {
+            Ok(()) => {
+                println!("Uninstalled EdgeDB {} ({})",
+                    pkg.major_version, meth_name.short_name());
+            }
+            Err(e) => {
+                log::error!("Cannot uninstall EdgeDB {}: {:#}",
+                    pkg.major_version, e);
+                failed.push(pkg.major_version.to_string());
+            }
+        }
+    }
+    if !failed.is_empty() {
+        anyhow::bail!("Failed to uninstall: {}", failed.join(", "));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use crate::server::detect::InstalledPackage;
+    use crate::server::version::Version;
+
+    use super::unused_versions;
+
+    fn package(major: &str, version: &str) -> InstalledPackage {
+        InstalledPackage {
+            package_name: "edgedb-server".into(),
+            major_version: Version(major.into()),
+            version: Version(version.into()),
+            revision: "2020100500".into(),
+        }
+    }
+
+    #[test]
+    fn test_unused_versions() {
+        let installed = vec![
+            package("1-alpha5", "1.0a5"),
+            package("1-alpha7", "1.0a7"),
+            package("1-alpha6", "1.0a6"),
+        ];
+        let mut used = BTreeSet::new();
+        used.insert((Version("1-alpha6".to_string()), false));
+        let names = |keep| unused_versions(&installed, &used, keep)
+            .iter().map(|pkg| pkg.major_version.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names(0), ["1-alpha7", "1-alpha5"]);
+        assert_eq!(names(1), ["1-alpha5"]);
+        assert!(names(3).is_empty());
+        // nightly instance doesn't keep the stable package
+        let mut used = BTreeSet::new();
+        used.insert((Version("1-alpha6".to_string()), true));
+        assert_eq!(unused_versions(&installed, &used, 0).len(), 3);
+    }
+}
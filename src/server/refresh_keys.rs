@@ -0,0 +1,69 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use anyhow::Context;
+use async_std::task;
+
+use crate::process;
+use crate::server::detect;
+use crate::server::install::{KEY_FILE_URL, KEY_FINGERPRINT};
+use crate::server::methods::InstallMethod;
+use crate::server::options::RefreshKeys;
+use crate::server::remote;
+
+
+fn normalize_fingerprint(fpr: &str) -> String {
+    fpr.chars()
+        .filter(|c| !c.is_whitespace())
+        .flat_map(|c| c.to_uppercase())
+        .collect()
+}
+
+/// Returns fingerprints of all the keys in the armored key file
+fn key_fingerprints(key: &str) -> anyhow::Result<Vec<String>> {
+    let mut cmd = Command::new("gpg");
+    cmd.arg("--with-colons");
+    cmd.arg("--import-options").arg("show-only");
+    cmd.arg("--import");
+    cmd.stdin(Stdio::piped());
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
+    let mut child = cmd.spawn()
+        .with_context(|| format!("error running {}",
+                                 process::describe(&cmd)))?;
+    child.stdin.take().unwrap().write_all(key.as_bytes())?;
+    let out = child.wait_with_output()?;
+    if !out.status.success() {
+        anyhow::bail!("process {} failed: {}: {}",
+            process::describe(&cmd), out.status,
+            String::from_utf8_lossy(&out.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).lines()
+        .filter_map(|line| line.strip_prefix("fpr:"))
+        .filter_map(|fields| fields.split(':').nth(8))
+        .map(normalize_fingerprint)
+        .collect())
+}
+
+pub fn refresh_keys(options: &RefreshKeys) -> anyhow::Result<()> {
+    if !options.repository_url.is_empty() {
+        remote::set_mirrors(options.repository_url.clone());
+    }
+    let key = task::block_on(remote::get_string(KEY_FILE_URL))
+        .context("downloading key file")?;
+    let expected = normalize_fingerprint(
+        options.fingerprint.as_deref().unwrap_or(KEY_FINGERPRINT));
+    let fingerprints = key_fingerprints(&key)
+        .context("cannot determine fingerprint of the key")?;
+    if !fingerprints.contains(&expected) {
+        anyhow::bail!("Key file {} has fingerprints {}, \
+            expected {}. Key is NOT imported.",
+            KEY_FILE_URL, fingerprints.join(", "), expected);
+    }
+    let os = detect::current_os()?;
+    let avail = os.get_available_methods()?;
+    let method = os.make_method(&InstallMethod::Package, &avail)?;
+    method.refresh_keys(&key)?;
+    eprintln!("Repository key {} is imported", expected);
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use std::fs;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_std::future::timeout;
+use async_std::task;
+use clap::Clap;
+
+use crate::server::options::{self, Reinit, Upgrade};
+use crate::server::ping::query_version;
+use crate::server::upgrade::{self, all_instances};
+
+
+/// Instances that respond within this time are considered healthy
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn reinit(options: &Reinit) -> anyhow::Result<()> {
+    let mut inst = all_instances()?.into_iter()
+        .find(|inst| inst.name == options.name)
+        .ok_or_else(|| anyhow::anyhow!("Instance {:?} not found",
+                                       options.name))?;
+    let mut ctl = inst.get_control()?;
+    let running = ctl.get_status()?.is_running();
+    if !options.force {
+        if !running {
+            // a stopped server doesn't respond, even if it's healthy
+            anyhow::bail!("Instance {:?} is not running, so it can't be \
+                checked that its data directory is corrupt. Start it \
+                first, or use `--force` to rebuild it anyway.", inst.name);
+        }
+        let healthy = task::block_on(
+            timeout(HEALTH_TIMEOUT, query_version(&inst, None)))
+            .map(|result| result.is_ok())
+            .unwrap_or(false);
+        if healthy {
+            anyhow::bail!("Instance {:?} responds to queries, so its data \
+                directory doesn't look corrupt. Use `--force` to rebuild \
+                it anyway.", inst.name);
+        }
+    }
+    if running {
+        // data directory is moved below, so the server must be gone
+        ctl.stop(&options::Stop {
+            name: inst.name.clone(),
+            wait: true,
+            wait_timeout: None,
+        })?;
+    }
+    // data directory is moved to `{name}.backup`, so the backup of the last
+    // upgrade is kept aside like `restore-backup` does
+    let backup = inst.data_dir.with_file_name(
+        format!("{}.backup", inst.name));
+    if backup.exists() {
+        let aside = backup.with_file_name(format!("{}.backup.{}", inst.name,
+            SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()));
+        log::info!("Moving previous backup to {}", aside.display());
+        fs::rename(&backup, &aside)?;
+    }
+    // reinit uses the same defaults as `edgedb server upgrade`
+    let mut upgrade_options = Upgrade::try_parse_from(&["upgrade"])?;
+    if let Some(path) = &options.server_binary {
+        upgrade::check_server_binary(path)?;
+        upgrade_options.server_binary = Some(path.clone());
+    }
+    upgrade::reinit_from_dump(&mut inst, options.dump_path.clone(),
+                              &upgrade_options)?;
+    eprintln!("Instance {:?} is rebuilt from the dump. Previous data \
+        directory is kept at {}", inst.name, backup.display());
+    Ok(())
+}
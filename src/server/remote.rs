@@ -1,11 +1,34 @@
-use std::path::Path;
+use std::future::Future;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
 use anyhow::Context;
 use async_std::fs;
-use async_std::io;
+use async_std::io::{self, prelude::WriteExt};
+use async_std::task;
 
 use fn_error_context::context;
+use once_cell::sync::OnceCell;
 use serde::de::DeserializeOwned;
 
+use crate::server::install::history::sha256_file;
+
+
+pub const BASE_URL: &str = "https://packages.edgedb.com";
+const ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Timeout of every read and write with `--no-verify-tls`
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_REDIRECTS: usize = 5;
+const MAX_HEADER_SIZE: usize = 65536;
+
+static MIRRORS: OnceCell<Vec<String>> = OnceCell::new();
+static NO_VERIFY_TLS: AtomicBool = AtomicBool::new(false);
+
 
 #[derive(Debug, thiserror::Error)]
 #[error("HTTP error: {0}")]
@@ -46,13 +69,329 @@ impl<T, E> HttpErrorExt<T> for Result<T, E>
     }
 }
 
+/// Use specified mirrors instead of `packages.edgedb.com`
+///
+/// Mirrors are tried in order. Only the first call has any effect.
+pub fn set_mirrors(urls: Vec<String>) {
+    let urls = urls.into_iter()
+        .map(|url| url.trim_end_matches('/').to_string())
+        .collect();
+    if MIRRORS.set(urls).is_err() {
+        log::warn!("Repository mirrors are already configured");
+    }
+}
+
+fn mirror_urls(url: &str) -> Vec<String> {
+    match (MIRRORS.get(), url.strip_prefix(BASE_URL)) {
+        (Some(mirrors), Some(path)) if !mirrors.is_empty() => {
+            mirrors.iter().map(|mirror| format!("{}{}", mirror, path))
+                .collect()
+        }
+        _ => vec![url.to_string()],
+    }
+}
+
+/// Don't verify TLS certificates of the repository (and its mirrors)
+///
+/// This is only meant for internal mirrors with self-signed certificates.
+pub fn disable_tls_verification() {
+    log::warn!("TLS certificate verification is DISABLED \
+        (`--no-verify-tls`). Anyone on the network path to the repository \
+        can replace package indexes, keys and packages.");
+    NO_VERIFY_TLS.store(true, Ordering::SeqCst);
+}
+
+fn verify_tls() -> bool {
+    !NO_VERIFY_TLS.load(Ordering::SeqCst)
+}
+
+/// Status line and headers of a response fetched by `get_unverified`
+struct Head {
+    status: u16,
+    /// Names are lowercase
+    headers: Vec<(String, String)>,
+}
+
+impl Head {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| &value[..])
+    }
+    /// Checks that `received` bytes of the body is as much as the server
+    /// announced. With HTTP/1.0 the body also ends when the connection is
+    /// closed, e.g. by a network failure
+    fn check_length(&self, received: u64) -> anyhow::Result<()> {
+        if let Some(length) = self.header("content-length") {
+            let length: u64 = length.parse()
+                .with_context(|| format!("invalid Content-Length {:?}",
+                                         length))?;
+            if received != length {
+                anyhow::bail!("response is truncated: received {} bytes \
+                    of {}", received, length);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn split_url(url: &str) -> anyhow::Result<(&str, &str)> {
+    let rest = url.strip_prefix("https://")
+        .ok_or_else(|| anyhow::anyhow!("only https URLs are supported \
+            with `--no-verify-tls`"))?;
+    match rest.find('/') {
+        Some(idx) => Ok((&rest[..idx], &rest[idx..])),
+        None => Ok((rest, "/")),
+    }
+}
+
+/// Splits `host:port` or `[ipv6]:port`, port is 443 if not specified
+fn split_authority(authority: &str) -> anyhow::Result<(&str, u16)> {
+    let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+        let end = rest.find(']')
+            .ok_or_else(|| anyhow::anyhow!("invalid host {:?}", authority))?;
+        let port = &rest[end+1..];
+        if !port.is_empty() && !port.starts_with(':') {
+            anyhow::bail!("invalid host {:?}", authority);
+        }
+        (&rest[..end], port.strip_prefix(':'))
+    } else {
+        match authority.rfind(':') {
+            Some(idx) => (&authority[..idx], Some(&authority[idx+1..])),
+            None => (authority, None),
+        }
+    };
+    let port = match port {
+        Some(port) => port.parse()
+            .with_context(|| format!("invalid port in {:?}", authority))?,
+        None => 443,
+    };
+    Ok((host, port))
+}
+
+/// Connects to the first address of the `host` that accepts connections
+fn connect(host: &str, port: u16) -> anyhow::Result<TcpStream> {
+    let addresses = (host, port).to_socket_addrs()
+        .with_context(|| format!("cannot resolve {}", host))?;
+    let mut error = None;
+    for address in addresses {
+        match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+            Ok(tcp) => return Ok(tcp),
+            Err(e) => {
+                log::debug!("Cannot connect to {}: {}", address, e);
+                error = Some(anyhow::Error::new(e)
+                    .context(format!("cannot connect to {}", address)));
+            }
+        }
+    }
+    Err(error.unwrap_or_else(|| anyhow::anyhow!("cannot resolve {}", host)))
+}
+
+fn read_head(reader: &mut impl BufRead) -> anyhow::Result<Head> {
+    let mut lines = Vec::new();
+    let mut size = 0;
+    loop {
+        let mut line = String::new();
+        let limit = (MAX_HEADER_SIZE - size) as u64;
+        let len = reader.by_ref().take(limit).read_line(&mut line)?;
+        if len == 0 {
+            anyhow::bail!("malformed HTTP response");
+        }
+        size += len;
+        if !line.ends_with('\n') {
+            anyhow::bail!("HTTP headers are too large");
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line.to_string());
+    }
+    let mut lines = lines.into_iter();
+    let status = lines.next()
+        .and_then(|line| line.split_whitespace().nth(1)?.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("malformed HTTP status line"))?;
+    let headers = lines
+        .filter_map(|line| {
+            let idx = line.find(':')?;
+            Some((line[..idx].trim().to_ascii_lowercase(),
+                  line[idx+1..].trim().to_string()))
+        })
+        .collect();
+    Ok(Head { status, headers })
+}
+
+/// Plain HTTP/1.0 GET over a TLS connection that accepts any certificate
+///
+/// The HTTP client used otherwise can't be configured to skip
+/// verification. HTTP/1.0 is used so that the body is never chunked and
+/// ends with the connection (callers check it with `Head::check_length`).
+/// Redirects are followed, but only to https URLs. Returns the head of the
+/// response and the reader of its body.
+fn get_unverified(url: &str, headers: &[(&str, String)])
+    -> anyhow::Result<(Head, Box<dyn Read + Send>)>
+{
+    let mut url = url.to_string();
+    for _ in 0..=MAX_REDIRECTS {
+        let (authority, path) = split_url(&url)?;
+        let (host, port) = split_authority(authority)?;
+        let tcp = connect(host, port)?;
+        tcp.set_read_timeout(Some(IO_TIMEOUT))?;
+        tcp.set_write_timeout(Some(IO_TIMEOUT))?;
+        // any certificate is accepted, for any host name too (OpenSSL
+        // skips verification altogether)
+        let connector = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .build()?;
+        let mut stream = connector.connect(host, tcp)?;
+        write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\n\
+            User-Agent: edgedb-cli\r\n", path, authority)?;
+        for (name, value) in headers {
+            write!(stream, "{}: {}\r\n", name, value)?;
+        }
+        write!(stream, "\r\n")?;
+        let mut reader = BufReader::new(stream);
+        let head = read_head(&mut reader)?;
+        let location = match head.header("location") {
+            Some(location) if is_redirect(head.status) => location,
+            _ => return Ok((head, Box::new(reader))),
+        };
+        let next = if location.starts_with("https://") {
+            location.to_string()
+        } else if location.starts_with('/') {
+            format!("https://{}{}", authority, location)
+        } else {
+            anyhow::bail!("unsupported redirect from {:?} to {:?}",
+                url, location);
+        };
+        log::debug!("Redirected {} -> {}", url, next);
+        url = next;
+    }
+    anyhow::bail!("too many redirects");
+}
+
+fn is_redirect(status: u16) -> bool {
+    matches!(status, 301 | 302 | 303 | 307 | 308)
+}
+
+/// Fetches the whole body with `get_unverified`, it's only read if the
+/// status is `200 OK`
+async fn fetch_unverified(url: &str) -> anyhow::Result<(u16, Vec<u8>)> {
+    let url = url.to_string();
+    task::spawn_blocking(move || {
+        let (head, mut reader) = get_unverified(&url, &[])?;
+        let mut body = Vec::new();
+        if head.status == 200 {
+            reader.read_to_end(&mut body)?;
+            head.check_length(body.len() as u64)?;
+        }
+        Ok((head.status, body))
+    }).await
+}
+
+/// Downloads `url` into the `part_path(dest)` file with `get_unverified`,
+/// continuing the partial download at `offset` if the server sends that
+/// range. Returns head of the response, the file is written only if it's
+/// `200 OK` or a continuation
+fn download_unverified(url: &str, offset: u64, validator: Option<&str>,
+    dest: &Path)
+    -> anyhow::Result<Head>
+{
+    let mut headers = Vec::new();
+    if offset > 0 {
+        headers.push(("Range", format!("bytes={}-", offset)));
+        if let Some(validator) = validator {
+            headers.push(("If-Range", validator.to_string()));
+        }
+    }
+    let (head, mut reader) = get_unverified(url, &headers)?;
+    let append = match continues_at(head.status, offset,
+                                    head.header("content-range"))
+    {
+        Some(append) => append,
+        None => return Ok(head),
+    };
+    let part = &part_path(dest);
+    let write_err = || format!("writing {:?}", part.display());
+    if !append {
+        start_partial(dest,
+            head.header("etag").or_else(|| head.header("last-modified")))?;
+    }
+    let mut file = if append {
+        std::fs::OpenOptions::new().append(true).open(part)
+            .with_context(write_err)?
+    } else {
+        std::fs::File::create(part).with_context(write_err)?
+    };
+    let received = std::io::copy(&mut reader, &mut file)
+        .with_context(write_err)?;
+    // received part is kept, so the next attempt continues from there
+    head.check_length(received)?;
+    Ok(head)
+}
+
+/// Same as `get_unverified` but fails unless status is `200 OK`
+async fn fetch_unverified_ok(url: &str) -> anyhow::Result<Vec<u8>> {
+    match fetch_unverified(url).await.url_context(url)? {
+        (200, body) => Ok(body),
+        (status, _) => Err(anyhow::anyhow!("HTTP failure: {}", status))
+            .url_context(url),
+    }
+}
+
+/// URL that is fetched first (in the first mirror if any are configured)
+pub fn resolve_url(url: &str) -> String {
+    mirror_urls(url).swap_remove(0)
+}
+
+/// Tries every mirror of the URL, retrying failures with an exponential
+/// backoff
+async fn with_retry<T, F, Fut>(url: &str, mut fetch: F)
+    -> Result<T, anyhow::Error>
+    where F: FnMut(String) -> Fut,
+          Fut: Future<Output=Result<T, anyhow::Error>>,
+{
+    let urls = mirror_urls(url);
+    let mirrors = urls.len() > 1;
+    let mut error = None;
+    for url in urls {
+        let mut delay = RETRY_DELAY;
+        for attempt in 1..=ATTEMPTS {
+            match fetch(url.clone()).await {
+                Ok(value) => {
+                    if mirrors || attempt > 1 {
+                        log::info!("Fetched {} (attempt {})", url, attempt);
+                    }
+                    return Ok(value);
+                }
+                Err(e) => {
+                    log::warn!("Attempt {}/{} to fetch {} failed: {:#}",
+                        attempt, ATTEMPTS, url, e);
+                    error = Some(e);
+                    if attempt < ATTEMPTS {
+                        task::sleep(delay).await;
+                        delay *= 2;
+                    }
+                }
+            }
+        }
+    }
+    Err(error.expect("at least one attempt is made"))
+}
+
 #[context("failed to fetch URL: {}", url)]
 pub async fn get_string(url: &str)
     -> Result<String, anyhow::Error>
 {
-    log::info!("Fetching {}", url);
-    Ok(surf::get(url).await.ensure200(url)?
-        .body_string().await.map_err(HttpError).url_context(url)?)
+    with_retry(url, |url| async move {
+        log::info!("Fetching {}", url);
+        if !verify_tls() {
+            let body = fetch_unverified_ok(&url).await?;
+            return Ok(String::from_utf8(body).url_context(&url)?);
+        }
+        Ok(surf::get(&url).await.ensure200(&url)?
+            .body_string().await.map_err(HttpError).url_context(&url)?)
+    }).await
 }
 
 #[context("failed to fetch JSON at URL: {}", url)]
@@ -60,27 +399,370 @@ pub async fn get_json_opt<T>(url: &str, context: &'static str)
     -> Result<Option<T>, anyhow::Error>
     where T: DeserializeOwned,
 {
-    log::info!("Fetching optional JSON at {}", url);
-    match surf::get(url).await {
-        Ok(res) if res.status() == 404 => Ok(None),
-        Ok(res) if res.status() != 200
-            => Err(HttpFailure(res)).context(context),
-        Ok(mut res) => Ok(Some(res.body_json::<T>().await.context(context)?)),
-        Err(e) => Err(HttpError(e)).context(context),
+    with_retry(url, |url| async move {
+        log::info!("Fetching optional JSON at {}", url);
+        if !verify_tls() {
+            return match fetch_unverified(&url).await.context(context)? {
+                (404, _) => Ok(None),
+                (200, body) => {
+                    Ok(Some(serde_json::from_slice(&body).context(context)?))
+                }
+                (status, _) => {
+                    Err(anyhow::anyhow!("HTTP failure: {}", status))
+                        .context(context)
+                }
+            };
+        }
+        match surf::get(&url).await {
+            Ok(res) if res.status() == 404 => Ok(None),
+            Ok(res) if res.status() != 200
+                => Err(HttpFailure(res)).context(context),
+            Ok(mut res) => {
+                Ok(Some(res.body_json::<T>().await.context(context)?))
+            }
+            Err(e) => Err(HttpError(e)).context(context),
+        }
+    }).await
+}
+
+/// File where `get_file` downloads to before the download is complete
+pub fn part_path(dest: &Path) -> PathBuf {
+    let mut name = dest.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    dest.with_file_name(name)
+}
+
+/// File with the `ETag` (or `Last-Modified`) of the partial download, so
+/// it's only resumed if the file on the server is still the same
+fn validator_path(dest: &Path) -> PathBuf {
+    let mut name = dest.file_name().unwrap_or_default().to_os_string();
+    name.push(".part.etag");
+    dest.with_file_name(name)
+}
+
+/// Size of the interrupted download of `dest`, which `get_file` resumes
+pub fn partial_size(dest: &Path) -> Option<u64> {
+    std::fs::metadata(part_path(dest)).ok()
+        .map(|meta| meta.len())
+        .filter(|&size| size > 0)
+}
+
+fn discard_partial(dest: &Path) -> anyhow::Result<()> {
+    for path in &[part_path(dest), validator_path(dest)] {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| {
+                    format!("cannot remove {:?}", path.display())
+                });
+            }
+            _ => {}
+        }
     }
+    Ok(())
 }
 
+/// Records the validator of the download that starts from scratch, before
+/// its body is written, so the download can be resumed if interrupted
+fn start_partial(dest: &Path, validator: Option<&str>) -> anyhow::Result<()> {
+    let path = validator_path(dest);
+    let result = match validator {
+        Some(validator) => std::fs::write(&path, validator),
+        // validator of the previous download doesn't match this one
+        None => match std::fs::remove_file(&path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        },
+    };
+    result.with_context(|| format!("writing {:?}", path.display()))
+}
+
+/// Whether the response to the request of the file starting at `offset`
+/// continues the partial download (`Some(true)`), replaces it
+/// (`Some(false)`), or should not be written at all (`None`)
+fn continues_at(status: u16, offset: u64, content_range: Option<&str>)
+    -> Option<bool>
+{
+    match status {
+        200 => Some(false),
+        206 if offset > 0 => {
+            // e.g. `bytes 100-199/200`
+            let start = content_range?.strip_prefix("bytes ")?
+                .split('-').next()?
+                .trim().parse::<u64>().ok()?;
+            if start == offset { Some(true) } else { None }
+        }
+        _ => None,
+    }
+}
+
+/// Downloads the file into `<dest>.part`, resuming the previous download
+/// if that file exists, and renames it to `dest` when complete
+///
+/// Downloads are resumed only if `sha256` is known, so that the complete
+/// file is verified, and only while the `ETag` of the file on the server is
+/// the same (using `If-Range`). Servers not supporting ranges send the
+/// whole file, which replaces the partial one.
 #[context("failed to download file at URL: {}", url)]
-pub async fn get_file(dest: impl AsRef<Path>, url: &str)
+pub async fn get_file(dest: impl AsRef<Path>, url: &str, sha256: Option<&str>)
     -> Result<(), anyhow::Error>
 {
     let dest = dest.as_ref();
-    log::info!("Downloading {} -> {}", url, dest.display());
-    let response = surf::get(url).await.ensure200(url)?;
-    let file = fs::File::create(dest).await
-        .with_context(|| format!("writing {:?}", dest.display()))?;
-    io::copy(response, file).await
-        .with_context(|| format!("downloading {:?} -> {:?}",
-                                 url, dest.display()))?;
-    Ok(())
+    let part = &part_path(dest);
+    with_retry(url, |url| async move {
+        if sha256.is_none() {
+            // there is no way to check that the parts match
+            discard_partial(dest)?;
+        }
+        let offset = partial_size(dest).unwrap_or(0);
+        let validator = if offset > 0 {
+            fs::read_to_string(validator_path(dest)).await.ok()
+        } else {
+            None
+        };
+        if offset > 0 {
+            log::info!("Resuming download {} -> {} at {} bytes",
+                url, dest.display(), offset);
+        } else {
+            log::info!("Downloading {} -> {}", url, dest.display());
+        }
+        let write_err = || format!("writing {:?}", part.display());
+        let (status, content_range) = if !verify_tls() {
+            let task_url = url.clone();
+            let task_dest = dest.to_path_buf();
+            let task_validator = validator.clone();
+            let head = task::spawn_blocking(move || {
+                download_unverified(&task_url, offset,
+                                    task_validator.as_deref(), &task_dest)
+            }).await.url_context(&url)?;
+            (head.status,
+             head.header("content-range").map(|v| v.to_string()))
+        } else {
+            let mut request = surf::get(&url);
+            if offset > 0 {
+                request = request.set_header("Range",
+                                             format!("bytes={}-", offset));
+                if let Some(validator) = &validator {
+                    request = request.set_header("If-Range",
+                                                 validator.clone());
+                }
+            }
+            let response = request.await.map_err(HttpError)
+                .url_context(&url)?;
+            let header = |name: &'static str| {
+                response.header(&name.into())
+                    .and_then(|values| values.last())
+                    .map(|value| value.as_str().to_string())
+            };
+            let status: u16 = response.status().into();
+            let content_range = header("Content-Range");
+            let new_validator = header("ETag")
+                .or_else(|| header("Last-Modified"));
+            if let Some(append) = continues_at(status, offset,
+                                               content_range.as_deref())
+            {
+                if !append {
+                    start_partial(dest, new_validator.as_deref())?;
+                }
+                let mut file = open_part(part, append).await
+                    .with_context(write_err)?;
+                io::copy(response, &mut file).await
+                    .with_context(|| format!("downloading {:?} -> {:?}",
+                                             url, part.display()))?;
+                file.flush().await.with_context(write_err)?;
+            } else if status != 416 && status != 206 {
+                return Err(HttpFailure(response)).url_context(&url);
+            }
+            (status, content_range)
+        };
+        match continues_at(status, offset, content_range.as_deref()) {
+            Some(true) => {}
+            Some(false) => {
+                if offset > 0 {
+                    log::warn!("Server doesn't support resuming downloads \
+                        or the file has changed, {} downloaded again \
+                        from the start", url);
+                }
+            }
+            None if status == 416 || status == 206 => {
+                // partial file is not a prefix of this one
+                discard_partial(dest)?;
+                anyhow::bail!("server can't continue the download \
+                    (status {}, range {:?}), discarding {} bytes \
+                    downloaded before", status, content_range, offset);
+            }
+            None => {
+                anyhow::bail!("HTTP failure: {}", status);
+            }
+        }
+        if let Some(expected) = sha256 {
+            let path = part.clone();
+            let actual = task::spawn_blocking(move || sha256_file(&path))
+                .await?;
+            if !actual.eq_ignore_ascii_case(expected) {
+                // next attempt starts from scratch
+                discard_partial(dest)?;
+                anyhow::bail!("checksum mismatch: expected sha256 {}, \
+                    downloaded file has {}", expected, actual);
+            }
+        }
+        fs::rename(part, dest).await
+            .with_context(|| format!("renaming {:?} -> {:?}",
+                                     part.display(), dest.display()))?;
+        discard_partial(dest)?;
+        Ok(())
+    }).await
+}
+
+async fn open_part(path: &Path, append: bool) -> io::Result<fs::File> {
+    if append {
+        fs::OpenOptions::new().append(true).open(path).await
+    } else {
+        fs::File::create(path).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use async_std::task;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::pkcs12::Pkcs12;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509, X509NameBuilder};
+
+    use super::{read_head, split_authority, fetch_unverified};
+    use super::{disable_tls_verification, get_file, part_path};
+    use super::validator_path;
+
+    /// Self-signed certificate for another host, like the one of an
+    /// internal mirror
+    fn identity() -> native_tls::Identity {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "mirror.invalid").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        cert.set_serial_number(&serial).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = cert.build();
+        let pkcs12 = Pkcs12::builder()
+            .build("test", "mirror", &key, &cert).unwrap();
+        native_tls::Identity::from_pkcs12(&pkcs12.to_der().unwrap(), "test")
+            .unwrap()
+    }
+
+    /// Serves `responses` over TLS to consecutive connections on 127.0.0.1
+    /// (`localhost` may resolve to `::1` first). Returns the port and the
+    /// thread returning headers of the requests
+    fn serve(responses: Vec<Vec<u8>>)
+        -> (u16, thread::JoinHandle<Vec<Vec<String>>>)
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let acceptor = native_tls::TlsAcceptor::new(identity()).unwrap();
+        let thread = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (tcp, _) = listener.accept().unwrap();
+                let stream = acceptor.accept(tcp).unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request = Vec::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    request.push(line.to_string());
+                }
+                let mut stream = reader.into_inner();
+                stream.write_all(&response).unwrap();
+                stream.shutdown().ok();
+                requests.push(request);
+            }
+            requests
+        });
+        (port, thread)
+    }
+
+    #[test]
+    fn test_self_signed_mirror() {
+        let (port, server) = serve(vec![
+            b"HTTP/1.0 200 OK\r\nContent-Length: 5\r\n\r\nhello".to_vec(),
+            b"HTTP/1.0 200 OK\r\nContent-Length: 10\r\n\r\nhello".to_vec(),
+            b"HTTP/1.0 404 Not Found\r\n\r\n".to_vec(),
+        ]);
+        let url = format!("https://localhost:{}/index.json", port);
+        assert_eq!(task::block_on(fetch_unverified(&url)).unwrap(),
+                   (200, b"hello".to_vec()));
+        let err = task::block_on(fetch_unverified(&url)).unwrap_err();
+        assert!(format!("{:#}", err).contains("truncated"), "{:#}", err);
+        assert_eq!(task::block_on(fetch_unverified(&url)).unwrap().0, 404);
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0][0], "GET /index.json HTTP/1.0");
+        assert!(requests[0].contains(&format!("Host: localhost:{}", port)));
+    }
+
+    #[test]
+    fn test_resume_download() {
+        let (port, server) = serve(vec![
+            b"HTTP/1.0 200 OK\r\nETag: \"v1\"\r\n\
+              Content-Length: 10\r\n\r\nhello".to_vec(),
+            b"HTTP/1.0 206 Partial Content\r\n\
+              Content-Range: bytes 5-9/10\r\n\
+              Content-Length: 5\r\n\r\nworld".to_vec(),
+        ]);
+        disable_tls_verification();
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("package.tar");
+        let url = format!("https://localhost:{}/package.tar", port);
+        // sha256 of `helloworld`
+        let sha256 = "936a185caaa266bb9cbe981e9e05cb78\
+                      cd732b0b3280eb944412bb6f8f8f07af";
+        task::block_on(get_file(&dest, &url, Some(sha256))).unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"helloworld");
+        assert!(!part_path(&dest).exists());
+        assert!(!validator_path(&dest).exists());
+        let requests = server.join().unwrap();
+        assert!(requests[1].contains(&"Range: bytes=5-".to_string()));
+        assert!(requests[1].contains(&"If-Range: \"v1\"".to_string()));
+    }
+
+    #[test]
+    fn test_authority() {
+        assert_eq!(split_authority("example.com").unwrap(),
+                   ("example.com", 443));
+        assert_eq!(split_authority("example.com:8443").unwrap(),
+                   ("example.com", 8443));
+        assert_eq!(split_authority("[::1]").unwrap(), ("::1", 443));
+        assert_eq!(split_authority("[::1]:8443").unwrap(), ("::1", 8443));
+        assert!(split_authority("[::1").is_err());
+        assert!(split_authority("[::1]x").is_err());
+    }
+
+    #[test]
+    fn test_head() {
+        let mut data = &b"HTTP/1.0 302 Found\r\n\
+            Location: /new\r\nContent-Length: 0\r\n\r\nbody"[..];
+        let head = read_head(&mut data).unwrap();
+        assert_eq!(head.status, 302);
+        assert_eq!(head.header("location"), Some("/new"));
+        assert_eq!(head.header("content-length"), Some("0"));
+        assert_eq!(data, b"body");
+        assert!(read_head(&mut &b"HTTP/1.0 200 OK\r\n"[..]).is_err());
+    }
 }
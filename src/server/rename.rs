@@ -0,0 +1,218 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::platform::home_dir;
+use crate::server::control::{read_metadata, get_instance_from_metadata};
+use crate::server::detect;
+use crate::server::init::{self, data_path, rename_port, Metadata};
+use crate::server::methods::InstallMethod;
+use crate::server::options::{self, Rename};
+use crate::server::os_trait::Method;
+use crate::server::{is_valid_name, linux, macos, NAME_RULES};
+
+
+fn rename_path(from: &Path, to: &Path) -> anyhow::Result<()> {
+    log::info!("Renaming {} -> {}", from.display(), to.display());
+    fs::rename(from, to)
+        .with_context(|| format!("cannot move {} to {}",
+                                 from.display(), to.display()))
+}
+
+/// Renames dump and backups made by upgrade (`{old}.dump`, `{old}.backup`
+/// and `{old}.backup.*`), so `restore-backup` finds them by the new name
+fn rename_leftovers(base: &Path, old: &str, new: &str) -> anyhow::Result<()> {
+    let prefix = format!("{}.", old);
+    for item in fs::read_dir(base)? {
+        let item = item?;
+        let file_name = item.file_name();
+        let suffix = match file_name.to_str()
+            .and_then(|name| name.strip_prefix(&prefix))
+        {
+            Some(suffix) => suffix,
+            None => continue,
+        };
+        if suffix == "dump" || suffix == "backup"
+            || suffix.starts_with("backup.")
+        {
+            rename_path(&item.path(),
+                        &base.join(format!("{}.{}", new, suffix)))?;
+        }
+    }
+    Ok(())
+}
+
+fn remove_service(name: &str) -> anyhow::Result<()> {
+    if cfg!(target_os="linux") {
+        linux::remove_systemd_service(name, false)?;
+    } else if cfg!(target_os="macos") {
+        macos::remove_launchd_service(name, false)?;
+    }
+    Ok(())
+}
+
+/// Moves everything but the data directory (which is already moved) to the
+/// new name. The service of the old name is removed last, so on failure
+/// it's still there
+fn move_instance(method: &dyn Method, meta: &Metadata, old: &str, new: &str,
+    base: &Path, old_credentials: &Path, new_credentials: &Path)
+    -> anyhow::Result<()>
+{
+    rename_leftovers(base, old, new)?;
+    if old_credentials.exists() {
+        rename_path(old_credentials, new_credentials)?;
+    }
+    rename_port(old, new, meta.port)?;
+
+    method.create_user_service(&init::Settings {
+        name: new.into(),
+        system: false,
+        version: meta.version.clone(),
+        nightly: meta.nightly,
+        method: meta.method.clone(),
+        directory: base.join(new),
+        credentials: new_credentials.to_path_buf(),
+        user: "edgedb".into(),
+        database: "edgedb".into(),
+        port: meta.port,
+        start_conf: meta.start_conf,
+        inhibit_user_creation: true,
+        inhibit_start: true,
+        upgrade_marker: None,
+    })?;
+    if cfg!(target_os="linux") && !meta.env.is_empty() {
+        linux::write_systemd_env(new, false, &meta.env)?;
+    }
+    remove_service(old)
+}
+
+/// Undoes `move_instance` and the move of the data directory, skipping the
+/// steps that weren't done
+fn move_back(meta: &Metadata, old: &str, new: &str,
+    base: &Path, old_credentials: &Path, new_credentials: &Path)
+    -> anyhow::Result<()>
+{
+    remove_service(new)?;
+    rename_port(new, old, meta.port)?;
+    if new_credentials.exists() {
+        rename_path(new_credentials, old_credentials)?;
+    }
+    rename_leftovers(base, new, old)?;
+    rename_path(&base.join(new), &base.join(old))
+}
+
+pub fn rename(options: &Rename) -> anyhow::Result<()> {
+    if !is_valid_name(&options.new_name) {
+        anyhow::bail!("Invalid name {:?}: {}", options.new_name, NAME_RULES);
+    }
+    let base = data_path(false)?;
+    let old_dir = base.join(&options.old_name);
+    let new_dir = base.join(&options.new_name);
+    if !old_dir.exists() {
+        anyhow::bail!("No instance {:?} found", options.old_name);
+    }
+    if new_dir.exists() {
+        anyhow::bail!("Instance {:?} already exists", options.new_name);
+    }
+    if old_dir.join("UPGRADE_IN_PROGRESS").exists() {
+        anyhow::bail!("Instance {:?} is being upgraded. Finish \
+            the upgrade first:\n  edgedb server upgrade {}",
+            options.old_name, options.old_name);
+    }
+    let credentials_dir = home_dir()?.join(".edgedb").join("credentials");
+    let old_credentials = credentials_dir
+        .join(format!("{}.json", options.old_name));
+    let new_credentials = credentials_dir
+        .join(format!("{}.json", options.new_name));
+    if new_credentials.exists() {
+        anyhow::bail!("Credentials file {} already exists",
+            new_credentials.display());
+    }
+    let meta = read_metadata(&old_dir)?;
+    if meta.method != InstallMethod::Package {
+        anyhow::bail!("Renaming instances installed by {} \
+            is not supported", meta.method.title());
+    }
+    let os = detect::current_os()?;
+    let avail = os.get_available_methods()?;
+    let method = os.make_method(&meta.method, &avail)?;
+
+    let mut ctl = get_instance_from_metadata(
+        &options.old_name, false, &meta)?;
+    let running = ctl.get_status()?.is_running();
+    if running {
+        // data directory is moved below, so the server must be gone
+        ctl.stop(&options::Stop {
+            name: options.old_name.clone(),
+            wait: true,
+            wait_timeout: None,
+        })?;
+    }
+    rename_path(&old_dir, &new_dir)?;
+    if let Err(e) = move_instance(&*method, &meta, &options.old_name,
+        &options.new_name, &base, &old_credentials, &new_credentials)
+    {
+        log::warn!("Renaming failed, moving instance {:?} back",
+                   options.old_name);
+        match move_back(&meta, &options.old_name,
+            &options.new_name, &base, &old_credentials, &new_credentials)
+        {
+            Ok(()) if running => {
+                ctl.start(&options::Start {
+                    name: options.old_name.clone(),
+                    foreground: false,
+                    wait: false,
+                    wait_timeout: None,
+                }).map_err(|e| log::error!("Cannot start instance {:?} \
+                    back: {:#}", options.old_name, e)).ok();
+            }
+            Ok(()) => {}
+            Err(e) => {
+                log::error!("Cannot move instance {:?} back: {:#}",
+                            options.old_name, e);
+            }
+        }
+        return Err(e);
+    }
+    if running {
+        let mut ctl = get_instance_from_metadata(
+            &options.new_name, false, &meta)?;
+        ctl.start(&options::Start {
+            name: options.new_name.clone(),
+            foreground: false,
+            wait: false,
+            wait_timeout: None,
+        })?;
+    }
+    eprintln!("Instance {:?} is renamed to {:?}",
+        options.old_name, options.new_name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::rename_leftovers;
+
+    #[test]
+    fn test_rename_leftovers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path();
+        for name in &["inst.dump", "inst.backup", "inst.backup.1600000000",
+                      "inst.other", "inst2.dump", "other.backup"]
+        {
+            fs::create_dir(base.join(name)).unwrap();
+        }
+        rename_leftovers(base, "inst", "renamed").unwrap();
+        let mut names = fs::read_dir(base).unwrap()
+            .map(|item| item.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, [
+            "inst.other", "inst2.dump", "other.backup",
+            "renamed.backup", "renamed.backup.1600000000", "renamed.dump",
+        ]);
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::Context;
+use prettytable::{Table, Row, Cell};
+
+use crate::process::get_text;
+use crate::server::confirm;
+use crate::server::control::read_metadata;
+use crate::server::detect;
+use crate::server::init::{Metadata, data_path, read_ports, write_metadata};
+use crate::server::methods::InstallMethod;
+use crate::server::options::{RepairMetadata, StartConf};
+use crate::server::version::Version;
+use crate::server::{linux, macos};
+use crate::table;
+
+
+#[derive(Debug, Default)]
+struct Detected {
+    version: Option<Version<String>>,
+    port: Option<u16>,
+    start_conf: Option<StartConf>,
+}
+
+
+fn version_from_path(path: &str) -> Option<Version<String>> {
+    const FRAMEWORK: &str = "EdgeDB.framework/Versions/";
+    if let Some(pos) = path.find(FRAMEWORK) {
+        let tail = &path[pos + FRAMEWORK.len()..];
+        let ver = tail.split('/').next().unwrap_or("");
+        if !ver.is_empty() {
+            return Some(Version(ver.into()));
+        }
+    }
+    Path::new(path).file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix("edgedb-server-"))
+        .filter(|ver| !ver.is_empty())
+        .map(|ver| Version(ver.into()))
+}
+
+fn port_from_args<'a>(args: impl IntoIterator<Item=&'a str>) -> Option<u16> {
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        if let Some(port) = arg.strip_prefix("--port=") {
+            return port.parse().ok();
+        }
+        if arg == "--port" {
+            return iter.next().and_then(|port| port.parse().ok());
+        }
+    }
+    None
+}
+
+fn detect_from_args(args: &[&str], detected: &mut Detected) {
+    if detected.version.is_none() {
+        detected.version = args.get(0).and_then(|x| version_from_path(x));
+    }
+    if detected.port.is_none() {
+        detected.port = port_from_args(args.iter().cloned());
+    }
+}
+
+fn detect_running(name: &str, detected: &mut Detected) {
+    if !cfg!(target_os="linux") {
+        return;
+    }
+    let pid = get_text(Command::new("systemctl")
+            .arg("--user")
+            .arg("show")
+            .arg("--property=MainPID")
+            .arg(format!("edgedb-server@{}", name)))
+        .map_err(|e| log::info!("Cannot get service info: {:#}", e))
+        .ok()
+        .and_then(|txt| txt.trim().strip_prefix("MainPID=")
+                           .and_then(|pid| pid.parse::<u32>().ok()))
+        .filter(|&pid| pid != 0);
+    if let Some(pid) = pid {
+        match fs::read(format!("/proc/{}/cmdline", pid)) {
+            Ok(data) => {
+                let cmdline = String::from_utf8_lossy(&data);
+                let args = cmdline.split('\0')
+                    .filter(|x| !x.is_empty())
+                    .collect::<Vec<_>>();
+                log::info!("Found running server: {:?}", args);
+                detect_from_args(&args, detected);
+            }
+            Err(e) => {
+                log::info!("Cannot read command-line of pid {}: {}", pid, e);
+            }
+        }
+    }
+}
+
+fn detect_systemd(name: &str, detected: &mut Detected) -> anyhow::Result<()> {
+    let unit_path = linux::systemd_service_path(name, false)?;
+    let data = match fs::read_to_string(&unit_path) {
+        Ok(data) => data,
+        Err(e) => {
+            log::info!("Cannot read {}: {}", unit_path.display(), e);
+            return Ok(());
+        }
+    };
+    for line in data.lines() {
+        if let Some(exec) = line.trim().strip_prefix("ExecStart=") {
+            detect_from_args(
+                &exec.split_whitespace().collect::<Vec<_>>(), detected);
+        }
+    }
+    if detected.start_conf.is_none() {
+        let unit_name = unit_path.file_name().expect("unit has file name");
+        let wants = unit_path.with_file_name("multi-user.target.wants")
+            .join(unit_name);
+        detected.start_conf = Some(if wants.exists() {
+            StartConf::Auto
+        } else {
+            StartConf::Manual
+        });
+    }
+    Ok(())
+}
+
+fn detect_launchd(name: &str, detected: &mut Detected) -> anyhow::Result<()> {
+    let plist_path = macos::launchd_plist_path(name, false)?;
+    let data = match fs::read_to_string(&plist_path) {
+        Ok(data) => data,
+        Err(e) => {
+            log::info!("Cannot read {}: {}", plist_path.display(), e);
+            return Ok(());
+        }
+    };
+    if let Some(start) = data.find("<key>ProgramArguments</key>") {
+        let tail = &data[start..];
+        let array = &tail[..tail.find("</array>").unwrap_or(tail.len())];
+        let args = array.split("<string>").skip(1)
+            .filter_map(|x| x.split("</string>").next())
+            .collect::<Vec<_>>();
+        detect_from_args(&args, detected);
+    }
+    if detected.start_conf.is_none() {
+        if let Some(pos) = data.find("<key>Disabled</key>") {
+            let tail = data[pos + "<key>Disabled</key>".len()..].trim_start();
+            detected.start_conf = Some(if tail.starts_with("<true/>") {
+                StartConf::Manual
+            } else {
+                StartConf::Auto
+            });
+        }
+    }
+    Ok(())
+}
+
+fn is_nightly(major_version: &Version<String>) -> bool {
+    let result = detect::current_os().and_then(|os| {
+        let avail = os.get_available_methods()?;
+        let method = os.make_method(&InstallMethod::Package, &avail)?;
+        Ok(method.installed_versions()?.iter()
+            .any(|pkg| &pkg.major_version == major_version &&
+                       pkg.is_nightly()))
+    });
+    match result {
+        Ok(nightly) => nightly,
+        Err(e) => {
+            log::warn!("Cannot check installed versions: {:#}. \
+                Assuming version {} is not a nightly.", e, major_version);
+            false
+        }
+    }
+}
+
+/// Detects metadata from the service (or the running server) of the
+/// instance. Docker instances have neither, so only package instances
+/// are detected
+fn detect_metadata(name: &str, labels: BTreeMap<String, String>,
+    env: BTreeMap<String, String>)
+    -> anyhow::Result<Metadata>
+{
+    let mut detected = Detected::default();
+    detect_running(name, &mut detected);
+    if cfg!(target_os="linux") {
+        detect_systemd(name, &mut detected)?;
+    } else if cfg!(target_os="macos") {
+        detect_launchd(name, &mut detected)?;
+    }
+    let version = detected.version.ok_or_else(|| {
+        anyhow::anyhow!("Cannot determine server version for instance {:?}: \
+            no running server or service file found. Only instances \
+            installed from packages can be repaired", name)
+    })?;
+    let port = match detected.port {
+        Some(port) => port,
+        None => read_ports()?.get(name).cloned()
+            .or_else(|| if name == "default" { Some(5656) } else { None })
+            .ok_or_else(|| anyhow::anyhow!(
+                "Cannot determine port for instance {:?}", name))?,
+    };
+    Ok(Metadata {
+        nightly: is_nightly(&version),
+        version,
+        method: InstallMethod::Package,
+        port,
+        start_conf: detected.start_conf.unwrap_or(StartConf::Auto),
+        labels,
+        env,
+        config: BTreeMap::new(),
+    })
+}
+
+fn print_metadata(meta: &Metadata) {
+    let mut table = Table::new();
+    table.add_row(Row::new(vec![
+        Cell::new("Version"),
+        Cell::new(&if meta.nightly {
+            format!("{} (nightly)", meta.version)
+        } else {
+            meta.version.to_string()
+        }),
+    ]));
+    table.add_row(Row::new(vec![
+        Cell::new("Installation method"),
+        Cell::new(meta.method.title()),
+    ]));
+    table.add_row(Row::new(vec![
+        Cell::new("Port"),
+        Cell::new(&meta.port.to_string()),
+    ]));
+    table.add_row(Row::new(vec![
+        Cell::new("Startup"),
+        Cell::new(&meta.start_conf.to_string()),
+    ]));
+    table.set_format(*table::FORMAT);
+    table.printstd();
+}
+
+pub fn repair_metadata(options: &RepairMetadata) -> anyhow::Result<()> {
+    let dir = data_path(false)?.join(&options.name);
+    if !dir.exists() {
+        anyhow::bail!("No data directory {} found for instance {:?}",
+            dir.display(), options.name);
+    }
+    let (labels, env, config) = match read_metadata(&dir) {
+        Ok(_) if !options.force => {
+            eprintln!("Metadata of instance {:?} is valid. \
+                Use `--force` to rebuild it anyway.", options.name);
+            return Ok(());
+        }
+        Ok(old) if old.method != InstallMethod::Package => {
+            anyhow::bail!("Instance {:?} is installed by {}, repairing its \
+                metadata is not supported", options.name, old.method.title());
+        }
+        Ok(old) => (old.labels, old.env, old.config),
+        Err(e) => {
+            log::warn!("{:#}", e);
+            (BTreeMap::new(), BTreeMap::new(), BTreeMap::new())
+        }
+    };
+    let mut metadata = detect_metadata(&options.name, labels, env)?;
+    metadata.config = config;
+    println!("Detected metadata for instance {:?}:", options.name);
+    print_metadata(&metadata);
+    if !options.from_detected {
+        if !confirm("Write metadata.json with these values?")? {
+            eprintln!("Metadata is not changed");
+            return Ok(());
+        }
+    }
+    write_metadata(&dir.join("metadata.json"), &metadata)?;
+    read_metadata(&dir)
+        .context("metadata.json written, but cannot be read back")?;
+    eprintln!("Metadata of instance {:?} is repaired", options.name);
+    Ok(())
+}
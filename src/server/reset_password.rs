@@ -1,6 +1,8 @@
 use std::fs;
+use std::io::Write;
 use std::path::Path;
 use std::default::Default;
+use std::time::Duration;
 
 use anyhow::Context;
 use async_std::task;
@@ -10,6 +12,7 @@ use edgeql_parser::helpers::{quote_string, quote_name};
 use fn_error_context::context;
 use rand::{Rng, SeedableRng};
 
+use crate::process::ProcessGuard;
 use crate::server;
 use crate::server::options::ResetPassword;
 use crate::platform::{home_dir, tmp_file_name};
@@ -37,7 +40,17 @@ pub fn write_credentials(path: &Path, credentials: &Credentials)
 {
     fs::create_dir_all(path.parent().unwrap())?;
     let tmp_path = path.with_file_name(tmp_file_name(path));
-    fs::write(&tmp_path, serde_json::to_vec_pretty(&credentials)?)?;
+    let mut file = fs::OpenOptions::new();
+    file.write(true).create(true).truncate(true);
+    // credentials contain the password, so only the owner may read them
+    #[cfg(unix)] {
+        use std::os::unix::fs::OpenOptionsExt;
+        file.mode(0o600);
+    }
+    let mut file = file.open(&tmp_path)?;
+    file.write_all(&serde_json::to_vec_pretty(&credentials)?)?;
+    file.sync_all()?;
+    drop(file);
     fs::rename(&tmp_path, path)?;
     Ok(())
 }
@@ -77,14 +90,30 @@ pub fn reset_password(options: &ResetPassword) -> anyhow::Result<()> {
     } else {
         generate_password()
     };
-    let path = server::get_instance(&options.name)
-        .and_then(|inst| inst.get_socket(true))
+    let inst = server::get_instance(&options.name)
+        .with_context(|| format!("cannot find instance {:?}", options.name))?;
+    let path = inst.get_socket(true)
         .with_context(|| format!("cannot find instance {:?}", options.name))?;
     let mut conn_params = Builder::new();
     conn_params.user("edgedb");
     conn_params.database("edgedb");
     conn_params.unix_addr(path);
-    task::block_on(async {
+    // The admin socket doesn't require a password, so if the instance is
+    // stopped a temporary server is run just to change the password
+    let mut server = if inst.get_status()?.is_running() {
+        None
+    } else {
+        if !options.quiet {
+            eprintln!("Instance {:?} is not running, starting a temporary \
+                server to change the password", options.name);
+        }
+        let mut cmd = inst.run_command()?;
+        conn_params.wait_until_available(Duration::from_secs(30));
+        Some(ProcessGuard::run(&mut cmd)
+            .with_context(|| format!("error running server {}",
+                                     crate::process::describe(&cmd)))?)
+    };
+    let result: anyhow::Result<()> = task::block_on(async {
         let mut cli = conn_params.connect().await?;
         cli.execute(&format!(r###"
             ALTER ROLE {name} {{
@@ -92,8 +121,14 @@ pub fn reset_password(options: &ResetPassword) -> anyhow::Result<()> {
             }}"###,
             name=quote_name(&user),
             password=quote_string(&password))
-        ).await
-    })?;
+        ).await?;
+        Ok(())
+    });
+    match &mut server {
+        Some(server) => server.with_output(result)?,
+        None => result?,
+    }
+    drop(server);
     if save {
         let mut creds = credentials.unwrap_or_else(Default::default);
         creds.user = user.into();
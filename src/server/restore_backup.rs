@@ -0,0 +1,172 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+
+use crate::platform::home_dir;
+use crate::server::control::{read_metadata, get_instance_from_metadata};
+use crate::server::detect;
+use crate::server::encrypted_backup::{self, BackupFormat};
+use crate::server::init::{self, data_path};
+use crate::server::options::{self, RestoreBackup};
+use crate::server::snapshot;
+use crate::server::upgrade::{BackupMeta, write_backup_meta, move_dir};
+use crate::server::upgrade::check_removable;
+
+
+pub struct Backup {
+    pub path: PathBuf,
+    pub meta: BackupMeta,
+}
+
+
+/// Finds directories named `{name}.backup` (made by upgrade) and
+/// `{name}.backup.*` (made by this command) having `backup.json`
+pub fn find_backups(name: &str) -> anyhow::Result<Vec<Backup>> {
+    let base = data_path(false)?;
+    let exact = format!("{}.backup", name);
+    let prefix = format!("{}.backup.", name);
+    let mut backups = Vec::new();
+    for item in fs::read_dir(&base)? {
+        let item = item?;
+        let file_name = item.file_name();
+        let file_name = match file_name.to_str() {
+            Some(file_name) => file_name,
+            None => continue,
+        };
+        if file_name != exact && !file_name.starts_with(&prefix) {
+            continue;
+        }
+        let path = item.path();
+        let meta_path = path.join("backup.json");
+        let meta = fs::read(&meta_path).map_err(anyhow::Error::from)
+            .and_then(|data| Ok(serde_json::from_slice(&data)?));
+        match meta {
+            Ok(meta) => backups.push(Backup { path, meta }),
+            Err(e) => {
+                log::warn!("Skipping {}: cannot read {}: {:#}",
+                    path.display(), meta_path.display(), e);
+            }
+        }
+    }
+    Ok(backups)
+}
+
+pub fn restore_backup(options: &RestoreBackup) -> anyhow::Result<()> {
+    let dir = data_path(false)?.join(&options.name);
+    if !dir.exists() {
+        anyhow::bail!("No instance {:?} found", options.name);
+    }
+    let backup = find_backups(&options.name)?.into_iter()
+        .filter(|b| options.timestamp.map(|t| b.meta.timestamp <= t)
+                    .unwrap_or(true))
+        .max_by_key(|b| b.meta.timestamp);
+    let backup = match backup {
+        Some(backup) => backup,
+        None if options.timestamp.is_some() => {
+            anyhow::bail!("No backup of instance {:?} made before {} found",
+                options.name,
+                humantime::format_rfc3339_seconds(options.timestamp.unwrap()));
+        }
+        None => {
+            anyhow::bail!("No backups of instance {:?} found", options.name);
+        }
+    };
+    // metadata is not encrypted, so the version is checked before asking
+    // for the key
+    let restored = read_metadata(&backup.path)?;
+    let os = detect::current_os()?;
+    let avail = os.get_available_methods()?;
+    let method = os.make_method(&restored.method, &avail)?;
+    let server_path = method.get_server_path(&restored.version)?;
+    if !server_path.exists() {
+        anyhow::bail!("Backup is made by version {}, which is not installed. \
+            Run:\n  edgedb server install --version={}",
+            restored.version, restored.version);
+    }
+    let key = if backup.meta.format == BackupFormat::Encrypted {
+        Some(encrypted_backup::read_key(options.backup_key_file.as_deref())?)
+    } else {
+        None
+    };
+    println!("Restoring {} made at {}", backup.path.display(),
+        humantime::format_rfc3339_seconds(backup.meta.timestamp));
+
+    let current = read_metadata(&dir)?;
+    let mut ctl = get_instance_from_metadata(
+        &options.name, false, &current)?;
+    if ctl.get_status()?.is_running() {
+        // data directory is moved below, so the server must be gone
+        ctl.stop(&options::Stop {
+            name: options.name.clone(),
+            wait: true,
+            wait_timeout: None,
+        })?;
+    }
+    let now = SystemTime::now();
+    let aside = dir.with_file_name(format!("{}.backup.{}", options.name,
+        now.duration_since(UNIX_EPOCH)?.as_secs()));
+    move_dir(&dir, &aside, true)?;
+    write_backup_meta(&aside.join("backup.json"), &BackupMeta {
+        timestamp: now,
+        snapshot: None,
+        format: BackupFormat::Directory,
+    })?;
+    let restored_data = match (&backup.meta.snapshot, &key) {
+        (Some(snap), _) => snapshot::by_name(&snap.filesystem)
+            .and_then(|snapshots| snapshots.rollback(&snap.id, &dir)),
+        (None, Some(key)) => {
+            encrypted_backup::decrypt_backup(&backup.path, &dir, key)
+        }
+        (None, None) => move_dir(&backup.path, &dir, true),
+    };
+    if let Err(e) = restored_data {
+        // failed steps remove their partial output, so the current data
+        // can be moved back in place
+        if dir.exists() {
+            return Err(e.context(format!("current data is kept at {}",
+                aside.display())));
+        }
+        fs::remove_file(aside.join("backup.json")).ok();
+        move_dir(&aside, &dir, true).with_context(|| format!(
+            "cannot move current data back from {}", aside.display()))?;
+        return Err(e);
+    }
+    if backup.meta.snapshot.is_none() && key.is_some() {
+        // archive is unpacked, like plain backups are moved, so the
+        // backup is not listed anymore
+        check_removable(&backup.path, &data_path(false)?)?;
+        fs::remove_dir_all(&backup.path)?;
+    }
+    fs::remove_file(dir.join("backup.json")).ok();
+    println!("Current data is kept at {}", aside.display());
+
+    // service file refers to the server binary of the current version
+    method.create_user_service(&init::Settings {
+        name: options.name.clone(),
+        system: false,
+        version: restored.version.clone(),
+        nightly: restored.nightly,
+        method: restored.method.clone(),
+        directory: dir.clone(),
+        credentials: home_dir()?.join(".edgedb").join("credentials")
+            .join(format!("{}.json", options.name)),
+        user: "edgedb".into(),
+        database: "edgedb".into(),
+        port: restored.port,
+        start_conf: restored.start_conf,
+        inhibit_user_creation: true,
+        inhibit_start: true,
+        upgrade_marker: None,
+    })?;
+    let mut ctl = get_instance_from_metadata(
+        &options.name, false, &restored)?;
+    ctl.start(&options::Start {
+        name: options.name.clone(),
+        foreground: false,
+        wait: false,
+        wait_timeout: None,
+    })?;
+    Ok(())
+}
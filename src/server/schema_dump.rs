@@ -0,0 +1,149 @@
+use serde_json::{json, Value};
+
+use crate::server::options::SchemaDump;
+
+
+fn string_map() -> Value {
+    json!({
+        "type": "object",
+        "additionalProperties": {"type": "string"},
+    })
+}
+
+fn timestamp() -> Value {
+    json!({
+        "type": "string",
+        "description": "RFC3339 timestamp",
+    })
+}
+
+/// Schema of `metadata.json` in the data directory
+fn metadata() -> Value {
+    json!({
+        "type": "object",
+        "required": ["version", "method", "port", "nightly", "start_conf"],
+        "properties": {
+            "version": {
+                "type": "string",
+                "description": "Major version of the server",
+            },
+            "method": {"enum": ["Package", "Docker"]},
+            "port": {"type": "integer", "minimum": 1, "maximum": 65535},
+            "nightly": {"type": "boolean"},
+            "start_conf": {"enum": ["Auto", "Manual"]},
+            "labels": string_map(),
+            "env": string_map(),
+            "config": string_map(),
+        },
+    })
+}
+
+/// Schema of `backup.json` in the backup directory
+fn backup_meta() -> Value {
+    json!({
+        "type": "object",
+        "required": ["timestamp"],
+        "properties": {
+            "timestamp": timestamp(),
+            "snapshot": {
+                "type": "object",
+                "required": ["filesystem", "id"],
+                "properties": {
+                    "filesystem": {"enum": ["btrfs"]},
+                    "id": {"type": "string"},
+                },
+            },
+            "format": {"enum": ["Directory", "Encrypted"]},
+        },
+    })
+}
+
+/// Schema of `UPGRADE_IN_PROGRESS` marker in the data directory
+fn upgrade_meta() -> Value {
+    json!({
+        "type": "object",
+        "required": ["source", "target", "started", "pid"],
+        "properties": {
+            "source": {"type": "string"},
+            "target": {"type": "string"},
+            "started": timestamp(),
+            "pid": {"type": "integer", "minimum": 0},
+            "cli_version": {"type": "string"},
+            "schema_version": {"type": "integer", "minimum": 0},
+        },
+    })
+}
+
+pub fn schema_dump(_: &SchemaDump) -> anyhow::Result<()> {
+    let schema = json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "definitions": {
+            "Metadata": metadata(),
+            "BackupMeta": backup_meta(),
+            "UpgradeMeta": upgrade_meta(),
+        },
+    });
+    println!("{}", serde_json::to_string_pretty(&schema)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::time::SystemTime;
+
+    use serde::Serialize;
+    use serde_json::Value;
+
+    use crate::server::encrypted_backup::BackupFormat;
+    use crate::server::init::Metadata;
+    use crate::server::methods::InstallMethod;
+    use crate::server::options::StartConf;
+    use crate::server::snapshot::SnapshotMeta;
+    use crate::server::upgrade::{BackupMeta, UpgradeMeta};
+    use crate::server::version::Version;
+
+    fn check(value: impl Serialize, schema: Value) {
+        let value = serde_json::to_value(&value).unwrap();
+        let fields = value.as_object().unwrap();
+        let properties = schema["properties"].as_object().unwrap();
+        for key in fields.keys() {
+            assert!(properties.contains_key(key), "{} is not in schema", key);
+        }
+        for key in schema["required"].as_array().unwrap() {
+            assert!(fields.contains_key(key.as_str().unwrap()));
+        }
+    }
+
+    #[test]
+    fn schema_matches() {
+        let mut labels = BTreeMap::new();
+        labels.insert("env".into(), "test".into());
+        check(Metadata {
+            version: Version("1-alpha5".into()),
+            method: InstallMethod::Package,
+            port: 10700,
+            nightly: false,
+            start_conf: StartConf::Auto,
+            labels: labels.clone(),
+            env: labels.clone(),
+            config: labels,
+        }, super::metadata());
+        check(BackupMeta {
+            timestamp: SystemTime::now(),
+            snapshot: Some(SnapshotMeta {
+                filesystem: "btrfs".into(),
+                id: "/var/lib/edgedb/data/inst.backup".into(),
+            }),
+            format: BackupFormat::Encrypted,
+        }, super::backup_meta());
+        check(UpgradeMeta {
+            source: Version("1-alpha4".into()),
+            target: Version("1-alpha5".into()),
+            started: SystemTime::now(),
+            pid: 1,
+            cli_version: "1.0.0".into(),
+            schema_version: 1,
+        }, super::upgrade_meta());
+    }
+}
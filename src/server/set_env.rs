@@ -0,0 +1,62 @@
+use crate::server::control::read_metadata;
+use crate::server::init::{data_path, write_metadata};
+use crate::server::linux;
+use crate::server::methods::InstallMethod;
+use crate::server::options::SetEnv;
+
+
+fn check_var(key: &str, value: &str) -> anyhow::Result<()> {
+    let mut chars = key.chars();
+    let valid = matches!(chars.next(),
+                         Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        anyhow::bail!("Invalid environment variable name {:?}", key);
+    }
+    if value.contains('\n') || value.contains('\0') {
+        anyhow::bail!("Value of environment variable {} must not contain \
+            newlines or null bytes", key);
+    }
+    Ok(())
+}
+
+pub fn set_env(options: &SetEnv) -> anyhow::Result<()> {
+    let dir = data_path(false)?.join(&options.name);
+    if !dir.exists() {
+        anyhow::bail!("No instance {:?} found", options.name);
+    }
+    let mut metadata = read_metadata(&dir)?;
+    if options.vars.is_empty() && options.unset.is_empty() {
+        // values are not printed as they often contain secrets
+        for key in metadata.env.keys() {
+            println!("{}", key);
+        }
+        return Ok(());
+    }
+    for (key, value) in &options.vars {
+        check_var(key, value)?;
+    }
+    for key in &options.unset {
+        if metadata.env.remove(key).is_none() {
+            log::warn!("Instance {:?} has no environment variable {:?}",
+                options.name, key);
+        }
+    }
+    for (key, value) in &options.vars {
+        metadata.env.insert(key.clone(), value.clone());
+    }
+    write_metadata(&dir.join("metadata.json"), &metadata)?;
+    match metadata.method {
+        InstallMethod::Package if cfg!(target_os="linux") => {
+            linux::write_systemd_env(&options.name, false, &metadata.env)?;
+        }
+        _ => {
+            log::warn!("Environment is only applied when the server is run \
+                in foreground or by `edgedb server upgrade` on this \
+                platform, not by the service manager");
+        }
+    }
+    eprintln!("Restart the instance to apply the changes:\n  \
+        edgedb server restart {}", options.name);
+    Ok(())
+}
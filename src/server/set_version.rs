@@ -0,0 +1,54 @@
+use async_std::task;
+
+use crate::server::detect;
+use crate::server::init::write_metadata;
+use crate::server::options::SetVersion;
+use crate::server::ping::query_version;
+use crate::server::upgrade::all_instances;
+use crate::server::version::Version;
+
+
+pub fn set_version(options: &SetVersion) -> anyhow::Result<()> {
+    let mut inst = all_instances()?.into_iter()
+        .find(|inst| inst.name == options.name)
+        .ok_or_else(|| anyhow::anyhow!("Instance {:?} not found",
+                                       options.name))?;
+    let os = detect::current_os()?;
+    let avail = os.get_available_methods()?;
+    let method = os.make_method(&inst.meta.method, &avail)?;
+    let package = method.installed_versions()?.iter()
+        .find(|pkg| pkg.major_version == options.version)
+        .ok_or_else(|| anyhow::anyhow!("Version {} is not installed. Run:\n  \
+            edgedb server install --version={} {}",
+            options.version, options.version, inst.meta.method.option()))?;
+
+    let ctl = inst.get_control()?;
+    if ctl.get_status()?.is_running() {
+        match task::block_on(query_version(&inst, None)) {
+            Ok((_, reported)) if
+                Version(&reported[..]) != package.version.to_ref()
+            => {
+                log::warn!("Instance {:?} is running EdgeDB {}, \
+                    but version {} is {}-{}. \
+                    Restart the instance to run the pinned version.",
+                    inst.name, reported, options.version,
+                    package.version, package.revision);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                log::warn!("Cannot check version of the running \
+                    server: {:#}", e);
+            }
+        }
+    }
+    if inst.meta.version == options.version {
+        log::info!("Instance {:?} is already at version {}",
+            inst.name, options.version);
+        return Ok(());
+    }
+    log::info!("Changing version of {:?} from {} to {}",
+        inst.name, inst.meta.version, options.version);
+    inst.meta.version = options.version.clone();
+    write_metadata(&inst.data_dir.join("metadata.json"), &inst.meta)?;
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::Context;
+use serde::{Serialize, Deserialize};
+
+use crate::process;
+
+
+/// Snapshot of the data directory made instead of moving it aside
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotMeta {
+    /// Name of the filesystem as returned by `Snapshots::name`
+    pub filesystem: String,
+    /// Filesystem-specific identifier of the snapshot
+    pub id: String,
+}
+
+/// Snapshot operations of a filesystem
+pub trait Snapshots {
+    fn name(&self) -> &'static str;
+    /// Makes a writable snapshot of `dir` at `dest`, returns its identifier
+    fn create(&self, dir: &Path, dest: &Path) -> anyhow::Result<String>;
+    /// Puts the snapshot back in place of `dir`, which must not exist
+    fn rollback(&self, id: &str, dir: &Path) -> anyhow::Result<()>;
+}
+
+struct Btrfs;
+
+impl Snapshots for Btrfs {
+    fn name(&self) -> &'static str {
+        "btrfs"
+    }
+    fn create(&self, dir: &Path, dest: &Path) -> anyhow::Result<String> {
+        process::run(Command::new("btrfs")
+            .arg("subvolume").arg("snapshot")
+            .arg(dir).arg(dest))?;
+        dest.to_str().map(|path| path.to_string())
+            .context("snapshot path must be valid UTF-8")
+    }
+    fn rollback(&self, id: &str, dir: &Path) -> anyhow::Result<()> {
+        // snapshot is a subvolume, so it's renamed like a directory
+        fs::rename(id, dir)
+            .with_context(|| format!("cannot move snapshot {} to {}",
+                                     id, dir.display()))
+    }
+}
+
+/// Returns snapshot operations if the filesystem of `dir` supports them
+/// for this directory
+pub fn detect(dir: &Path) -> Option<Box<dyn Snapshots>> {
+    if is_btrfs_subvolume(dir) && which::which("btrfs").is_ok() {
+        return Some(Box::new(Btrfs));
+    }
+    None
+}
+
+/// Returns snapshot operations by the name recorded in `SnapshotMeta`
+pub fn by_name(name: &str) -> anyhow::Result<Box<dyn Snapshots>> {
+    match name {
+        "btrfs" => Ok(Box::new(Btrfs)),
+        _ => anyhow::bail!("snapshots of {:?} are not supported", name),
+    }
+}
+
+/// Snapshots can be made of subvolumes only, so the data directory must be
+/// the root of a subvolume. It stays one after the upgrade, as `init`
+/// empties the directory instead of recreating it
+#[cfg(target_os="linux")]
+fn is_btrfs_subvolume(dir: &Path) -> bool {
+    use std::ffi::CString;
+    use std::mem::MaybeUninit;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    const BTRFS_SUPER_MAGIC: u32 = 0x9123683E;
+    /// Inode number of the root directory of every btrfs subvolume
+    const SUBVOLUME_INODE: u64 = 256;
+
+    let c_path = match CString::new(dir.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+    if unsafe { libc::statfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return false;
+    }
+    let stat = unsafe { stat.assume_init() };
+    stat.f_type as u32 == BTRFS_SUPER_MAGIC
+        && fs::metadata(dir).map(|m| m.ino() == SUBVOLUME_INODE)
+            .unwrap_or(false)
+}
+
+#[cfg(not(target_os="linux"))]
+fn is_btrfs_subvolume(_dir: &Path) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::{by_name, detect};
+
+    #[test]
+    fn test_plain_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("inst");
+        std::fs::create_dir(&dir).unwrap();
+        assert!(detect(&dir).is_none());
+        assert_eq!(by_name("btrfs").unwrap().name(), "btrfs");
+        assert!(by_name("zfs").is_err());
+    }
+}
@@ -12,12 +12,14 @@ use async_std::io::timeout;
 use once_cell::unsync::OnceCell;
 use fn_error_context::context;
 use prettytable::{Table, Row, Cell};
+use serde::Serialize;
 
 use crate::server::init::{Metadata, read_ports, data_path};
-use crate::server::upgrade::{UpgradeMeta, BackupMeta};
+use crate::server::upgrade::{UpgradeMeta, BackupMeta, is_dir_entry};
 use crate::server::control::read_metadata;
 use crate::server::{linux, macos};
 use crate::server::is_valid_name;
+use crate::server::version::Version;
 use crate::process::get_text;
 use crate::table;
 
@@ -63,6 +65,22 @@ pub struct Status {
     service_file_exists: bool,
 }
 
+/// Status of the instance for machine consumption (`server daemon`)
+#[derive(Debug, Serialize)]
+pub struct StatusInfo {
+    pub name: String,
+    /// `running`, `not running` or `inactive`
+    pub service: &'static str,
+    pub pid: Option<u32>,
+    pub version: Option<Version<String>>,
+    pub nightly: Option<bool>,
+    pub port: Option<u16>,
+    /// Upgrade marker is present in the data directory
+    pub upgrading: bool,
+    /// Backup made by upgrade is present
+    pub backup: bool,
+}
+
 pub struct Cache {
     launchctl_list: OnceCell<anyhow::Result<String>>,
     reserved_ports: OnceCell<Result<BTreeMap<String, u16>, ()>>,
@@ -78,6 +96,36 @@ fn format_duration(mut dur: Duration) -> String {
 }
 
 impl Status {
+    pub fn is_running(&self) -> bool {
+        matches!(self.service, Service::Running {..})
+    }
+    /// Pid of the server if it's running
+    pub fn pid(&self) -> Option<u32> {
+        match self.service {
+            Service::Running { pid } => Some(pid),
+            _ => None,
+        }
+    }
+    fn service_title(&self) -> &'static str {
+        match self.service {
+            Service::Running {..} => "running",
+            Service::Failed {..} => "not running",
+            Service::Inactive {..} => "inactive",
+        }
+    }
+    pub fn info(&self) -> StatusInfo {
+        let meta = self.metadata.as_ref().ok();
+        StatusInfo {
+            name: self.name.clone(),
+            service: self.service_title(),
+            pid: self.pid(),
+            version: meta.map(|m| m.version.clone()),
+            nightly: meta.map(|m| m.nightly),
+            port: meta.map(|m| m.port),
+            upgrading: matches!(self.data_status, DataDirectory::Upgrading(_)),
+            backup: matches!(self.backup, BackupStatus::Exists(_)),
+        }
+    }
     pub fn print_extended_and_exit(&self) -> ! {
         self.print_extended();
         self.exit()
@@ -143,11 +191,19 @@ impl Status {
             DataDirectory::NoMetadata => "METADATA ERROR".into(),
             DataDirectory::Upgrading(Err(e)) => format!("upgrading ({:#})", e),
             DataDirectory::Upgrading(Ok(up)) => {
-                format!("upgrading {} -> {} for {}",
+                let mut text = format!("upgrading {} -> {} for {}",
                         up.source, up.target,
                         format_duration(
                             up.started.elapsed().unwrap_or(Duration::new(0, 0))
-                        ))
+                        ));
+                if !up.cli_version.is_empty() {
+                    text.push_str(&format!(" (by edgedb-cli {})",
+                                           up.cli_version));
+                }
+                if let Err(e) = up.check_supported() {
+                    text.push_str(&format!(" ({:#})", e));
+                }
+                text
             }
             DataDirectory::Normal => "normal".into(),
         });
@@ -307,7 +363,7 @@ fn probe_port(metadata: &anyhow::Result<Metadata>, reserved: &Option<u16>)
 }
 
 #[context("failed to read upgrade file {}", file.display())]
-fn read_upgrade(file: &Path) -> anyhow::Result<UpgradeMeta> {
+pub fn read_upgrade(file: &Path) -> anyhow::Result<UpgradeMeta> {
     Ok(serde_json::from_slice(&fs::read(&file)?)?)
 }
 
@@ -405,7 +461,7 @@ fn instances_from_data_dir(dir: &Path, system: bool,
 {
     for item in fs::read_dir(&dir)? {
         let item = item?;
-        if !item.file_type()?.is_dir() {
+        if !is_dir_entry(&item) {
             continue;
         }
         if let Some(name) = item.file_name().to_str() {
@@ -438,13 +494,22 @@ impl Cache {
     }
 }
 
-pub fn print_status_all(extended: bool) -> anyhow::Result<()> {
+fn get_status_all() -> anyhow::Result<Vec<Status>> {
     let instances = all_instances()?;
     let cache = Cache::new();
     let mut statuses = Vec::new();
     for (name, system) in instances {
         statuses.push(get_status_with(&name, system, &cache)?);
     }
+    Ok(statuses)
+}
+
+pub fn status_info_all() -> anyhow::Result<Vec<StatusInfo>> {
+    Ok(get_status_all()?.iter().map(|s| s.info()).collect())
+}
+
+pub fn print_status_all(extended: bool) -> anyhow::Result<()> {
+    let statuses = get_status_all()?;
     if statuses.is_empty() {
         eprintln!("No instances found");
         return Ok(());
@@ -466,14 +531,43 @@ pub fn print_status_all(extended: bool) -> anyhow::Result<()> {
                     .map(|m| m.port.to_string()).unwrap_or("?".into())),
                 Cell::new(&status.metadata.as_ref()
                     .map(|m| m.version.to_string()).unwrap_or("?".into())),
-                Cell::new(match status.service {
-                    Service::Running {..} => "running",
-                    Service::Failed {..} => "not running",
-                    Service::Inactive {..} => "inactive",
-                }),
+                Cell::new(status.service_title()),
             ]));
         }
         table.printstd();
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use crate::server::options::OutputFormat;
+    use crate::server::serialize;
+    use crate::server::version::Version;
+    use super::StatusInfo;
+
+    #[test]
+    fn test_yaml() {
+        let info = StatusInfo {
+            // would be read as a boolean unless quoted
+            name: "yes".into(),
+            service: "running",
+            pid: Some(1234),
+            version: Some(Version("1-alpha5".into())),
+            nightly: Some(false),
+            port: None,
+            upgrading: false,
+            backup: true,
+        };
+        let yaml = serialize(OutputFormat::Yaml, &vec![info]).unwrap();
+        let value: serde_yaml::Value = serde_yaml::from_str(&yaml).unwrap();
+        let inst = &value[0];
+        assert_eq!(inst["name"].as_str(), Some("yes"));
+        assert_eq!(inst["service"].as_str(), Some("running"));
+        assert_eq!(inst["pid"].as_u64(), Some(1234));
+        assert_eq!(inst["version"].as_str(), Some("1-alpha5"));
+        assert_eq!(inst["nightly"].as_bool(), Some(false));
+        assert!(inst["port"].is_null());
+        assert_eq!(inst["backup"].as_bool(), Some(true));
+    }
+}
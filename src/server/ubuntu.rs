@@ -1,4 +1,5 @@
 use std::path::PathBuf;
+use std::process::Command;
 
 use serde::Serialize;
 
@@ -60,10 +61,16 @@ impl<'os> Method for PackageMethod<'os, Ubuntu> {
     fn install(&self, settings: &install::Settings)
         -> Result<(), anyhow::Error>
     {
+        if let Some(prefix) = &settings.prefix {
+            return self.os.common.install_to_prefix(settings, prefix);
+        }
         linux::perform_install(
             self.os.common.install_operations(settings)?,
             &self.os.linux)
     }
+    fn install_is_exclusive(&self) -> bool {
+        true
+    }
     fn all_versions(&self, nightly: bool) -> anyhow::Result<&[PackageInfo]> {
         Ok(self.os.common.get_repo(nightly)?
             .map(|x| &x.packages[..]).unwrap_or(&[]))
@@ -75,14 +82,37 @@ impl<'os> Method for PackageMethod<'os, Ubuntu> {
             .ok_or_else(|| anyhow::anyhow!("No repository found"))?;
         package::find_version(packages, query)
     }
+    fn refresh_keys(&self, key: &str) -> anyhow::Result<()> {
+        linux::perform_install(self.os.common.key_operations(key),
+                               &self.os.linux)
+    }
+    fn verify_installation(&self, settings: &install::Settings)
+        -> anyhow::Result<bool>
+    {
+        linux::verify_package(Command::new("dpkg")
+            .arg("--verify")
+            .arg(format!("{}-{}",
+                         settings.package_name, settings.major_version)))?;
+        Ok(true)
+    }
     fn installed_versions(&self) -> anyhow::Result<&[InstalledPackage]> {
         Ok(&self.installed.get_or_try_init(|| {
             debian_like::get_installed()
         })?)
     }
+    fn uninstall(&self, package: &InstalledPackage) -> anyhow::Result<()> {
+        linux::perform_install(debian_like::uninstall_operations(package)?,
+                               &self.os.linux)
+    }
     fn detect_all(&self) -> serde_json::Value {
         serde_json::to_value(self).expect("can serialize")
     }
+    fn extra_settings(&self) -> &[&'static str] {
+        debian_like::EXTRA_SETTINGS
+    }
+    fn supports_prefix(&self) -> bool {
+        true
+    }
     fn get_server_path(&self, major_version: &Version<String>)
         -> anyhow::Result<PathBuf>
     {
@@ -1,29 +1,75 @@
 use std::fs;
-use std::io;
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::TcpListener;
 use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::Arc;
+use std::thread;
 use std::collections::BTreeMap;
-use std::time::{SystemTime, Duration};
+use std::time::{SystemTime, Duration, Instant};
 
 use anyhow::Context;
+use async_std::prelude::FutureExt;
 use async_std::task;
 use fn_error_context::context;
-use linked_hash_map::LinkedHashMap;
+use once_cell::sync::OnceCell;
 use serde::{Serialize, Deserialize};
 
 use edgedb_client as client;
+use edgedb_client::client::Connection;
+use edgedb_client::credentials::Credentials;
+use edgeql_parser::helpers::{quote_name, quote_string};
+use crate::server::config;
 use crate::server::control;
 use crate::server::detect::{self, VersionQuery};
-use crate::server::init::{init, Metadata, data_path};
-use crate::server::install;
-use crate::server::options::{self, Upgrade};
+use crate::server::dump_layout;
+use crate::server::encrypted_backup::{self, BackupFormat};
+use crate::server::init::{self, init, Metadata, data_path, write_metadata};
+use crate::server::install::{self, exit_codes};
+use crate::server::memory_budget::MemoryBudget;
+use crate::server::methods::{self, InstallMethod};
+use crate::server::options::{self, Upgrade, ConnectMethod, OutputFormat};
+use crate::server::options::TempAuth;
 use crate::server::os_trait::Method;
+use crate::server::ports;
+use crate::server::remote;
+use crate::server::reset_password::write_credentials;
+use crate::server::snapshot::{self, SnapshotMeta};
+use crate::server::verify::{self, Fingerprint};
 use crate::server::version::Version;
-use crate::server::is_valid_name;
+use crate::server::wait;
+use crate::server::{confirm, is_valid_name, print_serialized};
 use crate::commands;
-use crate::process::ProcessGuard;
+use crate::platform::{tmp_file_name, home_dir};
+use crate::process::{ProcessGuard, TRACE, terminate_all};
+use crate::self_install::read_choice;
 
 
+/// Version of the `UpgradeMeta` format written by this tool
+///
+/// Markers written before the field was introduced are read as version `0`.
+pub const UPGRADE_META_VERSION: u32 = 1;
+
+pub const DUMP_META: &str = "upgrade-dump.json";
+/// Written into the data directory (as `UpgradeMeta`) once upgrade of the
+/// instance is complete
+pub const UPGRADE_DONE: &str = "upgrade-done.json";
+/// Restore speed (bytes of data directory per second) assumed for
+/// estimating downtime of instances that were never dumped before
+const ASSUMED_RESTORE_RATE: u64 = 20_000_000;
+/// Time needed to install package, reinit and restart the instance
+const RESTART_TIME: Duration = Duration::from_secs(30);
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Most major versions an upgrade may skip with `--compat-check`. Every
+/// major version may change the dump format, and restoring dumps of much
+/// older versions isn't guaranteed to work
+const MAX_SKIPPED_MAJORS: usize = 2;
+/// How long to wait for the server to accept connections, unless
+/// `--start-timeout` is specified
+pub const START_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UpgradeMeta {
     pub source: Version<String>,
@@ -31,32 +77,163 @@ pub struct UpgradeMeta {
     #[serde(with="humantime_serde")]
     pub started: SystemTime,
     pub pid: u32,
+    #[serde(default)]
+    pub cli_version: String,
+    #[serde(default)]
+    pub schema_version: u32,
+}
+
+/// Instance listed in the `--inventory` file (or given on stdin), as
+/// printed by `export-metadata --inventory-item`. Other fields are rejected,
+/// as a misspelled one (e.g. `version`) would change what is upgraded
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct InventoryItem {
+    pub name: String,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub method: Option<InstallMethod>,
+    /// Major version to upgrade to. By default the instance is upgraded to
+    /// the latest minor version (or the latest nightly)
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub target_version: Option<Version<String>>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct BackupMeta {
     #[serde(with="humantime_serde")]
     pub timestamp: SystemTime,
+    /// Set if backup is a filesystem snapshot
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub snapshot: Option<SnapshotMeta>,
+    /// Backups made before encryption was supported are plain directories
+    #[serde(default)]
+    pub format: BackupFormat,
+}
+
+/// Written into the dump directory once dump is complete
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DumpMeta {
+    pub source: Version<String>,
+    #[serde(with="humantime_serde")]
+    pub timestamp: SystemTime,
+    pub files: Vec<String>,
+    #[serde(default)]
+    pub fingerprint: Option<Fingerprint>,
+    #[serde(default, with="humantime_serde")]
+    pub duration: Option<Duration>,
+    /// Size of the data directory at the time of the dump
+    #[serde(default)]
+    pub data_size: Option<u64>,
+    /// Dump is made with `--schema-only`, so it has no data
+    #[serde(default)]
+    pub schema_only: bool,
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Time when `--deadline` is reached
+static DEADLINE: OnceCell<Instant> = OnceCell::new();
+
+/// Category of the upgrade failure
+///
+/// Returned wrapped into `anyhow::Error`, use `downcast_ref` to inspect.
+#[derive(Debug, thiserror::Error)]
+pub enum UpgradeError {
+    #[error("failed to dump instance {0:?}")]
+    DumpFailed(String, #[source] BoxError),
+    #[error("failed to install package")]
+    InstallFailed(#[source] BoxError),
+    #[error("failed to restore instance {0:?}")]
+    RestoreFailed(String, #[source] BoxError),
+    #[error("unable to determine version")]
+    VersionResolution(#[source] BoxError),
+    #[error("instance {0:?} not found")]
+    InstanceNotFound(String),
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Summary {
+    upgraded: usize,
+    up_to_date: usize,
+    skipped: usize,
+    failed: usize,
+    instances: Vec<InstanceSummary>,
+    /// Instances that were requested but not found
+    #[serde(skip_serializing_if="Vec::is_empty")]
+    not_found: Vec<String>,
+    /// Instances that failed with `--continue-on-error`
+    #[serde(skip_serializing_if="Vec::is_empty")]
+    errors: Vec<InstanceError>,
+}
+
+#[derive(Debug, Serialize)]
+struct InstanceError {
+    name: String,
+    error: String,
 }
 
-struct Instance {
+#[derive(Serialize)]
+struct Report<'a> {
+    #[serde(flatten)]
+    summary: &'a Summary,
+    #[serde(with="humantime_serde")]
+    elapsed: Duration,
+}
+
+#[derive(Debug, Serialize)]
+struct InstanceSummary {
     name: String,
-    meta: Metadata,
-    system: bool,
-    data_dir: PathBuf,
+    from: Option<Version<String>>,
+    to: Option<Version<String>>,
+    #[serde(flatten)]
+    metrics: TransferMetrics,
+}
+
+/// Sizes and durations of dump and restore of an instance. Sizes are of
+/// the dump files, so they aren't known if data is streamed or the dump
+/// is reused
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TransferMetrics {
+    bytes_dumped: Option<u64>,
+    #[serde(with="humantime_serde")]
+    dump_time: Option<Duration>,
+    bytes_restored: Option<u64>,
+    #[serde(with="humantime_serde")]
+    restore_time: Option<Duration>,
+}
+
+pub struct Instance {
+    pub name: String,
+    pub meta: Metadata,
+    pub system: bool,
+    pub data_dir: PathBuf,
     source: Option<Version<String>>,
     version: Option<Version<String>>,
+    fingerprint: Option<Fingerprint>,
+    metrics: TransferMetrics,
+    /// Dump to use instead of `{name}.dump` next to the data directory
+    /// (given to `reinit` or expanded from `--dump-layout`)
+    dump_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ChannelSwitch {
+    ToNightly,
+    ToStable,
 }
 
 enum ToDo {
     MinorUpgrade,
     InstanceUpgrade(String, VersionQuery),
     NightlyUpgrade,
+    AllChannels,
 }
 
 struct InstanceIterator {
     dir: fs::ReadDir,
     path: PathBuf,
+    /// Fail on unreadable metadata instead of skipping the instance
+    strict: bool,
 }
 
 
@@ -78,12 +255,54 @@ fn interpret_options(options: &Upgrade) -> ToDo {
         ToDo::InstanceUpgrade(name.into(), nver)
     } else if options.nightly {
         ToDo::NightlyUpgrade
+    } else if options.all_channels {
+        ToDo::AllChannels
     } else {
         ToDo::MinorUpgrade
     }
 }
 
-fn all_instances() -> anyhow::Result<Vec<Instance>> {
+fn channel_switch(was_nightly: bool, version: &VersionQuery)
+    -> Option<ChannelSwitch>
+{
+    match (was_nightly, version.is_nightly()) {
+        (false, true) => Some(ChannelSwitch::ToNightly),
+        (true, false) => Some(ChannelSwitch::ToStable),
+        _ => None,
+    }
+}
+
+fn check_channel_switch(inst: &Instance, version: &VersionQuery,
+    options: &Upgrade)
+    -> anyhow::Result<()>
+{
+    let (from, to) = match channel_switch(inst.meta.nightly, version) {
+        Some(ChannelSwitch::ToNightly) => ("stable", "nightly"),
+        Some(ChannelSwitch::ToStable) => ("nightly", "stable"),
+        None => return Ok(()),
+    };
+    if !options.allow_channel_switch {
+        anyhow::bail!("Instance {:?} is {}, upgrading it to {} \
+            might make data incompatible with the {} versions. \
+            Use `--allow-channel-switch` to upgrade anyway.",
+            inst.name, from, to, from);
+    }
+    if options.non_interactive {
+        return Ok(());
+    }
+    let question = format!("Switch instance {:?} from {} to {}?",
+        inst.name, from, to);
+    if !confirm(&question)? {
+        anyhow::bail!("Canceled by user");
+    }
+    Ok(())
+}
+
+pub fn all_instances() -> anyhow::Result<Vec<Instance>> {
+    read_instances(false)
+}
+
+pub fn read_instances(strict: bool) -> anyhow::Result<Vec<Instance>> {
     let path = data_path(false)?;
     if !path.exists() {
         return Ok(Vec::new());
@@ -91,9 +310,23 @@ fn all_instances() -> anyhow::Result<Vec<Instance>> {
     InstanceIterator {
         dir: fs::read_dir(&path)?,
         path: path.into(),
+        strict,
     }.collect::<Result<Vec<_>,_>>()
 }
 
+/// Whether the entry is a directory or a symlink to one (data directories
+/// are often symlinked when relocating storage). Broken symlinks and
+/// symlink loops are skipped
+pub fn is_dir_entry(item: &fs::DirEntry) -> bool {
+    match fs::metadata(item.path()) {
+        Ok(meta) => meta.is_dir(),
+        Err(e) => {
+            log::warn!("Skipping {}: {}", item.path().display(), e);
+            false
+        }
+    }
+}
+
 fn read_metadata(path: &Path) -> anyhow::Result<Metadata> {
     let file = fs::read(path)
         .with_context(|| format!("error reading {}", path.display()))?;
@@ -122,22 +355,23 @@ impl InstanceIterator {
         let item = item.with_context(
             || format!("error listing instances dir {}",
                        self.path.display()))?;
-        if !item.file_type()
-            .with_context(|| format!(
-                "error listing {}: cannot determine entry type",
-                self.path.display()))?
-            .is_dir()
-        {
+        if !is_dir_entry(&item) {
             return Ok(None);
         }
         if let Some(name) = item.file_name().to_str() {
             if !is_valid_name(name) {
+                log::debug!(target: "edgedb::server::upgrade",
+                    "Skipping {:?}: not a valid instance name", name);
                 return Ok(None);
             }
             let meta = match
                 read_metadata(&item.path().join("metadata.json"))
             {
                 Ok(metadata) => metadata,
+                Err(e) if self.strict => {
+                    return Err(e.context(format!("error reading metadata \
+                        for instance {:?} (`--abort-on-warning`)", name)));
+                }
                 Err(e) => {
                     log::warn!(target: "edgedb::server::upgrade",
                         "Error reading metadata for \
@@ -153,6 +387,9 @@ impl InstanceIterator {
                     data_dir: item.path(),
                     source: None,
                     version: None,
+                    fingerprint: None,
+                    metrics: TransferMetrics::default(),
+                    dump_dir: None,
             }));
         } else {
             return Ok(None);
@@ -160,42 +397,535 @@ impl InstanceIterator {
     }
 }
 
-fn get_instances(todo: &ToDo)
+fn get_instances(todo: &ToDo, strict: bool)
     -> anyhow::Result<Vec<Instance>>
 {
     use ToDo::*;
 
     let instances = match todo {
-        MinorUpgrade => all_instances()?.into_iter()
+        MinorUpgrade => read_instances(strict)?.into_iter()
             .filter(|inst| !inst.meta.nightly)
             .collect(),
-        NightlyUpgrade => all_instances()?.into_iter()
+        NightlyUpgrade => read_instances(strict)?.into_iter()
             .filter(|inst| inst.meta.nightly)
             .collect(),
-        InstanceUpgrade(name, ..) => all_instances()?.into_iter()
+        InstanceUpgrade(name, ..) => read_instances(strict)?.into_iter()
             .filter(|inst| &inst.name == name)
             .collect(),
+        AllChannels => read_instances(strict)?,
     };
     Ok(instances)
 }
 
 pub fn upgrade(options: &Upgrade) -> anyhow::Result<()> {
-    use ToDo::*;
+    if !options.repository_url.is_empty() {
+        remote::set_mirrors(options.repository_url.clone());
+    }
+    if options.no_verify_tls {
+        remote::disable_tls_verification();
+    }
+    if options.port.is_some() && options.name.is_none() {
+        anyhow::bail!("`--port` can only be used when upgrading \
+            a single instance");
+    }
+    if options.transfer_parallelism == 0 {
+        anyhow::bail!("`--transfer-parallelism` must be at least 1");
+    }
+    if options.max_parallel_stops == Some(0) {
+        anyhow::bail!("`--max-parallel-stops` must be at least 1");
+    }
+    if options.max_parallel_starts == Some(0) {
+        anyhow::bail!("`--max-parallel-starts` must be at least 1");
+    }
+    if options.max_memory == Some(0) {
+        anyhow::bail!("`--max-memory` must be greater than zero");
+    }
+    if let Some(path) = &options.post_restore_script {
+        if !path.exists() {
+            anyhow::bail!("Post-restore script {} does not exist",
+                path.display());
+        }
+    }
+    if options.stream && options.name.is_none() {
+        anyhow::bail!("`--stream` can only be used when upgrading \
+            a single instance");
+    }
+    if options.zero_downtime {
+        // without a change feed, writes made after the dump would be lost
+        // on the cutover
+        log::warn!(target: "edgedb::server::upgrade",
+            "`--zero-downtime` requires change capture, which the server \
+            doesn't support. Falling back to the usual upgrade: instances \
+            are unavailable while data is restored");
+    }
+    if options.checkpoint.is_some() {
+        log::warn!(target: "edgedb::server::upgrade",
+            "`--checkpoint` is ignored: restore protocol doesn't support \
+            resuming an interrupted restore, it's restarted from scratch");
+    }
+    if options.reuse_dump && options.dump_layout.as_ref()
+        .map(|layout| layout.has_timestamp()).unwrap_or(false)
+    {
+        anyhow::bail!("`--reuse-dump` can't find previous dumps \
+            if `--dump-layout` contains {{timestamp}}");
+    }
+    if let Some(path) = &options.server_binary {
+        check_server_binary(path)?;
+    }
+    if options.instances_from_stdin && needs_confirmation(options) {
+        // confirmation would be read from the list of instances
+        anyhow::bail!("`--instances-from-stdin` requires \
+            `--non-interactive` with `--allow-channel-switch`, \
+            `--allow-non-empty` or `--schema-only`");
+    }
+    if options.schema_only && !options.non_interactive
+        && !options.dry_run && !options.estimate
+    {
+        confirm_schema_only()?;
+    }
+    if options.backup_encrypt {
+        // fail before anything is changed rather than after the upgrade
+        encrypted_backup::read_key(options.backup_key_file.as_deref())?;
+    }
+    let started = Instant::now();
+    if let Some(deadline) = options.deadline {
+        start_deadline_watchdog(started, deadline);
+    }
+    let items = if let Some(path) = &options.inventory {
+        Some(read_inventory(path)?)
+    } else if options.instances_from_stdin {
+        let mut input = String::new();
+        io::stdin().read_to_string(&mut input)
+            .context("cannot read instances from stdin")?;
+        Some(parse_stdin_items(&input)?)
+    } else {
+        None
+    };
+    let from_items = items.is_some();
+    let mut summary = Summary::default();
+    let mut plan = if let Some(mut items) = items {
+        let instances = read_instances(options.abort_on_warning)?;
+        if options.continue_on_error {
+            items.retain(|item| {
+                let found = instances.iter().any(|i| i.name == item.name);
+                if !found {
+                    summary.not_found.push(item.name.clone());
+                }
+                found
+            });
+            if !summary.not_found.is_empty() {
+                log::warn!(target: "edgedb::server::upgrade",
+                    "Instances not found on this host: {}",
+                    summary.not_found.join(", "));
+                summary.failed += summary.not_found.len();
+            }
+        }
+        plan_inventory(items, instances)?.into_iter()
+            .map(|(todo, inst)| (todo, vec![inst]))
+            .collect::<Vec<_>>()
+    } else {
+        let todo = interpret_options(&options);
+        let instances = get_instances(&todo, options.abort_on_warning)?;
+        vec![(todo, instances)]
+    };
+    if !options.tags.is_empty() {
+        retain_instances(&mut plan, |inst| options.tags.iter()
+            .all(|(k, v)| inst.meta.labels.get(k) == Some(v)));
+        if plan.iter().any(|(_, instances)| !instances.is_empty()) {
+            println!("Instances matching labels: {}", plan_names(&plan));
+        }
+    }
+    let method = match &options.method {
+        Some(method) => Some(method.clone()),
+        None => methods::default_method()?,
+    };
+    if let Some(method) = &method {
+        if let [(ToDo::InstanceUpgrade(name, ..), instances)] = &plan[..] {
+            if let Some(inst) = instances.iter()
+                .find(|inst| &inst.meta.method != method)
+            {
+                anyhow::bail!("Instance {:?} is installed by {}, \
+                    not {} (set by `--method` or {})", name,
+                    inst.meta.method.title(), method.title(),
+                    methods::METHOD_ENV);
+            }
+        }
+        retain_instances(&mut plan, |inst| &inst.meta.method == method);
+    }
+    if let Some(major) = &options.only_major {
+        // nightly instances are kept, `--all-channels` upgrades them too
+        retain_instances(&mut plan, |inst| inst.meta.nightly
+                                           || &inst.meta.version == major);
+        let stable = plan.iter().flat_map(|(_, instances)| instances)
+            .filter(|inst| !inst.meta.nightly)
+            .map(|inst| &inst.name[..])
+            .collect::<Vec<_>>();
+        if stable.is_empty() {
+            anyhow::bail!("No instances of version {} found", major);
+        }
+        println!("Instances of version {}: {}", major, stable.join(", "));
+    }
+    if from_items {
+        plan.retain(|(_, instances)| !instances.is_empty());
+    } else if let [(todo, instances)] = &mut plan[..] {
+        if options.interactive && instances.len() > 1 {
+            if atty::is(atty::Stream::Stdin) {
+                *instances = select_instances(todo, mem::take(instances))?;
+                if instances.is_empty() {
+                    anyhow::bail!("Canceled by user");
+                }
+            } else {
+                log::warn!(target: "edgedb::server::upgrade",
+                    "Stdin is not a terminal, \
+                    upgrading all matching instances");
+            }
+        }
+        if instances.is_empty() {
+            if let ToDo::InstanceUpgrade(name, ..) = todo {
+                if options.tags.is_empty() {
+                    return Err(
+                        UpgradeError::InstanceNotFound(name.clone()))?;
+                }
+            }
+            if options.nightly {
+                log::warn!(target: "edgedb::server::upgrade",
+                    "No instances found. Nothing to upgrade.");
+            } else {
+                log::warn!(target: "edgedb::server::upgrade",
+                    "No instances found. Nothing to upgrade \
+                    (Note: nightly instances are upgraded only if \
+                    `--nightly` is specified).");
+            }
+            return Ok(());
+        }
+    }
+    let result = run_plan(plan, options, &mut summary,
+        |instances| print_estimates(instances, options),
+        |todo, instances, summary| {
+            upgrade_instances(todo, instances, options, summary)
+        });
+    if options.estimate {
+        return result;
+    }
+    finish(&summary, result, options, started)
+}
 
-    let todo = interpret_options(&options);
-    let instances = get_instances(&todo)?;
-    if instances.is_empty() {
-        if options.nightly {
-            log::warn!(target: "edgedb::server::upgrade",
-                "No instances found. Nothing to upgrade.");
+/// Removes instances not matching `filter` from every step of the plan
+fn retain_instances(plan: &mut [(ToDo, Vec<Instance>)],
+    mut filter: impl FnMut(&Instance) -> bool)
+{
+    for (_, instances) in plan {
+        instances.retain(|inst| filter(inst));
+    }
+}
+
+fn plan_names(plan: &[(ToDo, Vec<Instance>)]) -> String {
+    plan.iter().flat_map(|(_, instances)| instances)
+        .map(|inst| &inst.name[..])
+        .collect::<Vec<_>>().join(", ")
+}
+
+/// Estimates or upgrades instances of the plan, step by step
+///
+/// With `--continue-on-error` a failed step is recorded in the summary and
+/// the next one is run.
+fn run_plan(plan: Vec<(ToDo, Vec<Instance>)>, options: &Upgrade,
+    summary: &mut Summary,
+    estimate: impl FnOnce(&[Instance]) -> anyhow::Result<()>,
+    mut upgrade: impl FnMut(&ToDo, Vec<Instance>, &mut Summary)
+        -> anyhow::Result<()>)
+    -> anyhow::Result<()>
+{
+    if options.estimate {
+        let instances = plan.into_iter()
+            .flat_map(|(_, instances)| instances)
+            .collect::<Vec<_>>();
+        return estimate(&instances);
+    }
+    if options.allow_non_empty && !options.dry_run
+        && !options.non_interactive
+    {
+        confirm_non_empty(&plan_names(&plan))?;
+    }
+    let mut result = Ok(());
+    for (todo, instances) in plan {
+        result = check_deadline();
+        if result.is_err() {
+            break;
+        }
+        let name = instance_names(&instances);
+        result = upgrade(&todo, instances, summary);
+        if let Err(e) = &result {
+            if !options.continue_on_error || deadline_exceeded() {
+                break;
+            }
+            log::error!(target: "edgedb::server::upgrade",
+                "Upgrade of {} failed: {:#}. Continuing with \
+                the next instance.", name, e);
+            summary.errors.push(InstanceError {
+                name,
+                error: format!("{:#}", e),
+            });
+            result = Ok(());
+        }
+    }
+    if let Err(e) = &result {
+        if deadline_exceeded() {
+            log::error!(target: "edgedb::server::upgrade",
+                "{:#}. Data directories and backups (if made) are kept, \
+                run `edgedb server upgrade` again to continue or \
+                `edgedb server restore-backup` to roll back.", e);
+            result = Err(commands::ExitCode::new(
+                exit_codes::DEADLINE_EXCEEDED).into());
+        }
+    }
+    if result.is_ok() && summary.failed > 0 {
+        result = Err(commands::ExitCode::new(
+            exit_codes::PARTIALLY_FAILED).into());
+    }
+    result
+}
+
+/// Writes and prints the summary of the upgrade
+fn finish(summary: &Summary, result: anyhow::Result<()>, options: &Upgrade,
+    started: Instant)
+    -> anyhow::Result<()>
+{
+    if let Some(path) = &options.summary_file {
+        write_json_atomic(path, &summary.report(started.elapsed()))
+            .with_context(|| format!("cannot write summary to {}",
+                                     path.display()))?;
+    }
+    if !options.quiet {
+        summary.print(started.elapsed(), options.format)?;
+    }
+    result
+}
+
+/// Parses `--instances-from-stdin` input: an instance name or a JSON object
+/// (same as an inventory item) per line, empty lines are skipped
+fn parse_stdin_items(input: &str) -> anyhow::Result<Vec<InventoryItem>> {
+    let mut items = Vec::new();
+    for (idx, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('{') {
+            items.push(serde_json::from_str(line)
+                .with_context(|| format!("invalid instance on line {}",
+                                         idx + 1))?);
         } else {
-            log::warn!(target: "edgedb::server::upgrade",
-                "No instances found. Nothing to upgrade \
-                (Note: nightly instances are upgraded only if `--nightly` \
-                is specified).");
+            items.push(InventoryItem {
+                name: line.into(),
+                method: None,
+                target_version: None,
+            });
+        }
+    }
+    Ok(items)
+}
+
+#[context("cannot read inventory {}", path.display())]
+fn read_inventory(path: &Path) -> anyhow::Result<Vec<InventoryItem>> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+/// Matches inventory items with the local instances, returns what to do
+/// with each of them in the order of the inventory
+fn plan_inventory(items: Vec<InventoryItem>, instances: Vec<Instance>)
+    -> anyhow::Result<Vec<(ToDo, Instance)>>
+{
+    let mut instances = instances.into_iter()
+        .map(|inst| (inst.name.clone(), inst))
+        .collect::<BTreeMap<_, _>>();
+    let mut unknown = Vec::new();
+    let mut plan = Vec::new();
+    for item in items {
+        let inst = match instances.remove(&item.name) {
+            Some(inst) => inst,
+            None => {
+                unknown.push(item.name);
+                continue;
+            }
+        };
+        if let Some(method) = &item.method {
+            if method != &inst.meta.method {
+                anyhow::bail!("Instance {:?} is installed by {}, \
+                    but the inventory lists {}",
+                    inst.name, inst.meta.method.title(), method.title());
+            }
+        }
+        let todo = match item.target_version {
+            Some(ver) => ToDo::InstanceUpgrade(
+                inst.name.clone(), VersionQuery::Stable(Some(ver))),
+            None if inst.meta.nightly => ToDo::NightlyUpgrade,
+            None => ToDo::MinorUpgrade,
+        };
+        plan.push((todo, inst));
+    }
+    if !unknown.is_empty() {
+        anyhow::bail!("Instances listed in the inventory are not found \
+            on this host: {}", unknown.join(", "));
+    }
+    Ok(plan)
+}
+
+/// Whether upgrade may ask questions on stdin
+pub fn needs_confirmation(options: &Upgrade) -> bool {
+    (options.allow_channel_switch || options.allow_non_empty
+        || options.schema_only)
+        && !options.non_interactive
+}
+
+fn confirm_schema_only() -> anyhow::Result<()> {
+    eprintln!("WARNING: `--schema-only` transfers only schema to upgraded \
+        instances. All their data is lost, except for the backup of the \
+        data directory.");
+    if !confirm("Upgrade without data?")? {
+        anyhow::bail!("Canceled by user");
+    }
+    Ok(())
+}
+
+fn confirm_non_empty(names: &str) -> anyhow::Result<()> {
+    eprintln!("WARNING: `--allow-non-empty` restores dumps into databases \
+        that may already contain data. Objects of the dump conflicting \
+        with existing schema or data make the restore fail midway, \
+        and configuration and roles of the dump overwrite existing ones.");
+    let question = format!("Restore into non-empty instance(s) {}?",
+        names);
+    if !confirm(&question)? {
+        anyhow::bail!("Canceled by user");
+    }
+    Ok(())
+}
+
+/// Parses selection like `1,3-5` into zero-based indexes
+fn parse_selection(choice: &str, total: usize) -> anyhow::Result<Vec<usize>> {
+    if choice == "all" {
+        return Ok((0..total).collect());
+    }
+    let mut result = Vec::new();
+    for item in choice.split(',').map(|x| x.trim()).filter(|x| !x.is_empty())
+    {
+        let (start, end) = match item.find('-') {
+            Some(idx) => (&item[..idx], &item[idx+1..]),
+            None => (item, item),
+        };
+        let start: usize = start.trim().parse()
+            .with_context(|| format!("invalid number in {:?}", item))?;
+        let end: usize = end.trim().parse()
+            .with_context(|| format!("invalid number in {:?}", item))?;
+        if start < 1 || end > total || start > end {
+            anyhow::bail!("{:?} is out of range 1-{}", item, total);
+        }
+        for idx in start-1..end {
+            if !result.contains(&idx) {
+                result.push(idx);
+            }
+        }
+    }
+    result.sort();
+    Ok(result)
+}
+
+fn select_instances(todo: &ToDo, instances: Vec<Instance>)
+    -> anyhow::Result<Vec<Instance>>
+{
+    println!("Instances to upgrade:");
+    for (idx, inst) in instances.iter().enumerate() {
+        let target = match todo {
+            ToDo::NightlyUpgrade => "latest nightly".into(),
+            _ if inst.meta.nightly => "latest nightly".into(),
+            _ => format!("latest {}", inst.meta.version),
+        };
+        println!("{:>4}. {} ({} -> {})",
+            idx + 1, inst.name, inst.meta.version, target);
+    }
+    let selected = loop {
+        print!("Select instances (e.g. `1,3-5`, `all`, empty to cancel): ");
+        io::stdout().flush()?;
+        match parse_selection(&read_choice()?, instances.len()) {
+            Ok(selected) => break selected,
+            Err(e) => eprintln!("Invalid choice: {:#}", e),
+        }
+    };
+    Ok(instances.into_iter().enumerate()
+        .filter(|(idx, _)| selected.contains(idx))
+        .map(|(_, inst)| inst)
+        .collect())
+}
+
+/// Sets the `--deadline` checked by `check_deadline`
+///
+/// When it's reached, servers run by the upgrade are also stopped, so
+/// a phase waiting for them fails instead of hanging, and the failed
+/// instance is rolled back like on any other error.
+fn start_deadline_watchdog(started: Instant, deadline: Duration) {
+    if DEADLINE.set(started + deadline).is_err() {
+        log::warn!("Deadline of the upgrade is already set");
+        return;
+    }
+    thread::spawn(move || {
+        thread::sleep(deadline);
+        log::error!(target: "edgedb::server::upgrade",
+            "Upgrade did not finish within `--deadline` of {}, aborting",
+            humantime::format_duration(deadline));
+        terminate_all();
+    });
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("upgrade did not finish within `--deadline`")]
+pub struct DeadlineExceeded;
+
+fn deadline_exceeded() -> bool {
+    DEADLINE.get().map(|deadline| Instant::now() >= *deadline)
+        .unwrap_or(false)
+}
+
+/// Fails if `--deadline` is reached. It's checked between the phases of
+/// the upgrade, so an instance is never left in the middle of one
+fn check_deadline() -> anyhow::Result<()> {
+    if deadline_exceeded() {
+        return Err(DeadlineExceeded.into());
+    }
+    Ok(())
+}
+
+/// Fails with `DeadlineExceeded` if `future` doesn't complete before
+/// `--deadline`. It's for phases run by this process, which the watchdog
+/// can't interrupt by stopping servers
+async fn with_deadline<T>(future: impl Future<Output=anyhow::Result<T>>)
+    -> anyhow::Result<T>
+{
+    let deadline = match DEADLINE.get() {
+        Some(deadline) => *deadline,
+        None => return future.await,
+    };
+    let left = deadline.saturating_duration_since(Instant::now());
+    match async_std::future::timeout(left, future).await {
+        Ok(result) => result,
+        Err(_) => Err(DeadlineExceeded.into()),
+    }
+}
+
+fn upgrade_instances(todo: &ToDo, mut instances: Vec<Instance>,
+    options: &Upgrade, summary: &mut Summary)
+    -> anyhow::Result<()>
+{
+    use ToDo::*;
+
+    if let Some(layout) = &options.dump_layout {
+        // expanded once, so the timestamp is the same for dump and restore
+        let started = SystemTime::now();
+        for inst in &mut instances {
+            inst.dump_dir = Some(layout.expand(&data_path(inst.system)?,
+                &inst.name, started, inst.meta.version.as_ref())?);
         }
-        return Ok(());
     }
+
     let mut by_method = BTreeMap::new();
     for instance in instances {
         by_method.entry(instance.meta.method.clone())
@@ -206,6 +936,11 @@ pub fn upgrade(options: &Upgrade) -> anyhow::Result<()> {
     let os = detect::current_os()?;
     let avail = os.get_available_methods()?;
     for (meth_name, instances) in by_method {
+        if !avail.is_supported(&meth_name) && options.abort_on_warning {
+            anyhow::bail!("method {} is not available, \
+                aborting because of `--abort-on-warning`",
+                meth_name.title());
+        }
         if !avail.is_supported(&meth_name) {
             log::warn!(target: "edgedb::server::upgrade",
                 "method {} is not available. \
@@ -217,28 +952,100 @@ pub fn upgrade(options: &Upgrade) -> anyhow::Result<()> {
                     .collect::<Vec<_>>()
                     .join(", "),
             );
+            summary.skipped += instances.len();
             continue;
         }
-        let method = os.make_method(&meth_name, &avail)?;
-        match todo {
-            MinorUpgrade => {
-                do_minor_upgrade(&*method, instances, options)?;
-            }
-            NightlyUpgrade => {
-                do_nightly_upgrade(&*method, instances, options)?;
-            }
-            InstanceUpgrade(.., ref version) => {
-                for inst in instances {
-                    do_instance_upgrade(&*method, inst, version, options)?;
+        let total = instances.len();
+        let done = summary.upgraded + summary.up_to_date;
+        let result = os.make_method(&meth_name, &avail).and_then(|method| {
+            // validate before any instance is stopped
+            install::extra_settings(&*method, &options.extra)?;
+            match todo {
+                MinorUpgrade => {
+                    do_minor_upgrade(&*method, instances, options, summary)
+                }
+                NightlyUpgrade => {
+                    do_nightly_upgrade(&*method, instances, options, summary)
+                }
+                AllChannels => {
+                    let (nightly, stable) = instances.into_iter()
+                        .partition::<Vec<_>, _>(|inst| inst.meta.nightly);
+                    if !stable.is_empty() {
+                        do_minor_upgrade(&*method, stable, options, summary)?;
+                    }
+                    if !nightly.is_empty() {
+                        do_nightly_upgrade(&*method, nightly,
+                                           options, summary)?;
+                    }
+                    Ok(())
+                }
+                InstanceUpgrade(.., version) => {
+                    for inst in instances {
+                        do_instance_upgrade(&*method, inst, version,
+                                            options, summary)?;
+                    }
+                    Ok(())
                 }
             }
+        });
+        if let Err(e) = result {
+            summary.failed += total -
+                (summary.upgraded + summary.up_to_date - done);
+            return Err(e);
         }
     }
     Ok(())
 }
 
+/// Returns an error if the instance failed to stop and is still running
+/// (or its status is unknown), so package must not be installed over it
+fn check_stopped(name: &str, stopped: anyhow::Result<()>,
+    is_running: impl FnOnce() -> anyhow::Result<bool>, force: bool)
+    -> anyhow::Result<()>
+{
+    let err = match stopped {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+    match is_running() {
+        Ok(false) => {
+            log::warn!("Failed to stop instance {:?}: {:#}. \
+                It's not running, so continuing.", name, err);
+            Ok(())
+        }
+        _ if force => {
+            log::warn!("Failed to stop instance {:?}: {:#}. \
+                Upgrading anyway because of `--force`.", name, err);
+            Ok(())
+        }
+        _ => {
+            Err(err.context(format!("failed to stop instance {:?}, \
+                installing the package over the running server may leave \
+                it half-upgraded (use `--force` to upgrade anyway)", name)))
+        }
+    }
+}
+
+/// Starts the instance that was stopped for an upgrade which failed
+/// before installing the package
+fn start_back(name: &str, timeout: Duration) {
+    log::info!(target: "edgedb::server::upgrade",
+        "Starting instance {:?} back", name);
+    let started = wait::start(&options::Start {
+        name: name.into(),
+        foreground: false,
+        wait: true,
+        wait_timeout: Some(timeout),
+    });
+    if let Err(e) = started {
+        log::error!(target: "edgedb::server::upgrade",
+            "Cannot start instance {:?} back, start it manually: {:#}",
+            name, e);
+    }
+}
+
 fn do_minor_upgrade(method: &dyn Method,
-    instances: Vec<Instance>, options: &Upgrade)
+    instances: Vec<Instance>, options: &Upgrade, summary: &mut Summary)
     -> anyhow::Result<()>
 {
     let mut by_major = BTreeMap::new();
@@ -247,31 +1054,58 @@ fn do_minor_upgrade(method: &dyn Method,
             .or_insert_with(Vec::new)
             .push(inst);
     }
-    for (version, mut instances) in by_major {
-        let instances_str = instances
-            .iter().map(|inst| &inst.name[..]).collect::<Vec<_>>().join(", ");
-
-        let version_query = VersionQuery::Stable(Some(version.clone()));
-        let new = method.get_version(&version_query)
-            .context("Unable to determine version")?;
+    let queries = by_major.keys()
+        .map(|version| VersionQuery::Stable(Some(version.clone())))
+        .collect::<Vec<_>>();
+    let majors = by_major.keys().cloned().collect::<Vec<_>>();
+    let versions = method.latest_compatible_versions(&majors);
+    let groups = by_major.into_iter().zip(queries).zip(versions);
+    for (((version, instances), version_query), new) in groups {
+        let new = new.map_err(|e| UpgradeError::VersionResolution(e.into()))?;
         let old = get_installed(&version_query, method)?;
+        let target = new.full_version();
 
-        if !options.force {
-            if let Some(old_ver) = &old {
-                if old_ver >= &new.full_version() {
-                    log::info!(target: "edgedb::server::upgrade",
-                        "Version {} is up to date {}, skipping instances: {}",
-                        version, old_ver, instances_str);
-                    return Ok(());
-                }
+        let mut pending = Vec::new();
+        let revisions = group_by_revision(instances,
+            |inst| current_revision(inst, old.as_ref()));
+        for (revision, instances) in revisions {
+            if is_up_to_date(revision.as_ref(), &target, options.force) {
+                log::info!(target: "edgedb::server::upgrade",
+                    "Version {} is up to date {}, skipping instances: {}",
+                    version, revision.as_ref().unwrap(),
+                    instance_names(&instances));
+                summary.up_to_date += instances.len();
+            } else {
+                pending.push((revision, instances));
             }
         }
+        if pending.is_empty() {
+            continue;
+        }
+        // instances on older revisions are restarted even if the package
+        // is already installed (e.g. by another upgrade of the same major)
+        let install = options.force
+            || old.as_ref().map(|old| old < &target).unwrap_or(true);
+        let covered = pending.iter()
+            .map(|(revision, instances)| format!("{} (from {})",
+                instance_names(instances),
+                revision.as_ref().map(|r| r.as_ref()).unwrap_or("unknown")))
+            .collect::<Vec<_>>().join(", ");
 
+        if options.dry_run {
+            println!("Would upgrade version: {} to {}-{}, instances: {}",
+                version, new.version, new.revision, covered);
+            continue;
+        }
         println!("Upgrading version: {} to {}-{}, instances: {}",
-            version, new.version, new.revision, instances_str);
-        for inst in &mut instances {
-            inst.source = old.clone();
-            inst.version = Some(new.full_version());
+            version, new.version, new.revision, covered);
+        let mut instances = Vec::new();
+        for (revision, group) in pending {
+            for mut inst in group {
+                inst.source = revision.clone();
+                inst.version = Some(target.clone());
+                instances.push(inst);
+            }
         }
 
         // Stop instances first.
@@ -279,203 +1113,1634 @@ fn do_minor_upgrade(method: &dyn Method,
         // This (launchctl unload) is required for MacOS to reinstall
         // the pacakge. On other systems, this is also useful as in-place
         // modifying the running package isn't very good idea.
-        for inst in &mut instances {
-            let mut ctl = inst.get_control()?;
-            ctl.stop(&options::Stop { name: inst.name.clone() })
-                .map_err(|e| {
-                    log::warn!("Failed to stop instance {:?}: {:#}",
-                        inst.name, e);
-                })
-                .ok();
-        }
-
-        log::info!(target: "edgedb::server::upgrade", "Upgrading the package");
-        method.install(&install::Settings {
-            method: method.name(),
-            package_name: new.package_name,
-            major_version: version,
-            version: new.version,
-            nightly: false,
-            extra: LinkedHashMap::new(),
-        })?;
+        let timeout = options.start_timeout;
+        let strict = options.abort_on_warning;
+        let force = options.force;
+        let names = instances.iter().map(|inst| inst.name.clone())
+            .collect::<Vec<_>>();
+        let results = run_batched(names.clone(), options.max_parallel_stops,
+            move |name| {
+                let mut ctl = control::get_instance(&name)?;
+                let stopped = ctl.stop(&options::Stop {
+                    name: name.clone(),
+                    wait: true,
+                    wait_timeout: None,
+                });
+                if strict {
+                    stopped.with_context(|| format!("failed to stop \
+                        instance {:?} (`--abort-on-warning`)", name))
+                } else {
+                    check_stopped(&name, stopped,
+                        || Ok(ctl.get_status()?.is_running()), force)
+                }
+            });
+        if results.iter().any(|stopped| stopped.is_err()) {
+            // nothing is installed yet, so instances stopped so far are
+            // started back on the old version
+            for (name, stopped) in names.iter().zip(&results) {
+                if stopped.is_ok() {
+                    start_back(name, timeout);
+                }
+            }
+        }
+        for stopped in results {
+            stopped?;
+        }
 
-        for inst in &instances {
-            let mut ctl = inst.get_control()?;
-            ctl.start(&options::Start {
-                name: inst.name.clone(),
-                foreground: false,
-            })?;
+        if install {
+            log::info!(target: "edgedb::server::upgrade",
+                "Upgrading the package");
+            let prefix = install::prefix::find(&version);
+            install_package(method, &install::Settings {
+                method: method.name(),
+                package_name: new.package_name,
+                major_version: version,
+                version: new.version,
+                revision: new.revision,
+                nightly: false,
+                extra: install::extra_settings(method, &options.extra)?,
+                prefix,
+            }, options)?;
+        } else {
+            log::info!(target: "edgedb::server::upgrade",
+                "Package {} is already installed, restarting instances",
+                target);
+        }
+
+        let to_start = instances.iter()
+            .map(|inst| (inst.name.clone(), inst.data_dir.clone(),
+                         inst.upgrade_meta()))
+            .collect();
+        let budget = options.max_memory
+            .map(|max| Arc::new(MemoryBudget::new(max)));
+        let started = run_batched(to_start, options.max_parallel_starts,
+            move |(name, data_dir, meta)| {
+                // queued while starting servers would exceed the budget
+                let _reserved = budget.as_ref().map(|budget| {
+                    budget.reserve(dir_size(&data_dir).unwrap_or(0))
+                });
+                wait::start(&options::Start {
+                    name: name.clone(),
+                    foreground: false,
+                    wait: true,
+                    wait_timeout: Some(timeout),
+                })?;
+                // so the next upgrade knows the revision of this instance
+                write_json_atomic(&data_dir.join(UPGRADE_DONE), &meta)
+                    .map_err(|e| log::warn!(
+                        target: "edgedb::server::upgrade",
+                        "Cannot record upgrade of {:?}: {:#}", name, e))
+                    .ok();
+                Ok(())
+            });
+        let mut result = Ok(());
+        for (inst, started) in instances.iter().zip(started) {
+            match started {
+                Ok(()) => summary.add_upgraded(inst),
+                Err(e) if result.is_ok() => result = Err(e),
+                Err(_) => {}
+            }
         }
+        result?;
     }
     Ok(())
 }
 
-async fn dump_instance(inst: &Instance, socket: &Path)
-    -> anyhow::Result<()>
+/// Runs `f` for every item in its own thread, at most `limit` items at a
+/// time (all at once by default). Items after a batch that failed are not
+/// run, so there may be fewer results than items
+fn run_batched<T, F>(items: Vec<T>, limit: Option<usize>, f: F)
+    -> Vec<anyhow::Result<()>>
+    where T: Send + 'static,
+          F: Fn(T) -> anyhow::Result<()> + Send + Sync + 'static,
 {
-    log::info!(target: "edgedb::server::upgrade",
-        "Dumping instance {:?}", inst.name);
-    let path = data_path(false)?.join(format!("{}.dump", inst.name));
-    if path.exists() {
-        log::info!(target: "edgedb::server::upgrade",
-            "Removing old dump at {}", path.display());
-        fs::remove_dir_all(&path)?;
+    let f = Arc::new(f);
+    let limit = limit.unwrap_or(items.len()).max(1);
+    let mut results = Vec::new();
+    let mut items = items.into_iter().peekable();
+    while items.peek().is_some() {
+        let threads = items.by_ref().take(limit)
+            .map(|item| {
+                let f = f.clone();
+                thread::spawn(move || f(item))
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            results.push(thread.join().unwrap_or_else(|_| {
+                Err(anyhow::anyhow!("operation panicked"))
+            }));
+        }
+        if results.iter().any(|result| result.is_err()) {
+            break;
+        }
+    }
+    results
+}
+
+fn instance_names(instances: &[Instance]) -> String {
+    instances.iter().map(|inst| &inst.name[..])
+        .collect::<Vec<_>>().join(", ")
+}
+
+/// Full version the instance runs: the one recorded by its last upgrade,
+/// otherwise the installed package of its major version
+fn current_revision(inst: &Instance, installed: Option<&Version<String>>)
+    -> Option<Version<String>>
+{
+    fs::read(inst.data_dir.join(UPGRADE_DONE)).ok()
+        .and_then(|data| serde_json::from_slice::<UpgradeMeta>(&data).ok())
+        .map(|meta| meta.target)
+        .filter(|target| target.as_ref() != "unknown")
+        .or_else(|| installed.cloned())
+}
+
+/// Splits instances of one major version by their current revision
+fn group_by_revision(instances: Vec<Instance>,
+    revision: impl Fn(&Instance) -> Option<Version<String>>)
+    -> BTreeMap<Option<Version<String>>, Vec<Instance>>
+{
+    let mut groups = BTreeMap::new();
+    for inst in instances {
+        groups.entry(revision(&inst))
+            .or_insert_with(Vec::new)
+            .push(inst);
+    }
+    groups
+}
+
+fn is_up_to_date(revision: Option<&Version<String>>,
+    target: &Version<String>, force: bool)
+    -> bool
+{
+    !force && revision.map(|rev| rev >= target).unwrap_or(false)
+}
+
+fn unix_params(socket: &Path, start_timeout: Duration) -> client::Builder {
+    log::debug!(target: TRACE, "Connecting to {} as user \"edgedb\", \
+        database \"edgedb\", waiting up to {}", socket.display(),
+        humantime::format_duration(start_timeout));
+    let mut conn_params = client::Builder::new();
+    conn_params.user("edgedb");
+    conn_params.database("edgedb");
+    conn_params.unix_addr(socket);
+    conn_params.wait_until_available(start_timeout);
+    conn_params
+}
+
+async fn tcp_params(inst: &Instance, start_timeout: Duration)
+    -> anyhow::Result<client::Builder>
+{
+    let credentials = home_dir()?.join(".edgedb").join("credentials")
+        .join(format!("{}.json", inst.name));
+    let mut conn_params = client::Builder::read_credentials(credentials)
+        .await
+        .with_context(|| format!("cannot read credentials for TCP \
+            connection to {:?}", inst.name))?;
+    conn_params.tcp_addr("127.0.0.1", inst.meta.port);
+    conn_params.database("edgedb");
+    conn_params.wait_until_available(start_timeout);
+    log::debug!(target: TRACE, "Connecting to 127.0.0.1:{} as user from \
+        credentials file, database \"edgedb\", waiting up to {}",
+        inst.meta.port, humantime::format_duration(start_timeout));
+    Ok(conn_params)
+}
+
+pub async fn connect(inst: &Instance, socket: anyhow::Result<PathBuf>,
+    method: Option<ConnectMethod>)
+    -> anyhow::Result<(client::Builder, Connection)>
+{
+    connect_within(inst, socket, method, START_TIMEOUT).await
+}
+
+/// Connects to the instance, waiting up to `start_timeout` for the server
+/// to start accepting connections
+pub async fn connect_within(inst: &Instance,
+    socket: anyhow::Result<PathBuf>, method: Option<ConnectMethod>,
+    start_timeout: Duration)
+    -> anyhow::Result<(client::Builder, Connection)>
+{
+    let socket = match (method, socket) {
+        (Some(ConnectMethod::Tcp), _) => None,
+        (Some(ConnectMethod::Unix), socket) => Some(socket?),
+        (None, Ok(socket)) => Some(socket),
+        (None, Err(e)) => {
+            log::warn!(target: "edgedb::server::upgrade",
+                "Cannot determine socket path: {:#}. \
+                Connecting over TCP...", e);
+            None
+        }
+    };
+    if let Some(socket) = socket {
+        let conn_params = unix_params(&socket, start_timeout);
+        match conn_params.connect().await {
+            Ok(cli) => return Ok((conn_params, cli)),
+            Err(e) if method.is_none() => {
+                log::warn!(target: "edgedb::server::upgrade",
+                    "Cannot connect to {}: {:#}. Connecting over TCP...",
+                    socket.display(), e);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let conn_params = tcp_params(inst, start_timeout).await?;
+    let cli = conn_params.connect().await?;
+    Ok((conn_params, cli))
+}
+
+/// Bounds the duration of the upgrade phase (`--dump-timeout`,
+/// `--restore-timeout`)
+async fn phase_timeout<T>(phase: &str, limit: Option<Duration>,
+    future: impl Future<Output=anyhow::Result<T>>)
+    -> anyhow::Result<T>
+{
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return future.await,
+    };
+    match async_std::future::timeout(limit, future).await {
+        Ok(result) => result,
+        Err(_) => anyhow::bail!("{} didn't complete within {} \
+            (see `--{}-timeout`)",
+            phase, humantime::format_duration(limit), phase),
+    }
+}
+
+/// Options of `Upgrade` that affect the dump
+pub struct DumpSettings {
+    pub connect_method: Option<ConnectMethod>,
+    pub start_timeout: Duration,
+    /// Count objects in the instance, to verify them after restore
+    pub fingerprint: bool,
+    pub skip_empty: bool,
+    pub buffer_size: Option<usize>,
+    /// Databases that are not dumped
+    pub exclude_databases: Vec<String>,
+    /// Keep only the schema (data blocks are still sent by the server, but
+    /// not written)
+    pub schema_only: bool,
+}
+
+async fn dump_instance(inst: &Instance, socket: anyhow::Result<PathBuf>,
+    options: &Upgrade)
+    -> anyhow::Result<Option<Fingerprint>>
+{
+    log::info!(target: "edgedb::server::upgrade",
+        "Dumping instance {:?}", inst.name);
+    let path = inst.dump_path();
+    if path.exists() {
+        log::info!(target: "edgedb::server::upgrade",
+            "Removing old dump at {}", path.display());
+        if options.dump_layout.is_some() {
+            dump_layout::check_dump_files(&path)?;
+        } else {
+            check_removable(&path, &data_path(inst.system)?)?;
+        }
+        fs::remove_dir_all(&path)?;
+    }
+    dump_to(inst, socket, &path, &DumpSettings {
+        connect_method: options.connect_method,
+        start_timeout: options.start_timeout,
+        fingerprint: options.verify_after_restore,
+        skip_empty: options.skip_empty_dump,
+        buffer_size: options.transfer_buffer,
+        exclude_databases: options.exclude_database.clone(),
+        schema_only: options.schema_only,
+    }).await
+}
+
+/// Dumps all databases of the instance into a new directory `path`, and
+/// writes `DumpMeta` there
+pub async fn dump_to(inst: &Instance, socket: anyhow::Result<PathBuf>,
+    path: &Path, options: &DumpSettings)
+    -> anyhow::Result<Option<Fingerprint>>
+{
+    let data_size = dir_size(&inst.data_dir)
+        .map_err(|e| log::warn!(target: "edgedb::server::upgrade",
+            "Cannot determine size of {}: {:#}", inst.data_dir.display(), e))
+        .ok();
+    let (conn_params, mut cli) = connect_within(
+        inst, socket, options.connect_method, options.start_timeout).await?;
+    let fingerprint = if options.fingerprint {
+        log::info!(target: "edgedb::server::upgrade",
+            "Counting objects in {:?}", inst.name);
+        let mut fingerprint = verify::fingerprint(
+            &mut cli, &conn_params).await?;
+        for database in &options.exclude_databases {
+            fingerprint.remove(database);
+        }
+        Some(fingerprint)
+    } else {
+        None
+    };
+    let empty = if options.skip_empty {
+        match verify::is_empty(&mut cli).await {
+            Ok(empty) => empty,
+            Err(e) => {
+                log::warn!(target: "edgedb::server::upgrade",
+                    "Cannot check whether {:?} is empty: {:#}. \
+                    Dumping it as usual.", inst.name, e);
+                false
+            }
+        }
+    } else {
+        false
+    };
+    let started = Instant::now();
+    if empty {
+        // roles and config are still needed to recreate the instance
+        log::info!(target: "edgedb::server::upgrade",
+            "Instance {:?} has no data, skipping database dump",
+            inst.name);
+        commands::dump_init(&mut cli, path).await?;
+    } else {
+        let cmd_options = commands::Options {
+            command_line: true,
+            styler: None,
+            conn_params,
+        };
+        commands::dump_all(&mut cli, &cmd_options, path,
+                           options.buffer_size,
+                           &options.exclude_databases,
+                           options.schema_only).await?;
+    }
+    let mut files = Vec::new();
+    for item in fs::read_dir(path)? {
+        if let Some(name) = item?.file_name().to_str() {
+            if name.ends_with(".dump") {
+                files.push(name.to_string());
+            }
+        }
+    }
+    files.sort();
+    write_dump_meta(&path.join(DUMP_META), &DumpMeta {
+        source: inst.meta.version.clone(),
+        timestamp: SystemTime::now(),
+        files,
+        fingerprint: fingerprint.clone(),
+        duration: Some(started.elapsed()),
+        data_size,
+        schema_only: options.schema_only,
+    })?;
+    Ok(fingerprint)
+}
+
+/// When the server was last started on the data directory, if known.
+/// Postgres rewrites `postmaster.opts` at every start and, unlike
+/// `postmaster.pid`, keeps it after the shutdown
+fn last_started(data_dir: &Path) -> Option<SystemTime> {
+    fs::metadata(data_dir.join("postmaster.opts"))
+        .and_then(|meta| meta.modified())
+        .map_err(|e| log::debug!("Start time of the server is unknown: {}",
+                                 e))
+        .ok()
+}
+
+#[context("dump at {} can't be reused", inst.dump_path().display())]
+fn check_dump(inst: &Instance, ctl: &dyn control::Instance,
+    options: &Upgrade)
+    -> anyhow::Result<DumpMeta>
+{
+    if ctl.get_status()?.is_running() {
+        anyhow::bail!("instance is running, so data could have been \
+            modified since the dump");
+    }
+    let path = inst.dump_path();
+    let meta_path = path.join(DUMP_META);
+    let meta: DumpMeta = serde_json::from_slice(&fs::read(&meta_path)
+        .with_context(|| format!("cannot read {} (dump is incomplete?)",
+                                 meta_path.display()))?)
+        .with_context(|| format!("cannot decode {}", meta_path.display()))?;
+    if meta.source != inst.meta.version {
+        anyhow::bail!("dump is made by version {}, instance runs {}",
+            meta.source, inst.meta.version);
+    }
+    if let Some(started) = last_started(&inst.data_dir) {
+        if started > meta.timestamp {
+            anyhow::bail!("instance was started at {}, after the dump was \
+                made at {}, so data could have been modified since",
+                humantime::format_rfc3339_seconds(started),
+                humantime::format_rfc3339_seconds(meta.timestamp));
+        }
+    }
+    let age = meta.timestamp.elapsed().unwrap_or(Duration::new(0, 0));
+    if age > options.reuse_dump_max_age {
+        anyhow::bail!("dump is {} old (maximum is {}, \
+            see `--reuse-dump-max-age`)",
+            humantime::format_duration(
+                Duration::from_secs(age.as_secs())),
+            humantime::format_duration(options.reuse_dump_max_age));
+    }
+    for name in meta.files.iter().map(|x| &x[..]).chain(Some("init.edgeql")) {
+        if !path.join(name).exists() {
+            anyhow::bail!("file {:?} is missing", name);
+        }
+    }
+    if meta.schema_only && !options.schema_only {
+        anyhow::bail!("dump is made with `--schema-only`, so it has no data");
+    }
+    if options.verify_after_restore && meta.fingerprint.is_none() {
+        anyhow::bail!("dump is made without `--verify-after-restore`");
+    }
+    Ok(meta)
+}
+
+/// Name of the `path` if it's directly in `root`
+fn name_in<'a>(path: &'a Path, root: &Path) -> Option<&'a str> {
+    use std::path::Component::Normal;
+
+    match path.strip_prefix(root).ok()?.components().collect::<Vec<_>>()[..]
+    {
+        [Normal(name)] => name.to_str(),
+        _ => None,
+    }
+}
+
+/// Instance of the dump or backup named `<name>.dump`, `<name>.backup`
+/// (made by upgrade) or `<name>.backup.<timestamp>` (by restore-backup)
+fn dump_or_backup_of(name: &str) -> Option<&str> {
+    if let Some(inst) = name.strip_suffix(".dump") {
+        return Some(inst);
+    }
+    if let Some(inst) = name.strip_suffix(".backup") {
+        return Some(inst);
+    }
+    let idx = name.rfind(".backup.")?;
+    let timestamp = &name[idx + ".backup.".len()..];
+    if !timestamp.is_empty() && timestamp.bytes().all(|b| b.is_ascii_digit())
+    {
+        Some(&name[..idx])
+    } else {
+        None
+    }
+}
+
+/// Guards recursive removal: only a dump or a backup of an instance
+/// directly in the data directory `root` may be removed
+pub fn check_removable(path: &Path, root: &Path) -> anyhow::Result<()> {
+    let valid = name_in(path, root)
+        .and_then(dump_or_backup_of)
+        .map(is_valid_name).unwrap_or(false);
+    if !valid {
+        anyhow::bail!("refusing to remove {}: only dumps and backups \
+            of instances in {} can be removed",
+            path.display(), root.display());
     }
-    let mut conn_params = client::Builder::new();
-    conn_params.user("edgedb");
-    conn_params.database("edgedb");
-    conn_params.unix_addr(socket);
-    conn_params.wait_until_available(Duration::from_secs(30));
-    let mut cli = conn_params.connect().await?;
-    let options = commands::Options {
-        command_line: true,
-        styler: None,
-        conn_params,
-    };
-    commands::dump_all(&mut cli, &options, path.as_ref()).await?;
     Ok(())
 }
 
-async fn restore_instance(inst: &Instance, socket: &Path)
+/// Guards recursive removal of directories that are moved, unpacked and
+/// rolled back next to each other in the data directory: only a data
+/// directory of an instance, its dump or its backup may be removed
+pub fn check_removable_data(path: &Path) -> anyhow::Result<()> {
+    let valid = path.parent()
+        .filter(|root| !root.as_os_str().is_empty())
+        .and_then(|root| name_in(path, root))
+        .map(|name| dump_or_backup_of(name).unwrap_or(name))
+        .map(is_valid_name).unwrap_or(false);
+    if !valid {
+        anyhow::bail!("refusing to remove {}: only data directories, \
+            dumps and backups of instances can be removed", path.display());
+    }
+    Ok(())
+}
+
+pub fn dir_size(path: &Path) -> anyhow::Result<u64> {
+    let mut size = 0;
+    for item in fs::read_dir(path)? {
+        let item = item?;
+        let meta = item.metadata()?;
+        if meta.is_dir() {
+            size += dir_size(&item.path())?;
+        } else {
+            size += meta.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Moves the directory. If `to` is on another filesystem, the directory is
+/// copied recursively and then removed. With `preserve` set, copies keep
+/// permissions, ownership and modification times of the originals,
+/// because the server can't use a data directory that doesn't belong to it
+#[context("cannot move {} to {}", from.display(), to.display())]
+pub fn move_dir(from: &Path, to: &Path, preserve: bool)
+    -> anyhow::Result<()>
+{
+    match fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(e) if is_cross_device(&e) => {
+            log::info!(target: "edgedb::server::upgrade",
+                "{} is on another filesystem, copying", to.display());
+            move_by_copy(from, to, preserve)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Fallback of `move_dir` for `to` on another filesystem. On failure the
+/// partial copy is removed and `from` is kept
+fn move_by_copy(from: &Path, to: &Path, preserve: bool)
+    -> anyhow::Result<()>
+{
+    // checked before copying, as one of them is removed in the end
+    check_removable_data(from)?;
+    check_removable_data(to)?;
+    if let Err(e) = copy_tree(from, to, preserve) {
+        fs::remove_dir_all(to).ok();
+        return Err(e);
+    }
+    fs::remove_dir_all(from)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_cross_device(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EXDEV)
+}
+
+#[cfg(windows)]
+fn is_cross_device(e: &io::Error) -> bool {
+    const ERROR_NOT_SAME_DEVICE: i32 = 17;
+    e.raw_os_error() == Some(ERROR_NOT_SAME_DEVICE)
+}
+
+#[context("cannot copy {}", from.display())]
+fn copy_tree(from: &Path, to: &Path, preserve: bool) -> anyhow::Result<()> {
+    let meta = fs::symlink_metadata(from)?;
+    let kind = meta.file_type();
+    if kind.is_symlink() {
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(fs::read_link(from)?, to)?;
+        #[cfg(windows)]
+        anyhow::bail!("copying symlinks is not supported");
+    } else if kind.is_dir() {
+        fs::create_dir(to)?;
+        for item in fs::read_dir(from)? {
+            let item = item?;
+            copy_tree(&item.path(), &to.join(item.file_name()), preserve)?;
+        }
+    } else {
+        fs::copy(from, to)?;
+    }
+    // directory attributes are set last, as children change its mtime
+    if preserve {
+        copy_attributes(to, &meta)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_attributes(path: &Path, meta: &fs::Metadata) -> anyhow::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::lchown(c_path.as_ptr(), meta.uid(), meta.gid()) } != 0 {
+        return Err(io::Error::last_os_error())
+            .context("cannot change owner");
+    }
+    if meta.file_type().is_symlink() {
+        return Ok(());
+    }
+    fs::set_permissions(path, meta.permissions())?;
+    let times = [
+        libc::timeval {
+            tv_sec: meta.atime() as libc::time_t,
+            tv_usec: (meta.atime_nsec() / 1000) as libc::suseconds_t,
+        },
+        libc::timeval {
+            tv_sec: meta.mtime() as libc::time_t,
+            tv_usec: (meta.mtime_nsec() / 1000) as libc::suseconds_t,
+        },
+    ];
+    if unsafe { libc::utimes(c_path.as_ptr(), times.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error())
+            .context("cannot set modification time");
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn copy_attributes(path: &Path, meta: &fs::Metadata) -> anyhow::Result<()> {
+    fs::set_permissions(path, meta.permissions())?;
+    Ok(())
+}
+
+/// Checks the binary given by `--server-binary`, as failing to run it
+/// would only be noticed after the data directory is moved aside
+#[context("cannot use server binary {}", path.display())]
+pub fn check_server_binary(path: &Path) -> anyhow::Result<()> {
+    let meta = fs::metadata(path)?;
+    if !meta.is_file() {
+        anyhow::bail!("not a file");
+    }
+    #[cfg(unix)] {
+        use std::os::unix::fs::PermissionsExt;
+        if meta.permissions().mode() & 0o111 == 0 {
+            anyhow::bail!("file is not executable");
+        }
+    }
+    log::warn!(target: "edgedb::server::upgrade",
+        "Restoring with server binary {} instead of the installed one",
+        path.display());
+    Ok(())
+}
+
+#[context("cannot estimate downtime of {:?}", inst.name)]
+fn estimate_downtime(inst: &Instance) -> anyhow::Result<Duration> {
+    let size = dir_size(&inst.data_dir)?;
+    let previous = fs::read(inst.dump_path().join(DUMP_META)).ok()
+        .and_then(|data| serde_json::from_slice::<DumpMeta>(&data).ok())
+        .and_then(|meta| Some((meta.duration?, meta.data_size?)))
+        .filter(|&(_, prev_size)| prev_size > 0);
+    // restoring takes approximately as long as dumping
+    let restore = match previous {
+        Some((duration, prev_size)) => {
+            duration.mul_f64(size as f64 / prev_size as f64)
+        }
+        None => Duration::from_secs(size / ASSUMED_RESTORE_RATE),
+    };
+    Ok(restore + RESTART_TIME)
+}
+
+fn check_downtime(inst: &Instance, options: &Upgrade) -> anyhow::Result<()> {
+    let max_downtime = match options.max_downtime {
+        Some(max_downtime) => max_downtime,
+        None => return Ok(()),
+    };
+    let estimate = estimate_downtime(inst)?;
+    let estimate_str = humantime::format_duration(
+        Duration::from_secs(estimate.as_secs()));
+    if estimate <= max_downtime {
+        log::info!(target: "edgedb::server::upgrade",
+            "Instance {:?} is estimated to be down for {}",
+            inst.name, estimate_str);
+        return Ok(());
+    }
+    if options.force {
+        log::warn!(target: "edgedb::server::upgrade",
+            "Instance {:?} is estimated to be down for {}, \
+            which exceeds `--max-downtime`. Upgrading anyway.",
+            inst.name, estimate_str);
+        return Ok(());
+    }
+    anyhow::bail!("Instance {:?} is estimated to be down for {}, \
+        which exceeds `--max-downtime` of {}. \
+        Use `--force` to upgrade anyway.",
+        inst.name, estimate_str, humantime::format_duration(max_downtime));
+}
+
+/// Estimates dump size from the size of the data directory, scaled by the
+/// ratio of dump to data directory size of the previous dump if there is one
+#[context("cannot estimate dump size of {:?}", inst.name)]
+fn estimate_dump_size(inst: &Instance) -> anyhow::Result<u64> {
+    let size = dir_size(&inst.data_dir)?;
+    let dump_path = inst.dump_path();
+    let previous = fs::read(dump_path.join(DUMP_META)).ok()
+        .and_then(|data| serde_json::from_slice::<DumpMeta>(&data).ok())
+        .and_then(|meta| meta.data_size)
+        .filter(|&prev_size| prev_size > 0)
+        .and_then(|prev_size| Some((prev_size, dir_size(&dump_path).ok()?)));
+    match previous {
+        Some((prev_size, dump_size)) => {
+            Ok((dump_size as f64 * size as f64 / prev_size as f64) as u64)
+        }
+        // dump is usually smaller, as indexes are not dumped
+        None => Ok(size),
+    }
+}
+
+#[derive(Serialize)]
+struct Estimate<'a> {
+    name: &'a str,
+    dump_size: u64,
+    #[serde(with="humantime_serde")]
+    downtime: Duration,
+    /// Number of objects per database, if the instance is running
+    objects: Option<BTreeMap<String, i64>>,
+}
+
+fn print_estimates(instances: &[Instance], options: &Upgrade)
+    -> anyhow::Result<()>
+{
+    let mut estimates = Vec::new();
+    for inst in instances {
+        let ctl = inst.get_control()?;
+        let objects = if ctl.get_status()?.is_running() {
+            let fingerprint = task::block_on(async {
+                let (conn_params, mut cli) = connect_within(
+                    inst, ctl.get_socket(true), options.connect_method,
+                    options.start_timeout).await?;
+                verify::fingerprint(&mut cli, &conn_params).await
+            }).with_context(|| format!("cannot count objects in {:?}",
+                                       inst.name))?;
+            Some(fingerprint.into_iter()
+                .map(|(db, types)| (db, types.values().sum()))
+                .collect())
+        } else {
+            log::info!(target: "edgedb::server::upgrade",
+                "Instance {:?} is not running, \
+                estimating from the data directory only", inst.name);
+            None
+        };
+        estimates.push(Estimate {
+            name: &inst.name,
+            dump_size: estimate_dump_size(inst)?,
+            downtime: estimate_downtime(inst)?,
+            objects,
+        });
+    }
+    if options.format != OutputFormat::Human {
+        return print_serialized(options.format, &estimates);
+    }
+    for estimate in &estimates {
+        println!("Instance {:?}: dump of about {}, down for about {}",
+            estimate.name, format_bytes(Some(estimate.dump_size)),
+            humantime::format_duration(
+                Duration::from_secs(estimate.downtime.as_secs())));
+        for (database, num) in estimate.objects.iter().flatten() {
+            println!("  database {:?}: {} objects", database, num);
+        }
+    }
+    Ok(())
+}
+
+#[context("failed to write dump metadata file {}", path.display())]
+fn write_dump_meta(path: &Path, metadata: &DumpMeta)
+    -> anyhow::Result<()>
+{
+    write_json_atomic(path, metadata)
+}
+
+/// Writes the file so that readers never observe partially written data
+///
+/// Data is written to a temporary file in the same directory, which is
+/// then renamed over the destination.
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_file_name(tmp_file_name(path));
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp_path, path)
+}
+
+pub fn write_json_atomic(path: &Path, value: &impl Serialize)
+    -> anyhow::Result<()>
+{
+    write_atomic(path, &serde_json::to_vec(value)?)?;
+    Ok(())
+}
+
+async fn restore_instance(inst: &Instance, socket: anyhow::Result<PathBuf>,
+    options: &Upgrade)
     -> anyhow::Result<()>
 {
     use crate::commands::parser::Restore;
 
     log::info!(target: "edgedb::server::upgrade",
         "Restoring instance {:?}", inst.name);
-    let path = inst.data_dir.with_file_name(format!("{}.dump", inst.name));
-    let mut conn_params = client::Builder::new();
-    conn_params.user("edgedb");
-    conn_params.database("edgedb");
-    conn_params.unix_addr(socket);
-    conn_params.wait_until_available(Duration::from_secs(30));
-    let mut cli = conn_params.connect().await?;
-    let options = commands::Options {
+    let path = inst.dump_path();
+    let (conn_params, mut cli) = connect_within(
+        inst, socket, options.connect_method, options.start_timeout).await?;
+    let cmd_options = commands::Options {
         command_line: true,
         styler: None,
         conn_params,
     };
-    commands::restore_all(&mut cli, &options, &Restore {
+    // excluded databases are not in the dump, unless it's reused from
+    // an upgrade attempt made without excluding them
+    let exclude_database = options.exclude_database.iter()
+        .filter(|db| path.join(urlencoding::encode(db) + ".dump").exists())
+        .cloned()
+        .collect();
+    commands::restore_all(&mut cli, &cmd_options, &Restore {
         path,
         all: true,
-        allow_non_empty: false,
-        verbose: false,
+        allow_non_empty: options.allow_non_empty,
+        exclude_database,
+        schema_only: options.schema_only,
+        buffer_size: options.transfer_buffer,
+        jobs: options.transfer_parallelism,
+        verbose: options.verbose_restore,
     }).await?;
     Ok(())
 }
 
-fn do_nightly_upgrade(method: &dyn Method,
-    mut instances: Vec<Instance>, options: &Upgrade)
+async fn run_post_restore_script(inst: &Instance, path: &Path,
+    socket: anyhow::Result<PathBuf>, options: &Upgrade)
     -> anyhow::Result<()>
 {
-    let instances_str = instances
-        .iter().map(|inst| &inst.name[..]).collect::<Vec<_>>().join(", ");
+    log::info!(target: "edgedb::server::upgrade",
+        "Running post-restore script {}", path.display());
+    let (_, mut cli) = connect_within(inst, socket, options.connect_method,
+                                      options.start_timeout).await?;
+    let mut input = async_std::fs::File::open(path).await
+        .with_context(|| format!("cannot open {}", path.display()))?;
+    commands::apply_statements(&mut cli, &mut input).await
+}
 
+async fn transfer_instance(inst: &Instance, source_socket: &Path,
+    socket: anyhow::Result<PathBuf>, options: &Upgrade)
+    -> anyhow::Result<Option<Fingerprint>>
+{
+    log::info!(target: "edgedb::server::upgrade",
+        "Streaming data of {:?} to the new server", inst.name);
+    let source_params = unix_params(source_socket, options.start_timeout);
+    let mut source = source_params.connect().await
+        .context("cannot connect to the old server")?;
+    let fingerprint = if options.verify_after_restore {
+        log::info!(target: "edgedb::server::upgrade",
+            "Counting objects in {:?}", inst.name);
+        let mut fingerprint = verify::fingerprint(
+            &mut source, &source_params).await?;
+        for database in &options.exclude_database {
+            fingerprint.remove(database);
+        }
+        Some(fingerprint)
+    } else {
+        None
+    };
+    let (target_params, mut target) = connect_within(
+        inst, socket, options.connect_method, options.start_timeout).await?;
+    commands::transfer_all(&mut source, &source_params,
+                           &mut target, &target_params,
+                           options.transfer_parallelism,
+                           &options.exclude_database).await?;
+    Ok(fingerprint)
+}
+
+/// Runs the old server on the backup of the data directory, next to the
+/// new one, and transfers data between them
+fn stream_instance(inst: &Instance, backup: &Path, target: &mut ProcessGuard,
+    socket: anyhow::Result<PathBuf>, method: &dyn Method, options: &Upgrade)
+    -> anyhow::Result<Option<Fingerprint>>
+{
+    let port = TcpListener::bind(("127.0.0.1", 0))?.local_addr()?.port();
+    let runstate_dir = tempfile::tempdir()?;
+    let mut cmd = process::Command::new(
+        method.get_server_path(&inst.meta.version)?);
+    cmd.arg("--port").arg(port.to_string());
+    cmd.arg("--data-dir").arg(backup);
+    cmd.arg("--runstate-dir").arg(runstate_dir.path());
+    cmd.envs(&inst.meta.env);
+    let mut source = ProcessGuard::run_captured(&mut cmd)
+        .with_context(|| format!("error running server {}",
+                                 crate::process::describe(&cmd)))?;
+    let source_socket = runstate_dir.path()
+        .join(format!(".s.EDGEDB.admin.{}", port));
+    let result = task::block_on(phase_timeout(
+        "restore", options.restore_timeout,
+        transfer_instance(inst, &source_socket, socket, options))
+        .race(source.exited())
+        .race(target.exited()));
+    target.with_output(source.with_output(result))
+}
+
+fn do_nightly_upgrade(method: &dyn Method,
+    mut instances: Vec<Instance>, options: &Upgrade, summary: &mut Summary)
+    -> anyhow::Result<()>
+{
     let version_query = VersionQuery::Nightly;
     let new = method.get_version(&version_query)
-        .context("Unable to determine version")?;
+        .map_err(|e| UpgradeError::VersionResolution(e.into()))?;
     let old = get_installed(&version_query, method)?;
 
+    if !options.force {
+        // instances done by an interrupted run of the upgrade
+        let (done, rest) = instances.into_iter()
+            .partition::<Vec<_>, _>(|inst| {
+                is_upgraded_to(inst, &new.full_version())
+            });
+        for inst in &done {
+            log::info!(target: "edgedb::server::upgrade",
+                "Instance {:?} is already upgraded to {}, skipping",
+                inst.name, new.full_version());
+        }
+        summary.up_to_date += done.len();
+        instances = rest;
+        if instances.is_empty() {
+            return Ok(());
+        }
+    }
+    let instances_str = instances
+        .iter().map(|inst| &inst.name[..]).collect::<Vec<_>>().join(", ");
+
     if !options.force {
         if let Some(old_ver) = &old {
             if old_ver >= &new.full_version() {
                 log::info!(target: "edgedb::server::upgrade",
                     "Nightly is up to date {}, skipping instances: {}",
                     old_ver, instances_str);
+                summary.up_to_date += instances.len();
                 return Ok(());
             }
         }
     }
-    for inst in &mut instances {
-        inst.source = old.clone();
-        inst.version = Some(new.full_version());
+    if options.dry_run {
+        println!("Would upgrade nightly to {}-{}, instances: {}",
+            new.version, new.revision, instances_str);
+        return Ok(());
+    }
+    for inst in &mut instances {
+        inst.source = old.clone();
+        inst.version = Some(new.full_version());
+    }
+
+    if !options.assume_writable {
+        for inst in &instances {
+            check_writable(inst)?;
+        }
+    }
+    for inst in &instances {
+        check_downtime(inst, options)?;
+    }
+    for inst in &mut instances {
+        dump_and_stop(inst, options)
+            .map_err(|e| {
+                UpgradeError::DumpFailed(inst.name.clone(), e.into())
+            })?;
+    }
+
+    log::info!(target: "edgedb::server::upgrade", "Upgrading the package");
+    install_package(method, &install::Settings {
+        method: method.name(),
+        package_name: new.package_name,
+        major_version: new.major_version.clone(),
+        version: new.version,
+        revision: new.revision,
+        nightly: true,
+        extra: install::extra_settings(method, &options.extra)?,
+        prefix: install::prefix::find(&new.major_version),
+    }, options)?;
+
+    for mut inst in instances {
+        reinit_and_restore(&mut inst, &new.major_version, true,
+                           method, options)
+            .map_err(|e| {
+                UpgradeError::RestoreFailed(inst.name.clone(), e.into())
+            })?;
+        summary.add_upgraded(&inst);
+    }
+    Ok(())
+}
+
+#[context("cannot move instance {:?} to port {}", inst.name, port)]
+fn check_port(inst: &Instance, port: u16) -> anyhow::Result<()> {
+    match ports::used_ports()?.get(&port) {
+        Some(other) if other != &inst.name => {
+            anyhow::bail!("port is used by instance {:?}", other);
+        }
+        _ => {}
+    }
+    TcpListener::bind(("127.0.0.1", port)).context("port is not free")?;
+    Ok(())
+}
+
+#[context("cannot use credentials of {:?} for `--temp-auth=password`",
+          inst.name)]
+fn read_temp_credentials(inst: &Instance) -> anyhow::Result<Credentials> {
+    let path = home_dir()?.join(".edgedb").join("credentials")
+        .join(format!("{}.json", inst.name));
+    let credentials: Credentials = serde_json::from_slice(&fs::read(&path)
+        .with_context(|| format!("cannot read {}", path.display()))?)?;
+    // roles are restored from the dump, so the password only stays valid
+    // if it's the one of the role restored there
+    if credentials.user != "edgedb" {
+        anyhow::bail!("credentials are for user {:?}, \"edgedb\" is required",
+            credentials.user);
+    }
+    if credentials.password.is_none() {
+        anyhow::bail!("credentials file has no password");
+    }
+    Ok(credentials)
+}
+
+/// The new instance has no password until roles are restored, so it's set
+/// over the admin socket before any other connection is made
+async fn set_temp_password(inst: &Instance, socket: &Path, options: &Upgrade)
+    -> anyhow::Result<()>
+{
+    let credentials = read_temp_credentials(inst)?;
+    let password = credentials.password.as_deref().unwrap_or_default();
+    let mut cli = unix_params(socket, options.start_timeout).connect().await
+        .context("cannot set password on the temporary server")?;
+    cli.execute(&format!("ALTER ROLE {} {{ SET password := {}; }}",
+        quote_name(&credentials.user), quote_string(password))).await?;
+    Ok(())
+}
+
+#[context("cannot update port in credentials of {:?}", inst.name)]
+fn update_credentials_port(inst: &Instance) -> anyhow::Result<()> {
+    let path = home_dir()?.join(".edgedb").join("credentials")
+        .join(format!("{}.json", inst.name));
+    if !path.exists() {
+        return Ok(());
+    }
+    let mut credentials: Credentials = serde_json::from_slice(
+        &fs::read(&path)?)?;
+    credentials.port = inst.meta.port;
+    write_credentials(&path, &credentials)?;
+    Ok(())
+}
+
+/// Moves the instance, which is up to date, to another port. There's
+/// nothing to reinit, so only the service, metadata, port mapping and
+/// credentials are updated
+fn move_port(method: &dyn Method, inst: &mut Instance, port: u16,
+    options: &Upgrade)
+    -> anyhow::Result<()>
+{
+    check_port(inst, port)?;
+    let mut ctl = inst.get_control()?;
+    let running = ctl.get_status()?.is_running();
+    if running {
+        ctl.stop(&options::Stop {
+            name: inst.name.clone(),
+            wait: true,
+            wait_timeout: None,
+        })?;
+    }
+    log::info!(target: "edgedb::server::upgrade",
+        "Moving instance {:?} from port {} to {}",
+        inst.name, inst.meta.port, port);
+    inst.meta.port = port;
+    init::assign_port(&inst.name, port)?;
+    write_metadata(&inst.data_dir.join("metadata.json"), &inst.meta)?;
+    recreate_service(inst, method)?;
+    update_credentials_port(inst)?;
+    if running {
+        wait::start(&options::Start {
+            name: inst.name.clone(),
+            foreground: false,
+            wait: true,
+            wait_timeout: Some(options.start_timeout),
+        })?;
+    }
+    Ok(())
+}
+
+fn install_package(method: &dyn Method, settings: &install::Settings,
+    options: &Upgrade)
+    -> anyhow::Result<()>
+{
+    install::perform(method, settings)
+        .map_err(|e| UpgradeError::InstallFailed(e.into()))?;
+    if options.verify {
+        log::info!(target: "edgedb::server::upgrade",
+            "Verifying installed files");
+        method.verify_installation(settings)
+            .map_err(|e| UpgradeError::InstallFailed(e.into()))?;
+    }
+    Ok(())
+}
+
+#[context("cannot upgrade {:?}", inst.name)]
+fn check_writable(inst: &Instance) -> anyhow::Result<()> {
+    let base = inst.data_dir.parent().unwrap();
+    let probe = base.join(tmp_file_name(&inst.data_dir));
+    let result = fs::File::create(&probe)
+        .and_then(|_| fs::remove_file(&probe));
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            fs::remove_file(&probe).ok();
+            anyhow::bail!("directory {} is not writable ({}). \
+                The upgrade needs to rename the data directory and write \
+                new files next to it, so it is aborted before stopping \
+                the instance. If the filesystem is mounted read-only, \
+                remount it read-write. Use `--assume-writable` to skip \
+                this check.",
+                base.display(), e);
+        }
+    }
+}
+
+fn tcp_port(address: &str) -> Option<u16> {
+    address.rsplit(':').next()
+        .and_then(|port| u16::from_str_radix(port, 16).ok())
+}
+
+/// Counts clients connected to the instance, using `/proc/net/*` tables
+fn count_connections(inst: &Instance, ctl: &dyn control::Instance)
+    -> anyhow::Result<usize>
+{
+    let sockets = [ctl.get_socket(true)?, ctl.get_socket(false)?];
+    let mut count = 0;
+    // Sockets accepted by the server are listed with the path of the
+    // listening socket. State `03` is `SS_CONNECTED`.
+    let unix = fs::read_to_string("/proc/net/unix")?;
+    for line in unix.lines().skip(1) {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        if fields.len() >= 8 && fields[5] == "03" &&
+            sockets.iter().any(|sock| sock == Path::new(fields[7]))
+        {
+            count += 1;
+        }
+    }
+    // State `01` is `TCP_ESTABLISHED`.
+    for path in &["/proc/net/tcp", "/proc/net/tcp6"] {
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for line in data.lines().skip(1) {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if fields.len() >= 4 && fields[3] == "01" &&
+                tcp_port(fields[1]) == Some(inst.meta.port)
+            {
+                count += 1;
+            }
+        }
     }
+    Ok(count)
+}
 
-    for inst in &instances {
-        dump_and_stop(inst)?;
+/// Waits until clients disconnect from the instance (or timeout expires).
+/// Clients still connected after the timeout are only warned about, as
+/// they are disconnected when the instance is stopped
+fn drain(inst: &Instance, ctl: &dyn control::Instance, timeout: Duration) {
+    if !cfg!(target_os="linux") {
+        log::warn!(target: "edgedb::server::upgrade",
+            "Counting connections is not supported on this platform, \
+            `--drain-timeout` is ignored");
+        return;
     }
+    // no released server version can be asked to reject new connections
+    log::warn!(target: "edgedb::server::upgrade",
+        "Server does not support connection draining, \
+        waiting up to {} for clients of {:?} to disconnect",
+        humantime::format_duration(timeout), inst.name);
+    let deadline = Instant::now() + timeout;
+    loop {
+        match count_connections(inst, ctl) {
+            Ok(0) => return,
+            Ok(num) if Instant::now() >= deadline => {
+                log::warn!(target: "edgedb::server::upgrade",
+                    "{} clients are still connected to {:?} \
+                    after `--drain-timeout`. Stopping it anyway.",
+                    num, inst.name);
+                return;
+            }
+            Ok(num) => {
+                log::debug!(target: "edgedb::server::upgrade",
+                    "{} clients are still connected to {:?}",
+                    num, inst.name);
+            }
+            Err(e) => {
+                log::warn!(target: "edgedb::server::upgrade",
+                    "Cannot check connections to {:?}: {:#}",
+                    inst.name, e);
+                return;
+            }
+        }
+        thread::sleep(DRAIN_POLL_INTERVAL);
+    }
+}
 
-    log::info!(target: "edgedb::server::upgrade", "Upgrading the package");
-    method.install(&install::Settings {
-        method: method.name(),
-        package_name: new.package_name,
-        major_version: new.major_version.clone(),
-        version: new.version,
-        nightly: true,
-        extra: LinkedHashMap::new(),
-    })?;
-
-    for inst in instances {
-        reinit_and_restore(&inst, &new.major_version, true, method)?;
+fn check_connections(inst: &Instance, ctl: &dyn control::Instance,
+    options: &Upgrade)
+    -> anyhow::Result<()>
+{
+    if !cfg!(target_os="linux") {
+        return Ok(());
+    }
+    match count_connections(inst, ctl) {
+        Ok(0) => Ok(()),
+        Ok(num) if options.force => {
+            log::warn!(target: "edgedb::server::upgrade",
+                "{} clients are connected to instance {:?}. \
+                Stopping it anyway because of `--force`.",
+                num, inst.name);
+            Ok(())
+        }
+        Ok(num) => {
+            anyhow::bail!("{} clients are connected to instance {:?}. \
+                An application or another backup job might be using it. \
+                Disconnect them or use `--force` to stop the instance \
+                anyway.", num, inst.name);
+        }
+        Err(e) => {
+            log::warn!(target: "edgedb::server::upgrade",
+                "Cannot check connections to {:?}: {:#}", inst.name, e);
+            Ok(())
+        }
     }
-    Ok(())
 }
 
 #[context("failed to dump {:?}", inst.name)]
-fn dump_and_stop(inst: &Instance) -> anyhow::Result<()> {
+fn dump_and_stop(inst: &mut Instance, options: &Upgrade)
+    -> anyhow::Result<()>
+{
+    check_deadline()?;
     let mut ctl = inst.get_control()?;
+    if options.reuse_dump {
+        match check_dump(inst, &*ctl, options) {
+            Ok(meta) => {
+                log::info!(target: "edgedb::server::upgrade",
+                    "Reusing dump of {:?} made {} ago",
+                    inst.name, humantime::format_duration(
+                        Duration::from_secs(meta.timestamp.elapsed()
+                            .unwrap_or(Duration::new(0, 0)).as_secs())));
+                inst.fingerprint = meta.fingerprint;
+                return Ok(());
+            }
+            Err(e) => {
+                log::warn!(target: "edgedb::server::upgrade",
+                    "{:#}. Dumping again...", e);
+            }
+        }
+    }
     // in case not started for now
     log::info!(target: "edgedb::server::upgrade",
         "Ensuring instance is started");
-    ctl.start(&options::Start { name: inst.name.clone(), foreground: false })?;
-    task::block_on(dump_instance(inst, &ctl.get_socket(true)?))?;
+    ctl.start(&options::Start {
+        name: inst.name.clone(),
+        foreground: false,
+        wait: false,
+        wait_timeout: None,
+    })?;
+    wait::wait_started(inst, Some(options.start_timeout))?;
+    if let Some(timeout) = options.drain_timeout {
+        drain(inst, &*ctl, timeout);
+    } else {
+        check_connections(inst, &*ctl, options)?;
+    }
+    if options.stream {
+        log::info!(target: "edgedb::server::upgrade",
+            "Data of {:?} will be streamed after package upgrade",
+            inst.name);
+    } else {
+        let started = Instant::now();
+        inst.fingerprint = task::block_on(with_deadline(phase_timeout(
+            "dump", options.dump_timeout,
+            dump_instance(inst, ctl.get_socket(true), options))))?;
+        inst.metrics.dump_time = Some(started.elapsed());
+        inst.metrics.bytes_dumped = dir_size(&inst.dump_path()).ok();
+    }
     log::info!(target: "edgedb::server::upgrade",
         "Stopping the instance before package upgrade");
-    ctl.stop(&options::Stop { name: inst.name.clone() })?;
+    ctl.stop(&options::Stop {
+        name: inst.name.clone(),
+        wait: true,
+        wait_timeout: None,
+    })?;
     Ok(())
 }
 
-#[context("failed to restore {:?}", inst.name)]
-fn reinit_and_restore(inst: &Instance,
+fn reinit_and_restore(inst: &mut Instance,
     version: &Version<String>, nightly: bool,
-    method: &dyn Method)
+    method: &dyn Method, options: &Upgrade)
     -> anyhow::Result<()>
 {
+    let password_options;
+    let options = if options.temp_auth == TempAuth::Password {
+        if options.connect_method == Some(ConnectMethod::Unix) {
+            anyhow::bail!("`--temp-auth=password` connects over TCP, \
+                so it can't be used with `--connect-method=unix`");
+        }
+        // checked before the data directory is moved aside
+        read_temp_credentials(inst)?;
+        password_options = Upgrade {
+            connect_method: Some(ConnectMethod::Tcp),
+            ..options.clone()
+        };
+        &password_options
+    } else {
+        options
+    };
+    // the temporary server listens on the port of the instance only with
+    // `--connect-method=tcp`, otherwise there is no TCP to fall back to
+    let temp_options = Upgrade {
+        connect_method: Some(
+            options.connect_method.unwrap_or(ConnectMethod::Unix)),
+        ..options.clone()
+    };
+    check_deadline()?;
     let base = inst.data_dir.parent().unwrap();
     let backup = base.join(&format!("{}.backup", &inst.name));
-    fs::rename(&inst.data_dir, &backup)?;
-    write_backup_meta(&backup.join("backup.json"), &BackupMeta {
-        timestamp: SystemTime::now(),
+    let snapshots = if options.snapshot {
+        snapshot::detect(&inst.data_dir)
+    } else {
+        None
+    };
+    let snapshot = match snapshots {
+        Some(snapshots) => {
+            log::info!(target: "edgedb::server::upgrade",
+                "Making {} snapshot of {:?}", snapshots.name(), inst.name);
+            // contents of the data directory are removed by `init` below,
+            // the subvolume itself is kept for the next snapshots
+            let id = snapshots.create(&inst.data_dir, &backup)?;
+            Some(SnapshotMeta { filesystem: snapshots.name().into(), id })
+        }
+        None => {
+            if options.snapshot {
+                log::warn!(target: "edgedb::server::upgrade",
+                    "Filesystem of {} doesn't support snapshots, \
+                    moving it to {} instead",
+                    inst.data_dir.display(), backup.display());
+            }
+            move_dir(&inst.data_dir, &backup, options.preserve_permissions)?;
+            None
+        }
+    };
+    let data_dir = inst.data_dir.clone();
+    // until data is restored, any failure puts the backup back in place
+    let restored = with_rollback(&data_dir, &backup, snapshot.as_ref(), || {
+        write_backup_meta(&backup.join("backup.json"), &BackupMeta {
+            timestamp: SystemTime::now(),
+            snapshot: snapshot.clone(),
+            format: BackupFormat::Directory,
+        })?;
+
+        let meta = inst.upgrade_meta();
+        init(&options::Init {
+            name: inst.name.clone(),
+            system: inst.system,
+            interactive: false,
+            nightly,
+            version: Some(version.clone()),
+            method: Some(method.name()),
+            port: Some(inst.meta.port),
+            port_range: None,
+            start_conf: inst.meta.start_conf,
+            inhibit_user_creation: true,
+            inhibit_start: true,
+            upgrade_marker: Some(serde_json::to_string(&meta).unwrap()),
+            overwrite: true,
+            default_user: "edgedb".into(),
+            default_database: "edgedb".into(),
+        })?;
+        if !inst.meta.labels.is_empty() || !inst.meta.env.is_empty()
+            || !inst.meta.config.is_empty()
+        {
+            let mut meta = control::read_metadata(&inst.data_dir)?;
+            meta.labels = inst.meta.labels.clone();
+            meta.env = inst.meta.env.clone();
+            meta.config = inst.meta.config.clone();
+            write_metadata(&inst.data_dir.join("metadata.json"), &meta)?;
+        }
+        if snapshot.is_some() && snapshot::detect(&inst.data_dir).is_none() {
+            log::warn!(target: "edgedb::server::upgrade",
+                "Data directory {} is not a btrfs subvolume anymore, \
+                the next `--snapshot` upgrade will move it instead",
+                inst.data_dir.display());
+        }
+
+        check_deadline()?;
+        let ctl = inst.get_control()?;
+        // TCP connections use the port from the credentials file, otherwise
+        // the temporary server doesn't need the port of the instance
+        let runstate_dir = tempfile::tempdir()?;
+        let (mut cmd, temp_socket) =
+            if options.connect_method == Some(ConnectMethod::Tcp) {
+                let cmd = match &options.server_binary {
+                    Some(binary) => ctl.run_binary(binary)?,
+                    None => ctl.run_command()?,
+                };
+                (cmd, ctl.get_socket(true)?)
+            } else {
+                let port = match options.temp_port {
+                    Some(port) => port,
+                    None => TcpListener::bind(("127.0.0.1", 0))?
+                        .local_addr()?.port(),
+                };
+                log::info!(target: "edgedb::server::upgrade",
+                    "Running temporary server on port {}", port);
+                // version as written by init above
+                let new_meta = control::read_metadata(&inst.data_dir)?;
+                let binary = match &options.server_binary {
+                    Some(binary) => binary.clone(),
+                    None => method.get_server_path(&new_meta.version)?,
+                };
+                let cmd = ctl.run_temporary(&binary, port,
+                                            runstate_dir.path())?;
+                let socket = runstate_dir.path()
+                    .join(format!(".s.EDGEDB.admin.{}", port));
+                (cmd, socket)
+            };
+        // temporarily patch the edgedb issue of 1-alpha.4
+        cmd.arg("--default-database=edgedb");
+        cmd.arg("--default-database-user=edgedb");
+        let mut child = ProcessGuard::run_captured(&mut cmd)
+            .with_context(|| format!("error running server {}",
+                                     crate::process::describe(&cmd)))?;
+        if options.temp_auth == TempAuth::Password {
+            child.block_on(set_temp_password(inst, &temp_socket, options))?;
+        }
+
+        let started = Instant::now();
+        let fingerprint = if options.stream {
+            stream_instance(inst, &backup, &mut child,
+                Ok(temp_socket.clone()), method, &temp_options)?
+        } else {
+            child.block_on(phase_timeout(
+                "restore", options.restore_timeout,
+                restore_instance(inst, Ok(temp_socket.clone()),
+                                 &temp_options)))?;
+            inst.metrics.bytes_restored = dir_size(&inst.dump_path()).ok();
+            inst.fingerprint.clone()
+        };
+        inst.metrics.restore_time = Some(started.elapsed());
+        // not checking the deadline here, as the data is restored and
+        // rolling back would only lose it
+        Ok((ctl, child, temp_socket, runstate_dir, fingerprint))
+    });
+    let (mut ctl, mut child, temp_socket, _runstate_dir, fingerprint) =
+        match restored {
+            Ok(restored) => restored,
+            Err(e) => {
+                if !backup.exists() {
+                    // `init` has written the service of the new version
+                    if let Err(e) = recreate_service(inst, method) {
+                        log::warn!(target: "edgedb::server::upgrade",
+                            "Cannot restore service of {:?}: {:#}",
+                            inst.name, e);
+                    }
+                }
+                return Err(e);
+            }
+        };
+    if !inst.meta.config.is_empty() {
+        // applied after restore, so they take precedence over settings
+        // from the dump
+        child.block_on(async {
+            let (_, mut cli) = connect_within(inst, Ok(temp_socket.clone()),
+                temp_options.connect_method, options.start_timeout).await?;
+            config::apply(&mut cli, &inst.meta.config).await
+        }).context("cannot apply persisted settings")?;
+    }
+    if let Some(script) = &options.post_restore_script {
+        child.block_on(run_post_restore_script(
+            inst, script, Ok(temp_socket.clone()), &temp_options))
+            .with_context(|| format!("post-restore script failed \
+                (backup is kept at {})", backup.display()))?;
+    }
+    log::info!(target: "edgedb::server::upgrade",
+        "Restarting instance {:?} to apply changes from `restore --all`",
+        &inst.name);
+    drop(child);
+
+    ctl.start(&options::Start {
+        name: inst.name.clone(),
+        foreground: false,
+        wait: false,
+        wait_timeout: None,
     })?;
+    wait::wait_started(inst, Some(options.start_timeout))?;
+
+    if let Some(before) = &fingerprint {
+        log::info!(target: "edgedb::server::upgrade",
+            "Verifying restored data of {:?}", inst.name);
+        let after = task::block_on(async {
+            let (conn_params, mut cli) = connect_within(
+                inst, ctl.get_socket(true), options.connect_method,
+                options.start_timeout).await?;
+            verify::fingerprint(&mut cli, &conn_params).await
+        })?;
+        let errors = verify::compare(before, &after);
+        if !errors.is_empty() {
+            anyhow::bail!("restored data doesn't match the original \
+                (backup is kept at {}):\n  {}",
+                backup.display(), errors.join("\n  "));
+        }
+    }
+    write_json_atomic(&inst.data_dir.join(UPGRADE_DONE), &inst.upgrade_meta())
+        .context("cannot record that upgrade is complete")?;
+    if options.backup_encrypt {
+        log::info!(target: "edgedb::server::upgrade",
+            "Encrypting backup {}", backup.display());
+        // upgrade itself is complete, so the failure is not fatal
+        let encrypted = encrypted_backup::read_key(
+                options.backup_key_file.as_deref())
+            .and_then(|key| encrypted_backup::encrypt_backup(&backup, &key));
+        if let Err(e) = encrypted {
+            log::warn!(target: "edgedb::server::upgrade",
+                "{:#}. Backup is kept unencrypted at {}",
+                e, backup.display());
+        }
+    }
+    Ok(())
+}
+
+/// Runs `f` that reinitializes the data directory, and if it fails, puts
+/// the backup (moved directory or snapshot) back in place of the data
+/// directory, so the instance is left as before the upgrade
+fn with_rollback<T>(data_dir: &Path, backup: &Path,
+    snapshot: Option<&SnapshotMeta>, f: impl FnOnce() -> anyhow::Result<T>)
+    -> anyhow::Result<T>
+{
+    let err = match f() {
+        Ok(value) => return Ok(value),
+        Err(e) => e,
+    };
+    log::warn!(target: "edgedb::server::upgrade",
+        "Reinit failed, moving {} back to {}",
+        backup.display(), data_dir.display());
+    match rollback_reinit(data_dir, backup, snapshot) {
+        Ok(()) => {
+            log::info!(target: "edgedb::server::upgrade",
+                "Data directory {} is restored", data_dir.display());
+            Err(err)
+        }
+        Err(e) => {
+            Err(err.context(format!("cannot put the backup back ({:#}), \
+                it's kept at {}", e, backup.display())))
+        }
+    }
+}
+
+fn rollback_reinit(data_dir: &Path, backup: &Path,
+    snapshot: Option<&SnapshotMeta>)
+    -> anyhow::Result<()>
+{
+    if data_dir.exists() {
+        check_removable_data(data_dir)?;
+        fs::remove_dir_all(data_dir)?;
+    }
+    match snapshot {
+        Some(snap) => {
+            snapshot::by_name(&snap.filesystem)?.rollback(&snap.id, data_dir)?
+        }
+        None => move_dir(backup, data_dir, true)?,
+    }
+    fs::remove_file(data_dir.join("backup.json")).ok();
+    Ok(())
+}
 
-    let meta = inst.upgrade_meta();
-    init(&options::Init {
+/// Writes the service file for the version recorded in the metadata
+fn recreate_service(inst: &Instance, method: &dyn Method)
+    -> anyhow::Result<()>
+{
+    method.create_user_service(&init::Settings {
         name: inst.name.clone(),
         system: inst.system,
-        interactive: false,
-        nightly,
-        version: Some(version.clone()),
-        method: Some(method.name()),
-        port: Some(inst.meta.port),
+        version: inst.meta.version.clone(),
+        nightly: inst.meta.nightly,
+        method: inst.meta.method.clone(),
+        directory: inst.data_dir.clone(),
+        credentials: home_dir()?.join(".edgedb").join("credentials")
+            .join(format!("{}.json", inst.name)),
+        user: "edgedb".into(),
+        database: "edgedb".into(),
+        port: inst.meta.port,
         start_conf: inst.meta.start_conf,
         inhibit_user_creation: true,
         inhibit_start: true,
-        upgrade_marker: Some(serde_json::to_string(&meta).unwrap()),
-        overwrite: true,
-        default_user: "edgedb".into(),
-        default_database: "edgedb".into(),
-    })?;
+        upgrade_marker: None,
+    })
+}
 
-    let mut ctl = inst.get_control()?;
-    let mut cmd = ctl.run_command()?;
-    // temporarily patch the edgedb issue of 1-alpha.4
-    cmd.arg("--default-database=edgedb");
-    cmd.arg("--default-database-user=edgedb");
-    log::debug!("Running server: {:?}", cmd);
-    let child = ProcessGuard::run(&mut cmd)
-        .with_context(|| format!("error running server {:?}", cmd))?;
-
-    task::block_on(restore_instance(inst, &ctl.get_socket(true)?))?;
-    log::info!(target: "edgedb::server::upgrade",
-        "Restarting instance {:?} to apply changes from `restore --all`",
-        &inst.name);
-    drop(child);
+/// Rebuilds the data directory from the dump (`dump`, or the one made by
+/// the last upgrade) keeping the version of the instance. The instance must
+/// be stopped
+pub fn reinit_from_dump(inst: &mut Instance, dump: Option<PathBuf>,
+    options: &Upgrade)
+    -> anyhow::Result<()>
+{
+    inst.dump_dir = dump;
+    let meta_path = inst.dump_path().join(DUMP_META);
+    let dump: DumpMeta = serde_json::from_slice(&fs::read(&meta_path)
+        .with_context(|| format!("cannot read {} (dump is incomplete?)",
+                                 meta_path.display()))?)
+        .with_context(|| format!("cannot decode {}", meta_path.display()))?;
+    let os = detect::current_os()?;
+    let avail = os.get_available_methods()?;
+    let method = os.make_method(&inst.meta.method, &avail)?;
+    // the dump kept after an upgrade is made by the previous version
+    let majors = match method.all_versions(inst.meta.nightly) {
+        Ok(packages) => packages.iter()
+            .filter_map(|pkg| pkg.slot.clone())
+            .collect::<Vec<_>>(),
+        Err(e) => {
+            log::warn!(target: "edgedb::server::upgrade",
+                "Cannot fetch versions, skipped major versions \
+                 are not checked: {:#}", e);
+            Vec::new()
+        }
+    };
+    check_compat(&dump.source, &inst.meta.version, &majors)?;
+    inst.fingerprint = dump.fingerprint;
+    inst.source = Some(dump.source.clone());
+    inst.version = Some(inst.meta.version.clone());
 
-    ctl.start(&options::Start { name: inst.name.clone(), foreground: false })?;
-    Ok(())
+    let version = inst.meta.version.clone();
+    let nightly = inst.meta.nightly;
+    reinit_and_restore(inst, &version, nightly, &*method, options)
+}
+
+/// Whether the last complete upgrade of the instance was to the `target`
+/// version
+fn is_upgraded_to(inst: &Instance, target: &Version<String>) -> bool {
+    fs::read(inst.data_dir.join(UPGRADE_DONE)).ok()
+        .and_then(|data| serde_json::from_slice::<UpgradeMeta>(&data).ok())
+        .map(|meta| &meta.target == target)
+        .unwrap_or(false)
 }
 
-fn get_installed(version: &VersionQuery, method: &dyn Method)
+pub fn get_installed(version: &VersionQuery, method: &dyn Method)
     -> anyhow::Result<Option<Version<String>>>
 {
     for ver in method.installed_versions()? {
@@ -488,61 +2753,706 @@ fn get_installed(version: &VersionQuery, method: &dyn Method)
 }
 
 fn do_instance_upgrade(method: &dyn Method,
-    mut inst: Instance, version: &VersionQuery, options: &Upgrade)
+    mut inst: Instance, version: &VersionQuery, options: &Upgrade,
+    summary: &mut Summary)
     -> anyhow::Result<()>
 {
     let new = method.get_version(&version)
-        .context("Unable to determine version")?;
+        .map_err(|e| UpgradeError::VersionResolution(e.into()))?;
     let old = get_installed(version, method)?;
 
+    if let Some(expected) = &options.from_version {
+        if !is_baseline(expected, &inst.meta.version, old.as_ref()) {
+            anyhow::bail!("Instance {:?} is on version {}{}, not {} \
+                as required by `--from-version`", inst.name, inst.meta.version,
+                old.as_ref().map(|v| format!(" (installed {})", v))
+                    .unwrap_or_default(),
+                expected);
+        }
+    }
+    let new_port = options.port.filter(|&port| port != inst.meta.port);
     if !options.force {
         if let Some(old_ver) = &old {
             if old_ver >= &new.full_version() {
                 log::info!(target: "edgedb::server::upgrade",
                     "Version {} is up to date {}, skipping instance: {}",
                     version, old_ver, inst.name);
+                summary.up_to_date += 1;
+                match new_port {
+                    Some(port) if options.dry_run => {
+                        println!("Would move instance {:?} to port {}",
+                            inst.name, port);
+                    }
+                    Some(port) => move_port(method, &mut inst, port, options)?,
+                    None => {}
+                }
                 return Ok(());
             }
         }
     }
+    if options.compat_check {
+        let majors = method.all_versions(version.is_nightly())?.iter()
+            .filter_map(|pkg| pkg.slot.clone())
+            .collect::<Vec<_>>();
+        check_compat(&inst.meta.version, &new.major_version, &majors)
+            .with_context(|| format!("cannot upgrade {:?}", inst.name))?;
+    }
+    if options.dry_run {
+        println!("Would upgrade instance {:?} to {}-{}",
+            inst.name, new.version, new.revision);
+        return Ok(());
+    }
+    check_channel_switch(&inst, version, options)?;
     inst.source = old;
     inst.version = Some(new.full_version());
 
-    dump_and_stop(&inst)?;
+    if let Some(port) = new_port {
+        check_port(&inst, port)?;
+    }
+    let port = new_port.unwrap_or(inst.meta.port);
+    if method.upgrade_in_place(&inst, &new, port)? {
+        inst.meta.version = new.major_version.clone();
+        inst.meta.nightly = version.is_nightly();
+        inst.meta.port = port;
+        write_metadata(&inst.data_dir.join("metadata.json"), &inst.meta)?;
+        if new_port.is_some() {
+            init::assign_port(&inst.name, port)?;
+            update_credentials_port(&inst)?;
+        }
+        summary.add_upgraded(&inst);
+        return Ok(());
+    }
+    if options.stream && new.major_version == inst.meta.version {
+        anyhow::bail!("`--stream` requires upgrading {:?} to a new major \
+            version, because package upgrade replaces server {} \
+            in place", inst.name, inst.meta.version);
+    }
+    if !options.assume_writable {
+        check_writable(&inst)?;
+    }
+    check_downtime(&inst, options)?;
+    dump_and_stop(&mut inst, options)
+        .map_err(|e| UpgradeError::DumpFailed(inst.name.clone(), e.into()))?;
+    if let Some(port) = new_port {
+        log::info!(target: "edgedb::server::upgrade",
+            "Moving instance {:?} from port {} to {}",
+            inst.name, inst.meta.port, port);
+        inst.meta.port = port;
+    }
 
     log::info!(target: "edgedb::server::upgrade", "Installing the package");
-    method.install(&install::Settings {
+    install_package(method, &install::Settings {
         method: method.name(),
         package_name: new.package_name,
         major_version: new.major_version,
         version: new.version.clone(),
+        revision: new.revision,
         nightly: version.is_nightly(),
-        extra: LinkedHashMap::new(),
-    })?;
+        extra: install::extra_settings(method, &options.extra)?,
+        // install next to the binary the instance currently uses
+        prefix: install::prefix::find(&inst.meta.version),
+    }, options)?;
 
-    reinit_and_restore(&inst, &new.version, version.is_nightly(), method)?;
+    reinit_and_restore(&mut inst, &new.version, version.is_nightly(),
+        method, options)
+        .map_err(|e| {
+            UpgradeError::RestoreFailed(inst.name.clone(), e.into())
+        })?;
+    if new_port.is_some() {
+        update_credentials_port(&inst)?;
+    }
+    summary.add_upgraded(&inst);
     Ok(())
 }
 
-#[context("failed to write backup metadata file {}", path.display())]
-fn write_backup_meta(path: &Path, metadata: &BackupMeta)
+/// Checks that a dump made by the `source` major version can be restored
+/// into the `target` one (`--compat-check`). `majors` are the major versions
+/// known to the repository
+fn check_compat(source: &Version<String>, target: &Version<String>,
+    majors: &[Version<String>])
     -> anyhow::Result<()>
 {
-    fs::write(path, serde_json::to_vec(&metadata)?)?;
+    if target < source {
+        anyhow::bail!("dump made by {} can't be restored into \
+            older version {}", source, target);
+    }
+    let mut skipped = majors.iter()
+        .filter(|major| *major > source && *major < target)
+        .collect::<Vec<_>>();
+    skipped.sort();
+    skipped.dedup();
+    if skipped.len() > MAX_SKIPPED_MAJORS {
+        anyhow::bail!("upgrade from {} to {} skips {} major versions ({}), \
+            dump format compatibility is only checked for skipping up to {}. \
+            Upgrade to {} first.",
+            source, target, skipped.len(),
+            skipped.iter().map(|v| v.to_string())
+                .collect::<Vec<_>>().join(", "),
+            MAX_SKIPPED_MAJORS, skipped[MAX_SKIPPED_MAJORS - 1]);
+    }
     Ok(())
 }
 
+/// Whether `expected` is the major version of the instance or its full
+/// installed version (with or without the package revision)
+fn is_baseline(expected: &Version<String>, major: &Version<String>,
+    installed: Option<&Version<String>>)
+    -> bool
+{
+    if expected == major {
+        return true;
+    }
+    match installed {
+        Some(installed) => {
+            let expected: &str = expected.as_ref();
+            let installed: &str = installed.as_ref();
+            installed == expected ||
+                installed.strip_prefix(expected)
+                    .map(|rest| rest.starts_with('-'))
+                    .unwrap_or(false)
+        }
+        None => false,
+    }
+}
+
+#[context("failed to write backup metadata file {}", path.display())]
+pub fn write_backup_meta(path: &Path, metadata: &BackupMeta)
+    -> anyhow::Result<()>
+{
+    write_json_atomic(path, metadata)
+}
+
+impl Summary {
+    fn add_upgraded(&mut self, inst: &Instance) {
+        self.upgraded += 1;
+        self.instances.push(InstanceSummary {
+            name: inst.name.clone(),
+            from: inst.source.clone(),
+            to: inst.version.clone(),
+            metrics: inst.metrics.clone(),
+        });
+    }
+    fn report(&self, elapsed: Duration) -> Report<'_> {
+        Report {
+            summary: self,
+            elapsed: Duration::from_secs(elapsed.as_secs()),
+        }
+    }
+    fn print(&self, elapsed: Duration, format: OutputFormat)
+        -> anyhow::Result<()>
+    {
+        if format != OutputFormat::Human {
+            return print_serialized(format, &self.report(elapsed));
+        }
+        let elapsed = Duration::from_secs(elapsed.as_secs());
+        println!("Upgraded {} instances, {} up-to-date, {} skipped, \
+            {} failed in {}",
+            self.upgraded, self.up_to_date, self.skipped, self.failed,
+            humantime::format_duration(elapsed));
+        if !self.not_found.is_empty() {
+            println!("  not found: {}", self.not_found.join(", "));
+        }
+        for failed in &self.errors {
+            println!("  {}: failed: {}", failed.name, failed.error);
+        }
+        for inst in &self.instances {
+            let metrics = &inst.metrics;
+            let mut phases = Vec::new();
+            if let Some(time) = metrics.dump_time {
+                phases.push(format!("dumped {} in {}",
+                    format_bytes(metrics.bytes_dumped), format_time(time)));
+            }
+            if let Some(time) = metrics.restore_time {
+                phases.push(format!("restored {} in {}",
+                    format_bytes(metrics.bytes_restored), format_time(time)));
+            }
+            if !phases.is_empty() {
+                println!("  {}: {}", inst.name, phases.join(", "));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn format_time(time: Duration) -> humantime::FormattedDuration {
+    humantime::format_duration(Duration::from_millis(
+        time.as_millis() as u64 / 100 * 100))
+}
+
+pub fn format_bytes(bytes: Option<u64>) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let bytes = match bytes {
+        Some(bytes) => bytes,
+        None => return "(unknown size)".into(),
+    };
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+impl UpgradeMeta {
+    /// Returns an error if marker is written by a newer version of the tool
+    /// in a format that this version can't interpret
+    pub fn check_supported(&self) -> anyhow::Result<()> {
+        if self.schema_version > UPGRADE_META_VERSION {
+            anyhow::bail!("upgrade was started by edgedb-cli {} \
+                using upgrade metadata version {}, \
+                but this tool supports only version {}. \
+                Please use newer edgedb-cli.",
+                self.cli_version, self.schema_version, UPGRADE_META_VERSION);
+        }
+        Ok(())
+    }
+}
+
 impl Instance {
-    fn get_control(&self) -> anyhow::Result<Box<dyn control::Instance>> {
+    pub fn get_control(&self) -> anyhow::Result<Box<dyn control::Instance>> {
         control::get_instance_from_metadata(
             &self.name, self.system, &self.meta)
     }
+    fn dump_path(&self) -> PathBuf {
+        match &self.dump_dir {
+            Some(path) => path.clone(),
+            None => self.data_dir.with_file_name(
+                format!("{}.dump", self.name)),
+        }
+    }
     fn upgrade_meta(&self) -> UpgradeMeta {
         UpgradeMeta {
             source: self.source.clone().unwrap_or(Version("unknown".into())),
             target: self.version.clone().unwrap_or(Version("unknown".into())),
             started: SystemTime::now(),
             pid: process::id(),
+            cli_version: env!("CARGO_PKG_VERSION").into(),
+            schema_version: UPGRADE_META_VERSION,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::Path;
+
+    use crate::server::init::Metadata;
+    use crate::server::methods::InstallMethod;
+    use crate::server::options::StartConf;
+    use crate::server::version::Version;
+
+    use super::{channel_switch, check_stopped, ChannelSwitch};
+    use super::{write_atomic, tmp_file_name, parse_selection, format_bytes};
+    use super::{check_removable, check_removable_data, is_baseline};
+    use super::InstanceIterator;
+    use super::{check_compat, run_batched};
+    use super::{current_revision, group_by_revision, is_up_to_date};
+    use super::{write_json_atomic, UPGRADE_DONE};
+    use super::{last_started, move_by_copy, move_dir, with_rollback};
+    use super::{plan_inventory, Instance, InventoryItem, ToDo};
+    use super::{parse_stdin_items, run_plan, Summary};
+    use crate::server::detect::VersionQuery;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinked_instance() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let data = tmp.path().join("data");
+        let storage = tmp.path().join("storage");
+        fs::create_dir_all(&data).unwrap();
+        fs::create_dir_all(&storage).unwrap();
+        fs::write(storage.join("metadata.json"),
+            serde_json::to_vec(&Metadata {
+                version: Version("1-alpha6".into()),
+                method: InstallMethod::Package,
+                port: 10700,
+                nightly: false,
+                start_conf: StartConf::Auto,
+                labels: Default::default(),
+                env: Default::default(),
+                config: Default::default(),
+            }).unwrap()).unwrap();
+        symlink(&storage, data.join("relocated")).unwrap();
+        symlink(data.join("loop"), data.join("loop")).unwrap();
+        let names = InstanceIterator {
+            dir: fs::read_dir(&data).unwrap(),
+            path: data.clone(),
+            strict: false,
+        }.map(|inst| inst.unwrap().name).collect::<Vec<_>>();
+        assert_eq!(names, ["relocated"]);
+    }
+
+    #[test]
+    fn test_removable() {
+        let root = Path::new("/home/user/.local/share/edgedb/data");
+        assert!(check_removable(&root.join("inst.dump"), root).is_ok());
+        assert!(check_removable(&root.join("inst.backup"), root).is_ok());
+        assert!(check_removable(root, root).is_err());
+        assert!(check_removable(&root.join("inst"), root).is_err());
+        assert!(check_removable(&root.join(".dump"), root).is_err());
+        assert!(check_removable(&root.join("a/b.dump"), root).is_err());
+        assert!(check_removable(&root.join("../data.dump"), root).is_err());
+        assert!(check_removable(&root.join("x.backup/../.."), root)
+                .is_err());
+        assert!(check_removable(Path::new("/home/user.dump"), root)
+                .is_err());
+        assert!(check_removable(Path::new("inst.dump"), root).is_err());
+        assert!(check_removable(&root.join("inst.backup.1600000000"), root)
+                .is_ok());
+        assert!(check_removable(&root.join("inst.backup.x"), root).is_err());
+
+        assert!(check_removable_data(&root.join("inst")).is_ok());
+        assert!(check_removable_data(&root.join("inst.backup")).is_ok());
+        assert!(check_removable_data(Path::new("/")).is_err());
+        assert!(check_removable_data(&root.join("inst/..")).is_err());
+        assert!(check_removable_data(&root.join("my-inst")).is_err());
+        assert!(check_removable_data(Path::new("inst")).is_err());
+    }
+
+    fn instance(name: &str, nightly: bool) -> Instance {
+        Instance {
+            name: name.into(),
+            meta: Metadata {
+                version: Version("1-alpha6".into()),
+                method: InstallMethod::Package,
+                port: 10700,
+                nightly,
+                start_conf: StartConf::Auto,
+                labels: Default::default(),
+                env: Default::default(),
+                config: Default::default(),
+            },
+            system: false,
+            data_dir: Path::new("/nonexistent").join(name),
+            source: None,
+            version: None,
+            fingerprint: None,
+            metrics: Default::default(),
+            dump_dir: None,
+        }
+    }
+
+    #[test]
+    fn test_inventory() {
+        let items: Vec<InventoryItem> = serde_json::from_str(r#"[
+            {"name": "second", "target_version": "1-alpha7"},
+            {"name": "first", "method": "Package"},
+            {"name": "nightly"}
+        ]"#).unwrap();
+        let plan = plan_inventory(items, vec![
+            instance("first", false),
+            instance("nightly", true),
+            instance("second", false),
+            instance("unlisted", false),
+        ]).unwrap();
+        let names = plan.iter().map(|(_, inst)| &inst.name[..])
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["second", "first", "nightly"]);
+        assert!(matches!(&plan[0].0, ToDo::InstanceUpgrade(name, _)
+                                     if name == "second"));
+        assert!(matches!(plan[1].0, ToDo::MinorUpgrade));
+        assert!(matches!(plan[2].0, ToDo::NightlyUpgrade));
+
+        let items = serde_json::from_str(
+            r#"[{"name": "first"}, {"name": "missing"}]"#).unwrap();
+        // plan is not `Debug`, so `unwrap_err` can't be used
+        let err = plan_inventory(items, vec![instance("first", false)])
+            .err().unwrap();
+        assert!(err.to_string().contains("missing"));
+
+        // e.g. metadata of the instance instead of an item
+        assert!(serde_json::from_str::<Vec<InventoryItem>>(
+            r#"[{"name": "first", "version": "1-alpha6"}]"#).is_err());
+    }
+
+    #[test]
+    fn test_estimate_items() {
+        use clap::Clap;
+        use crate::server::options::Upgrade;
+
+        let options = Upgrade::try_parse_from(&[
+            "upgrade", "--instances-from-stdin", "--estimate",
+        ]).unwrap();
+        let plan = vec![
+            (ToDo::MinorUpgrade, vec![instance("first", false)]),
+            (ToDo::NightlyUpgrade, vec![instance("second", true)]),
+        ];
+        let mut estimated = Vec::new();
+        run_plan(plan, &options, &mut Summary::default(),
+            |instances| {
+                estimated.extend(instances.iter().map(|i| i.name.clone()));
+                Ok(())
+            },
+            |_, _, _| panic!("estimate must not upgrade")).unwrap();
+        assert_eq!(estimated, ["first", "second"]);
+    }
+
+    #[test]
+    fn test_stdin_items() {
+        let items = parse_stdin_items("first\n\n  second  \n\
+            {\"name\": \"third\", \"target_version\": \"1-beta1\"}\n")
+            .unwrap();
+        let names = items.iter().map(|item| &item.name[..])
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["first", "second", "third"]);
+        assert!(items[0].target_version.is_none());
+        assert_eq!(items[2].target_version, Some(Version("1-beta1".into())));
+        let err = parse_stdin_items("first\n{\"nme\": 1}\n").err().unwrap();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+    }
+
+    #[test]
+    fn test_failed_init_rolls_back() {
+        let tmp = tempfile::tempdir().unwrap();
+        let data_dir = tmp.path().join("inst");
+        let backup = tmp.path().join("inst.backup");
+        fs::create_dir(&data_dir).unwrap();
+        fs::write(data_dir.join("metadata.json"), "old").unwrap();
+        move_dir(&data_dir, &backup, true).unwrap();
+        fs::write(backup.join("backup.json"), "{}").unwrap();
+
+        let result = with_rollback::<()>(&data_dir, &backup, None, || {
+            // partially initialized data directory
+            fs::create_dir(&data_dir)?;
+            fs::write(data_dir.join("metadata.json"), "new")?;
+            anyhow::bail!("init failed");
+        });
+        assert_eq!(result.unwrap_err().to_string(), "init failed");
+        assert_eq!(fs::read(data_dir.join("metadata.json")).unwrap(), b"old");
+        assert!(!data_dir.join("backup.json").exists());
+        assert!(!backup.exists());
+
+        let result = with_rollback(&data_dir, &backup, None, || Ok(1));
+        assert_eq!(result.unwrap(), 1);
+        assert!(data_dir.exists());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_move_by_copy() {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::{MetadataExt, PermissionsExt, symlink};
+
+        // a time that can't be the time of copying
+        const MTIME: i64 = 1_600_000_000;
+        fn set_mtime(path: &Path) {
+            let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+            let time = libc::timeval {
+                tv_sec: MTIME as libc::time_t,
+                tv_usec: 0,
+            };
+            let times = [time, time];
+            let result = unsafe {
+                libc::utimes(c_path.as_ptr(), times.as_ptr())
+            };
+            assert_eq!(result, 0, "cannot set mtime of {:?}", path);
+        }
+
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("inst");
+        fs::create_dir_all(src.join("base")).unwrap();
+        fs::write(src.join("base").join("1"), "data").unwrap();
+        fs::set_permissions(src.join("base").join("1"),
+            fs::Permissions::from_mode(0o600)).unwrap();
+        fs::set_permissions(src.join("base"),
+            fs::Permissions::from_mode(0o700)).unwrap();
+        symlink("base/1", src.join("link")).unwrap();
+        for path in &["base/1", "base", ""] {
+            set_mtime(&src.join(path));
+        }
+        let paths = ["", "base", "base/1"];
+        let old = paths.iter()
+            .map(|path| fs::metadata(src.join(path)).unwrap())
+            .collect::<Vec<_>>();
+
+        let dest = tmp.path().join("inst.backup");
+        move_by_copy(&src, &dest, true).unwrap();
+        assert!(!src.exists());
+        for (path, old) in paths.iter().zip(&old) {
+            let new = fs::metadata(dest.join(path)).unwrap();
+            assert_eq!(old.mode(), new.mode(), "mode of {:?}", path);
+            assert_eq!(old.uid(), new.uid(), "owner of {:?}", path);
+            assert_eq!(old.gid(), new.gid(), "group of {:?}", path);
+            assert_eq!(new.mtime(), MTIME, "mtime of {:?}", path);
+        }
+        assert_eq!(fs::read_link(dest.join("link")).unwrap(),
+                   Path::new("base/1"));
+        assert_eq!(fs::read(dest.join("link")).unwrap(), b"data");
+
+        // destination can't be created, so the source is kept
+        let missing = tmp.path().join("missing").join("inst");
+        assert!(move_by_copy(&dest, &missing, true).is_err());
+        assert!(!missing.exists());
+        assert_eq!(fs::read(dest.join("base").join("1")).unwrap(), b"data");
+    }
+
+    #[test]
+    fn test_last_started() {
+        let tmp = tempfile::tempdir().unwrap();
+        assert!(last_started(tmp.path()).is_none());
+        let before = std::time::SystemTime::now()
+            - std::time::Duration::from_secs(1);
+        fs::write(tmp.path().join("postmaster.opts"), "edgedb-server")
+            .unwrap();
+        assert!(last_started(tmp.path()).unwrap() >= before);
+    }
+
+    #[test]
+    fn test_compat() {
+        let v = |s: &str| Version(s.to_string());
+        let majors = ["1-alpha4", "1-alpha5", "1-alpha6", "1-alpha7",
+                      "1-beta1", "1-beta1"]
+            .iter().map(|s| v(s)).collect::<Vec<_>>();
+        assert!(check_compat(&v("1-alpha4"), &v("1-alpha5"), &majors).is_ok());
+        assert!(check_compat(&v("1-alpha4"), &v("1-alpha7"), &majors).is_ok());
+        assert!(check_compat(&v("1-alpha5"), &v("1-alpha5"), &majors).is_ok());
+        let err = check_compat(&v("1-alpha4"), &v("1-beta1"), &majors)
+            .unwrap_err().to_string();
+        assert!(err.contains("1-alpha5, 1-alpha6, 1-alpha7"), "{}", err);
+        assert!(err.contains("Upgrade to 1-alpha6 first"), "{}", err);
+        assert!(check_compat(&v("1-alpha6"), &v("1-alpha5"), &majors)
+                .is_err());
+    }
+
+    #[test]
+    fn test_run_batched() {
+        let results = run_batched(vec![1, 2, 3, 4, 5], Some(2), |item| {
+            if item == 3 {
+                anyhow::bail!("failed {}", item);
+            }
+            Ok(())
+        });
+        // batch containing the failure completes, next ones aren't run
+        assert_eq!(results.len(), 4);
+        assert!(results[2].is_err());
+        assert!(results[3].is_ok());
+        assert_eq!(run_batched(vec![1, 2, 3], None, |_| Ok(())).len(), 3);
+    }
+
+    #[test]
+    fn test_mixed_revisions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let v = |s: &str| Version(s.to_string());
+        let installed = v("1.0a6-2020100502");
+        let target = v("1.0a6-2020110100");
+        let instances = ["a", "b", "c", "d"].iter().map(|name| {
+            let mut inst = instance(name, false);
+            inst.data_dir = tmp.path().join(name);
+            fs::create_dir(&inst.data_dir).unwrap();
+            inst
+        }).collect::<Vec<_>>();
+        for (inst, rev) in instances[1..].iter()
+            .zip(&["1.0a6-2020100100", "1.0a6-2020110100"])
+        {
+            let mut inst = instance(&inst.name, false);
+            inst.version = Some(v(rev));
+            write_json_atomic(&tmp.path().join(&inst.name).join(UPGRADE_DONE),
+                              &inst.upgrade_meta()).unwrap();
         }
+        let groups = group_by_revision(instances,
+            |inst| current_revision(inst, Some(&installed)));
+        let groups = groups.iter()
+            .map(|(rev, insts)| (
+                rev.as_ref().unwrap().to_string(),
+                insts.iter().map(|i| &i.name[..]).collect::<Vec<_>>(),
+                is_up_to_date(rev.as_ref(), &target, false),
+            ))
+            .collect::<Vec<_>>();
+        assert_eq!(groups, vec![
+            ("1.0a6-2020100100".into(), vec!["b"], false),
+            ("1.0a6-2020100502".into(), vec!["a", "d"], false),
+            ("1.0a6-2020110100".into(), vec!["c"], true),
+        ]);
+        assert!(!is_up_to_date(Some(&target), &target, true));
+        assert!(!is_up_to_date(None, &target, false));
+    }
+
+    #[test]
+    fn test_baseline() {
+        let major = Version("1-alpha5".into());
+        let installed = Version("1.0a5-2020100502".into());
+        let v = |s: &str| Version(s.to_string());
+        assert!(is_baseline(&v("1-alpha5"), &major, None));
+        assert!(is_baseline(&v("1.0a5"), &major, Some(&installed)));
+        assert!(is_baseline(&v("1.0a5-2020100502"), &major,
+                            Some(&installed)));
+        assert!(!is_baseline(&v("1.0a5-20"), &major, Some(&installed)));
+        assert!(!is_baseline(&v("1-alpha4"), &major, Some(&installed)));
+        assert!(!is_baseline(&v("1.0a5"), &major, None));
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(Some(0)), "0 B");
+        assert_eq!(format_bytes(Some(1023)), "1023 B");
+        assert_eq!(format_bytes(Some(1536)), "1.5 KiB");
+        assert_eq!(format_bytes(Some(5 << 30)), "5.0 GiB");
+        assert_eq!(format_bytes(None), "(unknown size)");
+    }
+
+    #[test]
+    fn test_selection() {
+        assert_eq!(parse_selection("all", 3).unwrap(), [0, 1, 2]);
+        assert_eq!(parse_selection("", 3).unwrap(), Vec::<usize>::new());
+        assert_eq!(parse_selection("3, 1", 3).unwrap(), [0, 2]);
+        assert_eq!(parse_selection("2-4,3", 5).unwrap(), [1, 2, 3]);
+        assert!(parse_selection("0", 3).is_err());
+        assert!(parse_selection("2-4", 3).is_err());
+        assert!(parse_selection("x", 3).is_err());
+    }
+
+    #[test]
+    fn test_interrupted_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("backup.json");
+        write_atomic(&path, br#"{"timestamp":"2020-10-01T00:00:00Z"}"#)
+            .unwrap();
+        // a write interrupted before rename leaves only the temporary file
+        let tmp_path = path.with_file_name(tmp_file_name(&path));
+        fs::write(&tmp_path, br#"{"times"#).unwrap();
+        assert_eq!(fs::read(&path).unwrap(),
+                   br#"{"timestamp":"2020-10-01T00:00:00Z"}"#);
+        write_atomic(&path, br#"{"timestamp":"2020-10-02T00:00:00Z"}"#)
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(),
+                   br#"{"timestamp":"2020-10-02T00:00:00Z"}"#);
+        assert!(!tmp_path.exists());
+    }
+
+    #[test]
+    fn test_stop_failure_blocks_install() {
+        let failed = || Err(anyhow::anyhow!("unit is busy"));
+        assert!(check_stopped("inst1", failed(), || Ok(true), false)
+                .is_err());
+        assert!(check_stopped("inst1", failed(),
+                              || Err(anyhow::anyhow!("no status")), false)
+                .is_err());
+        assert!(check_stopped("inst1", failed(), || Ok(false), false)
+                .is_ok());
+        assert!(check_stopped("inst1", failed(), || Ok(true), true).is_ok());
+        assert!(check_stopped("inst1", Ok(()), || Ok(true), false).is_ok());
+    }
+
+    #[test]
+    fn test_stable_to_nightly() {
+        assert_eq!(channel_switch(false, &VersionQuery::Nightly),
+                   Some(ChannelSwitch::ToNightly));
+        assert_eq!(channel_switch(true, &VersionQuery::Nightly), None);
+    }
+
+    #[test]
+    fn test_nightly_to_stable() {
+        assert_eq!(channel_switch(true, &VersionQuery::Stable(None)),
+                   Some(ChannelSwitch::ToStable));
+        assert_eq!(channel_switch(false, &VersionQuery::Stable(None)), None);
     }
 }
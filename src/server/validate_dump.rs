@@ -0,0 +1,337 @@
+use std::convert::TryInto;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Read, BufReader};
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::commands::{DUMP_MAGIC, MAX_SUPPORTED_DUMP_VER};
+use crate::server::options::ValidateDump;
+use crate::server::upgrade::{DumpMeta, DUMP_META};
+
+
+/// Code of the header containing server version in the dump header packet
+const DUMP_HEADER_SERVER_VER: u16 = 103;
+
+#[derive(Debug, Default)]
+struct DumpInfo {
+    dump_version: i64,
+    server_version: Option<String>,
+    protocol: (u16, u16),
+    types: u32,
+    blocks: u64,
+    data_size: u64,
+}
+
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if self.0.len() < len {
+            anyhow::bail!("dump header is truncated");
+        }
+        let (data, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(data)
+    }
+    fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    fn bytes(&mut self) -> anyhow::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+/// Size of the buffer packets are read and hashed through
+const CHUNK_SIZE: usize = 65536;
+
+/// Reads a packet checking its type and hash, `None` means end of file.
+/// Returns length of the packet, and its data if `keep` is set (length
+/// comes from the file, so data blocks are not kept in memory)
+fn read_packet(input: &mut impl Read, expected: u8, keep: bool)
+    -> anyhow::Result<Option<(u64, Vec<u8>)>>
+{
+    let mut buf = [0u8; 1+20+4];
+    let mut read = 0;
+    while read < buf.len() {
+        let n = input.read(&mut buf[read..])
+            .context("cannot read packet header")?;
+        if n == 0 {
+            if read == 0 {
+                return Ok(None);
+            }
+            anyhow::bail!("packet header is truncated");
+        }
+        read += n;
+    }
+    if buf[0] != expected {
+        anyhow::bail!("expected packet {:?}, got {:?}",
+            expected as char, buf[0] as char);
+    }
+    let len = u32::from_be_bytes(buf[1+20..].try_into().unwrap()) as u64;
+    let mut hash = sha1::Sha1::new();
+    let mut data = Vec::new();
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut left = len;
+    while left > 0 {
+        let size = left.min(CHUNK_SIZE as u64) as usize;
+        let n = match input.read(&mut chunk[..size]) {
+            Ok(0) => anyhow::bail!("packet of {} bytes is truncated", len),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e).context("cannot read packet"),
+        };
+        hash.update(&chunk[..n]);
+        if keep {
+            data.extend_from_slice(&chunk[..n]);
+        }
+        left -= n as u64;
+    }
+    if hash.digest().bytes()[..] != buf[1..1+20] {
+        anyhow::bail!("checksum mismatch in packet of {} bytes", len);
+    }
+    Ok(Some((len, data)))
+}
+
+fn parse_header(data: &[u8], info: &mut DumpInfo) -> anyhow::Result<()> {
+    let mut cur = Cursor(data);
+    let num_headers = cur.u16()?;
+    for _ in 0..num_headers {
+        let code = cur.u16()?;
+        let value = cur.bytes()?;
+        if code == DUMP_HEADER_SERVER_VER {
+            info.server_version = Some(
+                String::from_utf8_lossy(value).into_owned());
+        }
+    }
+    info.protocol = (cur.u16()?, cur.u16()?);
+    cur.bytes()?; // schema DDL
+    info.types = cur.u32()?;
+    Ok(())
+}
+
+fn validate_file(path: &Path) -> anyhow::Result<DumpInfo> {
+    let mut input = BufReader::new(fs::File::open(path)?);
+    let mut buf = [0u8; 17+8];
+    input.read_exact(&mut buf).context("cannot read file header")?;
+    if &buf[..17] != DUMP_MAGIC {
+        anyhow::bail!("file is not an EdgeDB dump");
+    }
+    let mut info = DumpInfo {
+        dump_version: i64::from_be_bytes(buf[17..].try_into().unwrap()),
+        .. DumpInfo::default()
+    };
+    if info.dump_version == 0 || info.dump_version > MAX_SUPPORTED_DUMP_VER {
+        anyhow::bail!("unsupported dump version {}", info.dump_version);
+    }
+    let (_, header) = read_packet(&mut input, b'H', true)?
+        .ok_or_else(|| anyhow::anyhow!("dump is empty"))?;
+    parse_header(&header, &mut info)?;
+    while let Some((len, _)) = read_packet(&mut input, b'D', false)
+        .with_context(|| format!("error in data block {}", info.blocks + 1))?
+    {
+        info.blocks += 1;
+        info.data_size += len;
+    }
+    Ok(info)
+}
+
+fn print_info(name: &str, info: &DumpInfo) {
+    println!("Database {:?}: server {}, dump format {} (protocol {}.{}), \
+        {} types, {} data blocks of {} bytes",
+        name,
+        info.server_version.as_ref().map(|x| &x[..]).unwrap_or("unknown"),
+        info.dump_version, info.protocol.0, info.protocol.1,
+        info.types, info.blocks, info.data_size);
+}
+
+fn validate_dir(path: &Path, problems: &mut Vec<String>)
+    -> anyhow::Result<()>
+{
+    let meta_path = path.join(DUMP_META);
+    if meta_path.exists() {
+        match fs::read(&meta_path).map_err(anyhow::Error::new)
+            .and_then(|data| Ok(serde_json::from_slice::<DumpMeta>(&data)?))
+        {
+            Ok(meta) => {
+                println!("Made by `edgedb server upgrade` of version {} \
+                    at {}", meta.source,
+                    humantime::format_rfc3339_seconds(meta.timestamp));
+                for file in &meta.files {
+                    if !path.join(file).exists() {
+                        problems.push(format!("{} is listed in {} \
+                            but missing", file, DUMP_META));
+                    }
+                }
+            }
+            Err(e) => problems.push(format!("cannot read {}: {:#}",
+                                            DUMP_META, e)),
+        }
+    }
+    let init = path.join("init.edgeql");
+    match fs::read(&init) {
+        Ok(data) if std::str::from_utf8(&data).is_ok() => {
+            println!("init.edgeql: {} bytes", data.len());
+        }
+        Ok(_) => problems.push("init.edgeql is not valid UTF-8".into()),
+        Err(e) => problems.push(format!("cannot read init.edgeql: {}", e)),
+    }
+    let mut files = Vec::new();
+    for item in fs::read_dir(path)? {
+        let file = item?.path();
+        if file.extension() == Some(OsStr::new("dump")) {
+            files.push(file);
+        }
+    }
+    files.sort();
+    if files.is_empty() {
+        println!("No databases in the dump");
+    }
+    for file in &files {
+        let name = file.file_stem().and_then(|x| x.to_str())
+            .and_then(|x| urlencoding::decode(x).ok())
+            .unwrap_or_else(|| file.display().to_string());
+        match validate_file(file) {
+            Ok(info) => print_info(&name, &info),
+            Err(e) => problems.push(format!("{}: {:#}",
+                                            file.display(), e)),
+        }
+    }
+    Ok(())
+}
+
+pub fn validate_dump(options: &ValidateDump) -> anyhow::Result<()> {
+    let path = &options.path;
+    let mut problems = Vec::new();
+    if path.is_dir() {
+        validate_dir(path, &mut problems)?;
+    } else {
+        match validate_file(path) {
+            Ok(info) => print_info(
+                &path.file_stem().unwrap_or(path.as_os_str())
+                    .to_string_lossy(),
+                &info),
+            Err(e) => problems.push(format!("{:#}", e)),
+        }
+    }
+    if !problems.is_empty() {
+        for problem in &problems {
+            eprintln!("Problem: {}", problem);
+        }
+        anyhow::bail!("Dump {} is invalid", path.display());
+    }
+    println!("Dump {} is valid", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::commands::DUMP_MAGIC;
+    use super::{validate_file, DUMP_HEADER_SERVER_VER};
+
+    fn packet(kind: u8, data: &[u8]) -> Vec<u8> {
+        let mut buf = vec![kind];
+        buf.extend(&sha1::Sha1::from(data).digest().bytes());
+        buf.extend(&(data.len() as u32).to_be_bytes());
+        buf.extend(data);
+        buf
+    }
+
+    fn header() -> Vec<u8> {
+        let mut header = Vec::new();
+        header.extend(&1u16.to_be_bytes());
+        header.extend(&DUMP_HEADER_SERVER_VER.to_be_bytes());
+        header.extend(&8u32.to_be_bytes());
+        header.extend(b"1-alpha5");
+        header.extend(&0u16.to_be_bytes());
+        header.extend(&8u16.to_be_bytes());
+        header.extend(&14u32.to_be_bytes());
+        header.extend(b"CREATE TYPE A;");
+        header.extend(&2u32.to_be_bytes());
+        header
+    }
+
+    fn dump_with(header: &[u8]) -> Vec<u8> {
+        let mut buf = DUMP_MAGIC.to_vec();
+        buf.extend(&1i64.to_be_bytes());
+        buf.extend(packet(b'H', header));
+        buf.extend(packet(b'D', b"block1"));
+        buf.extend(packet(b'D', b"block2"));
+        buf
+    }
+
+    fn dump() -> Vec<u8> {
+        dump_with(&header())
+    }
+
+    fn validate(data: &[u8]) -> anyhow::Result<super::DumpInfo> {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("edgedb.dump");
+        fs::write(&path, data).unwrap();
+        validate_file(&path)
+    }
+
+    #[test]
+    fn test_valid() {
+        let info = validate(&dump()).unwrap();
+        assert_eq!(info.dump_version, 1);
+        assert_eq!(info.server_version.as_deref(), Some("1-alpha5"));
+        assert_eq!(info.protocol, (0, 8));
+        assert_eq!(info.types, 2);
+        assert_eq!(info.blocks, 2);
+        assert_eq!(info.data_size, 12);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let data = dump();
+        let err = validate(&data[..data.len()-3]).unwrap_err();
+        assert!(format!("{:#}", err).contains("truncated"), "{:#}", err);
+        let err = validate(&data[..20]).unwrap_err();
+        assert!(format!("{:#}", err).contains("file header"), "{:#}", err);
+        // packet itself is intact, but the header in it is cut short
+        let header = header();
+        let err = validate(&dump_with(&header[..header.len()-2]))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "dump header is truncated");
+        // length of the packet is larger than the file
+        let mut data = dump();
+        data[17+8+1+20..17+8+1+20+4].copy_from_slice(&u32::MAX.to_be_bytes());
+        let err = validate(&data).unwrap_err();
+        assert!(format!("{:#}", err).contains("truncated"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_corrupted() {
+        let mut data = dump();
+        let last = data.len() - 1;
+        data[last] ^= 0xFF;
+        let err = validate(&data).unwrap_err();
+        assert!(format!("{:#}", err).contains("checksum mismatch"),
+                "{:#}", err);
+
+        let mut data = dump();
+        // first byte of the header packet data
+        data[17+8+1+20+4] ^= 0xFF;
+        let err = validate(&data).unwrap_err();
+        assert!(format!("{:#}", err).contains("checksum mismatch"),
+                "{:#}", err);
+
+        let mut data = dump();
+        data[0] = b'X';
+        let err = validate(&data).unwrap_err();
+        assert_eq!(err.to_string(), "file is not an EdgeDB dump");
+
+        let mut data = dump();
+        data[17+7] = 99;
+        let err = validate(&data).unwrap_err();
+        assert_eq!(err.to_string(), "unsupported dump version 99");
+    }
+}
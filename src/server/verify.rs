@@ -0,0 +1,140 @@
+use std::collections::BTreeMap;
+
+use async_std::stream::StreamExt;
+use edgeql_parser::helpers::{quote_string, quote_name};
+use edgedb_client as client;
+use edgedb_client::client::Connection;
+use edgedb_protocol::value::Value;
+
+
+/// Number of objects of each user type, per database
+pub type Fingerprint = BTreeMap<String, BTreeMap<String, i64>>;
+
+
+async fn query_strings(cli: &mut Connection, query: &str)
+    -> anyhow::Result<Vec<String>>
+{
+    let mut items = cli.query::<String>(query, &Value::empty_tuple()).await?;
+    let mut result = Vec::new();
+    while let Some(item) = items.next().await.transpose()? {
+        result.push(item);
+    }
+    Ok(result)
+}
+
+async fn count(cli: &mut Connection, type_name: &str) -> anyhow::Result<i64> {
+    let name = type_name.split("::")
+        .map(|part| quote_name(part).to_string())
+        .collect::<Vec<_>>()
+        .join("::");
+    let mut items = cli.query::<i64>(
+        &format!("SELECT count({})", name),
+        &Value::empty_tuple(),
+    ).await?;
+    let mut result = 0;
+    while let Some(num) = items.next().await.transpose()? {
+        result = num;
+    }
+    Ok(result)
+}
+
+async fn database_fingerprint(cli: &mut Connection)
+    -> anyhow::Result<BTreeMap<String, i64>>
+{
+    let mut result = BTreeMap::new();
+    let modules = query_strings(cli,
+        "SELECT schema::Module.name FILTER NOT schema::Module.builtin").await?;
+    for module in modules {
+        let types = query_strings(cli, &format!(
+            "SELECT schema::ObjectType.name \
+             FILTER schema::ObjectType.name LIKE {}",
+            quote_string(&format!("{}::%", module)))).await?;
+        for type_name in types {
+            let num = count(cli, &type_name).await?;
+            result.insert(type_name, num);
+        }
+    }
+    Ok(result)
+}
+
+/// Returns true if there are no databases except the default one, and no
+/// user schema or objects in it
+pub async fn is_empty(cli: &mut Connection) -> anyhow::Result<bool> {
+    let databases = query_strings(cli, "SELECT sys::Database.name").await?;
+    if databases.iter().any(|db| db != "edgedb" && db != "edgedb0") {
+        return Ok(false);
+    }
+    let mut items = cli.query::<i64>(r###"SELECT
+            count(
+                schema::Module
+                FILTER NOT .builtin AND NOT .name = "default"
+            ) + count(
+                schema::Object
+                FILTER .name LIKE "default::%"
+            )
+        "###, &Value::empty_tuple()).await?;
+    let mut result = 0;
+    while let Some(num) = items.next().await.transpose()? {
+        result = num;
+    }
+    Ok(result == 0)
+}
+
+pub async fn fingerprint(cli: &mut Connection, conn_params: &client::Builder)
+    -> anyhow::Result<Fingerprint>
+{
+    let mut result = BTreeMap::new();
+    let databases = query_strings(cli, "SELECT sys::Database.name").await?;
+    let mut conn_params = conn_params.clone();
+    for database in databases {
+        if database == "edgedb0" { continue; }
+        let mut db_conn = conn_params.database(&database).connect().await?;
+        let counts = database_fingerprint(&mut db_conn).await?;
+        result.insert(database, counts);
+    }
+    Ok(result)
+}
+
+/// Returns DDL of the schema of every database
+pub async fn schemas(cli: &mut Connection, conn_params: &client::Builder)
+    -> anyhow::Result<BTreeMap<String, String>>
+{
+    let mut result = BTreeMap::new();
+    let databases = query_strings(cli, "SELECT sys::Database.name").await?;
+    let mut conn_params = conn_params.clone();
+    for database in databases {
+        if database == "edgedb0" { continue; }
+        let mut db_conn = conn_params.database(&database).connect().await?;
+        let ddl = query_strings(&mut db_conn, "DESCRIBE SCHEMA").await?;
+        result.insert(database, ddl.join("\n"));
+    }
+    Ok(result)
+}
+
+pub fn compare(before: &Fingerprint, after: &Fingerprint) -> Vec<String> {
+    let mut errors = Vec::new();
+    for (database, types) in before {
+        let restored = match after.get(database) {
+            Some(restored) => restored,
+            None => {
+                errors.push(format!("database {:?} is missing", database));
+                continue;
+            }
+        };
+        for (type_name, num) in types {
+            match restored.get(type_name) {
+                Some(new_num) if new_num == num => {}
+                Some(new_num) => {
+                    errors.push(format!(
+                        "{}: {}: {} objects before dump, {} after restore",
+                        database, type_name, num, new_num));
+                }
+                None => {
+                    errors.push(format!("{}: type {} is missing",
+                        database, type_name));
+                }
+            }
+        }
+    }
+    errors
+}
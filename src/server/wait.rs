@@ -0,0 +1,78 @@
+use std::time::{Duration, Instant};
+
+use async_std::future::timeout;
+use async_std::task;
+
+use crate::commands::ExitCode;
+use crate::server::control::{self, WAIT_TIMEOUT};
+use crate::server::options::{ConnectMethod, Start, Wait};
+use crate::server::ping::query_version;
+use crate::server::upgrade::{all_instances, Instance};
+
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Queries the instance until it responds or the `deadline` expires,
+/// returns the version reported by the server
+pub async fn wait_ready(inst: &Instance, deadline: Duration,
+    connect_method: Option<ConnectMethod>)
+    -> anyhow::Result<String>
+{
+    let started = Instant::now();
+    loop {
+        let remaining = deadline.checked_sub(started.elapsed())
+            .unwrap_or(Duration::new(0, 0));
+        let result = timeout(remaining,
+                             query_version(inst, connect_method)).await;
+        let error = match result {
+            Ok(Ok((_, version))) => return Ok(version),
+            Ok(Err(e)) => e,
+            Err(_) => anyhow::anyhow!("no response"),
+        };
+        if started.elapsed() + POLL_INTERVAL >= deadline {
+            anyhow::bail!("instance {:?} is not ready after {}: {:#}",
+                inst.name, humantime::format_duration(deadline), error);
+        }
+        log::debug!("Instance {:?} is not ready yet: {:#}", inst.name, error);
+        task::sleep(POLL_INTERVAL).await;
+    }
+}
+
+fn find_instance(name: &str) -> anyhow::Result<Instance> {
+    all_instances()?.into_iter()
+        .find(|inst| inst.name == name)
+        .ok_or_else(|| anyhow::anyhow!("Instance {:?} not found", name))
+}
+
+/// Waits until the started server accepts queries (`start --wait`)
+pub fn wait_started(inst: &Instance, timeout: Option<Duration>)
+    -> anyhow::Result<()>
+{
+    task::block_on(wait_ready(inst, timeout.unwrap_or(WAIT_TIMEOUT), None))?;
+    Ok(())
+}
+
+/// Starts the instance, and waits for it with `--wait`
+pub fn start(options: &Start) -> anyhow::Result<()> {
+    control::get_instance(&options.name)?.start(options)?;
+    if options.wait {
+        wait_started(&find_instance(&options.name)?, options.wait_timeout)?;
+    }
+    Ok(())
+}
+
+pub fn wait(options: &Wait) -> anyhow::Result<()> {
+    let inst = find_instance(&options.name)?;
+    let result = task::block_on(
+        wait_ready(&inst, options.timeout, options.connect_method));
+    match result {
+        Ok(version) => {
+            eprintln!("Instance {:?} is ready: EdgeDB {}", inst.name, version);
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("{:#}", e);
+            Err(ExitCode::new(1).into())
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use crate::server::control::read_metadata;
+use crate::server::detect;
+use crate::server::init::data_path;
+use crate::server::options::Which;
+
+
+pub fn which(options: &Which) -> anyhow::Result<()> {
+    let os = detect::current_os()?;
+    let avail = os.get_available_methods()?;
+    if options.all {
+        let methods = avail.instantiate_all(&*os, true)?;
+        for (meth, method) in &methods {
+            for ver in method.installed_versions()? {
+                let path = method.get_server_path(&ver.major_version)?;
+                println!("{}\t{}-{}\t{}\t{}", ver.major_version,
+                    ver.version, ver.revision, meth.title(), path.display());
+            }
+        }
+        return Ok(());
+    }
+    let dir = data_path(false)?.join(&options.name);
+    if !dir.exists() {
+        anyhow::bail!("No instance {0:?} found. Run:\n  \
+            edgedb server init {0}", options.name);
+    }
+    let meta = read_metadata(&dir)?;
+    let method = os.make_method(&meta.method, &avail)?;
+    let path = method.get_server_path(&meta.version)?;
+    if !path.exists() {
+        log::warn!("Server binary {} for version {} does not exist",
+            path.display(), meta.version);
+    }
+    println!("{}", path.display());
+    Ok(())
+}